@@ -0,0 +1,552 @@
+// Generates the instructions of the reference implementation from our own, for
+// `string.dump` to write chunks loaded from source in the format of `luac`. Both use the
+// same registers; the few instructions that have no counterpart of their own are
+// spelled out with the spare registers after them.
+
+use std::collections::HashMap;
+
+use nom_lua53::op::{BinOp, UnOp};
+
+use types::LuaValue;
+use {LuaError, Result};
+use super::luac::*;
+use super::{Count, Instr, Proto, Reg};
+
+/// Registers a function can use, as the `A` argument only has 8 bits.
+const MAX_REGISTERS: usize = 255;
+
+struct Generator<'p> {
+    source: &'p Proto,
+    proto: Prototype,
+    /// Indices of the constants, which the names of globals and fields are added to.
+    constant_indices: HashMap<LuaValue, usize>,
+    /// The first instruction generated for each of ours, the targets of their jumps.
+    starts: Vec<usize>,
+    /// The jumps to point at their target once it is generated, with the index of the
+    /// instruction they go to among ours.
+    jumps: Vec<(usize, usize)>,
+    /// The registers after the ones of the source, and how many of them are used.
+    scratch: Reg,
+    scratch_used: usize,
+    line: isize,
+}
+
+fn arithmetic_opcode(op: &BinOp) -> Option<u32> {
+    Some(match *op {
+        BinOp::Plus => OP_ADD,
+        BinOp::Minus => OP_SUB,
+        BinOp::Mul => OP_MUL,
+        BinOp::Mod => OP_MOD,
+        BinOp::Pow => OP_POW,
+        BinOp::Div => OP_DIV,
+        BinOp::IntDiv => OP_IDIV,
+        BinOp::BitAnd => OP_BAND,
+        BinOp::BitOr => OP_BOR,
+        BinOp::BitXor => OP_BXOR,
+        BinOp::BitShl => OP_SHL,
+        BinOp::BitShr => OP_SHR,
+        _ => return None,
+    })
+}
+
+/// The test instruction of a comparison, the result it expects and whether its operands
+/// are swapped.
+fn comparison(op: &BinOp) -> Option<(u32, usize, bool)> {
+    Some(match *op {
+        BinOp::Eq => (OP_EQ, 1, false),
+        BinOp::Neq => (OP_EQ, 0, false),
+        BinOp::Lt => (OP_LT, 1, false),
+        BinOp::Leq => (OP_LE, 1, false),
+        BinOp::Gt => (OP_LT, 1, true),
+        BinOp::Geq => (OP_LE, 1, true),
+        _ => return None,
+    })
+}
+
+fn unary_opcode(op: &UnOp) -> u32 {
+    match *op {
+        UnOp::Minus => OP_UNM,
+        UnOp::BitNot => OP_BNOT,
+        UnOp::BoolNot => OP_NOT,
+        UnOp::Length => OP_LEN,
+    }
+}
+
+/// Encodes a count of values as a `B` or `C` argument, where 0 stands for all of them.
+fn encode_count(count: Count) -> usize {
+    match count {
+        Count::Fixed(n) => n + 1,
+        Count::Multi => 0,
+    }
+}
+
+fn unsupported() -> LuaError {
+    LuaError::OtherError("unable to dump given function".to_owned())
+}
+
+impl<'p> Generator<'p> {
+    fn emit(&mut self, i: u32) -> usize {
+        self.proto.code.push(i);
+        self.proto.line_info.push(self.line);
+        self.proto.code.len() - 1
+    }
+
+    /// Emits a jump to our instruction `target`.
+    fn jump(&mut self, target: usize) {
+        let at = self.emit(asbx(OP_JMP, 0, 0));
+        self.jumps.push((at, target));
+    }
+
+    /// The spare register `n`.
+    fn scratch(&mut self, n: usize) -> Reg {
+        self.scratch_used = self.scratch_used.max(n + 1);
+        self.scratch + n
+    }
+
+    fn constant(&mut self, value: &LuaValue) -> usize {
+        if let Some(&k) = self.constant_indices.get(value) {
+            return k;
+        }
+        self.proto.constants.push(value.clone());
+        let k = self.proto.constants.len() - 1;
+        self.constant_indices.insert(value.clone(), k);
+        k
+    }
+
+    fn load_constant(&mut self, dst: Reg, k: usize) {
+        if k <= MAX_BX {
+            self.emit(abx(OP_LOADK, dst, k));
+        } else {
+            self.emit(abx(OP_LOADKX, dst, 0));
+            self.emit(ax(OP_EXTRAARG, k));
+        }
+    }
+
+    /// An `RK` argument for the name `n`, which only goes in a spare register when there
+    /// are too many constants.
+    fn name_rk(&mut self, n: usize) -> usize {
+        let k = self.constant(&self.source.names[n].key);
+        if k <= MAX_RK_INDEX {
+            return k | RK_CONSTANT;
+        }
+        let reg = self.scratch(0);
+        self.load_constant(reg, k);
+        reg
+    }
+
+    fn instruction(&mut self, instr: &Instr) -> Result<()> {
+        match *instr {
+            Instr::Statement(line) => {
+                if let Some(line) = line {
+                    self.line = line;
+                }
+            }
+            Instr::LoadNil(a) => {
+                self.emit(abc(OP_LOADNIL, a, 0, 0));
+            }
+            Instr::LoadBool(a, b) => {
+                self.emit(abc(OP_LOADBOOL, a, b as usize, 0));
+            }
+            Instr::LoadConst(a, k) => self.load_constant(a, k),
+            Instr::Move(a, b) => {
+                self.emit(abc(OP_MOVE, a, b, 0));
+            }
+            Instr::GetUpval(a, n) => {
+                self.emit(abc(OP_GETUPVAL, a, n, 0));
+            }
+            Instr::SetUpval(n, a) => {
+                self.emit(abc(OP_SETUPVAL, a, n, 0));
+            }
+            Instr::GetGlobal(a, n) => {
+                let key = self.name_rk(n);
+                self.emit(abc(OP_GETTABUP, a, 0, key));
+            }
+            Instr::SetGlobal(n, a) => {
+                let key = self.name_rk(n);
+                self.emit(abc(OP_SETTABUP, 0, key, a));
+            }
+            Instr::GetIndex(a, b, c) => {
+                self.emit(abc(OP_GETTABLE, a, b, c));
+            }
+            // The table being built has no metatable yet, so a plain `SETTABLE` is raw.
+            Instr::SetIndex(a, b, c) | Instr::RawSet(a, b, c) => {
+                self.emit(abc(OP_SETTABLE, a, b, c));
+            }
+            Instr::NewTable(a, n) => {
+                self.emit(abc(OP_NEWTABLE, a, int_to_fb(n), 0));
+            }
+            Instr::SetList(a, count, offset) => {
+                let items = match count {
+                    Count::Fixed(n) if n <= MAX_C => n,
+                    Count::Multi => 0,
+                    Count::Fixed(_) => return Err(unsupported()),
+                };
+                if offset % FIELDS_PER_FLUSH != 0 {
+                    return Err(unsupported());
+                }
+                let batch = offset / FIELDS_PER_FLUSH + 1;
+                if batch <= MAX_C {
+                    self.emit(abc(OP_SETLIST, a, items, batch));
+                } else {
+                    self.emit(abc(OP_SETLIST, a, items, 0));
+                    self.emit(ax(OP_EXTRAARG, batch));
+                }
+            }
+            Instr::Binary(ref op, a, b, c) => self.binary(op, a, b, c)?,
+            Instr::Unary(ref op, a, b) => {
+                self.emit(abc(unary_opcode(op), a, b, 0));
+            }
+            Instr::Jump(target) => self.jump(target),
+            // `TEST` runs the jump after it when the truth value of the register is `C`.
+            Instr::JumpIf(a, value, target) => {
+                self.emit(abc(OP_TEST, a, 0, value as usize));
+                self.jump(target);
+            }
+            Instr::Method(a, b, n) => {
+                let key = self.name_rk(n);
+                self.emit(abc(OP_SELF, a, b, key));
+            }
+            Instr::Call(a, args, results, _) => {
+                self.emit(abc(OP_CALL, a, encode_count(args), encode_count(results)));
+            }
+            Instr::VarArgs(a, count) => {
+                self.emit(abc(OP_VARARG, a, encode_count(count), 0));
+            }
+            // `TFORLOOP` goes on with the body when the first variable isn't nil, and the
+            // jump after it leaves the loop otherwise.
+            Instr::ForIn(a, vars, exit) => {
+                self.emit(abc(OP_TFORCALL, a, 0, vars));
+                self.emit(asbx(OP_TFORLOOP, a + 2, 1));
+                self.jump(exit);
+            }
+            Instr::ForPrep(a, target) => {
+                let at = self.emit(asbx(OP_FORPREP, a, 0));
+                self.jumps.push((at, target));
+            }
+            Instr::ForLoop(a, target) => {
+                let at = self.emit(asbx(OP_FORLOOP, a, 0));
+                self.jumps.push((at, target));
+            }
+            Instr::Return(a, count) => {
+                self.emit(abc(OP_RETURN, a, encode_count(count), 0));
+            }
+            Instr::NotImplemented => return Err(unsupported()),
+        }
+        Ok(())
+    }
+
+    fn binary(&mut self, op: &BinOp, a: Reg, b: Reg, c: Reg) -> Result<()> {
+        if let Some(opcode) = arithmetic_opcode(op) {
+            self.emit(abc(opcode, a, b, c));
+        } else if let Some((opcode, expected, swapped)) = comparison(op) {
+            let (b, c) = if swapped { (c, b) } else { (b, c) };
+            self.emit(abc(opcode, expected, b, c));
+            self.emit(asbx(OP_JMP, 0, 1));
+            self.emit(abc(OP_LOADBOOL, a, 0, 1));
+            self.emit(abc(OP_LOADBOOL, a, 1, 0));
+        } else if *op == BinOp::Concat {
+            // `CONCAT` takes a range of registers.
+            let (b, c) = if c == b + 1 {
+                (b, c)
+            } else {
+                let (first, second) = (self.scratch(0), self.scratch(1));
+                self.emit(abc(OP_MOVE, first, b, 0));
+                self.emit(abc(OP_MOVE, second, c, 0));
+                (first, second)
+            };
+            self.emit(abc(OP_CONCAT, a, b, c));
+        } else {
+            // `and` and `or` are compiled to jumps.
+            return Err(unsupported());
+        }
+        Ok(())
+    }
+
+    fn generate(mut self) -> Result<Prototype> {
+        let source = self.source;
+        for instr in source.code.iter() {
+            self.starts.push(self.proto.code.len());
+            self.instruction(instr)?;
+        }
+        // Falling off the end of a chunk returns nothing.
+        self.starts.push(self.proto.code.len());
+        self.emit(abc(OP_RETURN, 0, 1, 0));
+        for &(at, target) in self.jumps.iter() {
+            let offset = self.starts[target] as isize - (at as isize + 1);
+            let i = self.proto.code[at];
+            self.proto.code[at] = asbx(opcode(i), arg_a(i), offset);
+        }
+        for local in source.locals.iter() {
+            self.proto.locals.push(LocalVar {
+                name: local.name.clone().into_bytes(),
+                start_pc: self.starts[local.start_pc],
+                end_pc: self.starts[local.end_pc],
+            });
+        }
+        let registers = self.scratch + self.scratch_used;
+        if registers > MAX_REGISTERS {
+            return Err(unsupported());
+        }
+        self.proto.max_stack_size = registers.max(2) as u8;
+        Ok(self.proto)
+    }
+}
+
+/// Generates the main function of a chunk compiled from source named `chunkname`. Fails
+/// on function definitions, which the compiler doesn't support yet.
+pub fn generate(source: &Proto, chunkname: &str) -> Result<Prototype> {
+    let mut constant_indices = HashMap::new();
+    for (k, constant) in source.constants.iter().enumerate() {
+        // NaN isn't equal to itself, so it can't be looked up.
+        constant_indices.entry(constant.clone()).or_insert(k);
+    }
+    let generator = Generator {
+        source,
+        proto: Prototype {
+            source: Some(chunkname.as_bytes().to_vec()),
+            line_defined: source.line_defined,
+            last_line_defined: source.last_line_defined,
+            num_params: source.params as u8,
+            is_vararg: source.is_vararg as u8,
+            constants: source.constants.clone(),
+            upvalues: vec![Upvalue {
+                in_stack: true,
+                index: 0,
+                name: Some(b"_ENV".to_vec()),
+            }],
+            ..Prototype::default()
+        },
+        constant_indices,
+        starts: Vec::with_capacity(source.code.len() + 1),
+        jumps: Vec::new(),
+        scratch: source.registers,
+        scratch_used: 0,
+        line: 0,
+    };
+    generator.generate()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    use nom_lua53::{Args, Assignment, Exp, ExpOrVarName, ExpSuffix, Field, ForIn, FunctionCall, IfThenElse,
+                    LVarAssignment, PrefixExp, Statement, WhileBlock};
+    use nom_lua53::name::VarName;
+    use nom_lua53::num::Numeral;
+    use nom_lua53::stat_expr_types::Block;
+    use nom_lua53::string::StringLit;
+    use std::borrow::Cow;
+
+    use bytecode::{compile, execute, translate, Frame};
+    use types::{LuaState, Number};
+
+    fn var(name: &'static [u8]) -> Exp<'static> {
+        Exp::PrefixExp(Box::new(PrefixExp {
+            prefix: ExpOrVarName::VarName(VarName(name)),
+            suffix_chain: Vec::new(),
+        }))
+    }
+
+    fn int(i: isize) -> Exp<'static> {
+        Exp::Num(Numeral::Int(i))
+    }
+
+    fn call(name: &'static [u8], args: Vec<Exp<'static>>) -> PrefixExp<'static> {
+        PrefixExp {
+            prefix: ExpOrVarName::VarName(VarName(name)),
+            suffix_chain: vec![ExpSuffix::FuncCall(FunctionCall {
+                method: None,
+                args: Args::ExpList(args),
+            })],
+        }
+    }
+
+    /// Runs a block both as compiled and through a dumped chunk.
+    fn run_both(block: &Block, ctx: &LuaState) -> (Vec<LuaValue>, Vec<LuaValue>) {
+        let run = |proto: &Proto| {
+            let env = RefCell::new(LuaValue::Table(ctx.globals()));
+            execute(proto, &Frame::new(proto, Vec::new()), &[env], ctx).unwrap()
+        };
+        let compiled = compile(block, &|_| None);
+        let direct = run(&compiled);
+        let proto = generate(&compiled, "=test").unwrap();
+        let loaded = undump(&dump(&proto, false), "=test").unwrap();
+        assert_eq!(loaded, proto);
+        (direct, run(&translate::translate(&loaded).unwrap()))
+    }
+
+    #[test]
+    fn test_dumped_expressions() {
+        let ctx = LuaState::new();
+        // local a, b = 6, 7; t = {a * b, x = "y" .. a}; return t[1], t.x, a < b and b or a, not a
+        let block = Block {
+            stmts: vec![
+                Statement::LVarAssign(LVarAssignment {
+                    vars: vec![VarName(b"a"), VarName(b"b")],
+                    vals: Some(vec![int(6), int(7)]),
+                }),
+                Statement::Assignment(Assignment {
+                    vars: vec![PrefixExp {
+                        prefix: ExpOrVarName::VarName(VarName(b"t")),
+                        suffix_chain: Vec::new(),
+                    }],
+                    vals: vec![Exp::Table(vec![
+                        Field::PosAssign(Exp::BinExp(Box::new(var(b"a")), BinOp::Mul, Box::new(var(b"b")))),
+                        Field::NameAssign(
+                            VarName(b"x"),
+                            Exp::BinExp(
+                                Box::new(Exp::Str(StringLit(Cow::from(&b"y"[..])))),
+                                BinOp::Concat,
+                                Box::new(var(b"a")),
+                            ),
+                        ),
+                    ])],
+                }),
+            ],
+            ret_stmt: Some(vec![
+                Exp::PrefixExp(Box::new(PrefixExp {
+                    prefix: ExpOrVarName::VarName(VarName(b"t")),
+                    suffix_chain: vec![ExpSuffix::TableIdx(int(1))],
+                })),
+                Exp::PrefixExp(Box::new(PrefixExp {
+                    prefix: ExpOrVarName::VarName(VarName(b"t")),
+                    suffix_chain: vec![ExpSuffix::TableDot(VarName(b"x"))],
+                })),
+                Exp::BinExp(
+                    Box::new(Exp::BinExp(
+                        Box::new(Exp::BinExp(Box::new(var(b"a")), BinOp::Lt, Box::new(var(b"b")))),
+                        BinOp::BoolAnd,
+                        Box::new(var(b"b")),
+                    )),
+                    BinOp::BoolOr,
+                    Box::new(var(b"a")),
+                ),
+                Exp::UnExp(UnOp::BoolNot, Box::new(var(b"a"))),
+            ]),
+        };
+        let (direct, translated) = run_both(&block, &ctx);
+        assert_eq!(
+            direct,
+            vec![
+                LuaValue::Number(Number::Int(42)),
+                LuaValue::Str("y6".into()),
+                LuaValue::Number(Number::Int(7)),
+                LuaValue::Boolean(false),
+            ]
+        );
+        assert_eq!(translated, direct);
+    }
+
+    #[test]
+    fn test_dumped_loops() {
+        let ctx = LuaState::new();
+        ctx.register_native("numbers", |_ctx: &LuaState, args: &[LuaValue]| {
+            Ok(vec![match args[1] {
+                LuaValue::Number(Number::Int(i)) if i < 5 => LuaValue::Number(Number::Int(i + 1)),
+                _ => LuaValue::Nil,
+            }])
+        });
+        let seen = Rc::new(RefCell::new(Vec::new()));
+        let record = seen.clone();
+        ctx.register_native("record", move |_ctx: &LuaState, args: &[LuaValue]| {
+            record.borrow_mut().push(args[0].clone());
+            Ok(Vec::new())
+        });
+        // for i in numbers, nil, 0 do if i == 4 then break end record(i) end
+        let block = Block {
+            stmts: vec![Statement::ForIn(ForIn {
+                vars: vec![VarName(b"i")],
+                exps: vec![var(b"numbers"), Exp::Nil, int(0)],
+                do_blk: Block {
+                    stmts: vec![
+                        Statement::Ite(IfThenElse {
+                            cond: Exp::BinExp(Box::new(var(b"i")), BinOp::Eq, Box::new(int(4))),
+                            then_blk: Block {
+                                stmts: vec![Statement::Break],
+                                ret_stmt: None,
+                            },
+                            elseifs: Vec::new(),
+                            else_blk: None,
+                        }),
+                        Statement::FuncCall(call(b"record", vec![var(b"i")])),
+                    ],
+                    ret_stmt: None,
+                },
+            })],
+            ret_stmt: None,
+        };
+        let (direct, translated) = run_both(&block, &ctx);
+        assert!(direct.is_empty() && translated.is_empty());
+        let ints = |values: &[isize]| values.iter().map(|&i| LuaValue::Number(Number::Int(i))).collect::<Vec<_>>();
+        assert_eq!(*seen.borrow(), ints(&[1, 2, 3, 1, 2, 3]));
+    }
+
+    #[test]
+    fn test_damaged_dumps() {
+        // local a = 1; while a < 3 do a = a .. "" + 1 end; return a
+        let block = Block {
+            stmts: vec![
+                Statement::LVarAssign(LVarAssignment {
+                    vars: vec![VarName(b"a")],
+                    vals: Some(vec![int(1)]),
+                }),
+                Statement::While(WhileBlock {
+                    cond: Exp::BinExp(Box::new(var(b"a")), BinOp::Lt, Box::new(int(3))),
+                    block: Block {
+                        stmts: vec![Statement::Assignment(Assignment {
+                            vars: vec![PrefixExp {
+                                prefix: ExpOrVarName::VarName(VarName(b"a")),
+                                suffix_chain: Vec::new(),
+                            }],
+                            vals: vec![Exp::BinExp(
+                                Box::new(Exp::BinExp(
+                                    Box::new(var(b"a")),
+                                    BinOp::Concat,
+                                    Box::new(Exp::Str(StringLit(Cow::from(&b""[..])))),
+                                )),
+                                BinOp::Plus,
+                                Box::new(int(1)),
+                            )],
+                        })],
+                        ret_stmt: None,
+                    },
+                }),
+            ],
+            ret_stmt: Some(vec![var(b"a")]),
+        };
+        let data = dump(&generate(&compile(&block, &|_| None), "=test").unwrap(), false);
+        let load = |data: &[u8]| undump(data, "=test").and_then(|proto| translate::translate(&proto));
+        assert!(load(&data).is_ok());
+        for len in 0..data.len() {
+            assert!(load(&data[..len]).is_err(), "prefix of {} bytes was loaded", len);
+        }
+        // Damaged chunks may still be valid, but can't be run as they may never end.
+        for i in 0..data.len() {
+            for &mask in &[0x01, 0x80, 0xff] {
+                let mut damaged = data.clone();
+                damaged[i] ^= mask;
+                let _ = load(&damaged);
+            }
+        }
+    }
+
+    #[test]
+    fn test_undumpable_code() {
+        let generated = |code: Vec<Instr>, registers: usize| {
+            let proto = Proto {
+                code,
+                registers,
+                ..Proto::default()
+            };
+            generate(&proto, "=test").map(|_| ())
+        };
+        assert!(generated(vec![Instr::NotImplemented], 0).is_err());
+        assert!(generated(vec![Instr::Binary(BinOp::BoolAnd, 0, 0, 0)], 1).is_err());
+        // A concatenation of registers that don't follow each other needs two more.
+        assert!(generated(vec![Instr::Binary(BinOp::Concat, 0, 2, 0)], 253).is_ok());
+        assert!(generated(vec![Instr::Binary(BinOp::Concat, 0, 2, 0)], 254).is_err());
+    }
+}
@@ -0,0 +1,830 @@
+// Compiles the syntax tree of a chunk to instructions. Registers are allocated as a
+// stack: each expression gets the first free register, and the ones it used for its
+// operands are freed once it is done. Locals are resolved along the way: each holds a
+// register for as long as it is in scope, and the other names are globals, fields of
+// `_ENV`.
+
+use std::collections::HashMap;
+use std::iter;
+
+use nom_lua53::{Args, Exp, ExpOrVarName, ExpSuffix, Field, ForIn, FunctionCall, IfThenElse, PrefixExp,
+                RepeatBlock, Statement, WhileBlock};
+use nom_lua53::name::VarName;
+use nom_lua53::num::Numeral;
+use nom_lua53::op::BinOp;
+use nom_lua53::stat_expr_types::Block;
+
+use types::{LuaValue, Number};
+use var_to_string;
+use super::{CallTarget, Count, Instr, LocalVar, Name, Proto, Reg};
+
+struct Compiler<'l> {
+    proto: Proto,
+    name_indices: HashMap<String, usize>,
+    /// The locals in scope, the innermost last, as indices in the locals of the proto.
+    actives: Vec<usize>,
+    /// First register that isn't in use.
+    free: Reg,
+    /// The jumps of the `break` statements of each loop being compiled, to point at its
+    /// end.
+    loops: Vec<Vec<usize>>,
+    line_of: &'l dyn Fn(&Statement) -> Option<isize>,
+}
+
+/// Compiles the main block of a chunk. `line_of` tells which line a statement starts on,
+/// when it is known.
+pub fn compile(block: &Block, line_of: &dyn Fn(&Statement) -> Option<isize>) -> Proto {
+    let mut compiler = Compiler {
+        // A chunk takes its arguments as `...`.
+        proto: Proto {
+            is_vararg: true,
+            ..Proto::default()
+        },
+        name_indices: HashMap::new(),
+        actives: Vec::new(),
+        free: 0,
+        loops: Vec::new(),
+        line_of,
+    };
+    compiler.block(block);
+    compiler.proto
+}
+
+/// Splits the suffixes of a prefix expression ending with a call into the call and the
+/// suffixes giving the function.
+fn call_parts<'s, 'a>(suffixes: &'s [ExpSuffix<'a>]) -> Option<(&'s FunctionCall<'a>, &'s [ExpSuffix<'a>])> {
+    match suffixes.split_last() {
+        Some((&ExpSuffix::FuncCall(ref call), rest)) => Some((call, rest)),
+        _ => None,
+    }
+}
+
+impl<'l> Compiler<'l> {
+    fn emit(&mut self, instr: Instr) -> usize {
+        self.proto.code.push(instr);
+        self.proto.code.len() - 1
+    }
+
+    /// Points the jump emitted at `at` to the next instruction.
+    fn patch(&mut self, at: usize) {
+        let target = self.proto.code.len();
+        match self.proto.code[at] {
+            Instr::Jump(ref mut t) | Instr::JumpIf(_, _, ref mut t) | Instr::ForIn(_, _, ref mut t) => *t = target,
+            ref other => panic!("not a jump: {:?}", other),
+        }
+    }
+
+    fn alloc(&mut self) -> Reg {
+        let reg = self.free;
+        self.reserve(1);
+        reg
+    }
+
+    /// Marks `n` more registers as in use.
+    fn reserve(&mut self, n: usize) {
+        self.free += n;
+        if self.free > self.proto.registers {
+            self.proto.registers = self.free;
+        }
+    }
+
+    fn constant(&mut self, value: LuaValue) -> usize {
+        self.proto.constants.push(value);
+        self.proto.constants.len() - 1
+    }
+
+    fn name(&mut self, var: &VarName) -> usize {
+        let name = var_to_string(var);
+        if let Some(&index) = self.name_indices.get(&name) {
+            return index;
+        }
+        let index = self.proto.names.len();
+        self.proto.names.push(Name {
+            name: name.clone(),
+            key: LuaValue::Str(name.clone().into()),
+        });
+        self.name_indices.insert(name, index);
+        index
+    }
+
+    fn load_name(&mut self, var: &VarName, dst: Reg) {
+        let key = self.constant(LuaValue::Str(var_to_string(var).into()));
+        self.emit(Instr::LoadConst(dst, key));
+    }
+
+    /// Brings a local into scope from the next instruction on.
+    fn activate(&mut self, name: String, reg: Reg) {
+        let start_pc = self.proto.code.len();
+        self.actives.push(self.proto.locals.len());
+        self.proto.locals.push(LocalVar {
+            name,
+            reg,
+            start_pc,
+            end_pc: start_pc,
+        });
+    }
+
+    /// Ends the scope of the locals declared after the first `n` ones in scope.
+    fn close_scope(&mut self, n: usize) {
+        let end_pc = self.proto.code.len();
+        for index in self.actives.drain(n..) {
+            self.proto.locals[index].end_pc = end_pc;
+        }
+    }
+
+    /// The register of the innermost local in scope named `name`.
+    fn resolve(&self, name: &str) -> Option<Reg> {
+        self.actives
+            .iter()
+            .rev()
+            .map(|&index| &self.proto.locals[index])
+            .find(|local| local.name == name)
+            .map(|local| local.reg)
+    }
+
+    /// Loads a variable: a local in scope, the `_ENV` upvalue, or a global, which is a
+    /// field of the innermost `_ENV`.
+    fn get_var(&mut self, var: &VarName, dst: Reg) {
+        let free = self.free;
+        let name = var_to_string(var);
+        if let Some(reg) = self.resolve(&name) {
+            self.emit(Instr::Move(dst, reg));
+        } else if name == "_ENV" {
+            self.emit(Instr::GetUpval(dst, 0));
+        } else if let Some(env) = self.resolve("_ENV") {
+            let key = self.alloc();
+            self.load_name(var, key);
+            self.emit(Instr::GetIndex(dst, env, key));
+        } else {
+            let name = self.name(var);
+            self.emit(Instr::GetGlobal(dst, name));
+        }
+        self.free = free;
+    }
+
+    fn set_var(&mut self, var: &VarName, value: Reg) {
+        let free = self.free;
+        let name = var_to_string(var);
+        if let Some(reg) = self.resolve(&name) {
+            self.emit(Instr::Move(reg, value));
+        } else if name == "_ENV" {
+            self.emit(Instr::SetUpval(0, value));
+        } else if let Some(env) = self.resolve("_ENV") {
+            let key = self.alloc();
+            self.load_name(var, key);
+            self.emit(Instr::SetIndex(env, key, value));
+        } else {
+            let name = self.name(var);
+            self.emit(Instr::SetGlobal(name, value));
+        }
+        self.free = free;
+    }
+
+    fn block(&mut self, block: &Block) {
+        let (actives, free) = (self.actives.len(), self.free);
+        self.statements(block);
+        self.close_scope(actives);
+        self.free = free;
+    }
+
+    /// The statements of a block and its `return`. The locals they declare stay in
+    /// scope until the caller closes it.
+    fn statements(&mut self, block: &Block) {
+        for stmt in block.stmts.iter() {
+            self.statement(stmt);
+        }
+        if let Some(ref exps) = block.ret_stmt {
+            let free = self.free;
+            let (base, count) = self.exp_list(exps);
+            self.emit(Instr::Return(base, count));
+            self.free = free;
+        }
+    }
+
+    fn statement(&mut self, stmt: &Statement) {
+        let line = (self.line_of)(stmt);
+        self.emit(Instr::Statement(line));
+        let mut free = self.free;
+        match *stmt {
+            Statement::LVarAssign(ref ass) => {
+                // The values are computed in the registers of the locals, which only come
+                // into scope afterwards and keep them until the end of the block.
+                let base = match ass.vals {
+                    Some(ref exps) => self.exp_list_fixed(exps, ass.vars.len()),
+                    None => self.exp_list_fixed(&[], ass.vars.len()),
+                };
+                for (i, var) in ass.vars.iter().enumerate() {
+                    self.activate(var_to_string(var), base + i);
+                }
+                free = base + ass.vars.len();
+            }
+            Statement::Assignment(ref ass) => {
+                let base = self.exp_list_fixed(&ass.vals, ass.vars.len());
+                for (i, target) in ass.vars.iter().enumerate() {
+                    self.assign(target, base + i);
+                }
+            }
+            Statement::FuncCall(ref call) => match call_parts(&call.suffix_chain) {
+                Some((function, rest)) => {
+                    self.call(&call.prefix, rest, function, Count::Fixed(0));
+                }
+                None => {
+                    let dst = self.alloc();
+                    self.prefix_exp(&call.prefix, &call.suffix_chain, dst);
+                }
+            },
+            Statement::Do(ref blk) => self.block(blk),
+            Statement::Ite(ref ite) => self.if_then_else(ite),
+            Statement::While(ref blk) => self.while_loop(blk),
+            Statement::Repeat(ref blk) => self.repeat_loop(blk),
+            Statement::ForIn(ref for_in) => self.for_in(for_in),
+            Statement::Break => self.break_loop(),
+            Statement::Semicolon | Statement::Label(_) => {}
+            // Function definitions, numeric `for` and `goto` aren't supported yet, and
+            // fail when they are reached rather than being skipped.
+            Statement::Goto(_)
+            | Statement::ForRange(_)
+            | Statement::FuncDef(_)
+            | Statement::LFuncDef(_) => {
+                self.emit(Instr::NotImplemented);
+            }
+        }
+        self.free = free;
+    }
+
+    /// Stores the value in `value` into the variable or field `target`.
+    fn assign(&mut self, target: &PrefixExp, value: Reg) {
+        let free = self.free;
+        match (target.suffix_chain.split_last(), &target.prefix) {
+            (None, &ExpOrVarName::VarName(ref var)) => self.set_var(var, value),
+            (Some((&ExpSuffix::TableDot(ref field), rest)), _) => {
+                let object = self.alloc();
+                self.prefix_exp(&target.prefix, rest, object);
+                let key = self.alloc();
+                self.load_name(field, key);
+                self.emit(Instr::SetIndex(object, key, value));
+            }
+            (Some((&ExpSuffix::TableIdx(ref exp), rest)), _) => {
+                let object = self.alloc();
+                self.prefix_exp(&target.prefix, rest, object);
+                let key = self.alloc();
+                self.exp_to(exp, key);
+                self.emit(Instr::SetIndex(object, key, value));
+            }
+            // The parser doesn't let anything else be assigned to.
+            _ => {
+                self.emit(Instr::NotImplemented);
+            }
+        }
+        self.free = free;
+    }
+
+    fn if_then_else(&mut self, ite: &IfThenElse) {
+        let mut exits = Vec::new();
+        let branches = iter::once((&ite.cond, &ite.then_blk)).chain(ite.elseifs.iter().map(|&(ref c, ref b)| (c, b)));
+        for (cond, block) in branches {
+            let free = self.free;
+            let test = self.operand(cond);
+            self.free = free;
+            let skip = self.emit(Instr::JumpIf(test, false, 0));
+            self.block(block);
+            exits.push(self.emit(Instr::Jump(0)));
+            self.patch(skip);
+        }
+        if let Some(ref block) = ite.else_blk {
+            self.block(block);
+        }
+        for exit in exits {
+            self.patch(exit);
+        }
+    }
+
+    fn begin_loop(&mut self) {
+        self.loops.push(Vec::new());
+    }
+
+    fn end_loop(&mut self) {
+        let breaks = self.loops.pop().expect("no loop to end");
+        for jump in breaks {
+            self.patch(jump);
+        }
+    }
+
+    fn while_loop(&mut self, blk: &WhileBlock) {
+        let start = self.proto.code.len();
+        let free = self.free;
+        let test = self.operand(&blk.cond);
+        self.free = free;
+        let exit = self.emit(Instr::JumpIf(test, false, 0));
+        self.begin_loop();
+        self.block(&blk.block);
+        self.emit(Instr::Jump(start));
+        self.patch(exit);
+        self.end_loop();
+    }
+
+    /// The condition of a `repeat` loop sees the locals of its body, so it is evaluated
+    /// before leaving its scope.
+    fn repeat_loop(&mut self, blk: &RepeatBlock) {
+        let start = self.proto.code.len();
+        self.begin_loop();
+        let (actives, free) = (self.actives.len(), self.free);
+        self.statements(&blk.block);
+        let test = self.operand(&blk.cond);
+        self.close_scope(actives);
+        self.free = free;
+        self.emit(Instr::JumpIf(test, false, start));
+        self.end_loop();
+    }
+
+    /// The iterator, its state and the control value take three registers, followed by
+    /// the loop variables, which are locals of the body. They are all named the way the
+    /// reference implementation names them for the `debug` library.
+    fn for_in(&mut self, for_in: &ForIn) {
+        let actives = self.actives.len();
+        let base = self.exp_list_fixed(&for_in.exps, 3);
+        self.reserve(for_in.vars.len());
+        for (i, name) in ["(for generator)", "(for state)", "(for control)"].iter().enumerate() {
+            self.activate(name.to_string(), base + i);
+        }
+        let step = self.emit(Instr::ForIn(base, for_in.vars.len(), 0));
+        self.begin_loop();
+        for (i, var) in for_in.vars.iter().enumerate() {
+            self.activate(var_to_string(var), base + 3 + i);
+        }
+        self.block(&for_in.do_blk);
+        self.close_scope(actives + 3);
+        self.emit(Instr::Jump(step));
+        self.patch(step);
+        self.close_scope(actives);
+        self.end_loop();
+    }
+
+    /// Jumps past the innermost loop. Outside of a loop, `break` ends the chunk.
+    fn break_loop(&mut self) {
+        if self.loops.is_empty() {
+            self.emit(Instr::Return(0, Count::Fixed(0)));
+            return;
+        }
+        let jump = self.emit(Instr::Jump(0));
+        self.loops.last_mut().unwrap().push(jump);
+    }
+
+    /// Compiles a list of expressions into consecutive registers starting from the first
+    /// free one. Only the last expression gets to give several values, in which case the
+    /// count is `Multi`.
+    fn exp_list(&mut self, exps: &[Exp]) -> (Reg, Count) {
+        let base = self.free;
+        if let Some((last, init)) = exps.split_last() {
+            for exp in init {
+                let dst = self.alloc();
+                self.exp_to(exp, dst);
+            }
+            if self.multi_exp(last, Count::Multi) {
+                return (base, Count::Multi);
+            }
+            let dst = self.alloc();
+            self.exp_to(last, dst);
+        }
+        (base, Count::Fixed(exps.len()))
+    }
+
+    /// Compiles a list of expressions adjusted to `n` values, as in assignments. Extra
+    /// values are evaluated and dropped, and missing ones are nil.
+    fn exp_list_fixed(&mut self, exps: &[Exp], n: usize) -> Reg {
+        let base = self.free;
+        let mut filled = exps.len();
+        if let Some((last, init)) = exps.split_last() {
+            for exp in init {
+                let dst = self.alloc();
+                self.exp_to(exp, dst);
+            }
+            let wanted = n.saturating_sub(init.len());
+            if self.multi_exp(last, Count::Fixed(wanted)) {
+                filled = init.len() + wanted;
+            } else {
+                let dst = self.alloc();
+                self.exp_to(last, dst);
+            }
+        }
+        self.free = base;
+        self.reserve(n);
+        for reg in base + filled..base + n {
+            self.emit(Instr::LoadNil(reg));
+        }
+        base
+    }
+
+    /// Compiles an expression that may give several values, a call or `...`, so that they
+    /// are stored from the first free register. Returns false for other expressions,
+    /// leaving them to `exp_to`.
+    fn multi_exp(&mut self, exp: &Exp, results: Count) -> bool {
+        match *exp {
+            Exp::Ellipses => {
+                let base = self.free;
+                self.emit(Instr::VarArgs(base, results));
+                if let Count::Fixed(n) = results {
+                    self.reserve(n);
+                }
+                true
+            }
+            Exp::PrefixExp(ref e) | Exp::FuncCall(ref e) => match call_parts(&e.suffix_chain) {
+                Some((call, rest)) => {
+                    self.call(&e.prefix, rest, call, results);
+                    true
+                }
+                None => false,
+            },
+            _ => false,
+        }
+    }
+
+    /// The register holding the value of an expression: the register of a local, or the
+    /// first free one, which it is compiled to.
+    fn operand(&mut self, exp: &Exp) -> Reg {
+        if let Exp::PrefixExp(ref e) = *exp {
+            if let (&ExpOrVarName::VarName(ref var), true) = (&e.prefix, e.suffix_chain.is_empty()) {
+                if let Some(reg) = self.resolve(&var_to_string(var)) {
+                    return reg;
+                }
+            }
+        }
+        let dst = self.alloc();
+        self.exp_to(exp, dst);
+        dst
+    }
+
+    /// Compiles an expression whose value goes to `dst`. A call or `...` only keeps its
+    /// first value.
+    fn exp_to(&mut self, exp: &Exp, dst: Reg) {
+        let free = self.free;
+        match *exp {
+            Exp::Nil => {
+                self.emit(Instr::LoadNil(dst));
+            }
+            Exp::Bool(b) => {
+                self.emit(Instr::LoadBool(dst, b));
+            }
+            Exp::Num(num) => {
+                let value = match num {
+                    Numeral::Float(f) => LuaValue::Number(Number::Float(f)),
+                    Numeral::Int(i) => LuaValue::Number(Number::Int(i)),
+                };
+                let k = self.constant(value);
+                self.emit(Instr::LoadConst(dst, k));
+            }
+            Exp::Str(ref s) => {
+                let k = self.constant(LuaValue::Str(s.0.to_vec().into()));
+                self.emit(Instr::LoadConst(dst, k));
+            }
+            Exp::BinExp(ref left, ref op, ref right) => self.binary(left, op, right, dst),
+            Exp::UnExp(ref op, ref operand) => {
+                let src = self.operand(operand);
+                self.emit(Instr::Unary(op.clone(), dst, src));
+            }
+            Exp::Table(ref fields) => self.table(fields, dst),
+            Exp::PrefixExp(ref e) | Exp::FuncCall(ref e) => self.prefix_exp(&e.prefix, &e.suffix_chain, dst),
+            Exp::Ellipses => {
+                self.emit(Instr::VarArgs(dst, Count::Fixed(1)));
+            }
+            Exp::Lambda(_) => {
+                self.emit(Instr::NotImplemented);
+            }
+        }
+        self.free = free;
+    }
+
+    /// `and` and `or` only evaluate their right operand when the left one doesn't decide
+    /// the result on its own.
+    fn binary(&mut self, left: &Exp, op: &BinOp, right: &Exp, dst: Reg) {
+        let short_circuit = match *op {
+            BinOp::BoolAnd => Some(false),
+            BinOp::BoolOr => Some(true),
+            _ => None,
+        };
+        match short_circuit {
+            Some(decisive) => {
+                self.exp_to(left, dst);
+                let jump = self.emit(Instr::JumpIf(dst, decisive, 0));
+                self.exp_to(right, dst);
+                self.patch(jump);
+            }
+            None => {
+                let a = self.operand(left);
+                let b = self.operand(right);
+                self.emit(Instr::Binary(op.clone(), dst, a, b));
+            }
+        }
+    }
+
+    /// A table constructor. Its fields are set in order, and a call among its positional
+    /// fields only gives its first value.
+    fn table(&mut self, fields: &[Field], dst: Reg) {
+        let positional = fields.iter().filter(|f| matches!(**f, Field::PosAssign(_))).count();
+        self.emit(Instr::NewTable(dst, positional));
+        let mut next_index = 1;
+        for field in fields {
+            let free = self.free;
+            let key = self.alloc();
+            let value = self.alloc();
+            match *field {
+                Field::PosAssign(ref exp) => {
+                    let k = self.constant(LuaValue::Number(Number::Int(next_index)));
+                    self.emit(Instr::LoadConst(key, k));
+                    self.exp_to(exp, value);
+                    next_index += 1;
+                }
+                Field::ExpAssign(ref k, ref v) => {
+                    self.exp_to(k, key);
+                    self.exp_to(v, value);
+                }
+                Field::NameAssign(ref name, ref v) => {
+                    self.load_name(name, key);
+                    self.exp_to(v, value);
+                }
+            }
+            self.emit(Instr::RawSet(dst, key, value));
+            self.free = free;
+        }
+    }
+
+    fn prefix_root(&mut self, prefix: &ExpOrVarName, dst: Reg) {
+        match *prefix {
+            ExpOrVarName::VarName(ref var) => self.get_var(var, dst),
+            ExpOrVarName::Exp(ref e) => self.exp_to(e, dst),
+        }
+    }
+
+    /// Compiles a prefix expression whose value goes to `dst`. A call only keeps its
+    /// first value.
+    fn prefix_exp(&mut self, prefix: &ExpOrVarName, suffixes: &[ExpSuffix], dst: Reg) {
+        let free = self.free;
+        match suffixes.split_last() {
+            None => self.prefix_root(prefix, dst),
+            Some((&ExpSuffix::FuncCall(ref call), rest)) => {
+                let base = self.call(prefix, rest, call, Count::Fixed(1));
+                self.emit(Instr::Move(dst, base));
+            }
+            Some((&ExpSuffix::TableDot(ref field), rest)) => {
+                self.prefix_exp(prefix, rest, dst);
+                let key = self.alloc();
+                self.load_name(field, key);
+                self.emit(Instr::GetIndex(dst, dst, key));
+            }
+            Some((&ExpSuffix::TableIdx(ref exp), rest)) => {
+                self.prefix_exp(prefix, rest, dst);
+                let key = self.alloc();
+                self.exp_to(exp, key);
+                self.emit(Instr::GetIndex(dst, dst, key));
+            }
+        }
+        self.free = free;
+    }
+
+    /// Describes the function called by `prefix` followed by `rest` and `call` the way
+    /// the reference implementation names it in tracebacks.
+    fn call_target(&mut self, prefix: &ExpOrVarName, rest: &[ExpSuffix], call: &FunctionCall) -> CallTarget {
+        match (&call.method, rest.last(), prefix) {
+            (&Some(ref method), _, _) => CallTarget::Method(self.name(method)),
+            (&None, Some(&ExpSuffix::TableDot(ref field)), _) => CallTarget::Field(self.name(field)),
+            (&None, None, &ExpOrVarName::VarName(ref var)) => {
+                if self.resolve(&var_to_string(var)).is_some() {
+                    CallTarget::Local(self.name(var))
+                } else {
+                    CallTarget::Global(self.name(var))
+                }
+            }
+            _ => CallTarget::Unnamed,
+        }
+    }
+
+    /// Compiles a call: the function goes to the first free register, followed by its
+    /// arguments, and its results are stored from there. Returns that register, which
+    /// stays in use along with the `Fixed` results.
+    fn call(&mut self, prefix: &ExpOrVarName, rest: &[ExpSuffix], call: &FunctionCall, results: Count) -> Reg {
+        let base = self.alloc();
+        self.prefix_exp(prefix, rest, base);
+        let target = self.call_target(prefix, rest, call);
+        let mut fixed = 0;
+        if let Some(ref method) = call.method {
+            let name = self.name(method);
+            self.emit(Instr::Method(base, base, name));
+            self.reserve(1);
+            fixed = 1;
+        }
+        let args = match call.args {
+            Args::ExpList(ref exps) => match self.exp_list(exps).1 {
+                Count::Fixed(n) => Count::Fixed(fixed + n),
+                Count::Multi => Count::Multi,
+            },
+            Args::Table(ref fields) => {
+                let dst = self.alloc();
+                self.table(fields, dst);
+                Count::Fixed(fixed + 1)
+            }
+            Args::Str(ref s) => {
+                let dst = self.alloc();
+                let k = self.constant(LuaValue::Str(s.0.to_vec().into()));
+                self.emit(Instr::LoadConst(dst, k));
+                Count::Fixed(fixed + 1)
+            }
+        };
+        self.emit(Instr::Call(base, args, results, target));
+        self.free = base;
+        match results {
+            Count::Fixed(n) => self.reserve(n.max(1)),
+            Count::Multi => self.reserve(1),
+        }
+        base
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    use nom_lua53::{Args, Assignment, Exp, ExpOrVarName, ExpSuffix, ForRange, FunctionCall, LVarAssignment, PrefixExp,
+                    RepeatBlock, Statement};
+    use nom_lua53::name::VarName;
+    use nom_lua53::num::Numeral;
+    use nom_lua53::op::BinOp;
+    use nom_lua53::stat_expr_types::Block;
+    use chunk::load_block;
+    use expression::call_function;
+    use types::{LuaState, LuaValue, Number};
+    use LuaError;
+
+    fn int(i: isize) -> Exp<'static> {
+        Exp::Num(Numeral::Int(i))
+    }
+
+    fn number(i: isize) -> LuaValue {
+        LuaValue::Number(Number::Int(i))
+    }
+
+    fn target(name: &'static [u8]) -> PrefixExp<'static> {
+        PrefixExp {
+            prefix: ExpOrVarName::VarName(VarName(name)),
+            suffix_chain: Vec::new(),
+        }
+    }
+
+    fn var(name: &'static [u8]) -> Exp<'static> {
+        Exp::PrefixExp(Box::new(target(name)))
+    }
+
+    fn local(name: &'static [u8], value: Exp<'static>) -> Statement<'static> {
+        Statement::LVarAssign(LVarAssignment {
+            vars: vec![VarName(name)],
+            vals: Some(vec![value]),
+        })
+    }
+
+    fn assign(name: &'static [u8], value: Exp<'static>) -> Statement<'static> {
+        Statement::Assignment(Assignment {
+            vars: vec![target(name)],
+            vals: vec![value],
+        })
+    }
+
+    fn block(stmts: Vec<Statement<'static>>, ret: Option<Vec<Exp<'static>>>) -> Block<'static> {
+        Block {
+            stmts,
+            ret_stmt: ret,
+        }
+    }
+
+    fn run(ctx: &LuaState, stmts: Vec<Statement>) -> ::Result<Vec<LuaValue>> {
+        let block = Block {
+            stmts,
+            ret_stmt: Some(vec![int(1)]),
+        };
+        load_block(ctx, &block).call(ctx, &[])
+    }
+
+    #[test]
+    fn test_unsupported_statements() {
+        let ctx = LuaState::new();
+        let one = vec![LuaValue::Number(Number::Int(1))];
+        assert_eq!(run(&ctx, vec![Statement::Semicolon, Statement::Label(VarName(b"top"))]), Ok(one));
+        assert_eq!(
+            run(&ctx, vec![Statement::Goto(VarName(b"top"))]),
+            Err(LuaError::NotImplementedError)
+        );
+        let for_range = ForRange {
+            var: VarName(b"i"),
+            exps: (int(1), int(3), None),
+            do_blk: Block {
+                stmts: Vec::new(),
+                ret_stmt: None,
+            },
+        };
+        assert_eq!(
+            run(&ctx, vec![Statement::ForRange(for_range)]),
+            Err(LuaError::NotImplementedError)
+        );
+    }
+
+    #[test]
+    fn test_shadowing() {
+        let ctx = LuaState::new();
+        // local x = 1; do local x = 2; do local x = 3; z = x end; y = x end; return x, y, z
+        let inner = block(vec![local(b"x", int(3)), assign(b"z", var(b"x"))], None);
+        let outer = block(
+            vec![local(b"x", int(2)), Statement::Do(inner), assign(b"y", var(b"x"))],
+            None,
+        );
+        let main = block(
+            vec![local(b"x", int(1)), Statement::Do(outer)],
+            Some(vec![var(b"x"), var(b"y"), var(b"z")]),
+        );
+        assert_eq!(load_block(&ctx, &main).call(&ctx, &[]), Ok(vec![number(1), number(2), number(3)]));
+    }
+
+    #[test]
+    fn test_local_env() {
+        let ctx = LuaState::new();
+        // local t = {}; do local _ENV = t; x = 1; y = x end; return t.y, x
+        let field = Exp::PrefixExp(Box::new(PrefixExp {
+            prefix: ExpOrVarName::VarName(VarName(b"t")),
+            suffix_chain: vec![ExpSuffix::TableDot(VarName(b"y"))],
+        }));
+        let main = block(
+            vec![
+                local(b"t", Exp::Table(Vec::new())),
+                Statement::Do(block(
+                    vec![local(b"_ENV", var(b"t")), assign(b"x", int(1)), assign(b"y", var(b"x"))],
+                    None,
+                )),
+            ],
+            Some(vec![field, var(b"x")]),
+        );
+        assert_eq!(load_block(&ctx, &main).call(&ctx, &[]), Ok(vec![number(1), LuaValue::Nil]));
+    }
+
+    #[test]
+    fn test_repeat_condition_scope() {
+        let ctx = LuaState::new();
+        // local n = 0; repeat local done = n == 2; n = n + 1 until done; return n
+        let body = block(
+            vec![
+                local(b"done", Exp::BinExp(Box::new(var(b"n")), BinOp::Eq, Box::new(int(2)))),
+                assign(b"n", Exp::BinExp(Box::new(var(b"n")), BinOp::Plus, Box::new(int(1)))),
+            ],
+            None,
+        );
+        let main = block(
+            vec![
+                local(b"n", int(0)),
+                Statement::Repeat(RepeatBlock {
+                    block: body,
+                    cond: var(b"done"),
+                }),
+            ],
+            Some(vec![var(b"n")]),
+        );
+        assert_eq!(load_block(&ctx, &main).call(&ctx, &[]), Ok(vec![number(3)]));
+    }
+
+    #[test]
+    fn test_local_numbering() {
+        let ctx = LuaState::new();
+        let getlocal = match ctx.get_global_table().get_string(&ctx.intern(b"debug")) {
+            LuaValue::Table(t) => t.get_string(&ctx.intern(b"getlocal")),
+            _ => panic!("no debug library"),
+        };
+        let seen = Rc::new(RefCell::new(Vec::new()));
+        let record = seen.clone();
+        ctx.register_native("probe", move |ctx: &LuaState, _args: &[LuaValue]| {
+            for n in 1..3 {
+                let result = call_function(&getlocal, &[number(2), number(n)], ctx)?;
+                record.borrow_mut().extend(result);
+            }
+            Ok(Vec::new())
+        });
+        let probe = Statement::FuncCall(PrefixExp {
+            prefix: ExpOrVarName::VarName(VarName(b"probe")),
+            suffix_chain: vec![ExpSuffix::FuncCall(FunctionCall {
+                method: None,
+                args: Args::ExpList(Vec::new()),
+            })],
+        });
+        // do local a = 1 end; local b = 2; do local c = 3; probe() end; probe()
+        let main = block(
+            vec![
+                Statement::Do(block(vec![local(b"a", int(1))], None)),
+                local(b"b", int(2)),
+                Statement::Do(block(vec![local(b"c", int(3)), probe.clone()], None)),
+                probe,
+            ],
+            None,
+        );
+        load_block(&ctx, &main).call(&ctx, &[]).unwrap();
+        let name = |s: &str| LuaValue::Str(s.into());
+        assert_eq!(
+            *seen.borrow(),
+            vec![name("b"), number(2), name("c"), number(3), name("b"), number(2), LuaValue::Nil]
+        );
+    }
+}
@@ -0,0 +1,660 @@
+// The precompiled chunks of the reference implementation, as written by `luac` and
+// `string.dump` in Lua 5.3: a header describing the machine, then the main function with
+// its instructions, constants, upvalues, nested functions and debug information.
+
+use chunk::BINARY_SIGNATURE;
+use types::{LuaValue, Number};
+use {LuaError, Result};
+
+const VERSION: u8 = 0x53;
+const FORMAT: u8 = 0;
+/// Catches the conversions a chunk goes through when it is transferred as text.
+const DATA: &[u8] = b"\x19\x93\r\n\x1a\n";
+const INT_SIZE: u8 = 4;
+const SIZE_T_SIZE: u8 = 8;
+const INSTRUCTION_SIZE: u8 = 4;
+const INTEGER_SIZE: u8 = 8;
+const NUMBER_SIZE: u8 = 8;
+/// Values telling the byte order and the float format.
+const TEST_INT: i64 = 0x5678;
+const TEST_NUM: f64 = 370.5;
+
+const TAG_NIL: u8 = 0;
+const TAG_BOOLEAN: u8 = 1;
+const TAG_FLOAT: u8 = 3;
+const TAG_INT: u8 = 3 | (1 << 4);
+const TAG_SHORT_STR: u8 = 4;
+const TAG_LONG_STR: u8 = 4 | (1 << 4);
+/// Longest string stored as a short one.
+const MAX_SHORT_LEN: usize = 40;
+/// Deepest nesting of functions a chunk may have, the limit of C calls of the reference
+/// implementation.
+const MAX_NESTING: usize = 200;
+
+pub const OP_MOVE: u32 = 0;
+pub const OP_LOADK: u32 = 1;
+pub const OP_LOADKX: u32 = 2;
+pub const OP_LOADBOOL: u32 = 3;
+pub const OP_LOADNIL: u32 = 4;
+pub const OP_GETUPVAL: u32 = 5;
+pub const OP_GETTABUP: u32 = 6;
+pub const OP_GETTABLE: u32 = 7;
+pub const OP_SETTABUP: u32 = 8;
+pub const OP_SETUPVAL: u32 = 9;
+pub const OP_SETTABLE: u32 = 10;
+pub const OP_NEWTABLE: u32 = 11;
+pub const OP_SELF: u32 = 12;
+pub const OP_ADD: u32 = 13;
+pub const OP_SUB: u32 = 14;
+pub const OP_MUL: u32 = 15;
+pub const OP_MOD: u32 = 16;
+pub const OP_POW: u32 = 17;
+pub const OP_DIV: u32 = 18;
+pub const OP_IDIV: u32 = 19;
+pub const OP_BAND: u32 = 20;
+pub const OP_BOR: u32 = 21;
+pub const OP_BXOR: u32 = 22;
+pub const OP_SHL: u32 = 23;
+pub const OP_SHR: u32 = 24;
+pub const OP_UNM: u32 = 25;
+pub const OP_BNOT: u32 = 26;
+pub const OP_NOT: u32 = 27;
+pub const OP_LEN: u32 = 28;
+pub const OP_CONCAT: u32 = 29;
+pub const OP_JMP: u32 = 30;
+pub const OP_EQ: u32 = 31;
+pub const OP_LT: u32 = 32;
+pub const OP_LE: u32 = 33;
+pub const OP_TEST: u32 = 34;
+pub const OP_TESTSET: u32 = 35;
+pub const OP_CALL: u32 = 36;
+pub const OP_TAILCALL: u32 = 37;
+pub const OP_RETURN: u32 = 38;
+pub const OP_FORLOOP: u32 = 39;
+pub const OP_FORPREP: u32 = 40;
+pub const OP_TFORCALL: u32 = 41;
+pub const OP_TFORLOOP: u32 = 42;
+pub const OP_SETLIST: u32 = 43;
+pub const OP_CLOSURE: u32 = 44;
+pub const OP_VARARG: u32 = 45;
+pub const OP_EXTRAARG: u32 = 46;
+
+/// Flag of the `B` and `C` arguments referring to a constant rather than a register.
+pub const RK_CONSTANT: usize = 1 << 8;
+pub const MAX_RK_INDEX: usize = RK_CONSTANT - 1;
+pub const MAX_BX: usize = (1 << 18) - 1;
+pub const MAX_C: usize = (1 << 9) - 1;
+const MAX_SBX: isize = (MAX_BX >> 1) as isize;
+/// Number of list items a `SETLIST` stores at most.
+pub const FIELDS_PER_FLUSH: usize = 50;
+
+pub fn opcode(i: u32) -> u32 {
+    i & 0x3f
+}
+
+pub fn arg_a(i: u32) -> usize {
+    ((i >> 6) & 0xff) as usize
+}
+
+pub fn arg_b(i: u32) -> usize {
+    ((i >> 23) & 0x1ff) as usize
+}
+
+pub fn arg_c(i: u32) -> usize {
+    ((i >> 14) & 0x1ff) as usize
+}
+
+pub fn arg_bx(i: u32) -> usize {
+    (i >> 14) as usize
+}
+
+pub fn arg_sbx(i: u32) -> isize {
+    arg_bx(i) as isize - MAX_SBX
+}
+
+pub fn arg_ax(i: u32) -> usize {
+    (i >> 6) as usize
+}
+
+pub fn abc(op: u32, a: usize, b: usize, c: usize) -> u32 {
+    op | (a as u32) << 6 | (b as u32) << 23 | (c as u32) << 14
+}
+
+pub fn abx(op: u32, a: usize, bx: usize) -> u32 {
+    op | (a as u32) << 6 | (bx as u32) << 14
+}
+
+pub fn asbx(op: u32, a: usize, sbx: isize) -> u32 {
+    abx(op, a, (sbx + MAX_SBX) as usize)
+}
+
+pub fn ax(op: u32, ax: usize) -> u32 {
+    op | (ax as u32) << 6
+}
+
+/// Encodes a table size in the "floating point byte" of `NEWTABLE`, rounding up.
+pub fn int_to_fb(mut x: usize) -> usize {
+    let mut e = 0;
+    if x < 8 {
+        return x;
+    }
+    while x >= (8 << 4) {
+        x = (x + 0xf) >> 4;
+        e += 4;
+    }
+    while x >= (8 << 1) {
+        x = (x + 1) >> 1;
+        e += 1;
+    }
+    ((e + 1) << 3) | (x - 8)
+}
+
+pub fn fb_to_int(x: usize) -> usize {
+    if x < 8 {
+        x
+    } else {
+        ((x & 7) + 8) << ((x >> 3) - 1)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Upvalue {
+    /// Whether the upvalue is a register of the enclosing function rather than one of its
+    /// upvalues.
+    pub in_stack: bool,
+    pub index: u8,
+    pub name: Option<Vec<u8>>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct LocalVar {
+    pub name: Vec<u8>,
+    /// First instruction where the local is active, and the first one where it isn't.
+    pub start_pc: usize,
+    pub end_pc: usize,
+}
+
+/// A function of a precompiled chunk. The debug information is empty when it was
+/// stripped.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Prototype {
+    /// The chunk name of the source the function comes from. Nested functions only
+    /// repeat it when it differs from the one of their parent.
+    pub source: Option<Vec<u8>>,
+    pub line_defined: isize,
+    pub last_line_defined: isize,
+    pub num_params: u8,
+    pub is_vararg: u8,
+    pub max_stack_size: u8,
+    pub code: Vec<u32>,
+    pub constants: Vec<LuaValue>,
+    pub upvalues: Vec<Upvalue>,
+    pub protos: Vec<Prototype>,
+    /// Line of each instruction.
+    pub line_info: Vec<isize>,
+    pub locals: Vec<LocalVar>,
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+    /// How error messages name the chunk.
+    name: String,
+    /// Number of functions enclosing the one being read.
+    depth: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn error(&self, why: &str) -> LuaError {
+        LuaError::SyntaxError(format!("{}: {} precompiled chunk", self.name, why))
+    }
+
+    fn block(&mut self, n: usize) -> Result<&'a [u8]> {
+        if self.data.len() - self.pos < n {
+            return Err(self.error("truncated"));
+        }
+        let block = &self.data[self.pos..self.pos + n];
+        self.pos += n;
+        Ok(block)
+    }
+
+    fn byte(&mut self) -> Result<u8> {
+        Ok(self.block(1)?[0])
+    }
+
+    fn bytes<T: Copy + Default + AsMut<[u8]>>(&mut self) -> Result<T> {
+        let mut buffer = T::default();
+        let n = buffer.as_mut().len();
+        buffer.as_mut().copy_from_slice(self.block(n)?);
+        Ok(buffer)
+    }
+
+    fn int(&mut self) -> Result<isize> {
+        Ok(i32::from_le_bytes(self.bytes()?) as isize)
+    }
+
+    /// A count of items, which can't exceed what is left of the chunk.
+    fn count(&mut self) -> Result<usize> {
+        let n = self.int()?;
+        if n < 0 || n as usize > self.data.len() - self.pos {
+            return Err(self.error("truncated"));
+        }
+        Ok(n as usize)
+    }
+
+    fn integer(&mut self) -> Result<i64> {
+        Ok(i64::from_le_bytes(self.bytes()?))
+    }
+
+    fn number(&mut self) -> Result<f64> {
+        Ok(f64::from_bits(u64::from_le_bytes(self.bytes()?)))
+    }
+
+    fn string(&mut self) -> Result<Option<Vec<u8>>> {
+        let size = match self.byte()? {
+            0 => return Ok(None),
+            0xff => u64::from_le_bytes(self.bytes()?) as usize,
+            size => size as usize,
+        };
+        if size == 0 {
+            return Err(self.error("corrupted"));
+        }
+        Ok(Some(self.block(size - 1)?.to_vec()))
+    }
+
+    fn literal(&mut self, expected: &[u8], why: &str) -> Result<()> {
+        if self.block(expected.len()).ok() != Some(expected) {
+            return Err(self.error(why));
+        }
+        Ok(())
+    }
+
+    fn size(&mut self, expected: u8, what: &str) -> Result<()> {
+        if self.byte()? != expected {
+            return Err(self.error(&format!("{} size mismatch in", what)));
+        }
+        Ok(())
+    }
+
+    fn header(&mut self) -> Result<()> {
+        self.literal(BINARY_SIGNATURE, "not a")?;
+        if self.byte()? != VERSION {
+            return Err(self.error("version mismatch in"));
+        }
+        if self.byte()? != FORMAT {
+            return Err(self.error("format mismatch in"));
+        }
+        self.literal(DATA, "corrupted")?;
+        self.size(INT_SIZE, "int")?;
+        self.size(SIZE_T_SIZE, "size_t")?;
+        self.size(INSTRUCTION_SIZE, "Instruction")?;
+        self.size(INTEGER_SIZE, "lua_Integer")?;
+        self.size(NUMBER_SIZE, "lua_Number")?;
+        if self.integer()? != TEST_INT {
+            return Err(self.error("endianness mismatch in"));
+        }
+        if self.number()? != TEST_NUM {
+            return Err(self.error("float format mismatch in"));
+        }
+        Ok(())
+    }
+
+    fn constant(&mut self) -> Result<LuaValue> {
+        Ok(match self.byte()? {
+            TAG_NIL => LuaValue::Nil,
+            TAG_BOOLEAN => LuaValue::Boolean(self.byte()? != 0),
+            TAG_FLOAT => LuaValue::Number(Number::Float(self.number()?)),
+            TAG_INT => LuaValue::Number(Number::Int(self.integer()? as isize)),
+            TAG_SHORT_STR | TAG_LONG_STR => match self.string()? {
+                Some(s) => LuaValue::Str(s.into()),
+                None => return Err(self.error("corrupted")),
+            },
+            _ => return Err(self.error("corrupted")),
+        })
+    }
+
+    fn function(&mut self, parent_source: Option<&Vec<u8>>) -> Result<Prototype> {
+        let mut proto = Prototype::default();
+        proto.source = match self.string()? {
+            Some(source) => Some(source),
+            None => parent_source.cloned(),
+        };
+        proto.line_defined = self.int()?;
+        proto.last_line_defined = self.int()?;
+        proto.num_params = self.byte()?;
+        proto.is_vararg = self.byte()?;
+        proto.max_stack_size = self.byte()?;
+        let n = self.count()?;
+        for _ in 0..n {
+            proto.code.push(u32::from_le_bytes(self.bytes()?));
+        }
+        let n = self.count()?;
+        for _ in 0..n {
+            let constant = self.constant()?;
+            proto.constants.push(constant);
+        }
+        let n = self.count()?;
+        for _ in 0..n {
+            let in_stack = self.byte()? != 0;
+            let index = self.byte()?;
+            proto.upvalues.push(Upvalue {
+                in_stack,
+                index,
+                name: None,
+            });
+        }
+        let n = self.count()?;
+        if n > 0 && self.depth == MAX_NESTING {
+            return Err(self.error("corrupted"));
+        }
+        self.depth += 1;
+        for _ in 0..n {
+            let nested = self.function(proto.source.as_ref())?;
+            proto.protos.push(nested);
+        }
+        self.depth -= 1;
+        let n = self.count()?;
+        for _ in 0..n {
+            let line = self.int()?;
+            proto.line_info.push(line);
+        }
+        let n = self.count()?;
+        for _ in 0..n {
+            let name = self.string()?.unwrap_or_default();
+            let start_pc = self.int()? as usize;
+            let end_pc = self.int()? as usize;
+            proto.locals.push(LocalVar {
+                name,
+                start_pc,
+                end_pc,
+            });
+        }
+        let n = self.count()?;
+        for i in 0..n {
+            let name = self.string()?;
+            match proto.upvalues.get_mut(i) {
+                Some(upvalue) => upvalue.name = name,
+                None => return Err(self.error("corrupted")),
+            }
+        }
+        Ok(proto)
+    }
+}
+
+/// Reads a precompiled chunk. `chunkname` only names it in error messages, as the
+/// chunk records the name of its source.
+pub fn undump(data: &[u8], chunkname: &str) -> Result<Prototype> {
+    let name = if chunkname.starts_with('@') || chunkname.starts_with('=') {
+        chunkname[1..].to_owned()
+    } else if chunkname.as_bytes().starts_with(&BINARY_SIGNATURE[..1]) {
+        "binary string".to_owned()
+    } else {
+        chunkname.to_owned()
+    };
+    let mut reader = Reader {
+        data,
+        pos: 0,
+        name,
+        depth: 0,
+    };
+    reader.header()?;
+    // Number of upvalues of the main function, which the function repeats.
+    reader.byte()?;
+    reader.function(None)
+}
+
+struct Writer {
+    buffer: Vec<u8>,
+    strip: bool,
+}
+
+impl Writer {
+    fn byte(&mut self, b: u8) {
+        self.buffer.push(b);
+    }
+
+    fn int(&mut self, n: isize) {
+        self.buffer.extend_from_slice(&(n as i32).to_le_bytes());
+    }
+
+    fn integer(&mut self, n: i64) {
+        self.buffer.extend_from_slice(&n.to_le_bytes());
+    }
+
+    fn number(&mut self, n: f64) {
+        self.buffer.extend_from_slice(&n.to_bits().to_le_bytes());
+    }
+
+    fn string(&mut self, s: Option<&[u8]>) {
+        match s {
+            None => self.byte(0),
+            Some(s) => {
+                let size = s.len() + 1;
+                if size < 0xff {
+                    self.byte(size as u8);
+                } else {
+                    self.byte(0xff);
+                    self.buffer.extend_from_slice(&(size as u64).to_le_bytes());
+                }
+                self.buffer.extend_from_slice(s);
+            }
+        }
+    }
+
+    fn header(&mut self) {
+        self.buffer.extend_from_slice(BINARY_SIGNATURE);
+        self.byte(VERSION);
+        self.byte(FORMAT);
+        self.buffer.extend_from_slice(DATA);
+        self.byte(INT_SIZE);
+        self.byte(SIZE_T_SIZE);
+        self.byte(INSTRUCTION_SIZE);
+        self.byte(INTEGER_SIZE);
+        self.byte(NUMBER_SIZE);
+        self.integer(TEST_INT);
+        self.number(TEST_NUM);
+    }
+
+    fn constant(&mut self, constant: &LuaValue) {
+        match *constant {
+            LuaValue::Boolean(b) => {
+                self.byte(TAG_BOOLEAN);
+                self.byte(b as u8);
+            }
+            LuaValue::Number(Number::Float(f)) => {
+                self.byte(TAG_FLOAT);
+                self.number(f);
+            }
+            LuaValue::Number(Number::Int(i)) => {
+                self.byte(TAG_INT);
+                self.integer(i as i64);
+            }
+            LuaValue::Str(ref s) => {
+                self.byte(if s.len() <= MAX_SHORT_LEN {
+                    TAG_SHORT_STR
+                } else {
+                    TAG_LONG_STR
+                });
+                self.string(Some(s));
+            }
+            // Only nil is left that a constant can be.
+            _ => self.byte(TAG_NIL),
+        }
+    }
+
+    fn function(&mut self, proto: &Prototype, parent_source: Option<&Vec<u8>>) {
+        if self.strip || proto.source.as_ref() == parent_source {
+            self.string(None);
+        } else {
+            self.string(proto.source.as_ref().map(|s| &s[..]));
+        }
+        self.int(proto.line_defined);
+        self.int(proto.last_line_defined);
+        self.byte(proto.num_params);
+        self.byte(proto.is_vararg);
+        self.byte(proto.max_stack_size);
+        self.int(proto.code.len() as isize);
+        for &i in proto.code.iter() {
+            self.buffer.extend_from_slice(&i.to_le_bytes());
+        }
+        self.int(proto.constants.len() as isize);
+        for constant in proto.constants.iter() {
+            self.constant(constant);
+        }
+        self.int(proto.upvalues.len() as isize);
+        for upvalue in proto.upvalues.iter() {
+            self.byte(upvalue.in_stack as u8);
+            self.byte(upvalue.index);
+        }
+        self.int(proto.protos.len() as isize);
+        for nested in proto.protos.iter() {
+            self.function(nested, proto.source.as_ref());
+        }
+        let strip = self.strip;
+        let lines: &[isize] = if strip { &[] } else { &proto.line_info };
+        self.int(lines.len() as isize);
+        for &line in lines {
+            self.int(line);
+        }
+        let locals: &[LocalVar] = if strip { &[] } else { &proto.locals };
+        self.int(locals.len() as isize);
+        for local in locals {
+            self.string(Some(&local.name));
+            self.int(local.start_pc as isize);
+            self.int(local.end_pc as isize);
+        }
+        let upvalues: &[Upvalue] = if strip { &[] } else { &proto.upvalues };
+        self.int(upvalues.len() as isize);
+        for upvalue in upvalues {
+            self.string(upvalue.name.as_ref().map(|s| &s[..]));
+        }
+    }
+}
+
+/// Writes a function as a precompiled chunk, without its debug information if `strip`
+/// is set.
+pub fn dump(proto: &Prototype, strip: bool) -> Vec<u8> {
+    let mut writer = Writer {
+        buffer: Vec::new(),
+        strip,
+    };
+    writer.header();
+    writer.byte(proto.upvalues.len() as u8);
+    writer.function(proto, None);
+    writer.buffer
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample() -> Prototype {
+        let nested = Prototype {
+            source: Some(b"@sample.lua".to_vec()),
+            line_defined: 2,
+            last_line_defined: 4,
+            num_params: 1,
+            max_stack_size: 2,
+            code: vec![abc(OP_RETURN, 0, 2, 0), abc(OP_RETURN, 0, 1, 0)],
+            upvalues: vec![Upvalue {
+                in_stack: false,
+                index: 0,
+                name: Some(b"_ENV".to_vec()),
+            }],
+            line_info: vec![3, 4],
+            ..Prototype::default()
+        };
+        Prototype {
+            source: Some(b"@sample.lua".to_vec()),
+            is_vararg: 1,
+            max_stack_size: 2,
+            code: vec![abx(OP_LOADK, 0, 0), abx(OP_CLOSURE, 1, 0), abc(OP_RETURN, 0, 1, 0)],
+            constants: vec![
+                LuaValue::Nil,
+                LuaValue::Boolean(true),
+                LuaValue::Number(Number::Int(-3)),
+                LuaValue::Number(Number::Float(0.5)),
+                LuaValue::Str("short".into()),
+                LuaValue::Str(vec![b'x'; 300].into()),
+            ],
+            upvalues: vec![Upvalue {
+                in_stack: true,
+                index: 0,
+                name: Some(b"_ENV".to_vec()),
+            }],
+            protos: vec![nested],
+            line_info: vec![1, 2, 5],
+            locals: vec![LocalVar {
+                name: b"a".to_vec(),
+                start_pc: 1,
+                end_pc: 3,
+            }],
+            ..Prototype::default()
+        }
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let proto = sample();
+        let data = dump(&proto, false);
+        assert!(data.starts_with(b"\x1bLua\x53\x00"));
+        assert_eq!(undump(&data, "=test").unwrap(), proto);
+
+        let stripped = undump(&dump(&proto, true), "=test").unwrap();
+        assert_eq!(stripped.source, None);
+        assert_eq!(stripped.code, proto.code);
+        assert_eq!(stripped.constants, proto.constants);
+        assert!(stripped.line_info.is_empty() && stripped.locals.is_empty());
+        assert_eq!(stripped.upvalues[0].name, None);
+    }
+
+    #[test]
+    fn test_header_errors() {
+        let data = dump(&sample(), false);
+        let error = |data: &[u8]| match undump(data, "=test") {
+            Err(LuaError::SyntaxError(msg)) => msg,
+            other => panic!("unexpected {:?}", other),
+        };
+        assert_eq!(error(&data[..20]), "test: truncated precompiled chunk");
+        assert_eq!(error(&data[..data.len() - 1]), "test: truncated precompiled chunk");
+        let mut other_version = data.clone();
+        other_version[4] = 0x52;
+        assert_eq!(error(&other_version), "test: version mismatch in precompiled chunk");
+        let mut converted = data.clone();
+        converted[8] = b'\n';
+        assert_eq!(error(&converted), "test: corrupted precompiled chunk");
+        assert!(undump(&data[..3], "\x1bLua").is_err());
+    }
+
+    #[test]
+    fn test_nesting_limit() {
+        let nest = |depth: usize| {
+            let mut proto = Prototype::default();
+            for _ in 0..depth {
+                proto = Prototype {
+                    protos: vec![proto],
+                    ..Prototype::default()
+                };
+            }
+            dump(&proto, true)
+        };
+        assert!(undump(&nest(MAX_NESTING), "=test").is_ok());
+        match undump(&nest(MAX_NESTING + 1), "=test") {
+            Err(LuaError::SyntaxError(msg)) => assert_eq!(msg, "test: corrupted precompiled chunk"),
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_instruction_fields() {
+        let i = abc(OP_SETTABLE, 3, RK_CONSTANT | 7, 200);
+        assert_eq!((opcode(i), arg_a(i), arg_b(i), arg_c(i)), (OP_SETTABLE, 3, RK_CONSTANT | 7, 200));
+        let j = asbx(OP_JMP, 0, -5);
+        assert_eq!((opcode(j), arg_sbx(j)), (OP_JMP, -5));
+        assert_eq!(arg_bx(abx(OP_LOADK, 1, MAX_BX)), MAX_BX);
+        assert_eq!(arg_ax(ax(OP_EXTRAARG, 1 << 20)), 1 << 20);
+        for &n in [0, 7, 8, 50, 100, 1000].iter() {
+            assert!(fb_to_int(int_to_fb(n)) >= n);
+        }
+    }
+}
@@ -0,0 +1,146 @@
+// A register-based instruction set for the syntax tree to be compiled to once, when a
+// chunk is loaded, rather than walked each time it runs.
+
+pub mod codegen;
+pub mod compile;
+pub mod luac;
+pub mod translate;
+pub mod vm;
+
+use nom_lua53::op::{BinOp, UnOp};
+
+use types::LuaValue;
+
+pub use self::compile::compile;
+pub use self::vm::{execute, Frame};
+
+/// Index of a register in the frame of the running chunk.
+pub type Reg = usize;
+
+/// How many values an instruction reads or writes from a register onwards: a fixed
+/// number, or all of them up to the end of the values left by the last call or `...`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Count {
+    Fixed(usize),
+    Multi,
+}
+
+/// A name of global or field, kept both as a string for messages and as the value used
+/// to index tables.
+#[derive(Debug, Clone)]
+pub struct Name {
+    pub name: String,
+    pub key: LuaValue,
+}
+
+/// A local variable, held by a register from the instruction at `start_pc` until the one
+/// at `end_pc`. Only the `debug` library needs its name.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LocalVar {
+    pub name: String,
+    pub reg: Reg,
+    pub start_pc: usize,
+    pub end_pc: usize,
+}
+
+/// How the calling code refers to a function, for the `debug` library.
+#[derive(Debug, Clone, PartialEq)]
+pub enum CallTarget {
+    Unnamed,
+    Local(usize),
+    Global(usize),
+    Method(usize),
+    Field(usize),
+}
+
+#[derive(Debug, Clone)]
+pub enum Instr {
+    /// Starts a statement on the given line, for hooks and the `debug` library.
+    Statement(Option<isize>),
+    LoadNil(Reg),
+    LoadBool(Reg, bool),
+    /// Loads the constant at the given index.
+    LoadConst(Reg, usize),
+    Move(Reg, Reg),
+    /// Loads an upvalue of the running chunk. Chunks only have one, their `_ENV`.
+    GetUpval(Reg, usize),
+    SetUpval(usize, Reg),
+    /// Reads a global variable, the field of the `_ENV` upvalue with the given name.
+    GetGlobal(Reg, usize),
+    SetGlobal(usize, Reg),
+    /// `a = b[c]`, through the `__index` metamethods.
+    GetIndex(Reg, Reg, Reg),
+    /// `a[b] = c`, through the `__newindex` metamethods.
+    SetIndex(Reg, Reg, Reg),
+    /// Creates a table with room for the given number of positional fields.
+    NewTable(Reg, usize),
+    /// Sets a field of a table being built, bypassing its metatable.
+    RawSet(Reg, Reg, Reg),
+    /// Stores the values after the table in `a` at its positive integer keys, starting
+    /// after the given index.
+    SetList(Reg, Count, usize),
+    /// `a = b op c`. The `and` and `or` operators are compiled to jumps instead.
+    Binary(BinOp, Reg, Reg, Reg),
+    Unary(UnOp, Reg, Reg),
+    Jump(usize),
+    /// Jumps when the truth value of the register is the given one.
+    JumpIf(Reg, bool, usize),
+    /// Prepares `b:name(...)`: `a` gets the method and `a + 1` the object.
+    Method(Reg, Reg, usize),
+    /// Calls the function in `a` with the arguments after it, and stores the results
+    /// from `a` onwards.
+    Call(Reg, Count, Count, CallTarget),
+    VarArgs(Reg, Count),
+    /// One step of a generic `for` whose iterator, state and control value are in `a`
+    /// and the two registers after it. The loop variables are stored after them, or the
+    /// loop exits to the given instruction when the first of them is nil.
+    ForIn(Reg, usize, usize),
+    /// Checks the initial value, limit and step of a numeric `for` in `a` and the two
+    /// registers after it, then jumps to its first `ForLoop`.
+    ForPrep(Reg, usize),
+    /// Steps a numeric `for` and jumps back to its body while the limit isn't passed,
+    /// with the loop variable in `a + 3`.
+    ForLoop(Reg, usize),
+    Return(Reg, Count),
+    NotImplemented,
+}
+
+/// A compiled chunk.
+#[derive(Debug, Default)]
+pub struct Proto {
+    pub code: Vec<Instr>,
+    pub constants: Vec<LuaValue>,
+    pub names: Vec<Name>,
+    /// The locals in the order they are declared in.
+    pub locals: Vec<LocalVar>,
+    /// Number of registers the code uses, not counting the values with a `Multi` count.
+    pub registers: usize,
+    /// Number of fixed parameters, and whether `...` takes the extra arguments.
+    pub params: usize,
+    pub is_vararg: bool,
+    /// Lines where the function starts and ends, both 0 for a main chunk.
+    pub line_defined: isize,
+    pub last_line_defined: isize,
+}
+
+impl Proto {
+    /// The locals in scope at the instruction `pc`, numbered as the `debug` library does.
+    pub fn active_locals<'p>(&'p self, pc: usize) -> impl Iterator<Item = &'p LocalVar> + 'p {
+        self.locals.iter().filter(move |local| local.start_pc <= pc && pc < local.end_pc)
+    }
+}
+
+/// Evaluates a single expression in the global environment, as the tests of the
+/// operators do.
+#[cfg(test)]
+pub fn eval_exp(exp: &::nom_lua53::Exp, ctx: &::types::LuaState) -> ::Result<LuaValue> {
+    use nom_lua53::stat_expr_types::Block;
+    let block = Block {
+        stmts: Vec::new(),
+        ret_stmt: Some(vec![exp.clone()]),
+    };
+    let proto = compile(&block, &|_| None);
+    let frame = Frame::new(&proto, Vec::new());
+    let env = ::std::cell::RefCell::new(LuaValue::Table(ctx.globals()));
+    Ok(::expression::first_value(execute(&proto, &frame, &[env], ctx)?))
+}
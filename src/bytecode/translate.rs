@@ -0,0 +1,455 @@
+// Turns the main function of a precompiled chunk into our instructions. Its registers
+// map to ours one for one, with a few more after them for the constants and temporaries
+// the instructions of the reference implementation don't need a register for.
+
+use nom_lua53::op::{BinOp, UnOp};
+
+use {LuaError, Result};
+use super::luac::*;
+use super::{CallTarget, Count, Instr, LocalVar, Proto, Reg};
+
+struct Translator<'p> {
+    source: &'p Prototype,
+    proto: Proto,
+    /// Our first instruction for each of the source function, the targets of its jumps.
+    starts: Vec<usize>,
+    /// The registers after the ones of the source function.
+    scratch: Reg,
+}
+
+fn binary_op(op: u32) -> Option<BinOp> {
+    Some(match op {
+        OP_ADD => BinOp::Plus,
+        OP_SUB => BinOp::Minus,
+        OP_MUL => BinOp::Mul,
+        OP_MOD => BinOp::Mod,
+        OP_POW => BinOp::Pow,
+        OP_DIV => BinOp::Div,
+        OP_IDIV => BinOp::IntDiv,
+        OP_BAND => BinOp::BitAnd,
+        OP_BOR => BinOp::BitOr,
+        OP_BXOR => BinOp::BitXor,
+        OP_SHL => BinOp::BitShl,
+        OP_SHR => BinOp::BitShr,
+        _ => return None,
+    })
+}
+
+fn unary_op(op: u32) -> Option<UnOp> {
+    Some(match op {
+        OP_UNM => UnOp::Minus,
+        OP_BNOT => UnOp::BitNot,
+        OP_NOT => UnOp::BoolNot,
+        OP_LEN => UnOp::Length,
+        _ => return None,
+    })
+}
+
+/// The count of values given by a `B` or `C` argument, where 0 stands for all of them.
+fn count(arg: usize) -> Count {
+    match arg {
+        0 => Count::Multi,
+        n => Count::Fixed(n - 1),
+    }
+}
+
+impl<'p> Translator<'p> {
+    fn corrupted(&self) -> LuaError {
+        LuaError::SyntaxError("corrupted precompiled chunk".to_owned())
+    }
+
+    fn emit(&mut self, instr: Instr) {
+        self.proto.code.push(instr);
+    }
+
+    fn jump_target(&self, pc: usize, offset: isize) -> Result<usize> {
+        let target = pc as isize + 1 + offset;
+        if target < 0 || target as usize > self.source.code.len() {
+            return Err(self.corrupted());
+        }
+        Ok(target as usize)
+    }
+
+    /// Checks that the `n` registers from `first` on are among those of the source
+    /// function.
+    fn registers(&self, first: usize, n: usize) -> Result<Reg> {
+        if first + n > self.source.max_stack_size as usize {
+            return Err(self.corrupted());
+        }
+        Ok(first)
+    }
+
+    fn reg(&self, r: usize) -> Result<Reg> {
+        self.registers(r, 1)
+    }
+
+    /// Checks the registers holding `count` values from `first` on. Those with a `Multi`
+    /// count are past the registers, so only `first` is checked then.
+    fn values(&self, first: usize, count: Count) -> Result<Reg> {
+        match count {
+            Count::Fixed(n) => self.registers(first, n),
+            Count::Multi => self.reg(first),
+        }
+    }
+
+    fn constant(&self, k: usize) -> Result<usize> {
+        if k >= self.proto.constants.len() {
+            return Err(self.corrupted());
+        }
+        Ok(k)
+    }
+
+    /// The register holding the value of an `RK` argument, loading the constant into
+    /// the scratch register `n` if it refers to one.
+    fn rk(&mut self, arg: usize, n: usize) -> Result<Reg> {
+        if arg & RK_CONSTANT == 0 {
+            return self.reg(arg);
+        }
+        let k = self.constant(arg & MAX_RK_INDEX)?;
+        let reg = self.scratch + n;
+        self.emit(Instr::LoadConst(reg, k));
+        Ok(reg)
+    }
+
+    /// Checks that an upvalue is the `_ENV` of the chunk, the only upvalue of a main
+    /// function.
+    fn upvalue(&self, index: usize) -> Result<usize> {
+        if index != 0 {
+            return Err(self.corrupted());
+        }
+        Ok(index)
+    }
+
+    /// Checks the registers of a call: the function in `a`, followed by `b - 1` arguments
+    /// or all of those up to the top when `b` is 0.
+    fn call(&self, a: usize, b: usize) -> Result<Reg> {
+        match b {
+            0 => self.reg(a),
+            b => self.registers(a, b),
+        }
+    }
+
+    /// The argument of the `EXTRAARG` following the instruction at `pc`.
+    fn extra_arg(&self, pc: usize) -> Result<usize> {
+        match self.source.code.get(pc + 1) {
+            Some(&next) if opcode(next) == OP_EXTRAARG => Ok(arg_ax(next)),
+            _ => Err(self.corrupted()),
+        }
+    }
+
+    fn instruction(&mut self, pc: usize, i: u32) -> Result<()> {
+        let (a, b, c) = (arg_a(i), arg_b(i), arg_c(i));
+        let s = self.scratch;
+        match opcode(i) {
+            OP_MOVE => {
+                let (a, b) = (self.reg(a)?, self.reg(b)?);
+                self.emit(Instr::Move(a, b));
+            }
+            OP_LOADK => {
+                let (a, k) = (self.reg(a)?, self.constant(arg_bx(i))?);
+                self.emit(Instr::LoadConst(a, k));
+            }
+            OP_LOADKX => {
+                let (a, k) = (self.reg(a)?, self.constant(self.extra_arg(pc)?)?);
+                self.emit(Instr::LoadConst(a, k));
+            }
+            OP_LOADBOOL => {
+                self.emit(Instr::LoadBool(self.reg(a)?, b != 0));
+                if c != 0 {
+                    let target = self.jump_target(pc, 1)?;
+                    self.emit(Instr::Jump(target));
+                }
+            }
+            OP_LOADNIL => {
+                for reg in self.registers(a, b + 1)?..=a + b {
+                    self.emit(Instr::LoadNil(reg));
+                }
+            }
+            OP_GETUPVAL => {
+                let (a, n) = (self.reg(a)?, self.upvalue(b)?);
+                self.emit(Instr::GetUpval(a, n));
+            }
+            OP_GETTABUP => {
+                let (a, n) = (self.reg(a)?, self.upvalue(b)?);
+                let key = self.rk(c, 1)?;
+                self.emit(Instr::GetUpval(s, n));
+                self.emit(Instr::GetIndex(a, s, key));
+            }
+            OP_GETTABLE => {
+                let (a, b) = (self.reg(a)?, self.reg(b)?);
+                let key = self.rk(c, 1)?;
+                self.emit(Instr::GetIndex(a, b, key));
+            }
+            OP_SETTABUP => {
+                let n = self.upvalue(a)?;
+                let key = self.rk(b, 1)?;
+                let value = self.rk(c, 2)?;
+                self.emit(Instr::GetUpval(s, n));
+                self.emit(Instr::SetIndex(s, key, value));
+            }
+            OP_SETUPVAL => {
+                let (a, n) = (self.reg(a)?, self.upvalue(b)?);
+                self.emit(Instr::SetUpval(n, a));
+            }
+            OP_CLOSURE => self.emit(Instr::NotImplemented),
+            OP_SETTABLE => {
+                let a = self.reg(a)?;
+                let key = self.rk(b, 1)?;
+                let value = self.rk(c, 2)?;
+                self.emit(Instr::SetIndex(a, key, value));
+            }
+            OP_NEWTABLE => self.emit(Instr::NewTable(self.reg(a)?, fb_to_int(b))),
+            OP_SELF => {
+                let (a, b) = (self.registers(a, 2)?, self.reg(b)?);
+                let key = self.rk(c, 1)?;
+                self.emit(Instr::Move(s, b));
+                self.emit(Instr::GetIndex(a, s, key));
+                self.emit(Instr::Move(a + 1, s));
+            }
+            op if binary_op(op).is_some() => {
+                let a = self.reg(a)?;
+                let left = self.rk(b, 1)?;
+                let right = self.rk(c, 2)?;
+                self.emit(Instr::Binary(binary_op(op).unwrap(), a, left, right));
+            }
+            op if unary_op(op).is_some() => {
+                let (a, b) = (self.reg(a)?, self.reg(b)?);
+                self.emit(Instr::Unary(unary_op(op).unwrap(), a, b));
+            }
+            OP_CONCAT => {
+                if c < b {
+                    return Err(self.corrupted());
+                }
+                let (a, b) = (self.reg(a)?, self.registers(b, c - b + 1)?);
+                self.emit(Instr::Move(s, c));
+                for reg in (b..c).rev() {
+                    self.emit(Instr::Binary(BinOp::Concat, s, reg, s));
+                }
+                self.emit(Instr::Move(a, s));
+            }
+            OP_JMP => {
+                let target = self.jump_target(pc, arg_sbx(i))?;
+                self.emit(Instr::Jump(target));
+            }
+            OP_EQ | OP_LT | OP_LE => {
+                let op = match opcode(i) {
+                    OP_EQ => BinOp::Eq,
+                    OP_LT => BinOp::Lt,
+                    _ => BinOp::Leq,
+                };
+                let left = self.rk(b, 1)?;
+                let right = self.rk(c, 2)?;
+                let skip = self.jump_target(pc, 1)?;
+                self.emit(Instr::Binary(op, s, left, right));
+                // The next instruction is skipped when the result isn't the one in `a`.
+                self.emit(Instr::JumpIf(s, a == 0, skip));
+            }
+            OP_TEST => {
+                let (a, skip) = (self.reg(a)?, self.jump_target(pc, 1)?);
+                self.emit(Instr::JumpIf(a, c == 0, skip));
+            }
+            OP_TESTSET => {
+                let (a, b, skip) = (self.reg(a)?, self.reg(b)?, self.jump_target(pc, 1)?);
+                self.emit(Instr::JumpIf(b, c == 0, skip));
+                self.emit(Instr::Move(a, b));
+            }
+            OP_CALL => {
+                let a = self.call(a, b)?;
+                self.values(a, count(c))?;
+                self.emit(Instr::Call(a, count(b), count(c), CallTarget::Unnamed));
+            }
+            OP_TAILCALL => {
+                let a = self.call(a, b)?;
+                self.emit(Instr::Call(a, count(b), Count::Multi, CallTarget::Unnamed));
+                self.emit(Instr::Return(a, Count::Multi));
+            }
+            OP_RETURN => {
+                let a = self.values(a, count(b))?;
+                self.emit(Instr::Return(a, count(b)));
+            }
+            OP_FORLOOP => {
+                let (a, target) = (self.registers(a, 4)?, self.jump_target(pc, arg_sbx(i))?);
+                self.emit(Instr::ForLoop(a, target));
+            }
+            OP_FORPREP => {
+                let (a, target) = (self.registers(a, 4)?, self.jump_target(pc, arg_sbx(i))?);
+                self.emit(Instr::ForPrep(a, target));
+            }
+            OP_TFORCALL => {
+                // The `TFORLOOP` after it loops while the first value isn't nil, which our
+                // `ForIn` checks on its own.
+                match self.source.code.get(pc + 1) {
+                    Some(&next) if opcode(next) == OP_TFORLOOP && arg_a(next) == a + 2 => {}
+                    _ => return Err(self.corrupted()),
+                }
+                let (a, exit) = (self.registers(a, 3 + c)?, self.jump_target(pc, 1)?);
+                self.emit(Instr::ForIn(a, c, exit));
+            }
+            OP_TFORLOOP => {
+                self.registers(a, 2)?;
+                let target = self.jump_target(pc, arg_sbx(i))?;
+                self.emit(Instr::Jump(target));
+            }
+            OP_SETLIST => {
+                let batch = if c == 0 { self.extra_arg(pc)? } else { c };
+                if batch == 0 {
+                    return Err(self.corrupted());
+                }
+                let items = if b == 0 { Count::Multi } else { Count::Fixed(b) };
+                let a = self.reg(a)?;
+                self.values(a + 1, items)?;
+                self.emit(Instr::SetList(a, items, (batch - 1) * FIELDS_PER_FLUSH));
+            }
+            OP_VARARG => {
+                let a = self.values(a, count(b))?;
+                self.emit(Instr::VarArgs(a, count(b)));
+            }
+            OP_EXTRAARG => {}
+            _ => return Err(self.corrupted()),
+        }
+        Ok(())
+    }
+
+    /// Locates the locals of the source function in our code. A local is in the register
+    /// after those of the locals still in scope where it starts, the way the reference
+    /// implementation finds them.
+    fn locals(&mut self) -> Result<()> {
+        let source = self.source;
+        for (i, local) in source.locals.iter().enumerate() {
+            if local.start_pc > local.end_pc || local.end_pc >= self.starts.len() {
+                return Err(self.corrupted());
+            }
+            let reg = source.locals[..i]
+                .iter()
+                .filter(|outer| outer.start_pc <= local.start_pc && local.start_pc < outer.end_pc)
+                .count();
+            self.proto.locals.push(LocalVar {
+                name: String::from_utf8_lossy(&local.name).into_owned(),
+                reg,
+                start_pc: self.starts[local.start_pc],
+                end_pc: self.starts[local.end_pc],
+            });
+        }
+        Ok(())
+    }
+
+    fn translate(mut self) -> Result<Proto> {
+        let mut line = None;
+        for (pc, &i) in self.source.code.iter().enumerate() {
+            self.starts.push(self.proto.code.len());
+            let current = self.source.line_info.get(pc).cloned();
+            if current.is_some() && current != line {
+                self.emit(Instr::Statement(current));
+                line = current;
+            }
+            self.instruction(pc, i)?;
+        }
+        self.starts.push(self.proto.code.len());
+        self.locals()?;
+        let starts = self.starts;
+        for instr in self.proto.code.iter_mut() {
+            match *instr {
+                Instr::Jump(ref mut t)
+                | Instr::JumpIf(_, _, ref mut t)
+                | Instr::ForIn(_, _, ref mut t)
+                | Instr::ForPrep(_, ref mut t)
+                | Instr::ForLoop(_, ref mut t) => *t = starts[*t],
+                _ => {}
+            }
+        }
+        Ok(self.proto)
+    }
+}
+
+/// Translates the main function of a precompiled chunk. Nested functions are kept in
+/// the prototype but can't be created, as with functions in source code.
+pub fn translate(source: &Prototype) -> Result<Proto> {
+    let registers = source.max_stack_size as usize;
+    let translator = Translator {
+        source,
+        proto: Proto {
+            constants: source.constants.clone(),
+            registers: registers + 3,
+            params: source.num_params as usize,
+            is_vararg: source.is_vararg != 0,
+            line_defined: source.line_defined,
+            last_line_defined: source.last_line_defined,
+            ..Proto::default()
+        },
+        starts: Vec::with_capacity(source.code.len() + 1),
+        scratch: registers,
+    };
+    translator.translate()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+
+    use bytecode::{execute, Frame};
+    use types::{LuaState, LuaValue};
+
+    /// A main function with two registers and a single constant.
+    fn main_function(code: Vec<u32>) -> Prototype {
+        Prototype {
+            max_stack_size: 2,
+            code,
+            constants: vec![LuaValue::Nil],
+            upvalues: vec![Upvalue {
+                in_stack: true,
+                index: 0,
+                name: None,
+            }],
+            ..Prototype::default()
+        }
+    }
+
+    #[test]
+    fn test_corrupted_operands() {
+        let corrupted = [
+            abc(OP_MOVE, 0, 5, 0),
+            abc(OP_LOADNIL, 1, 3, 0),
+            abc(OP_ADD, 0, 1, 9),
+            abc(OP_CONCAT, 0, 0, 2),
+            abc(OP_CALL, 1, 3, 1),
+            abc(OP_CALL, 0, 1, 4),
+            abc(OP_RETURN, 1, 4, 0),
+            abc(OP_SETLIST, 1, 2, 1),
+            abc(OP_FORPREP, 0, 0, 0),
+            // These skip the next instruction, which isn't there.
+            abc(OP_LOADBOOL, 0, 1, 1),
+            abc(OP_EQ, 1, 0, 1),
+            abc(OP_TEST, 0, 0, 0),
+            abc(OP_TESTSET, 0, 1, 0),
+        ];
+        for &i in corrupted.iter() {
+            match translate(&main_function(vec![i])) {
+                Err(LuaError::SyntaxError(msg)) => assert_eq!(msg, "corrupted precompiled chunk"),
+                other => panic!("{:08x} gave {:?}", i, other),
+            }
+        }
+    }
+
+    #[test]
+    fn test_huge_table_size() {
+        // `B` asks for 15 << 62 slots, which mustn't be reserved.
+        let ctx = LuaState::new();
+        let code = vec![abc(OP_NEWTABLE, 0, 511, 0), abc(OP_RETURN, 0, 2, 0)];
+        let proto = translate(&main_function(code)).unwrap();
+        let env = RefCell::new(LuaValue::Table(ctx.globals()));
+        match execute(&proto, &Frame::new(&proto, Vec::new()), &[env], &ctx) {
+            Ok(ref values) if values.len() == 1 => assert_eq!(values[0].type_name(), "table"),
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_return_without_values() {
+        // Nothing left any values after the register of `RETURN 1 0`.
+        let ctx = LuaState::new();
+        let proto = translate(&main_function(vec![abc(OP_RETURN, 1, 0, 0)])).unwrap();
+        let env = RefCell::new(LuaValue::Table(ctx.globals()));
+        assert_eq!(execute(&proto, &Frame::new(&proto, Vec::new()), &[env], &ctx), Ok(Vec::new()));
+    }
+}
@@ -0,0 +1,649 @@
+// Runs compiled chunks. Their locals live in the registers of their frame, which the
+// `debug` library reaches through the call running the chunk, and only globals are
+// looked up in `_ENV`.
+
+use std::cell::{Cell, RefCell};
+use std::ops::{Index, IndexMut};
+
+use expression::{boolean_coercion, call_named_function, eval_binary_op, eval_unary_op, index_value, num_coercion,
+                 set_index};
+use sanitizer;
+use types::{CallName, LuaState, LuaTable, LuaValue, Number};
+use {LuaError, Result};
+use super::{CallTarget, Count, Instr, Proto, Reg};
+
+/// The registers of a running chunk. Values with a `Multi` count are kept past the
+/// registers the chunk reserved, up to `top`.
+#[derive(Debug)]
+struct Registers {
+    values: Vec<LuaValue>,
+    top: Reg,
+}
+
+impl Registers {
+    /// Stores values from `base` onwards: exactly `count` of them, padded with nils, or
+    /// all of them if the count is `Multi`.
+    fn store(&mut self, base: Reg, mut values: Vec<LuaValue>, count: Count) {
+        let n = match count {
+            Count::Fixed(n) => n,
+            Count::Multi => values.len(),
+        };
+        values.resize(n, LuaValue::Nil);
+        if self.values.len() < base + n {
+            self.values.resize(base + n, LuaValue::Nil);
+        }
+        for (i, value) in values.into_iter().enumerate() {
+            self.values[base + i] = value;
+        }
+        self.top = base + n;
+    }
+
+    fn values(&self, base: Reg, count: Count) -> &[LuaValue] {
+        match count {
+            Count::Fixed(n) => &self.values[base..base + n],
+            // Nothing may have set the top past `base` in a precompiled chunk.
+            Count::Multi => &self.values[base..self.top.max(base)],
+        }
+    }
+}
+
+impl Index<Reg> for Registers {
+    type Output = LuaValue;
+
+    fn index(&self, reg: Reg) -> &LuaValue {
+        &self.values[reg]
+    }
+}
+
+impl IndexMut<Reg> for Registers {
+    fn index_mut(&mut self, reg: Reg) -> &mut LuaValue {
+        &mut self.values[reg]
+    }
+}
+
+/// The state of a running chunk: its registers, its extra arguments and the instruction
+/// it is at. The dispatch loop doesn't keep the registers borrowed while it calls out,
+/// so that the `debug` library can reach them.
+#[derive(Debug)]
+pub struct Frame {
+    registers: RefCell<Registers>,
+    varargs: RefCell<Vec<LuaValue>>,
+    pc: Cell<usize>,
+    /// Where the nil values of the registers come from, when the state tracks it.
+    origins: RefCell<Vec<Option<String>>>,
+}
+
+impl Frame {
+    pub fn new(proto: &Proto, varargs: Vec<LuaValue>) -> Frame {
+        Frame {
+            registers: RefCell::new(Registers {
+                values: vec![LuaValue::Nil; proto.registers],
+                top: 0,
+            }),
+            varargs: RefCell::new(varargs),
+            pc: Cell::new(0),
+            origins: RefCell::new(Vec::new()),
+        }
+    }
+
+    pub fn get(&self, reg: Reg) -> LuaValue {
+        self.registers.borrow()[reg].clone()
+    }
+
+    pub fn set(&self, reg: Reg, value: LuaValue) {
+        self.registers.borrow_mut()[reg] = value;
+    }
+
+    /// The extra argument `n`, counting from 0.
+    pub fn vararg(&self, n: usize) -> Option<LuaValue> {
+        self.varargs.borrow().get(n).cloned()
+    }
+
+    pub fn set_vararg(&self, n: usize, value: LuaValue) {
+        if let Some(slot) = self.varargs.borrow_mut().get_mut(n) {
+            *slot = value;
+        }
+    }
+
+    /// The instruction being run.
+    pub fn pc(&self) -> usize {
+        self.pc.get()
+    }
+
+    fn store(&self, base: Reg, values: Vec<LuaValue>, count: Count) {
+        self.registers.borrow_mut().store(base, values, count);
+    }
+
+    /// Where the value of a register comes from, if it is a nil whose origin is known.
+    fn origin(&self, reg: Reg) -> Option<String> {
+        if self.get(reg) != LuaValue::Nil {
+            return None;
+        }
+        self.origins.borrow().get(reg).cloned().and_then(|origin| origin)
+    }
+
+    fn set_origin(&self, reg: Reg, origin: Option<String>) {
+        let mut origins = self.origins.borrow_mut();
+        if origins.len() <= reg {
+            if origin.is_none() {
+                return;
+            }
+            origins.resize(reg + 1, None);
+        }
+        origins[reg] = origin;
+    }
+
+    /// Forgets the origins of the registers `instr` wrote, then records the new ones.
+    fn track(&self, instr: &Instr, origins: Vec<(Reg, String)>) {
+        if let Instr::Move(a, b) = *instr {
+            let origin = self.origins.borrow().get(b).cloned().and_then(|origin| origin);
+            self.set_origin(a, origin);
+            return;
+        }
+        if let Some((base, count)) = destinations(instr) {
+            let end = match count {
+                Count::Fixed(n) => base + n,
+                Count::Multi => self.registers.borrow().top,
+            };
+            for reg in base..end {
+                self.set_origin(reg, None);
+            }
+        }
+        for (reg, origin) in origins {
+            self.set_origin(reg, Some(origin));
+        }
+    }
+
+    /// Adds to an error where the nil values among the given registers come from.
+    fn explain_nils(&self, err: LuaError, regs: &[Reg]) -> LuaError {
+        let mut origins: Vec<String> = Vec::new();
+        for &reg in regs {
+            match self.origin(reg) {
+                Some(ref origin) if !origins.contains(origin) => origins.push(origin.clone()),
+                _ => {}
+            }
+        }
+        if origins.is_empty() {
+            return err;
+        }
+        let origins = format!(" (nil from {})", origins.join(", and from "));
+        match err {
+            LuaError::TypeError(msg) => LuaError::TypeError(msg + &origins),
+            LuaError::IndexError(msg) => LuaError::IndexError(msg + &origins),
+            LuaError::ArithmeticError(msg) => LuaError::ArithmeticError(msg + &origins),
+            LuaError::OtherError(msg) => LuaError::OtherError(msg + &origins),
+            err => err,
+        }
+    }
+}
+
+/// The registers an instruction writes, but for the ones it only moves.
+fn destinations(instr: &Instr) -> Option<(Reg, Count)> {
+    match *instr {
+        Instr::LoadNil(a)
+        | Instr::LoadBool(a, _)
+        | Instr::LoadConst(a, _)
+        | Instr::GetUpval(a, _)
+        | Instr::GetGlobal(a, _)
+        | Instr::GetIndex(a, _, _)
+        | Instr::NewTable(a, _)
+        | Instr::Binary(_, a, _, _)
+        | Instr::Unary(_, a, _) => Some((a, Count::Fixed(1))),
+        Instr::Method(a, _, _) => Some((a, Count::Fixed(2))),
+        Instr::Call(a, _, results, _) => Some((a, results)),
+        Instr::VarArgs(a, count) => Some((a, count)),
+        Instr::ForIn(a, vars, _) => Some((a + 2, Count::Fixed(vars + 1))),
+        Instr::ForPrep(a, _) | Instr::ForLoop(a, _) => Some((a, Count::Fixed(4))),
+        _ => None,
+    }
+}
+
+/// Where the nil loaded in `reg` by the instruction at `pc` comes from: the declaration of
+/// the local it starts, if the statement declares one there, or the code itself.
+fn loaded_nil(proto: &Proto, pc: usize, reg: Reg, ctx: &LuaState) -> String {
+    let statement_end = proto.code[pc..]
+        .iter()
+        .position(|instr| matches!(*instr, Instr::Statement(_)))
+        .map_or(proto.code.len(), |n| pc + n);
+    let local = proto.locals.iter().find(|local| {
+        local.reg == reg && local.start_pc > pc && local.start_pc <= statement_end
+    });
+    match local {
+        Some(local) => format!("the declaration of local '{}' at {}", local.name, sanitizer::location(ctx)),
+        None => format!("an explicit nil at {}", sanitizer::location(ctx)),
+    }
+}
+
+/// Where the nils among the results of a call come from.
+fn call_results(name: Option<CallName>, base: Reg, values: &[LuaValue], count: Count, ctx: &LuaState) -> Vec<(Reg, String)> {
+    let call = match name {
+        Some(name) => format!("a call to '{}' at {}", name.name, sanitizer::location(ctx)),
+        None => format!("a call at {}", sanitizer::location(ctx)),
+    };
+    let n = match count {
+        Count::Fixed(n) => n,
+        Count::Multi => values.len(),
+    };
+    (0..n)
+        .filter(|&i| values.get(i).map_or(true, |value| *value == LuaValue::Nil))
+        .map(|i| {
+            let origin = if i < values.len() {
+                format!("the nil returned by {}", call)
+            } else {
+                let plural = if values.len() == 1 { "" } else { "s" };
+                format!("{} that returned only {} value{}", call, values.len(), plural)
+            };
+            (base + i, origin)
+        })
+        .collect()
+}
+
+/// Where the nil read as the field `key` of `object` comes from.
+fn missing_field(object: &LuaValue, key: &LuaValue, ctx: &LuaState) -> String {
+    let site = match *object {
+        LuaValue::Table(ref t) => t.get_site().map(|site| format!(" of a table created at {}", site)),
+        _ => None,
+    };
+    format!(
+        "the missing field {}{}, read at {}",
+        sanitizer::describe_value(key),
+        site.unwrap_or_default(),
+        sanitizer::location(ctx)
+    )
+}
+
+fn call_name(proto: &Proto, target: &CallTarget) -> Option<CallName> {
+    let (n, kind) = match *target {
+        CallTarget::Unnamed => return None,
+        CallTarget::Local(n) => (n, "local"),
+        CallTarget::Global(n) => (n, "global"),
+        CallTarget::Method(n) => (n, "method"),
+        CallTarget::Field(n) => (n, "field"),
+    };
+    Some(CallName {
+        name: proto.names[n].name.clone(),
+        kind,
+    })
+}
+
+/// The limit of a numeric `for` over integers, rounded towards its initial value when it
+/// is a float.
+fn for_limit(limit: &LuaValue, step: isize) -> Option<isize> {
+    match num_coercion(limit.clone()) {
+        LuaValue::Number(Number::Int(i)) => Some(i),
+        LuaValue::Number(Number::Float(f)) => {
+            let f = if step < 0 { f.ceil() } else { f.floor() };
+            if f.is_nan() {
+                None
+            } else if f >= isize::max_value() as f64 {
+                Some(isize::max_value())
+            } else if f < isize::min_value() as f64 {
+                Some(isize::min_value())
+            } else {
+                Some(f as isize)
+            }
+        }
+        _ => None,
+    }
+}
+
+fn for_number(value: &LuaValue, what: &str) -> Result<f64> {
+    match num_coercion(value.clone()) {
+        LuaValue::Number(n) => Ok(n.to_float()),
+        _ => Err(LuaError::OtherError(format!("'for' {} must be a number", what))),
+    }
+}
+
+/// Prepares a numeric `for`: the loop runs over integers when its initial value and step
+/// are integers, over floats otherwise. The control value starts one step early, since
+/// `ForLoop` steps it first.
+fn for_prep(regs: &mut [LuaValue], a: Reg) -> Result<()> {
+    if let (&LuaValue::Number(Number::Int(init)), &LuaValue::Number(Number::Int(step))) = (&regs[a], &regs[a + 2]) {
+        if let Some(limit) = for_limit(&regs[a + 1], step) {
+            regs[a] = LuaValue::Number(Number::Int(init.wrapping_sub(step)));
+            regs[a + 1] = LuaValue::Number(Number::Int(limit));
+            return Ok(());
+        }
+    }
+    let limit = for_number(&regs[a + 1], "limit")?;
+    let step = for_number(&regs[a + 2], "step")?;
+    let init = for_number(&regs[a], "initial value")?;
+    regs[a] = LuaValue::Number(Number::Float(init - step));
+    regs[a + 1] = LuaValue::Number(Number::Float(limit));
+    regs[a + 2] = LuaValue::Number(Number::Float(step));
+    Ok(())
+}
+
+/// Steps a numeric `for`, and tells whether the loop goes on.
+fn for_loop(regs: &mut [LuaValue], a: Reg) -> bool {
+    let (next, goes_on) = match (&regs[a], &regs[a + 1], &regs[a + 2]) {
+        (&LuaValue::Number(Number::Int(i)), &LuaValue::Number(Number::Int(limit)), &LuaValue::Number(Number::Int(step))) => {
+            let i = i.wrapping_add(step);
+            let goes_on = if step > 0 { i <= limit } else { limit <= i };
+            (LuaValue::Number(Number::Int(i)), goes_on)
+        }
+        (&LuaValue::Number(ref i), &LuaValue::Number(ref limit), &LuaValue::Number(ref step)) => {
+            let (i, limit, step) = (i.to_float() + step.to_float(), limit.to_float(), step.to_float());
+            let goes_on = if step > 0.0 { i <= limit } else { limit <= i };
+            (LuaValue::Number(Number::Float(i)), goes_on)
+        }
+        _ => return false,
+    };
+    regs[a] = next.clone();
+    if goes_on {
+        regs[a + 3] = next;
+    }
+    goes_on
+}
+
+/// Runs compiled code in `frame`, with the given upvalues, and returns the values of its
+/// `return` statement, if it reaches one.
+pub fn execute(proto: &Proto, frame: &Frame, upvalues: &[RefCell<LuaValue>], ctx: &LuaState) -> Result<Vec<LuaValue>> {
+    let tracking = sanitizer::tracks_nils(ctx);
+    let mut pc = 0;
+    while pc < proto.code.len() {
+        frame.pc.set(pc);
+        let instr = &proto.code[pc];
+        pc += 1;
+        let mut origins = Vec::new();
+        {
+            // The registers stay borrowed for this instruction only.
+            let mut regs = frame.registers.borrow_mut();
+            match *instr {
+                Instr::Statement(line) => {
+                    drop(regs);
+                    ctx.trace_statement(line)?;
+                }
+                Instr::LoadNil(a) => {
+                    regs[a] = LuaValue::Nil;
+                    if tracking {
+                        origins.push((a, loaded_nil(proto, pc - 1, a, ctx)));
+                    }
+                }
+                Instr::LoadBool(a, b) => regs[a] = LuaValue::Boolean(b),
+                Instr::LoadConst(a, k) => regs[a] = proto.constants[k].clone(),
+                Instr::Move(a, b) => regs[a] = regs[b].clone(),
+                Instr::GetUpval(a, n) => regs[a] = upvalues[n].borrow().clone(),
+                Instr::SetUpval(n, a) => *upvalues[n].borrow_mut() = regs[a].clone(),
+                Instr::GetGlobal(a, n) => {
+                    drop(regs);
+                    let env = upvalues[0].borrow().clone();
+                    let value = index_value(&env, &proto.names[n].key, ctx)?;
+                    if value == LuaValue::Nil {
+                        sanitizer::check_global_read(ctx, &proto.names[n].name)?;
+                        if tracking {
+                            let location = sanitizer::location(ctx);
+                            origins.push((a, format!("the unset global '{}' read at {}", proto.names[n].name, location)));
+                        }
+                    }
+                    frame.set(a, value);
+                }
+                Instr::SetGlobal(n, a) => {
+                    let value = regs[a].clone();
+                    drop(regs);
+                    let env = upvalues[0].borrow().clone();
+                    let is_new = || match env {
+                        LuaValue::Table(ref t) => t.get(&proto.names[n].key) == LuaValue::Nil,
+                        _ => false,
+                    };
+                    sanitizer::check_global_write(ctx, &proto.names[n].name, is_new)?;
+                    set_index(&env, &proto.names[n].key, &value, ctx)?;
+                }
+                Instr::GetIndex(a, b, c) => {
+                    let (object, key) = (regs[b].clone(), regs[c].clone());
+                    drop(regs);
+                    let value = index_value(&object, &key, ctx).map_err(|err| frame.explain_nils(err, &[b]))?;
+                    if tracking && value == LuaValue::Nil {
+                        origins.push((a, missing_field(&object, &key, ctx)));
+                    }
+                    frame.set(a, value);
+                }
+                Instr::SetIndex(a, b, c) => {
+                    let (object, key, value) = (regs[a].clone(), regs[b].clone(), regs[c].clone());
+                    drop(regs);
+                    set_index(&object, &key, &value, ctx).map_err(|err| frame.explain_nils(err, &[a, b]))?;
+                }
+                Instr::NewTable(a, n) => {
+                    let table = LuaTable::with_capacity(ctx.get_ref_id(), n);
+                    sanitizer::table_created(ctx, &table);
+                    regs[a] = LuaValue::Table(table);
+                }
+                Instr::RawSet(a, b, c) => {
+                    if let LuaValue::Table(ref t) = regs[a] {
+                        sanitizer::check_key_write(ctx, t, &regs[b], &regs[c])?;
+                        t.set(&regs[b], &regs[c])?;
+                    }
+                }
+                Instr::SetList(a, count, offset) => {
+                    if let LuaValue::Table(ref t) = regs[a] {
+                        for (i, value) in regs.values(a + 1, count).iter().enumerate() {
+                            t.set(&LuaValue::Number(Number::Int((offset + i + 1) as isize)), value)?;
+                        }
+                    }
+                }
+                Instr::Binary(ref op, a, b, c) => {
+                    sanitizer::check_binary_coercion(ctx, op, &regs[b], &regs[c])?;
+                    let value = eval_binary_op(regs[b].clone(), regs[c].clone(), op);
+                    drop(regs);
+                    frame.set(a, value.map_err(|err| frame.explain_nils(err, &[b, c]))?);
+                }
+                Instr::Unary(ref op, a, b) => {
+                    let operand = regs[b].clone();
+                    drop(regs);
+                    sanitizer::check_unary_coercion(ctx, op, &operand)?;
+                    let value = eval_unary_op(operand, op, ctx).map_err(|err| frame.explain_nils(err, &[b]))?;
+                    frame.set(a, value);
+                }
+                Instr::Jump(target) => pc = target,
+                Instr::JumpIf(a, truth, target) => {
+                    if boolean_coercion(&regs[a]) == truth {
+                        pc = target;
+                    }
+                }
+                Instr::Method(a, b, n) => {
+                    let object = regs[b].clone();
+                    drop(regs);
+                    let method = index_value(&object, &proto.names[n].key, ctx).map_err(|err| frame.explain_nils(err, &[b]))?;
+                    let mut regs = frame.registers.borrow_mut();
+                    regs[a] = method;
+                    regs[a + 1] = object;
+                }
+                Instr::Call(a, args, results, ref target) => {
+                    let function = regs[a].clone();
+                    let args = regs.values(a + 1, args).to_vec();
+                    drop(regs);
+                    let name = call_name(proto, target);
+                    let values = call_named_function(&function, &args, name.clone(), ctx)
+                        .map_err(|err| frame.explain_nils(err, &[a]))?;
+                    if tracking {
+                        origins = call_results(name, a, &values, results, ctx);
+                    }
+                    frame.store(a, values, results);
+                }
+                Instr::VarArgs(a, count) => {
+                    let values = frame.varargs.borrow().clone();
+                    regs.store(a, values, count);
+                }
+                Instr::ForIn(a, vars, exit) => {
+                    let name = CallName {
+                        name: "for iterator".to_owned(),
+                        kind: "for iterator",
+                    };
+                    let iterator = regs[a].clone();
+                    let args = [regs[a + 1].clone(), regs[a + 2].clone()];
+                    drop(regs);
+                    let values = call_named_function(&iterator, &args, Some(name), ctx)?;
+                    match values.first().cloned() {
+                        None | Some(LuaValue::Nil) => pc = exit,
+                        Some(control) => {
+                            let mut regs = frame.registers.borrow_mut();
+                            regs[a + 2] = control;
+                            regs.store(a + 3, values, Count::Fixed(vars));
+                        }
+                    }
+                }
+                Instr::ForPrep(a, target) => {
+                    for_prep(&mut regs.values, a)?;
+                    pc = target;
+                }
+                Instr::ForLoop(a, target) => {
+                    if for_loop(&mut regs.values, a) {
+                        pc = target;
+                    }
+                }
+                Instr::Return(a, count) => return Ok(regs.values(a, count).to_vec()),
+                Instr::NotImplemented => return Err(LuaError::NotImplementedError),
+            }
+        }
+        if tracking {
+            frame.track(instr, origins);
+        }
+    }
+    Ok(Vec::new())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    use nom_lua53::{Args, Exp, ExpOrVarName, ExpSuffix, ForIn, FunctionCall, PrefixExp, Statement};
+    use nom_lua53::name::VarName;
+    use nom_lua53::num::Numeral;
+    use nom_lua53::op::BinOp;
+    use nom_lua53::stat_expr_types::{Block, LVarAssignment};
+    use chunk::load_block;
+    use sanitizer::Sanitizers;
+    use types::Number;
+    use bytecode::compile;
+
+    fn var(name: &'static [u8]) -> Exp<'static> {
+        Exp::PrefixExp(Box::new(PrefixExp {
+            prefix: ExpOrVarName::VarName(VarName(name)),
+            suffix_chain: Vec::new(),
+        }))
+    }
+
+    #[test]
+    fn test_native_iterator() {
+        let ctx = LuaState::new();
+        ctx.register_native("numbers", |_ctx: &LuaState, args: &[LuaValue]| {
+            Ok(vec![match args[1] {
+                LuaValue::Number(Number::Int(i)) if i < 3 => LuaValue::Number(Number::Int(i + 1)),
+                _ => LuaValue::Nil,
+            }])
+        });
+        let seen = Rc::new(RefCell::new(Vec::new()));
+        let record = seen.clone();
+        ctx.register_native("record", move |_ctx: &LuaState, args: &[LuaValue]| {
+            record.borrow_mut().push(args[0].clone());
+            Ok(Vec::new())
+        });
+
+        // for i in numbers, nil, 0 do record(i) end
+        let body = Statement::FuncCall(PrefixExp {
+            prefix: ExpOrVarName::VarName(VarName(b"record")),
+            suffix_chain: vec![ExpSuffix::FuncCall(FunctionCall {
+                method: None,
+                args: Args::ExpList(vec![var(b"i")]),
+            })],
+        });
+        let for_in = ForIn {
+            vars: vec![VarName(b"i")],
+            exps: vec![var(b"numbers"), Exp::Nil, Exp::Num(Numeral::Int(0))],
+            do_blk: Block {
+                stmts: vec![body],
+                ret_stmt: None,
+            },
+        };
+        // The loop variable doesn't outlive the loop, after which `i` is a global.
+        let block = Block {
+            stmts: vec![Statement::ForIn(for_in)],
+            ret_stmt: Some(vec![var(b"i")]),
+        };
+        let proto = compile(&block, &|_| None);
+        let frame = Frame::new(&proto, Vec::new());
+        let env = RefCell::new(LuaValue::Table(ctx.globals()));
+        assert_eq!(execute(&proto, &frame, &[env], &ctx).unwrap(), vec![LuaValue::Nil]);
+        let int = |i| LuaValue::Number(Number::Int(i));
+        assert_eq!(*seen.borrow(), vec![int(1), int(2), int(3)]);
+    }
+
+    fn local(name: &'static [u8], value: Option<Exp<'static>>) -> Statement<'static> {
+        Statement::LVarAssign(LVarAssignment {
+            vars: vec![VarName(name)],
+            vals: value.map(|value| vec![value]),
+        })
+    }
+
+    fn error_of(ctx: &LuaState, stmts: Vec<Statement>, ret: Exp) -> String {
+        let block = Block {
+            stmts,
+            ret_stmt: Some(vec![ret]),
+        };
+        match load_block(ctx, &block).call(ctx, &[]) {
+            Err(err) => err.to_string(),
+            Ok(values) => panic!("{:?}", values),
+        }
+    }
+
+    #[test]
+    fn test_nil_provenance() {
+        let ctx = LuaState::new();
+        let plus_one = |exp| Exp::BinExp(Box::new(exp), BinOp::Plus, Box::new(Exp::Num(Numeral::Int(1))));
+        let field = |object: &'static [u8], name: &'static [u8]| {
+            Exp::PrefixExp(Box::new(PrefixExp {
+                prefix: ExpOrVarName::VarName(VarName(object)),
+                suffix_chain: vec![ExpSuffix::TableDot(VarName(name))],
+            }))
+        };
+        // local x; return x + 1
+        let message = error_of(&ctx, vec![local(b"x", None)], plus_one(var(b"x")));
+        assert!(!message.contains("nil from"));
+
+        ctx.set_sanitizers(Sanitizers {
+            nil_provenance: true,
+            ..Sanitizers::default()
+        });
+        let message = error_of(&ctx, vec![local(b"x", None)], plus_one(var(b"x")));
+        assert!(message.ends_with(" (nil from the declaration of local 'x' at test)"), "{}", message);
+
+        // local t = {}; return t.a.b
+        let message = error_of(&ctx, vec![local(b"t", Some(Exp::Table(Vec::new())))], {
+            Exp::PrefixExp(Box::new(PrefixExp {
+                prefix: ExpOrVarName::VarName(VarName(b"t")),
+                suffix_chain: vec![ExpSuffix::TableDot(VarName(b"a")), ExpSuffix::TableDot(VarName(b"b"))],
+            }))
+        });
+        assert!(
+            message.ends_with(" (nil from the missing field \"a\" of a table created at test, read at test)"),
+            "{}",
+            message
+        );
+
+        // return missing + 1
+        let message = error_of(&ctx, vec![], plus_one(var(b"missing")));
+        assert!(message.ends_with(" (nil from the unset global 'missing' read at test)"), "{}", message);
+
+        // local a, b = one(); return b.x
+        ctx.register_native("one", |_ctx: &LuaState, _args: &[LuaValue]| Ok(vec![LuaValue::Number(Number::Int(1))]));
+        let call = Exp::FuncCall(Box::new(PrefixExp {
+            prefix: ExpOrVarName::VarName(VarName(b"one")),
+            suffix_chain: vec![ExpSuffix::FuncCall(FunctionCall {
+                method: None,
+                args: Args::ExpList(Vec::new()),
+            })],
+        }));
+        let declare = Statement::LVarAssign(LVarAssignment {
+            vars: vec![VarName(b"a"), VarName(b"b")],
+            vals: Some(vec![call]),
+        });
+        let message = error_of(&ctx, vec![declare], field(b"b", b"x"));
+        assert!(
+            message.ends_with(" (nil from a call to 'one' at test that returned only 1 value)"),
+            "{}",
+            message
+        );
+    }
+}
@@ -0,0 +1,373 @@
+// Chunks are pieces of source code loaded as functions, by `require` or `load`.
+
+use std::cell::RefCell;
+use std::borrow::Cow;
+use std::rc::Rc;
+use std::slice;
+
+use nom_lua53::{parse_all, ParseResult};
+use nom_lua53::{Args, Exp, ExpOrVarName, ExpSuffix, Field, PrefixExp, Statement};
+use nom_lua53::stat_expr_types::Block;
+
+use bytecode::{self, codegen, luac, translate, Frame, Proto};
+use bytecode::luac::Prototype;
+use types::{LuaFunction, LuaState, LuaValue};
+use {LuaError, Result};
+
+/// First bytes of precompiled chunks.
+pub const BINARY_SIGNATURE: &[u8] = b"\x1bLua";
+
+/// Longest chunk identifier in error messages, as `LUA_IDSIZE`.
+const ID_SIZE: usize = 60;
+
+/// Source code or a precompiled chunk loaded as a function. Its only upvalue is `_ENV`.
+pub struct Chunk {
+    source: Vec<u8>,
+    chunkname: String,
+    /// Offsets of the first byte of each line.
+    line_starts: Vec<usize>,
+    env: RefCell<LuaValue>,
+    proto: Proto,
+    /// The prototype a precompiled chunk was loaded from, dumped again by `string.dump`.
+    binary: Option<Prototype>,
+}
+
+impl Chunk {
+    fn new(source: Vec<u8>, chunkname: &str, env: LuaValue) -> Chunk {
+        let mut line_starts = vec![0];
+        line_starts.extend(source.iter().enumerate().filter(|&(_, &c)| c == b'\n').map(|(i, _)| i + 1));
+        Chunk {
+            source,
+            chunkname: chunkname.to_owned(),
+            line_starts,
+            env: RefCell::new(env),
+            proto: Proto::default(),
+            binary: None,
+        }
+    }
+
+    /// Compiles the source. The syntax tree borrows from it, so it doesn't outlive this.
+    fn compile(&self) -> Result<Proto> {
+        match parse_all(&self.source) {
+            ParseResult::Done(block) => {
+                Ok(bytecode::compile(&block, &|stmt| statement_start(stmt).and_then(|ptr| self.line_of(ptr))))
+            }
+            ParseResult::Error(rest, _) => Err(syntax_error(&self.source, rest, &self.chunkname)),
+        }
+    }
+
+    pub fn get_chunkname(&self) -> &str {
+        &self.chunkname
+    }
+
+    pub fn get_proto(&self) -> &Proto {
+        &self.proto
+    }
+
+    pub fn get_env(&self) -> LuaValue {
+        self.env.borrow().clone()
+    }
+
+    pub fn set_env(&self, env: LuaValue) {
+        *self.env.borrow_mut() = env;
+    }
+
+    /// Line of the byte `ptr` points to, if it lies within the source. The syntax tree
+    /// borrows its names and strings from the source, which locates the statements.
+    fn line_of(&self, ptr: *const u8) -> Option<isize> {
+        let start = self.source.as_ptr() as usize;
+        let ptr = ptr as usize;
+        if ptr < start || ptr >= start + self.source.len() {
+            return None;
+        }
+        let line = match self.line_starts.binary_search(&(ptr - start)) {
+            Ok(idx) => idx + 1,
+            Err(idx) => idx,
+        };
+        Some(line as isize)
+    }
+
+    /// Lines holding a statement whose position is known, in order.
+    pub fn active_lines(&self) -> Vec<isize> {
+        let mut lines = Vec::new();
+        if let Some(ref binary) = self.binary {
+            lines.extend(binary.line_info.iter().cloned());
+        } else if let ParseResult::Done(block) = parse_all(&self.source) {
+            collect_lines(self, &block, &mut lines);
+        }
+        lines.sort();
+        lines.dedup();
+        lines
+    }
+}
+
+/// Turns source code into a function whose `_ENV` is `env`, compiling it once and for
+/// all. A precompiled chunk is translated instead, and named after the source it was
+/// dumped from.
+pub fn load(ctx: &LuaState, source: Vec<u8>, chunkname: &str, env: LuaValue) -> Result<LuaFunction> {
+    if !source.starts_with(BINARY_SIGNATURE) {
+        let mut chunk = Chunk::new(source, chunkname, env);
+        chunk.proto = chunk.compile()?;
+        intern_proto(ctx, &mut chunk.proto);
+        return Ok(LuaFunction::new_chunk(ctx.get_ref_id(), chunk));
+    }
+    let binary = luac::undump(&source, chunkname)?;
+    let name = match binary.source {
+        Some(ref name) => String::from_utf8_lossy(name).into_owned(),
+        None => "=?".to_owned(),
+    };
+    let mut chunk = Chunk::new(source, &name, env);
+    chunk.proto = translate::translate(&binary)?;
+    intern_proto(ctx, &mut chunk.proto);
+    chunk.binary = Some(binary);
+    Ok(LuaFunction::new_chunk(ctx.get_ref_id(), chunk))
+}
+
+/// Shares the string constants and global names of a proto with the rest of the state,
+/// so that looking them up in tables compares pointers.
+fn intern_proto(ctx: &LuaState, proto: &mut Proto) {
+    for constant in &mut proto.constants {
+        *constant = ctx.intern_value(constant.clone());
+    }
+    for name in &mut proto.names {
+        name.key = ctx.intern_value(name.key.clone());
+    }
+}
+
+/// Dumps a chunk as a precompiled chunk of the reference implementation, without its
+/// debug information if `strip` is set. A chunk loaded from source is translated from
+/// its compiled code.
+pub fn dump(chunk: &Chunk, strip: bool) -> Result<Vec<u8>> {
+    if let Some(ref binary) = chunk.binary {
+        return Ok(luac::dump(binary, strip));
+    }
+    let proto = codegen::generate(&chunk.proto, &chunk.chunkname)?;
+    Ok(luac::dump(&proto, strip))
+}
+
+/// Runs a chunk in a frame of its own, whose extra arguments are `args`. The call that
+/// runs it gets the frame, for the `debug` library.
+pub fn call(chunk: &Chunk, ctx: &LuaState, args: &[LuaValue]) -> Result<Vec<LuaValue>> {
+    let frame = Rc::new(Frame::new(&chunk.proto, args.to_vec()));
+    if let Some(info) = ctx.get_call_stack().last() {
+        *info.frame.borrow_mut() = Some(frame.clone());
+    }
+    bytecode::execute(&chunk.proto, &frame, slice::from_ref(&chunk.env), ctx)
+}
+
+/// Loads a syntax tree built by a test as a chunk whose `_ENV` is the global table.
+#[cfg(test)]
+pub fn load_block(ctx: &LuaState, block: &Block) -> LuaFunction {
+    let mut chunk = Chunk::new(Vec::new(), "=test", LuaValue::Table(ctx.globals()));
+    chunk.proto = bytecode::compile(block, &|_| None);
+    LuaFunction::new_chunk(ctx.get_ref_id(), chunk)
+}
+
+/// Checks that the kind of a chunk, text or binary, is allowed by `mode`.
+pub fn check_mode(source: &[u8], mode: &[u8]) -> Result<()> {
+    let (kind, flag) = if source.starts_with(BINARY_SIGNATURE) {
+        ("binary", b'b')
+    } else {
+        ("text", b't')
+    };
+    if !mode.contains(&flag) {
+        return Err(LuaError::SyntaxError(format!(
+            "attempt to load a {} chunk (mode is '{}')",
+            kind,
+            String::from_utf8_lossy(mode)
+        )));
+    }
+    Ok(())
+}
+
+fn collect_lines(chunk: &Chunk, block: &Block, lines: &mut Vec<isize>) {
+    for stmt in block.stmts.iter() {
+        if let Some(line) = statement_start(stmt).and_then(|ptr| chunk.line_of(ptr)) {
+            lines.push(line);
+        }
+        match *stmt {
+            Statement::Do(ref blk) => collect_lines(chunk, blk, lines),
+            Statement::While(ref w) => collect_lines(chunk, &w.block, lines),
+            Statement::Repeat(ref r) => collect_lines(chunk, &r.block, lines),
+            Statement::Ite(ref ite) => {
+                collect_lines(chunk, &ite.then_blk, lines);
+                for &(_, ref blk) in ite.elseifs.iter() {
+                    collect_lines(chunk, blk, lines);
+                }
+                if let Some(ref blk) = ite.else_blk {
+                    collect_lines(chunk, blk, lines);
+                }
+            }
+            Statement::ForRange(ref f) => collect_lines(chunk, &f.do_blk, lines),
+            Statement::ForIn(ref f) => collect_lines(chunk, &f.do_blk, lines),
+            _ => {}
+        }
+    }
+}
+
+/// Address of the first name or string of a statement found in the source, which is as
+/// close as the syntax tree gets to its position.
+fn statement_start(stmt: &Statement) -> Option<*const u8> {
+    match *stmt {
+        Statement::Semicolon | Statement::Break => None,
+        Statement::Goto(ref name) | Statement::Label(ref name) => Some(name.0.as_ptr()),
+        Statement::Do(ref blk) => block_start(blk),
+        Statement::While(ref w) => exp_start(&w.cond).or_else(|| block_start(&w.block)),
+        Statement::Repeat(ref r) => block_start(&r.block).or_else(|| exp_start(&r.cond)),
+        Statement::Ite(ref ite) => exp_start(&ite.cond).or_else(|| block_start(&ite.then_blk)),
+        Statement::ForRange(ref f) => Some(f.var.0.as_ptr()),
+        Statement::ForIn(ref f) => f.vars.first().map(|v| v.0.as_ptr()),
+        Statement::FuncDef(ref f) => f.name.path.first().map(|v| v.0.as_ptr()),
+        Statement::LFuncDef(ref f) => Some(f.name.0.as_ptr()),
+        Statement::LVarAssign(ref ass) => ass.vars.first().map(|v| v.0.as_ptr()),
+        Statement::Assignment(ref ass) => ass.vars.first().and_then(prefix_exp_start),
+        Statement::FuncCall(ref call) => prefix_exp_start(call),
+    }
+}
+
+fn block_start(block: &Block) -> Option<*const u8> {
+    block.stmts.iter().filter_map(statement_start).next()
+}
+
+fn prefix_exp_start(exp: &PrefixExp) -> Option<*const u8> {
+    let start = match exp.prefix {
+        ExpOrVarName::VarName(ref name) => Some(name.0.as_ptr()),
+        ExpOrVarName::Exp(ref e) => exp_start(e),
+    };
+    start.or_else(|| {
+        exp.suffix_chain.iter().filter_map(|suffix| match *suffix {
+            ExpSuffix::TableDot(ref name) => Some(name.0.as_ptr()),
+            ExpSuffix::TableIdx(ref e) => exp_start(e),
+            ExpSuffix::FuncCall(ref call) => match call.args {
+                Args::ExpList(ref exps) => exps.iter().filter_map(exp_start).next(),
+                Args::Table(ref fields) => fields.iter().filter_map(field_start).next(),
+                Args::Str(ref s) => borrowed_start(&s.0),
+            },
+        }).next()
+    })
+}
+
+fn exp_start(exp: &Exp) -> Option<*const u8> {
+    match *exp {
+        Exp::Str(ref s) => borrowed_start(&s.0),
+        Exp::Lambda(ref body) => body.params.param_list.first().map(|v| v.0.as_ptr())
+            .or_else(|| block_start(&body.body)),
+        Exp::PrefixExp(ref e) | Exp::FuncCall(ref e) => prefix_exp_start(e),
+        Exp::Table(ref fields) => fields.iter().filter_map(field_start).next(),
+        Exp::BinExp(ref left, _, ref right) => exp_start(left).or_else(|| exp_start(right)),
+        Exp::UnExp(_, ref e) => exp_start(e),
+        Exp::Nil | Exp::Ellipses | Exp::Bool(_) | Exp::Num(_) => None,
+    }
+}
+
+fn field_start(field: &Field) -> Option<*const u8> {
+    match *field {
+        Field::ExpAssign(ref k, ref v) => exp_start(k).or_else(|| exp_start(v)),
+        Field::NameAssign(ref name, _) => Some(name.0.as_ptr()),
+        Field::PosAssign(ref e) => exp_start(e),
+    }
+}
+
+/// Strings with escape sequences are copied out of the source, so only the others
+/// have a position.
+fn borrowed_start(s: &Cow<[u8]>) -> Option<*const u8> {
+    match *s {
+        Cow::Borrowed(s) => Some(s.as_ptr()),
+        Cow::Owned(_) => None,
+    }
+}
+
+/// Builds the name of a chunk used in messages: "=name" stands for itself, "@file" for a
+/// file name, and anything else is the source code.
+pub fn chunk_id(chunkname: &str) -> String {
+    if chunkname.starts_with('=') {
+        chunkname[1..].chars().take(ID_SIZE - 1).collect()
+    } else if chunkname.starts_with('@') {
+        let name = &chunkname[1..];
+        let count = name.chars().count();
+        if count < ID_SIZE {
+            name.to_owned()
+        } else {
+            let tail: String = name.chars().skip(count - (ID_SIZE - 4)).collect();
+            format!("...{}", tail)
+        }
+    } else {
+        let first_line = chunkname.lines().next().unwrap_or("");
+        let max = ID_SIZE - "[string \"...\"]".len() - 1;
+        if first_line.len() == chunkname.len() && first_line.chars().count() <= max {
+            format!("[string \"{}\"]", first_line)
+        } else {
+            let truncated: String = first_line.chars().take(max).collect();
+            format!("[string \"{}...\"]", truncated)
+        }
+    }
+}
+
+/// Reports where the parser stopped, `rest` being the input it couldn't consume.
+fn syntax_error(source: &[u8], rest: &[u8], chunkname: &str) -> LuaError {
+    let consumed = &source[..source.len() - rest.len()];
+    let line = consumed.iter().filter(|&&c| c == b'\n').count() + 1;
+    let token: Vec<u8> = rest.iter()
+        .cloned()
+        .skip_while(|c| c.is_ascii_whitespace())
+        .take_while(|c| !c.is_ascii_whitespace())
+        .take(20)
+        .collect();
+    let near = if token.is_empty() {
+        "<eof>".to_owned()
+    } else {
+        format!("'{}'", String::from_utf8_lossy(&token))
+    };
+    LuaError::SyntaxError(format!(
+        "{}:{}: syntax error near {}",
+        chunk_id(chunkname),
+        line,
+        near
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_chunk_id() {
+        assert_eq!(chunk_id("=stdin"), "stdin");
+        assert_eq!(chunk_id("@script.lua"), "script.lua");
+        assert_eq!(chunk_id("return 1"), "[string \"return 1\"]");
+        assert_eq!(chunk_id("x = 1\nreturn x"), "[string \"x = 1...\"]");
+        let long = format!("@{}", "d/".repeat(40));
+        assert_eq!(chunk_id(&long), format!("...{}", "d/".repeat(28)));
+    }
+
+    #[test]
+    fn test_statement_lines() {
+        use nom_lua53::LVarAssignment;
+        use nom_lua53::name::VarName;
+        let chunk = Chunk::new(b"local a = 1\n\nprint(a)\n".to_vec(), "=test", LuaValue::Nil);
+        let local = Statement::LVarAssign(LVarAssignment {
+            vars: vec![VarName(&chunk.source[6..7])],
+            vals: None,
+        });
+        let call = Statement::FuncCall(PrefixExp {
+            prefix: ExpOrVarName::VarName(VarName(&chunk.source[13..18])),
+            suffix_chain: Vec::new(),
+        });
+        let line = |stmt: &Statement| statement_start(stmt).and_then(|ptr| chunk.line_of(ptr));
+        assert_eq!(line(&local), Some(1));
+        assert_eq!(line(&call), Some(3));
+        assert_eq!(line(&Statement::Break), None);
+        let elsewhere = Statement::Label(VarName(b"a"));
+        assert_eq!(line(&elsewhere), None);
+    }
+
+    #[test]
+    fn test_syntax_error_position() {
+        let source = b"local x = 1\nx = = 2\n";
+        let err = syntax_error(source, &source[16..], "=test");
+        assert_eq!(
+            err,
+            LuaError::SyntaxError("test:2: syntax error near '='".to_owned())
+        );
+    }
+}
@@ -0,0 +1,435 @@
+// Conversions between Rust values and `LuaValue`, which let the host write native
+// functions with ordinary Rust parameters and results.
+
+use std::collections::HashMap;
+use std::hash::Hash;
+
+use types::{LuaFunction, LuaState, LuaTable, LuaUserData, LuaValue, Number};
+use expression::{boolean_coercion, num_coercion};
+use {LuaError, Result};
+
+/// A Rust value that can be turned into a Lua value.
+pub trait IntoLua {
+    fn into_lua(self, ctx: &LuaState) -> Result<LuaValue>;
+}
+
+/// A Rust value that can be read from a Lua value. Errors only hold the reason, e.g.
+/// "string expected, got nil", so that callers can tell which value was wrong.
+pub trait FromLua: Sized {
+    fn from_lua(value: LuaValue, ctx: &LuaState) -> Result<Self>;
+}
+
+/// Several values, as passed to or returned by a function. Tuples stand for as many
+/// values as they have elements, and any other value for a single one.
+pub trait IntoLuaMulti {
+    fn into_lua_multi(self, ctx: &LuaState) -> Result<Vec<LuaValue>>;
+}
+
+pub trait FromLuaMulti: Sized {
+    /// Reads the values, missing ones reading as nil.
+    fn from_lua_multi(values: Vec<LuaValue>, ctx: &LuaState) -> Result<Self>;
+}
+
+/// The error for a value of the wrong type.
+pub fn type_mismatch(expected: &str, got: &LuaValue) -> LuaError {
+    LuaError::TypeError(format!("{} expected, got {}", expected, got.type_name()))
+}
+
+impl IntoLua for LuaValue {
+    fn into_lua(self, _ctx: &LuaState) -> Result<LuaValue> {
+        Ok(self)
+    }
+}
+
+impl FromLua for LuaValue {
+    fn from_lua(value: LuaValue, _ctx: &LuaState) -> Result<LuaValue> {
+        Ok(value)
+    }
+}
+
+impl IntoLua for bool {
+    fn into_lua(self, _ctx: &LuaState) -> Result<LuaValue> {
+        Ok(LuaValue::Boolean(self))
+    }
+}
+
+/// Any value converts to a boolean, nil and false being the only false ones.
+impl FromLua for bool {
+    fn from_lua(value: LuaValue, _ctx: &LuaState) -> Result<bool> {
+        Ok(boolean_coercion(&value))
+    }
+}
+
+fn to_integer(value: LuaValue) -> Result<isize> {
+    match num_coercion(value.clone()) {
+        LuaValue::Number(n) => n.to_exact_int().ok_or_else(|| {
+            LuaError::OtherError("number has no integer representation".to_owned())
+        }),
+        _ => Err(type_mismatch("number", &value)),
+    }
+}
+
+macro_rules! integer_conversions {
+    ($($t:ty),*) => {
+        $(
+            impl IntoLua for $t {
+                fn into_lua(self, _ctx: &LuaState) -> Result<LuaValue> {
+                    // Integers too large for a Lua integer become floats.
+                    let value = self as i128;
+                    if value >= isize::min_value() as i128 && value <= isize::max_value() as i128 {
+                        Ok(LuaValue::Number(Number::Int(value as isize)))
+                    } else {
+                        Ok(LuaValue::Number(Number::Float(value as f64)))
+                    }
+                }
+            }
+
+            impl FromLua for $t {
+                fn from_lua(value: LuaValue, _ctx: &LuaState) -> Result<$t> {
+                    let i = to_integer(value)?;
+                    let min = <$t>::min_value() as i128;
+                    let max = <$t>::max_value() as i128;
+                    if (i as i128) < min || (i as i128) > max {
+                        return Err(LuaError::OtherError("value out of range".to_owned()));
+                    }
+                    Ok(i as $t)
+                }
+            }
+        )*
+    }
+}
+
+integer_conversions!(i8, i16, i32, i64, isize, u8, u16, u32, u64, usize);
+
+macro_rules! float_conversions {
+    ($($t:ty),*) => {
+        $(
+            impl IntoLua for $t {
+                fn into_lua(self, _ctx: &LuaState) -> Result<LuaValue> {
+                    Ok(LuaValue::Number(Number::Float(self as f64)))
+                }
+            }
+
+            impl FromLua for $t {
+                fn from_lua(value: LuaValue, _ctx: &LuaState) -> Result<$t> {
+                    match num_coercion(value.clone()) {
+                        LuaValue::Number(n) => Ok(n.to_float() as $t),
+                        _ => Err(type_mismatch("number", &value)),
+                    }
+                }
+            }
+        )*
+    }
+}
+
+float_conversions!(f32, f64);
+
+impl IntoLua for Number {
+    fn into_lua(self, _ctx: &LuaState) -> Result<LuaValue> {
+        Ok(LuaValue::Number(self))
+    }
+}
+
+impl FromLua for Number {
+    fn from_lua(value: LuaValue, _ctx: &LuaState) -> Result<Number> {
+        match num_coercion(value.clone()) {
+            LuaValue::Number(n) => Ok(n),
+            _ => Err(type_mismatch("number", &value)),
+        }
+    }
+}
+
+impl IntoLua for String {
+    fn into_lua(self, _ctx: &LuaState) -> Result<LuaValue> {
+        Ok(LuaValue::Str(self.into()))
+    }
+}
+
+impl<'a> IntoLua for &'a str {
+    fn into_lua(self, _ctx: &LuaState) -> Result<LuaValue> {
+        Ok(LuaValue::Str(self.as_bytes().into()))
+    }
+}
+
+/// Numbers are accepted too, as by the functions of the string library.
+impl FromLua for String {
+    fn from_lua(value: LuaValue, _ctx: &LuaState) -> Result<String> {
+        match value {
+            LuaValue::Str(s) => String::from_utf8(s.to_vec())
+                .map_err(|_| LuaError::OtherError("string is not valid UTF-8".to_owned())),
+            LuaValue::Number(n) => Ok(n.to_string()),
+            other => Err(type_mismatch("string", &other)),
+        }
+    }
+}
+
+impl<T: IntoLua> IntoLua for Option<T> {
+    fn into_lua(self, ctx: &LuaState) -> Result<LuaValue> {
+        match self {
+            Some(value) => value.into_lua(ctx),
+            None => Ok(LuaValue::Nil),
+        }
+    }
+}
+
+impl<T: FromLua> FromLua for Option<T> {
+    fn from_lua(value: LuaValue, ctx: &LuaState) -> Result<Option<T>> {
+        match value {
+            LuaValue::Nil => Ok(None),
+            value => T::from_lua(value, ctx).map(Some),
+        }
+    }
+}
+
+/// Becomes a sequence.
+impl<T: IntoLua> IntoLua for Vec<T> {
+    fn into_lua(self, ctx: &LuaState) -> Result<LuaValue> {
+        let table = LuaTable::with_capacity(ctx.get_ref_id(), self.len());
+        for (i, value) in self.into_iter().enumerate() {
+            let key = LuaValue::Number(Number::Int(i as isize + 1));
+            table.set(&key, &value.into_lua(ctx)?)?;
+        }
+        Ok(LuaValue::Table(table))
+    }
+}
+
+/// Reads the sequence of a table, up to its first border.
+impl<T: FromLua> FromLua for Vec<T> {
+    fn from_lua(value: LuaValue, ctx: &LuaState) -> Result<Vec<T>> {
+        let table = LuaTable::from_lua(value, ctx)?;
+        let border = table.sequence_border();
+        let mut result = Vec::with_capacity(border);
+        for (i, value) in table.sequence_range(1, border).into_iter().enumerate() {
+            result.push(T::from_lua(value, ctx).map_err(|err| {
+                LuaError::TypeError(format!("invalid value at index {} ({})", i + 1, err))
+            })?);
+        }
+        Ok(result)
+    }
+}
+
+impl<K: IntoLua + Eq + Hash, V: IntoLua> IntoLua for HashMap<K, V> {
+    fn into_lua(self, ctx: &LuaState) -> Result<LuaValue> {
+        let table = LuaTable::new(ctx.get_ref_id());
+        for (key, value) in self {
+            table.set(&key.into_lua(ctx)?, &value.into_lua(ctx)?)?;
+        }
+        Ok(LuaValue::Table(table))
+    }
+}
+
+impl<K: FromLua + Eq + Hash, V: FromLua> FromLua for HashMap<K, V> {
+    fn from_lua(value: LuaValue, ctx: &LuaState) -> Result<HashMap<K, V>> {
+        let table = LuaTable::from_lua(value, ctx)?;
+        let border = table.sequence_border();
+        let sequence = table.sequence_range(1, border)
+            .into_iter()
+            .enumerate()
+            .map(|(i, value)| (LuaValue::Number(Number::Int(i as isize + 1)), value));
+        let mut result = HashMap::new();
+        for (key, value) in sequence.chain(table.hash_entries()) {
+            let key = K::from_lua(key, ctx)
+                .map_err(|err| LuaError::TypeError(format!("invalid key ({})", err)))?;
+            let value = V::from_lua(value, ctx)
+                .map_err(|err| LuaError::TypeError(format!("invalid value ({})", err)))?;
+            result.insert(key, value);
+        }
+        Ok(result)
+    }
+}
+
+macro_rules! reference_conversions {
+    ($($t:ident => $variant:ident, $name:expr);*) => {
+        $(
+            impl IntoLua for $t {
+                fn into_lua(self, _ctx: &LuaState) -> Result<LuaValue> {
+                    Ok(LuaValue::$variant(self))
+                }
+            }
+
+            impl FromLua for $t {
+                fn from_lua(value: LuaValue, _ctx: &LuaState) -> Result<$t> {
+                    match value {
+                        LuaValue::$variant(v) => Ok(v),
+                        other => Err(type_mismatch($name, &other)),
+                    }
+                }
+            }
+        )*
+    }
+}
+
+reference_conversions!(
+    LuaTable => Table, "table";
+    LuaFunction => Function, "function";
+    LuaUserData => UserData, "userdata"
+);
+
+impl<T: IntoLua> IntoLuaMulti for T {
+    fn into_lua_multi(self, ctx: &LuaState) -> Result<Vec<LuaValue>> {
+        Ok(vec![self.into_lua(ctx)?])
+    }
+}
+
+impl<T: FromLua> FromLuaMulti for T {
+    fn from_lua_multi(mut values: Vec<LuaValue>, ctx: &LuaState) -> Result<T> {
+        values.truncate(1);
+        T::from_lua(values.pop().unwrap_or(LuaValue::Nil), ctx)
+    }
+}
+
+macro_rules! tuple_conversions {
+    ($($name:ident),*) => {
+        impl<$($name: IntoLua),*> IntoLuaMulti for ($($name,)*) {
+            #[allow(non_snake_case, unused_variables)]
+            fn into_lua_multi(self, ctx: &LuaState) -> Result<Vec<LuaValue>> {
+                let ($($name,)*) = self;
+                Ok(vec![$($name.into_lua(ctx)?),*])
+            }
+        }
+
+        impl<$($name: FromLua),*> FromLuaMulti for ($($name,)*) {
+            #[allow(unused_mut, unused_variables)]
+            fn from_lua_multi(values: Vec<LuaValue>, ctx: &LuaState) -> Result<($($name,)*)> {
+                let mut values = values.into_iter();
+                Ok(($($name::from_lua(values.next().unwrap_or(LuaValue::Nil), ctx)?,)*))
+            }
+        }
+    }
+}
+
+tuple_conversions!();
+tuple_conversions!(A);
+tuple_conversions!(A, B);
+tuple_conversions!(A, B, C);
+tuple_conversions!(A, B, C, D);
+tuple_conversions!(A, B, C, D, E);
+tuple_conversions!(A, B, C, D, E, G);
+
+/// Reads the argument `n` of a native function, reporting a wrong one as the reference
+/// implementation does.
+fn argument<T: FromLua>(args: &[LuaValue], n: usize, fname: &str, ctx: &LuaState) -> Result<T> {
+    let value = args.get(n - 1).cloned().unwrap_or(LuaValue::Nil);
+    T::from_lua(value, ctx).map_err(|err| {
+        let msg = format!("bad argument #{} to '{}' ({})", n, fname, err);
+        match err {
+            LuaError::TypeError(_) => LuaError::TypeError(msg),
+            _ => LuaError::OtherError(msg),
+        }
+    })
+}
+
+/// A Rust closure taking parameters that convert from Lua values, and returning a result
+/// that converts back. `Args` is the tuple of its parameter types.
+pub trait HostFunction<Args, Ret> {
+    fn call_host(&self, ctx: &LuaState, args: &[LuaValue], fname: &str) -> Result<Vec<LuaValue>>;
+}
+
+macro_rules! host_functions {
+    ($($name:ident $n:expr),*) => {
+        impl<F, R, $($name),*> HostFunction<($($name,)*), R> for F
+        where
+            F: Fn($($name),*) -> Result<R>,
+            R: IntoLuaMulti,
+            $($name: FromLua,)*
+        {
+            #[allow(unused_variables)]
+            fn call_host(&self, ctx: &LuaState, args: &[LuaValue], fname: &str) -> Result<Vec<LuaValue>> {
+                self($(argument::<$name>(args, $n, fname, ctx)?),*)?.into_lua_multi(ctx)
+            }
+        }
+    }
+}
+
+host_functions!();
+host_functions!(A 1);
+host_functions!(A 1, B 2);
+host_functions!(A 1, B 2, C 3);
+host_functions!(A 1, B 2, C 3, D 4);
+host_functions!(A 1, B 2, C 3, D 4, E 5);
+host_functions!(A 1, B 2, C 3, D 4, E 5, G 6);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn string(s: &str) -> LuaValue {
+        LuaValue::Str(s.into())
+    }
+
+    fn int(i: isize) -> LuaValue {
+        LuaValue::Number(Number::Int(i))
+    }
+
+    #[test]
+    fn test_scalars() {
+        let ctx = LuaState::new();
+        assert_eq!(i64::from_lua(string("12"), &ctx), Ok(12));
+        assert_eq!(i32::from_lua(LuaValue::Number(Number::Float(3.)), &ctx), Ok(3));
+        assert!(i32::from_lua(LuaValue::Number(Number::Float(3.5)), &ctx).is_err());
+        assert!(u8::from_lua(int(256), &ctx).is_err());
+        assert_eq!(f64::from_lua(int(2), &ctx), Ok(2.));
+        assert_eq!(bool::from_lua(int(0), &ctx), Ok(true));
+        assert_eq!(String::from_lua(int(7), &ctx), Ok("7".to_owned()));
+        assert_eq!(
+            String::from_lua(LuaValue::Nil, &ctx),
+            Err(LuaError::TypeError("string expected, got nil".to_owned()))
+        );
+        assert_eq!(Option::<i64>::from_lua(LuaValue::Nil, &ctx), Ok(None));
+        assert_eq!(u64::max_value().into_lua(&ctx).unwrap().type_name(), "number");
+        assert_eq!(Some("x").into_lua(&ctx), Ok(string("x")));
+    }
+
+    #[test]
+    fn test_containers() {
+        let ctx = LuaState::new();
+        let list = vec![1, 2, 3].into_lua(&ctx).unwrap();
+        assert_eq!(Vec::<i64>::from_lua(list.clone(), &ctx), Ok(vec![1, 2, 3]));
+        assert!(Vec::<String>::from_lua(LuaValue::Boolean(true), &ctx).is_err());
+
+        let mut map = HashMap::new();
+        map.insert("a".to_owned(), 1.5);
+        map.insert("b".to_owned(), 2.);
+        let table = map.clone().into_lua(&ctx).unwrap();
+        assert_eq!(HashMap::<String, f64>::from_lua(table, &ctx), Ok(map));
+        let by_index = HashMap::<i64, i64>::from_lua(list, &ctx).unwrap();
+        assert_eq!(by_index.get(&3), Some(&3));
+
+        assert_eq!((1, "two", None::<bool>).into_lua_multi(&ctx).unwrap(),
+                   vec![int(1), string("two"), LuaValue::Nil]);
+        assert_eq!(().into_lua_multi(&ctx).unwrap(), Vec::new());
+        let (a, b): (i64, Option<String>) = FromLuaMulti::from_lua_multi(vec![int(4)], &ctx).unwrap();
+        assert_eq!((a, b), (4, None));
+    }
+
+    #[test]
+    fn test_create_function() {
+        let ctx = LuaState::new();
+        let f = ctx.create_function("f", |a: i64, name: String| -> Result<bool> {
+            Ok(name.len() as i64 == a)
+        });
+        let f = match f {
+            LuaValue::Function(f) => f,
+            _ => unreachable!(),
+        };
+        assert_eq!(f.call(&ctx, &[int(3), string("abc")]), Ok(vec![LuaValue::Boolean(true)]));
+        assert_eq!(
+            f.call(&ctx, &[int(3), LuaValue::Nil]),
+            Err(LuaError::TypeError(
+                "bad argument #2 to 'f' (string expected, got nil)".to_owned()
+            ))
+        );
+        assert_eq!(
+            f.call(&ctx, &[LuaValue::Number(Number::Float(0.5))]),
+            Err(LuaError::OtherError(
+                "bad argument #1 to 'f' (number has no integer representation)".to_owned()
+            ))
+        );
+
+        let pair = ctx.create_function("pair", || Ok((1, "x")));
+        match pair {
+            LuaValue::Function(f) => assert_eq!(f.call(&ctx, &[]), Ok(vec![int(1), string("x")])),
+            _ => unreachable!(),
+        }
+    }
+}
@@ -2,40 +2,33 @@ use super::*;
 use super::LuaError::*;
 
 use nom_lua53::op::BinOp;
-use nom_lua53::Exp;
 
-fn eval_cmp_expr(
-    left_op: &Box<Exp>,
-    right_op: &Box<Exp>,
+fn compare_values(
+    left_op: &LuaValue,
+    right_op: &LuaValue,
     nb_fn: fn(f64, f64) -> bool,
-    str_fn: fn(&String, &String) -> bool,
-    ctx: &LuaState,
-) -> Result<LuaValue> {
-    let left_op = eval_expr(&left_op, ctx)?;
-    let right_op = eval_expr(&right_op, ctx)?;
-
-    match (&left_op, &right_op) {
-        (&LuaValue::Str(ref s1), &LuaValue::Str(ref s2)) => Ok(LuaValue::Boolean(str_fn(s1, s2))),
-        (&LuaValue::Number(ref num1), &LuaValue::Number(ref num2)) => Ok(LuaValue::Boolean(nb_fn(num1.to_float(), num2.to_float()))),
+    str_fn: fn(&[u8], &[u8]) -> bool,
+) -> Result<bool> {
+    match (left_op, right_op) {
+        (&LuaValue::Str(ref s1), &LuaValue::Str(ref s2)) => Ok(str_fn(s1.as_bytes(), s2.as_bytes())),
+        (&LuaValue::Number(ref num1), &LuaValue::Number(ref num2)) => Ok(nb_fn(num1.to_float(), num2.to_float())),
         _ => Err(TypeError(
             format!("Trying to compare {:?} and  {:?}.", left_op, right_op).to_owned(),
         )),
     }
 }
 
-fn concatenation_operator(
-    left_op: &Box<Exp>,
-    right_op: &Box<Exp>,
-    ctx: &LuaState,
-) -> Result<LuaValue> {
-    let left_op = num_coercion(eval_expr(&left_op, ctx)?);
-    let right_op = num_coercion(eval_expr(&right_op, ctx)?);
+/// The `<` operator on already evaluated values, as used by `table.sort`.
+pub fn less_than(left_op: &LuaValue, right_op: &LuaValue) -> Result<bool> {
+    compare_values(left_op, right_op, |i1, i2| i1 < i2, |s1, s2| s1 < s2)
+}
 
-    match (left_op, right_op) {
-        (LuaValue::Str(s1),     LuaValue::Str(s2))      => Ok(LuaValue::Str(format!("{}{}", s1, s2))),
-        (LuaValue::Number(n),   LuaValue::Str(s))       => Ok(LuaValue::Str(format!("{}{}", n.to_string(), s))),
-        (LuaValue::Str(s),      LuaValue::Number(n))    => Ok(LuaValue::Str(format!("{}{}", s, n.to_string()))),
-        (LuaValue::Number(n1),  LuaValue::Number(n2))   => Ok(LuaValue::Str(format!("{}{}", n1.to_string(), n2.to_string()))),
+fn concatenation_operator(left_op: LuaValue, right_op: LuaValue) -> Result<LuaValue> {
+    match (num_coercion(left_op), num_coercion(right_op)) {
+        (LuaValue::Str(s1),     LuaValue::Str(s2))      => { let mut s = s1.to_vec(); s.extend(s2.as_bytes()); Ok(LuaValue::Str(s.into())) },
+        (LuaValue::Number(n),   LuaValue::Str(s))       => { let mut s1 = n.to_string().into_bytes(); s1.extend(s.as_bytes()); Ok(LuaValue::Str(s1.into())) },
+        (LuaValue::Str(s),      LuaValue::Number(n))    => { let mut s1 = s.to_vec(); s1.extend(n.to_string().into_bytes()); Ok(LuaValue::Str(s1.into())) },
+        (LuaValue::Number(n1),  LuaValue::Number(n2))   => Ok(LuaValue::Str(format!("{}{}", n1.to_string(), n2.to_string()).into())),
         _ => Err(TypeError(
             "Trying to do concatenation on non-string nor numerical values.".to_owned(),
         )),
@@ -59,16 +52,12 @@ fn safe_left_shift(left: isize, right: isize) -> isize {
 }
 
 fn eval_arithmetic(
-    left_op: &Box<Exp>,
-    right_op: &Box<Exp>,
+    left_op: LuaValue,
+    right_op: LuaValue,
     integer: fn(isize, isize) -> Result<LuaValue>,
     float: fn(f64, f64) -> Result<LuaValue>,
-    ctx: &LuaState,
 ) -> Result<LuaValue> {
-    let left_op = num_coercion(eval_expr(&left_op, ctx)?);
-    let right_op = num_coercion(eval_expr(&right_op, ctx)?);
-
-    match (left_op, right_op) {
+    match (num_coercion(left_op), num_coercion(right_op)) {
         (LuaValue::Number(num1), LuaValue::Number(num2)) => match (&num1, &num2) {
             (&Number::Int(i1), &Number::Int(i2))      => integer(i1, i2),
             _ => float(num1.to_float(), num2.to_float())
@@ -79,36 +68,28 @@ fn eval_arithmetic(
     }
 }
 
-pub fn eval_binary_expr(
-    left_op: &Box<Exp>,
-    right_op: &Box<Exp>,
-    operator: &BinOp,
-    ctx: &LuaState,
-) -> Result<LuaValue> {
-    // We cannot yet evaluate the operands as some binary operators are used to shortcut
-    // evaluation.
+/// Applies a binary operator to operands already evaluated. The compiler turns `and` and
+/// `or` into jumps so that they short-circuit, which leaves them only picking a value here.
+pub fn eval_binary_op(left_op: LuaValue, right_op: LuaValue, operator: &BinOp) -> Result<LuaValue> {
     match *operator {
-        BinOp::Concat => concatenation_operator(left_op, right_op, ctx),
+        BinOp::Concat => concatenation_operator(left_op, right_op),
         BinOp::Plus => eval_arithmetic(
             left_op,
             right_op,
             |i, j| Ok(LuaValue::Number(Number::Int(i + j))),
             |i, j| Ok(LuaValue::Number(Number::Float(i + j))),
-            ctx,
         ),
         BinOp::Minus => eval_arithmetic(
             left_op,
             right_op,
             |i, j| Ok(LuaValue::Number(Number::Int(i - j))),
             |i, j| Ok(LuaValue::Number(Number::Float(i - j))),
-            ctx,
         ),
         BinOp::Mul => eval_arithmetic(
             left_op,
             right_op,
             |i, j| Ok(LuaValue::Number(Number::Int(i * j))),
             |i, j| Ok(LuaValue::Number(Number::Float(i * j))),
-            ctx,
         ),
         BinOp::Div => eval_arithmetic(
             left_op,
@@ -127,7 +108,6 @@ pub fn eval_binary_expr(
                     Ok(LuaValue::Number(Number::Float(i / j)))
                 }
             },
-            ctx,
         ),
         BinOp::Mod => eval_arithmetic(
             left_op,
@@ -148,7 +128,6 @@ pub fn eval_binary_expr(
                     Ok(LuaValue::Number(Number::Float(i - (i / j).floor() * j)))
                 }
             },
-            ctx,
         ),
         BinOp::IntDiv => eval_arithmetic(
             left_op,
@@ -167,14 +146,12 @@ pub fn eval_binary_expr(
                     Ok(LuaValue::Number(Number::Int((i / j).floor() as isize)))
                 }
             },
-            ctx,
         ),
         BinOp::Pow => eval_arithmetic(
             left_op,
             right_op,
             |i, j| Ok(LuaValue::Number(Number::Float((i as f64).powf(j as f64)))),
             |i, j| Ok(LuaValue::Number(Number::Float(i.powf(j)))),
-            ctx,
         ),
         BinOp::BitAnd => eval_arithmetic(
             left_op,
@@ -183,7 +160,6 @@ pub fn eval_binary_expr(
             // This is inefficient as there might have been some casting
             // already...
             |i, j| Ok(LuaValue::Number(Number::Int((i as isize) & (j as isize)))),
-            ctx,
         ),
         BinOp::BitOr => eval_arithmetic(
             left_op,
@@ -192,7 +168,6 @@ pub fn eval_binary_expr(
             // This is inefficient as there might have been some casting
             // already...
             |i, j| Ok(LuaValue::Number(Number::Int((i as isize) | (j as isize)))),
-            ctx,
         ),
         BinOp::BitXor => eval_arithmetic(
             left_op,
@@ -200,14 +175,12 @@ pub fn eval_binary_expr(
             |i, j| Ok(LuaValue::Number(Number::Int(i ^ j))),
             // See BitAnd comment
             |i, j| Ok(LuaValue::Number(Number::Int((i as isize) ^ (j as isize)))),
-            ctx,
         ),
         BinOp::BitShl => eval_arithmetic(
             left_op,
             right_op,
             |i, j| Ok(LuaValue::Number(Number::Int(safe_left_shift(i, j)))),
             |i, j| Ok(LuaValue::Number(Number::Int(safe_left_shift(i as isize, j as isize)))),
-            ctx,
         ),
         BinOp::BitShr => eval_arithmetic(
             left_op,
@@ -219,42 +192,15 @@ pub fn eval_binary_expr(
                     -(j as isize),
                 ))))
             },
-            ctx,
         ),
-        BinOp::Leq => eval_cmp_expr(left_op, right_op, |s1, s2| s1 <= s2, |i1, i2| i1 <= i2, ctx),
-        BinOp::Lt => eval_cmp_expr(left_op, right_op, |s1, s2| s1 < s2, |i1, i2| i1 < i2, ctx),
-        BinOp::Geq => eval_cmp_expr(left_op, right_op, |s1, s2| s1 >= s2, |i1, i2| i1 >= i2, ctx),
-        BinOp::Gt => eval_cmp_expr(left_op, right_op, |s1, s2| s1 > s2, |i1, i2| i1 > i2, ctx),
-        BinOp::Eq => Ok(LuaValue::Boolean(
-            eval_expr(left_op, ctx)? == eval_expr(right_op, ctx)?,
-        )),
-        BinOp::Neq => Ok(LuaValue::Boolean(
-            eval_expr(left_op, ctx)? != eval_expr(right_op, ctx)?,
-        )),
-        BinOp::BoolAnd => {
-            let left_op = eval_expr(left_op, ctx)?;
-            match left_op {
-                LuaValue::Nil => Ok(LuaValue::Nil),
-                LuaValue::Boolean(b) => if b {
-                    eval_expr(right_op, ctx)
-                } else {
-                    Ok(LuaValue::Boolean(b))
-                },
-                _ => eval_expr(right_op, ctx),
-            }
-        }
-        BinOp::BoolOr => {
-            let left_op = eval_expr(left_op, ctx)?;
-            match left_op {
-                LuaValue::Nil => eval_expr(right_op, ctx),
-                LuaValue::Boolean(b) => if b {
-                    Ok(LuaValue::Boolean(b))
-                } else {
-                    eval_expr(right_op, ctx)
-                },
-                _ => Ok(left_op),
-            }
-        }
+        BinOp::Leq => compare_values(&left_op, &right_op, |s1, s2| s1 <= s2, |i1, i2| i1 <= i2).map(LuaValue::Boolean),
+        BinOp::Lt => compare_values(&left_op, &right_op, |s1, s2| s1 < s2, |i1, i2| i1 < i2).map(LuaValue::Boolean),
+        BinOp::Geq => compare_values(&left_op, &right_op, |s1, s2| s1 >= s2, |i1, i2| i1 >= i2).map(LuaValue::Boolean),
+        BinOp::Gt => compare_values(&left_op, &right_op, |s1, s2| s1 > s2, |i1, i2| i1 > i2).map(LuaValue::Boolean),
+        BinOp::Eq => Ok(LuaValue::Boolean(left_op == right_op)),
+        BinOp::Neq => Ok(LuaValue::Boolean(left_op != right_op)),
+        BinOp::BoolAnd => Ok(if boolean_coercion(&left_op) { right_op } else { left_op }),
+        BinOp::BoolOr => Ok(if boolean_coercion(&left_op) { left_op } else { right_op }),
     }
 }
 
@@ -269,6 +215,10 @@ mod tests {
 
     use std::borrow::Cow;
 
+    fn eval_binary_expr(left: &Box<Exp>, right: &Box<Exp>, operator: &BinOp, ctx: &LuaState) -> Result<LuaValue> {
+        ::bytecode::eval_exp(&Exp::BinExp(left.clone(), operator.clone(), right.clone()), ctx)
+    }
+
     #[test]
     fn test_addition() {
         let ctx = LuaState::new();
@@ -1,93 +1,156 @@
 mod binop;
 mod unop;
-pub mod prefixexp;
 
-use super::{var_to_string, LuaError, Result};
-use super::types::{LuaState, LuaTable, LuaValue, Number};
+use super::{LuaError, Result};
+use super::sanitizer;
+use super::interner::LuaString;
+use super::types::{CallName, LuaState, LuaValue, Number};
 
-use nom_lua53;
 use std;
 
-// What do we say? We say "Merci Basile!"
-fn lit_to_string(string: &nom_lua53::string::StringLit) -> String {
-    return String::from_utf8_lossy(&(string.0)).to_string();
-}
+pub use self::binop::{eval_binary_op, less_than};
+pub use self::unop::eval_unary_op;
+
+/// Maximum length of a chain of `__index` or `__newindex` metamethods, as in the
+/// reference implementation.
+const MAX_META_CHAIN: usize = 2000;
 
 pub fn num_coercion(val: LuaValue) -> LuaValue {
     match val {
         LuaValue::Str(s) => {
-            let parsed = str::parse::<isize>(&s);
+            let parsed = match std::str::from_utf8(&s) {
+                Ok(text) => str::parse::<isize>(text)
+                    .map(Number::Int)
+                    .or_else(|_| str::parse::<f64>(text).map(Number::Float))
+                    .ok(),
+                Err(_) => None,
+            };
             match parsed {
-                Ok(n) => LuaValue::Number(Number::Int(n)),
-                Err(_) => {
-                    let parsed = str::parse::<f64>(&s);
-                    match parsed {
-                        Ok(n) => LuaValue::Number(Number::Float(n)),
-                        Err(_) => LuaValue::Str(s),
-                    }
-                }
+                Some(n) => LuaValue::Number(n),
+                None => LuaValue::Str(s),
             }
         }
         _ => val,
     }
 }
 
-pub fn eval_expr(expr: &nom_lua53::Exp, ctx: &LuaState) -> Result<LuaValue> {
-    match *expr {
-        nom_lua53::Exp::Nil => Ok(LuaValue::Nil),
-        nom_lua53::Exp::Bool(val) => Ok(LuaValue::Boolean(val)),
-        nom_lua53::Exp::Num(val) => Ok(match val {
-            nom_lua53::num::Numeral::Float(fl) => LuaValue::Number(Number::Float(fl)),
-            nom_lua53::num::Numeral::Int(i) => LuaValue::Number(Number::Int(i)),
-        }),
-        nom_lua53::Exp::BinExp(ref left, ref op, ref right) => {
-            binop::eval_binary_expr(left, right, &op, ctx)
-        }
-        nom_lua53::Exp::UnExp(ref operator, ref operand) => {
-            unop::eval_unary_expr(operand, &operator, ctx)
+/// Keeps only the first of the values returned by a function, as when a call is used
+/// where a single value is expected.
+pub fn first_value(mut values: Vec<LuaValue>) -> LuaValue {
+    if values.is_empty() {
+        LuaValue::Nil
+    } else {
+        values.swap_remove(0)
+    }
+}
+
+/// The handler of `event` in the metatable of a value, one of the names of the state.
+pub fn get_metamethod(value: &LuaValue, event: &LuaString) -> LuaValue {
+    match value {
+        &LuaValue::Table(ref t) => match t.get_metatable() {
+            Some(mt) => mt.get_string(event),
+            None => LuaValue::Nil,
+        },
+        &LuaValue::UserData(ref u) => match u.get_metatable() {
+            Some(metatable) => metatable.get_string(event),
+            None => LuaValue::Nil,
+        },
+        _ => LuaValue::Nil,
+    }
+}
+
+/// Indexes a value the way the language does, going through the `__index` metamethods
+/// when the key is absent.
+pub fn index_value(value: &LuaValue, key: &LuaValue, ctx: &LuaState) -> Result<LuaValue> {
+    let mut current = value.clone();
+    for _ in 0..MAX_META_CHAIN {
+        let handler = get_metamethod(&current, &ctx.names().index);
+        if let LuaValue::Table(ref t) = current {
+            let raw = t.get(key);
+            if raw != LuaValue::Nil || handler == LuaValue::Nil {
+                if raw == LuaValue::Nil {
+                    sanitizer::check_key_miss(ctx, t, key)?;
+                }
+                return Ok(raw);
+            }
+        } else if handler == LuaValue::Nil {
+            return Err(LuaError::TypeError("Not indexable".to_owned()));
         }
-        nom_lua53::Exp::Str(ref s) => Ok(LuaValue::Str(lit_to_string(s))),
-        nom_lua53::Exp::Table(ref t) => eval_inline_table(t, ctx),
-        nom_lua53::Exp::PrefixExp(ref e) => {
-            prefixexp::eval_prefix_expr(&e.prefix, &e.suffix_chain, ctx)
+        if let LuaValue::Function(_) = handler {
+            return Ok(first_value(call_function(&handler, &[current, key.clone()], ctx)?));
         }
-        nom_lua53::Exp::Ellipses => Err(LuaError::NotImplementedError),
-        nom_lua53::Exp::FuncCall(_) => Err(LuaError::NotImplementedError),
-        nom_lua53::Exp::Lambda(_) => Err(LuaError::NotImplementedError),
+        current = handler;
     }
+    Err(LuaError::OtherError(
+        "'__index' chain too long; possibly a loop".to_owned(),
+    ))
 }
 
-fn eval_inline_table(src: &nom_lua53::TableLit, ctx: &LuaState) -> Result<LuaValue> {
-    // First, we count how many positional epxressions there are to only do one allocation.
-    let mut sequence_count = 0;
-    for field in src.into_iter() {
-        match field {
-            &nom_lua53::Field::PosAssign(_) => sequence_count = sequence_count + 1,
-            _ => (),
+/// Assigns to a field the way the language does, going through the `__newindex`
+/// metamethods when the key is absent.
+pub fn set_index(target: &LuaValue, key: &LuaValue, value: &LuaValue, ctx: &LuaState) -> Result<()> {
+    let mut current = target.clone();
+    for _ in 0..MAX_META_CHAIN {
+        let handler = get_metamethod(&current, &ctx.names().newindex);
+        if let LuaValue::Table(ref t) = current {
+            if handler == LuaValue::Nil || t.get(key) != LuaValue::Nil {
+                sanitizer::check_key_write(ctx, t, key, value)?;
+                return t.set(&ctx.intern_value(key.clone()), value);
+            }
+        } else if handler == LuaValue::Nil {
+            return Err(LuaError::TypeError("Not indexable".to_owned()));
+        }
+        if let LuaValue::Function(_) = handler {
+            call_function(&handler, &[current, key.clone(), value.clone()], ctx)?;
+            return Ok(());
         }
+        current = handler;
     }
+    Err(LuaError::OtherError(
+        "'__newindex' chain too long; possibly a loop".to_owned(),
+    ))
+}
 
-    let ret = LuaTable::with_capacity(ctx.get_ref_id(), sequence_count);
-    let mut next_index = 1;
-    for field in src.iter() {
-        match *field {
-            nom_lua53::Field::PosAssign(ref exp) => {
-                ret.set(&LuaValue::Number(Number::Int(next_index)), &eval_expr(exp, ctx)?)?;
-                next_index = next_index + 1;
-            }
-            nom_lua53::Field::ExpAssign(ref key, ref value) => {
-                let key = eval_expr(key, ctx)?;
-                let value = eval_expr(value, ctx)?;
-                ret.set(&key, &value)?;
-            }
-            nom_lua53::Field::NameAssign(ref key, ref value) => {
-                let key = var_to_string(key);
-                let value = eval_expr(value, ctx)?;
-                ret.set(&LuaValue::Str(key), &value)?;
-            }
+/// Computes the length of a value as the `#` operator, honouring the `__len` metamethod.
+pub fn length_of(value: &LuaValue, ctx: &LuaState) -> Result<LuaValue> {
+    let handler = get_metamethod(value, &ctx.names().len);
+    if handler != LuaValue::Nil {
+        return Ok(first_value(call_function(&handler, &[value.clone()], ctx)?));
+    }
+    match value {
+        &LuaValue::Str(ref s) => Ok(LuaValue::Number(Number::Int(s.len() as isize))),
+        &LuaValue::Table(ref t) => Ok(LuaValue::Number(Number::Int(t.sequence_border() as isize))),
+        _ => Err(LuaError::TypeError(
+            "Trying to do get size on an unsupported type.".to_owned(),
+        )),
+    }
+}
+
+pub fn call_function(function: &LuaValue, args: &[LuaValue], ctx: &LuaState) -> Result<Vec<LuaValue>> {
+    call_named_function(function, args, None, ctx)
+}
+
+/// Calls a value, `name` being how the calling code refers to it.
+pub fn call_named_function(
+    function: &LuaValue,
+    args: &[LuaValue],
+    name: Option<CallName>,
+    ctx: &LuaState,
+) -> Result<Vec<LuaValue>> {
+    if let &LuaValue::Function(ref f) = function {
+        return f.call_named(ctx, args, name);
+    }
+    match get_metamethod(function, &ctx.names().call) {
+        LuaValue::Function(ref handler) => {
+            let mut full_args = Vec::with_capacity(args.len() + 1);
+            full_args.push(function.clone());
+            full_args.extend_from_slice(args);
+            handler.call_named(ctx, &full_args, name)
         }
+        _ => Err(LuaError::TypeError(
+            format!("Trying to call a {} value.", function.type_name()),
+        )),
     }
-    return Ok(LuaValue::Table(ret));
 }
 
 pub fn boolean_coercion(val: &LuaValue) -> bool {
@@ -101,10 +164,29 @@ pub fn boolean_coercion(val: &LuaValue) -> bool {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use types::LuaTable;
+
+    #[test]
+    fn test_call_metamethod() {
+        let ctx = LuaState::new();
+        let table = LuaTable::new(ctx.get_ref_id());
+        let metatable = LuaTable::new(ctx.get_ref_id());
+        let handler = ctx.create_native("__call", |_ctx: &LuaState, args: &[LuaValue]| {
+            Ok(vec![LuaValue::Number(Number::Int(args.len() as isize))])
+        });
+        metatable.set_string(&ctx.intern(b"__call"), &handler);
+        let callable = LuaValue::Table(table.clone());
+        assert!(call_function(&callable, &[], &ctx).is_err());
+        table.set_metatable(Some(metatable));
+        assert_eq!(
+            call_function(&callable, &[LuaValue::Nil, LuaValue::Nil], &ctx).unwrap(),
+            vec![LuaValue::Number(Number::Int(3))]
+        );
+    }
 
     #[test]
     fn test_boolean_coercion() {
-        assert!(boolean_coercion(&LuaValue::Str("".to_owned())));
+        assert!(boolean_coercion(&LuaValue::Str("".into())));
         assert!(boolean_coercion(&LuaValue::Number(Number::Int(0))));
         assert!(boolean_coercion(&LuaValue::Number(Number::Int(2))));
         assert!(!boolean_coercion(&LuaValue::Nil));
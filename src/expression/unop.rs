@@ -1,11 +1,11 @@
-use super::{boolean_coercion, eval_expr, num_coercion, LuaState, LuaValue, Result, Number};
+use super::{boolean_coercion, length_of, num_coercion, LuaState, LuaValue, Result, Number};
 use nom_lua53::op::UnOp;
-use nom_lua53::Exp;
+use sanitizer;
 
 use LuaError::*;
 
-pub fn eval_unary_expr(operand: &Box<Exp>, operator: &UnOp, ctx: &LuaState) -> Result<LuaValue> {
-    let operand = eval_expr(&operand, ctx)?;
+/// Applies a unary operator to an operand already evaluated.
+pub fn eval_unary_op(operand: LuaValue, operator: &UnOp, ctx: &LuaState) -> Result<LuaValue> {
     match *operator {
         UnOp::BoolNot => Ok(LuaValue::Boolean(!boolean_coercion(&operand))),
         UnOp::Minus => match num_coercion(operand) {
@@ -17,13 +17,12 @@ pub fn eval_unary_expr(operand: &Box<Exp>, operator: &UnOp, ctx: &LuaState) -> R
                 "Trying to do arithmetic on a non-numerical value.".to_owned(),
             )),
         },
-        UnOp::Length => match operand {
-            LuaValue::Str(s) => Ok(LuaValue::Number(Number::Int(s.len() as isize))),
-            LuaValue::Table(t) => Ok(LuaValue::Number(Number::Int(t.sequence_border() as isize))),
-            _ => Err(TypeError(
-                "Trying to do get size on an unsupported type.".to_owned(),
-            )),
-        },
+        UnOp::Length => {
+            if let LuaValue::Table(ref t) = operand {
+                sanitizer::check_sequence(ctx, t, "'#'")?;
+            }
+            length_of(&operand, ctx)
+        }
         UnOp::BitNot => match num_coercion(operand) {
             LuaValue::Number(num) => Ok(LuaValue::Number(Number::Int(!num.to_int()))),
             _ => Err(TypeError(
@@ -44,6 +43,10 @@ mod tests {
 
     use std::borrow::Cow;
 
+    fn eval_unary_expr(operand: &Box<Exp>, operator: &UnOp, ctx: &LuaState) -> Result<LuaValue> {
+        ::bytecode::eval_exp(&Exp::UnExp(operator.clone(), operand.clone()), ctx)
+    }
+
     #[test]
     fn test_math_negation() {
         let ctx = LuaState::new();
@@ -0,0 +1,318 @@
+// Strings of Lua values. Each string carries the hash of its bytes, computed once when it
+// is made, and the short ones are interned by the state: the names and keys used by
+// chunks are then shared, and compare by pointer.
+
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::fmt;
+use std::hash::{BuildHasherDefault, Hash, Hasher};
+use std::ops::Deref;
+use std::rc::Rc;
+
+/// Longest string that gets interned, as `LUAI_MAXSHORTLEN`.
+pub const MAX_SHORT_LEN: usize = 40;
+
+/// Number of interned strings under which the interner doesn't bother dropping the
+/// unused ones.
+const MIN_SWEEP: usize = 256;
+
+/// FNV-1a, which is enough for keys whose hash is only computed once.
+fn hash_bytes(bytes: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for &b in bytes {
+        hash ^= b as u64;
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+    hash
+}
+
+struct StrData {
+    hash: u64,
+    bytes: Box<[u8]>,
+}
+
+/// An immutable string, cheap to clone, hash and compare.
+#[derive(Clone)]
+pub struct LuaString(Rc<StrData>);
+
+impl LuaString {
+    /// Makes a string of its own, which only the interner shares.
+    pub fn new(bytes: Vec<u8>) -> LuaString {
+        LuaString(Rc::new(StrData {
+            hash: hash_bytes(&bytes),
+            bytes: bytes.into_boxed_slice(),
+        }))
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0.bytes
+    }
+}
+
+impl Deref for LuaString {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.0.bytes
+    }
+}
+
+impl PartialEq for StrData {
+    fn eq(&self, other: &StrData) -> bool {
+        self.hash == other.hash && self.bytes == other.bytes
+    }
+}
+
+/// Interned strings are equal when they are the same, the others are compared byte by
+/// byte once their hashes match.
+impl PartialEq for LuaString {
+    fn eq(&self, other: &LuaString) -> bool {
+        Rc::ptr_eq(&self.0, &other.0) || *self.0 == *other.0
+    }
+}
+
+impl Eq for LuaString {}
+
+impl Hash for LuaString {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        state.write_u64(self.0.hash);
+    }
+}
+
+impl PartialOrd for LuaString {
+    fn partial_cmp(&self, other: &LuaString) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for LuaString {
+    fn cmp(&self, other: &LuaString) -> Ordering {
+        self.as_bytes().cmp(other.as_bytes())
+    }
+}
+
+impl fmt::Debug for LuaString {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}", String::from_utf8_lossy(&self.0.bytes))
+    }
+}
+
+impl From<Vec<u8>> for LuaString {
+    fn from(bytes: Vec<u8>) -> LuaString {
+        LuaString::new(bytes)
+    }
+}
+
+impl<'a> From<&'a [u8]> for LuaString {
+    fn from(bytes: &'a [u8]) -> LuaString {
+        LuaString::new(bytes.to_vec())
+    }
+}
+
+impl From<String> for LuaString {
+    fn from(s: String) -> LuaString {
+        LuaString::new(s.into_bytes())
+    }
+}
+
+impl<'a> From<&'a str> for LuaString {
+    fn from(s: &'a str) -> LuaString {
+        LuaString::new(s.as_bytes().to_vec())
+    }
+}
+
+/// A hasher for the keys of tables. Strings come with their hash, and the other keys are
+/// numbers or identifiers, which don't need SipHash either.
+#[derive(Default)]
+pub struct KeyHasher {
+    hash: u64,
+}
+
+impl Hasher for KeyHasher {
+    fn finish(&self) -> u64 {
+        self.hash
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for &b in bytes {
+            self.write_u64(b as u64);
+        }
+    }
+
+    fn write_u64(&mut self, n: u64) {
+        self.hash = (self.hash.rotate_left(5) ^ n).wrapping_mul(0x517c_c1b7_2722_0a95);
+    }
+
+    fn write_usize(&mut self, n: usize) {
+        self.write_u64(n as u64);
+    }
+
+    fn write_isize(&mut self, n: isize) {
+        self.write_u64(n as u64);
+    }
+}
+
+pub type BuildKeyHasher = BuildHasherDefault<KeyHasher>;
+
+/// The short strings of a state, bucketed by hash. The strings nothing else refers to any
+/// more are dropped whenever their number doubles.
+#[derive(Debug, Default)]
+pub struct Interner {
+    buckets: HashMap<u64, Vec<LuaString>, BuildKeyHasher>,
+    count: usize,
+    sweep_at: usize,
+}
+
+impl Interner {
+    pub fn new() -> Interner {
+        Interner {
+            sweep_at: MIN_SWEEP,
+            ..Interner::default()
+        }
+    }
+
+    /// The string with the given bytes, shared with the other strings equal to it if it
+    /// is short.
+    pub fn intern(&mut self, bytes: &[u8]) -> LuaString {
+        if bytes.len() > MAX_SHORT_LEN {
+            return LuaString::from(bytes);
+        }
+        let hash = hash_bytes(bytes);
+        if let Some(s) = self.find(hash, bytes) {
+            return s;
+        }
+        let s = LuaString(Rc::new(StrData {
+            hash,
+            bytes: bytes.to_vec().into_boxed_slice(),
+        }));
+        self.insert(s.clone());
+        s
+    }
+
+    /// Interns a string already made, keeping it if no equal one was interned before.
+    pub fn intern_string(&mut self, s: &LuaString) -> LuaString {
+        if s.len() > MAX_SHORT_LEN {
+            return s.clone();
+        }
+        if let Some(interned) = self.find(s.0.hash, s) {
+            return interned;
+        }
+        self.insert(s.clone());
+        s.clone()
+    }
+
+    fn find(&self, hash: u64, bytes: &[u8]) -> Option<LuaString> {
+        self.buckets
+            .get(&hash)
+            .and_then(|bucket| bucket.iter().find(|s| s.as_bytes() == bytes))
+            .cloned()
+    }
+
+    fn insert(&mut self, s: LuaString) {
+        if self.count >= self.sweep_at {
+            self.sweep();
+        }
+        self.buckets.entry(s.0.hash).or_default().push(s);
+        self.count += 1;
+    }
+
+    fn sweep(&mut self) {
+        for bucket in self.buckets.values_mut() {
+            bucket.retain(|s| Rc::strong_count(&s.0) > 1);
+        }
+        self.buckets.retain(|_, bucket| !bucket.is_empty());
+        self.count = self.buckets.values().map(|bucket| bucket.len()).sum();
+        self.sweep_at = (self.count * 2).max(MIN_SWEEP);
+    }
+}
+
+/// The strings the interpreter looks up by itself: the events of metamethods and the
+/// keys of the registry. They are interned once per state so that these lookups
+/// neither allocate nor compare bytes.
+#[derive(Debug)]
+pub struct Names {
+    pub index: LuaString,
+    pub newindex: LuaString,
+    pub len: LuaString,
+    pub call: LuaString,
+    pub tostring: LuaString,
+    pub name: LuaString,
+    /// `package.loaded` and `package.preload`.
+    pub loaded: LuaString,
+    pub preload: LuaString,
+    /// The metatable of the files of the `io` library, and its default files.
+    pub file_handle: LuaString,
+    pub io_input: LuaString,
+    pub io_output: LuaString,
+}
+
+impl Names {
+    pub fn new(interner: &mut Interner) -> Names {
+        Names {
+            index: interner.intern(b"__index"),
+            newindex: interner.intern(b"__newindex"),
+            len: interner.intern(b"__len"),
+            call: interner.intern(b"__call"),
+            tostring: interner.intern(b"__tostring"),
+            name: interner.intern(b"__name"),
+            loaded: interner.intern(b"_LOADED"),
+            preload: interner.intern(b"_PRELOAD"),
+            file_handle: interner.intern(b"FILE*"),
+            io_input: interner.intern(b"_IO_input"),
+            io_output: interner.intern(b"_IO_output"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_interning() {
+        let mut interner = Interner::new();
+        let a = interner.intern(b"name");
+        let b = interner.intern(b"name");
+        assert!(Rc::ptr_eq(&a.0, &b.0));
+        let own = LuaString::from("name");
+        assert!(!Rc::ptr_eq(&a.0, &own.0));
+        assert_eq!(a, own);
+        assert!(Rc::ptr_eq(&interner.intern_string(&own).0, &a.0));
+
+        let long = vec![b'x'; MAX_SHORT_LEN + 1];
+        let c = interner.intern(&long);
+        assert!(!Rc::ptr_eq(&c.0, &interner.intern(&long).0));
+        assert!(LuaString::from("a") < LuaString::from("b"));
+    }
+
+    #[test]
+    fn test_sweep() {
+        let mut interner = Interner::new();
+        let kept = interner.intern(b"kept");
+        for i in 0..MIN_SWEEP * 2 {
+            interner.intern(format!("s{}", i).as_bytes());
+        }
+        assert!(interner.count < MIN_SWEEP * 2);
+        assert!(Rc::ptr_eq(&interner.intern(b"kept").0, &kept.0));
+    }
+
+    #[test]
+    fn test_names() {
+        let mut interner = Interner::new();
+        let names = Names::new(&mut interner);
+        for i in 0..MIN_SWEEP * 2 {
+            interner.intern(format!("s{}", i).as_bytes());
+        }
+        assert!(Rc::ptr_eq(&interner.intern(b"__index").0, &names.index.0));
+        assert!(Rc::ptr_eq(&interner.intern(b"_LOADED").0, &names.loaded.0));
+    }
+
+    #[test]
+    fn test_keys() {
+        let mut map: HashMap<LuaString, usize, BuildKeyHasher> = HashMap::default();
+        map.insert(LuaString::from("a"), 1);
+        assert_eq!(map.get(&LuaString::from("a")), Some(&1));
+        assert_eq!(map.get(&LuaString::from("b")), None);
+    }
+}
@@ -1,11 +1,23 @@
 extern crate nom_lua53;
 
-use nom_lua53::{parse_all, ParseResult};
 use nom_lua53::name::VarName;
 
+use std::fmt;
+
+mod bytecode;
 mod expression;
 mod types;
-mod control_flow;
+mod chunk;
+mod stdlib;
+mod convert;
+mod interner;
+mod sanitizer;
+
+pub use convert::{FromLua, FromLuaMulti, HostFunction, IntoLua, IntoLuaMulti};
+pub use interner::LuaString;
+pub use sanitizer::{Finding, Sanitizers, Severity};
+pub use stdlib::Capabilities;
+pub use types::{LuaFunction, LuaState, LuaTable, LuaUserData, LuaValue, NativeFunction, Number};
 
 #[derive(PartialEq, Eq, Debug, Clone)]
 pub enum LuaError {
@@ -13,28 +25,25 @@ pub enum LuaError {
     IndexError(String),
     ArithmeticError(String),
     OtherError(String),
+    SyntaxError(String),
     NotImplementedError,
 }
 
-type Result<T> = std::result::Result<T, LuaError>;
-
-pub fn var_to_string(var: &VarName) -> String {
-    String::from_utf8_lossy(var.0).to_string()
+impl fmt::Display for LuaError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            LuaError::TypeError(ref msg)
+            | LuaError::IndexError(ref msg)
+            | LuaError::ArithmeticError(ref msg)
+            | LuaError::OtherError(ref msg)
+            | LuaError::SyntaxError(ref msg) => write!(f, "{}", msg),
+            LuaError::NotImplementedError => write!(f, "not implemented"),
+        }
+    }
 }
 
-pub fn eval_file(input: &[u8]) -> Result<()> {
-    let mut ctx = types::LuaState::new();
-    match parse_all(input) {
-        ParseResult::Done(blk) => {
-            let _ = control_flow::exec_block(&blk, &mut ctx);
-            println!("{:?}", ctx.get_local_scope());
-            println!("{:?}", ctx);
-        }
+pub type Result<T> = std::result::Result<T, LuaError>;
 
-        ParseResult::Error(rest, ss) => {
-            println!("Error. statements == {:#?}", ss);
-            println!("rest == '{}'", String::from_utf8_lossy(rest));
-        }
-    };
-    return Ok(());
+pub fn var_to_string(var: &VarName) -> String {
+    String::from_utf8_lossy(var.0).to_string()
 }
@@ -1,12 +1,293 @@
+// The `seo2` command-line interpreter, which follows the options of the reference `lua`
+// program.
+
+extern crate rustyline;
 extern crate seo2;
 
-use std::io::{self, Read};
+mod repl;
+
+use std::env;
+use std::io::{self, IsTerminal, Write};
+use std::process;
+
+use seo2::{Capabilities, LuaError, LuaFunction, LuaState, LuaTable, LuaValue, Number};
+
+const USAGE: &str = "[options] [script [args]]\n\
+                     Available options are:\n  \
+                     -e stat  execute string 'stat'\n  \
+                     -i       enter interactive mode after executing 'script'\n  \
+                     -l name  require library 'name'\n  \
+                     -v       show version information\n  \
+                     -E       ignore environment variables\n  \
+                     --       stop handling options\n  \
+                     -        stop handling options and execute stdin";
+
+/// The options of the command line, in the order they were given.
+#[derive(Debug, Default, PartialEq)]
+struct Options {
+    /// The `-e` and `-l` options, run one after the other before the script.
+    actions: Vec<Action>,
+    interactive: bool,
+    version: bool,
+    ignore_env: bool,
+    /// Index of the script name in the arguments, if there is one.
+    script: Option<usize>,
+}
+
+#[derive(Debug, PartialEq)]
+enum Action {
+    Exec(Vec<u8>),
+    Require(Vec<u8>),
+}
+
+#[cfg(unix)]
+fn to_bytes(arg: std::ffi::OsString) -> Vec<u8> {
+    use std::os::unix::ffi::OsStringExt;
+    arg.into_vec()
+}
+
+#[cfg(not(unix))]
+fn to_bytes(arg: std::ffi::OsString) -> Vec<u8> {
+    arg.to_string_lossy().into_owned().into_bytes()
+}
+
+/// Reads the options, which stop at the first argument that isn't one: the script name.
+fn collect_args(args: &[Vec<u8>]) -> Result<Options, String> {
+    let mut options = Options::default();
+    let mut i = 1;
+    while i < args.len() {
+        let arg = &args[i];
+        match &arg[..] {
+            b"--" => {
+                if i + 1 < args.len() {
+                    options.script = Some(i + 1);
+                }
+                return Ok(options);
+            }
+            b"-" => {
+                options.script = Some(i);
+                return Ok(options);
+            }
+            b"-E" => options.ignore_env = true,
+            b"-i" => {
+                options.interactive = true;
+                options.version = true;
+            }
+            b"-v" => options.version = true,
+            _ if arg.starts_with(b"-e") || arg.starts_with(b"-l") => {
+                // The argument is either glued to the option or the next one.
+                let value = if arg.len() > 2 {
+                    arg[2..].to_vec()
+                } else {
+                    i += 1;
+                    match args.get(i) {
+                        Some(value) if !value.starts_with(b"-") => value.clone(),
+                        _ => return Err(format!("'{}' needs argument", String::from_utf8_lossy(arg))),
+                    }
+                };
+                options.actions.push(if arg[1] == b'e' {
+                    Action::Exec(value)
+                } else {
+                    Action::Require(value)
+                });
+            }
+            _ if arg.starts_with(b"-") => {
+                return Err(format!("unrecognized option '{}'", String::from_utf8_lossy(arg)));
+            }
+            _ => {
+                options.script = Some(i);
+                return Ok(options);
+            }
+        }
+        i += 1;
+    }
+    Ok(options)
+}
+
+/// Builds the `arg` table: the script name at index 0, its arguments after it, and the
+/// interpreter with its options at negative indices. Without a script, the interpreter
+/// name is at index 0.
+fn create_arg_table(ctx: &LuaState, args: &[Vec<u8>], script: Option<usize>) -> LuaTable {
+    let table = LuaTable::new(ctx.get_ref_id());
+    let base = script.unwrap_or(0) as isize;
+    for (i, arg) in args.iter().enumerate() {
+        let key = LuaValue::Number(Number::Int(i as isize - base));
+        let _ = table.set(&key, &LuaValue::Str(arg.clone().into()));
+    }
+    table
+}
+
+/// Describes an error as the message followed by the stack at the time it was raised,
+/// when it was raised while running code.
+fn describe_error(ctx: &LuaState, err: &LuaError) -> String {
+    match ctx.take_traceback() {
+        Some(traceback) => format!("{}\n{}", err, traceback),
+        None => err.to_string(),
+    }
+}
+
+fn report(progname: &str, msg: &str) {
+    let stderr = io::stderr();
+    let mut stderr = stderr.lock();
+    let _ = writeln!(stderr, "{}: {}", progname, msg);
+    let _ = stderr.flush();
+}
+
+fn print_version() {
+    println!("SeO2 {} -- a Lua 5.3 interpreter in Rust", env!("CARGO_PKG_VERSION"));
+}
+
+fn run(ctx: &LuaState, function: seo2::Result<LuaFunction>, args: &[LuaValue]) -> Result<Vec<LuaValue>, String> {
+    function
+        .and_then(|f| f.call(ctx, args))
+        .map_err(|err| describe_error(ctx, &err))
+}
+
+/// Runs `LUA_INIT_5_3`, or `LUA_INIT` when it isn't set: a file when it starts with '@',
+/// source code otherwise.
+fn handle_init(ctx: &LuaState) -> Result<(), String> {
+    let (name, init) = match env::var_os("LUA_INIT_5_3") {
+        Some(init) => ("=LUA_INIT_5_3", init),
+        None => match env::var_os("LUA_INIT") {
+            Some(init) => ("=LUA_INIT", init),
+            None => return Ok(()),
+        },
+    };
+    let init = to_bytes(init);
+    let function = if init.starts_with(b"@") {
+        ctx.load_file(Some(&init[1..]))
+    } else {
+        ctx.load(&init, name)
+    };
+    run(ctx, function, &[]).map(|_| ())
+}
+
+/// Requires a module and stores it in the global of the same name, as `-l` does.
+fn require(ctx: &LuaState, name: &[u8]) -> Result<(), String> {
+    let module = ctx
+        .get_global::<LuaValue>("require")
+        .and_then(|require| ctx.call::<_, LuaValue>(&require, LuaValue::Str(name.into())))
+        .map_err(|err| describe_error(ctx, &err))?;
+    ctx.set_global(&String::from_utf8_lossy(name), module)
+        .map_err(|err| err.to_string())
+}
+
+/// Runs the `-e` and `-l` options in order.
+fn run_actions(ctx: &LuaState, actions: &[Action]) -> Result<(), String> {
+    for action in actions {
+        match *action {
+            Action::Exec(ref source) => run(ctx, ctx.load(source, "=(command line)"), &[]).map(|_| ())?,
+            Action::Require(ref name) => require(ctx, name)?,
+        }
+    }
+    Ok(())
+}
+
+/// Runs the script at `args[script]` with the arguments after it. A lone "-" stands for
+/// the standard input, unless it comes after "--".
+fn run_script(ctx: &LuaState, args: &[Vec<u8>], script: usize) -> Result<(), String> {
+    let filename = &args[script];
+    let function = if filename == b"-" && args[script - 1] != b"--" {
+        ctx.load_file(None)
+    } else {
+        ctx.load_file(Some(filename))
+    };
+    let script_args: Vec<LuaValue> = args[script + 1..]
+        .iter()
+        .map(|arg| LuaValue::Str(arg.clone().into()))
+        .collect();
+    run(ctx, function, &script_args).map(|_| ())
+}
 
 fn main() {
-    let mut input = Vec::new();
-    io::stdin()
-        .read_to_end(&mut input)
-        .expect("Couldn't read from stdin");
+    let args: Vec<Vec<u8>> = env::args_os().map(to_bytes).collect();
+    let progname = match args.first() {
+        Some(name) => String::from_utf8_lossy(name).into_owned(),
+        None => "seo2".to_owned(),
+    };
+    let options = match collect_args(&args) {
+        Ok(options) => options,
+        Err(msg) => {
+            report(&progname, &msg);
+            let _ = writeln!(io::stderr(), "usage: {} {}", progname, USAGE);
+            process::exit(1);
+        }
+    };
+    if options.version {
+        print_version();
+    }
+
+    let ctx = LuaState::with_capabilities(Capabilities {
+        path_env: !options.ignore_env,
+        ..Capabilities::all()
+    });
+    let arg = create_arg_table(&ctx, &args, options.script);
+    ctx.globals().set_string(&ctx.intern(b"arg"), &LuaValue::Table(arg));
+
+    let mut result = if options.ignore_env {
+        Ok(())
+    } else {
+        handle_init(&ctx)
+    };
+    result = result.and_then(|_| run_actions(&ctx, &options.actions));
+    if let Some(script) = options.script {
+        result = result.and_then(|_| run_script(&ctx, &args, script));
+    }
+    if let Err(msg) = result {
+        report(&progname, &msg);
+        process::exit(1);
+    }
+
+    if options.interactive {
+        repl::run(&ctx, &progname);
+    } else if options.script.is_none() && options.actions.is_empty() && !options.version {
+        if io::stdin().is_terminal() {
+            print_version();
+            repl::run(&ctx, &progname);
+        } else if let Err(msg) = run(&ctx, ctx.load_file(None), &[]) {
+            report(&progname, &msg);
+            process::exit(1);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<Vec<u8>> {
+        args.iter().map(|a| a.as_bytes().to_vec()).collect()
+    }
+
+    #[test]
+    fn test_collect_args() {
+        let options = collect_args(&args(&["seo2", "-e", "x=1", "-lmod", "-E", "s.lua", "-v"])).unwrap();
+        assert_eq!(
+            options,
+            Options {
+                actions: vec![Action::Exec(b"x=1".to_vec()), Action::Require(b"mod".to_vec())],
+                ignore_env: true,
+                script: Some(5),
+                ..Options::default()
+            }
+        );
+        assert_eq!(collect_args(&args(&["seo2", "--"])).unwrap().script, None);
+        assert_eq!(collect_args(&args(&["seo2", "--", "-"])).unwrap().script, Some(2));
+        assert!(collect_args(&args(&["seo2", "-i"])).unwrap().version);
+        assert_eq!(collect_args(&args(&["seo2", "-e"])), Err("'-e' needs argument".to_owned()));
+        assert_eq!(collect_args(&args(&["seo2", "-x"])), Err("unrecognized option '-x'".to_owned()));
+    }
 
-    println!("{:?}", seo2::eval_file(&input));
+    #[test]
+    fn test_arg_table() {
+        let ctx = LuaState::new();
+        let all = args(&["seo2", "-E", "s.lua", "a"]);
+        let table = create_arg_table(&ctx, &all, Some(2));
+        let int = |i: isize| LuaValue::Number(Number::Int(i));
+        assert_eq!(table.get(&int(-2)), LuaValue::Str("seo2".into()));
+        assert_eq!(table.get(&int(0)), LuaValue::Str("s.lua".into()));
+        assert_eq!(table.get(&int(1)), LuaValue::Str("a".into()));
+        let table = create_arg_table(&ctx, &all[..2], None);
+        assert_eq!(table.get(&int(0)), LuaValue::Str("seo2".into()));
+    }
 }
@@ -0,0 +1,356 @@
+// The interactive mode of the interpreter: a line editor with history and completion of
+// names, running each statement in the same state as it is entered.
+
+use std::env;
+use std::path::PathBuf;
+
+use rustyline::completion::Completer;
+use rustyline::error::ReadlineError;
+use rustyline::highlight::Highlighter;
+use rustyline::hint::Hinter;
+use rustyline::validate::Validator;
+use rustyline::{Context, Editor, Helper};
+
+use seo2::{LuaFunction, LuaState, LuaString, LuaTable, LuaValue};
+
+const PROMPT: &str = "> ";
+/// Prompt of the lines that continue an incomplete statement.
+const CONTINUATION_PROMPT: &str = ">> ";
+const HISTORY_FILE: &str = ".seo2_history";
+/// How many `__index` tables completion follows, in case they form a loop.
+const MAX_INDEX_CHAIN: usize = 10;
+
+/// Completes the names of globals and of table fields from the current state.
+struct LuaHelper {
+    globals: LuaTable,
+}
+
+impl Completer for LuaHelper {
+    type Candidate = String;
+
+    fn complete(&self, line: &str, pos: usize, _ctx: &Context<'_>) -> rustyline::Result<(usize, Vec<String>)> {
+        Ok(complete_names(&self.globals, &line[..pos]))
+    }
+}
+
+impl Hinter for LuaHelper {
+    type Hint = String;
+}
+
+impl Highlighter for LuaHelper {}
+
+impl Validator for LuaHelper {}
+
+impl Helper for LuaHelper {}
+
+/// A field of a table, looked up in the `__index` tables of its metatables when it is
+/// missing. `__index` functions are never called.
+fn field_of(table: &LuaTable, name: &str) -> LuaValue {
+    let mut table = table.clone();
+    for _ in 0..MAX_INDEX_CHAIN {
+        let value = table.get_string(&LuaString::from(name));
+        if value != LuaValue::Nil {
+            return value;
+        }
+        table = match index_table(&table) {
+            Some(index) => index,
+            None => break,
+        };
+    }
+    LuaValue::Nil
+}
+
+fn index_table(table: &LuaTable) -> Option<LuaTable> {
+    match table.get_metatable().map(|mt| mt.get_string(&LuaString::from("__index"))) {
+        Some(LuaValue::Table(index)) => Some(index),
+        _ => None,
+    }
+}
+
+/// The string keys of a table and of its `__index` tables.
+fn field_names(table: &LuaTable) -> Vec<String> {
+    let mut names = Vec::new();
+    let mut table = table.clone();
+    for _ in 0..MAX_INDEX_CHAIN {
+        for (key, _) in table.pairs() {
+            if let LuaValue::Str(key) = key {
+                if let Ok(name) = String::from_utf8(key.to_vec()) {
+                    names.push(name);
+                }
+            }
+        }
+        table = match index_table(&table) {
+            Some(index) => index,
+            None => break,
+        };
+    }
+    names
+}
+
+fn is_name_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_'
+}
+
+/// Completes the name ending the text before the cursor, such as `string.fo` or
+/// `obj:me`. Returns where the candidates start in the line, and the candidates.
+fn complete_names(globals: &LuaTable, before: &str) -> (usize, Vec<String>) {
+    let start = before
+        .char_indices()
+        .rev()
+        .find(|&(_, c)| !is_name_char(c) && c != '.' && c != ':')
+        .map_or(0, |(i, c)| i + c.len_utf8());
+    let word = &before[start..];
+    let (path, partial, partial_start) = match word.rfind(&['.', ':'][..]) {
+        Some(i) => (&word[..i], &word[i + 1..], start + i + 1),
+        None => ("", word, start),
+    };
+    let mut table = globals.clone();
+    if !path.is_empty() {
+        for field in path.split(&['.', ':'][..]) {
+            table = match field_of(&table, field) {
+                LuaValue::Table(t) => t,
+                _ => return (partial_start, Vec::new()),
+            };
+        }
+    }
+    let mut names: Vec<String> = field_names(&table)
+        .into_iter()
+        .filter(|name| name.starts_with(partial) && !name.is_empty())
+        .filter(|name| name.chars().all(is_name_char) && !name.starts_with(|c: char| c.is_ascii_digit()))
+        .collect();
+    names.sort();
+    names.dedup();
+    (partial_start, names)
+}
+
+/// The level of the long bracket `source` starts with, such as 2 for `[==[`, if any.
+fn long_bracket(source: &[u8]) -> Option<usize> {
+    if source.first() != Some(&b'[') {
+        return None;
+    }
+    let level = source[1..].iter().take_while(|&&c| c == b'=').count();
+    if source.get(level + 1) == Some(&b'[') {
+        Some(level)
+    } else {
+        None
+    }
+}
+
+/// Offset just past the long bracket of `level` closing the one `source` starts with.
+fn long_bracket_end(source: &[u8], level: usize) -> Option<usize> {
+    let mut close = vec![b']'];
+    close.extend(vec![b'='; level]);
+    close.push(b']');
+    source[level + 2..]
+        .windows(close.len())
+        .position(|w| w == &close[..])
+        .map(|i| level + 2 + i + close.len())
+}
+
+/// Whether a chunk that doesn't compile may only lack its end: a block, a bracket, a
+/// long string or a comment left open, or an operator still waiting for its operand.
+fn is_incomplete(source: &[u8]) -> bool {
+    let mut blocks = 0isize;
+    let mut brackets = 0isize;
+    let mut pending_operator = false;
+    let mut i = 0;
+    while i < source.len() {
+        let c = source[i];
+        if c == b'-' && source.get(i + 1) == Some(&b'-') {
+            i += 2;
+            match long_bracket(&source[i..]) {
+                Some(level) => match long_bracket_end(&source[i..], level) {
+                    Some(end) => i += end,
+                    None => return true,
+                },
+                None => {
+                    while i < source.len() && source[i] != b'\n' {
+                        i += 1;
+                    }
+                }
+            }
+            continue;
+        }
+        if c.is_ascii_whitespace() {
+            i += 1;
+            continue;
+        }
+        pending_operator = false;
+        if let Some(level) = long_bracket(&source[i..]) {
+            match long_bracket_end(&source[i..], level) {
+                Some(end) => i += end,
+                None => return true,
+            }
+            continue;
+        }
+        match c {
+            b'"' | b'\'' => {
+                i += 1;
+                while i < source.len() && source[i] != c {
+                    match source[i] {
+                        b'\\' => i += 1,
+                        // An unfinished string is an error, not something to continue.
+                        b'\n' => return false,
+                        _ => (),
+                    }
+                    i += 1;
+                }
+                if i >= source.len() {
+                    return true;
+                }
+            }
+            b'(' | b'[' | b'{' => brackets += 1,
+            b')' | b']' | b'}' => brackets -= 1,
+            _ if c.is_ascii_alphanumeric() || c == b'_' => {
+                let end = source[i..]
+                    .iter()
+                    .position(|&c| !(c.is_ascii_alphanumeric() || c == b'_'))
+                    .map_or(source.len(), |len| i + len);
+                match &source[i..end] {
+                    b"function" | b"do" | b"if" | b"repeat" => blocks += 1,
+                    b"end" | b"until" => blocks -= 1,
+                    b"and" | b"or" | b"not" | b"local" => pending_operator = true,
+                    _ => (),
+                }
+                i = end;
+                continue;
+            }
+            b'=' | b'+' | b'-' | b'*' | b'/' | b'%' | b'^' | b'#' | b'&' | b'|' | b'~' | b'<' | b'>' | b',' | b'.'
+            | b':' => pending_operator = true,
+            _ => (),
+        }
+        i += 1;
+    }
+    blocks > 0 || brackets > 0 || pending_operator
+}
+
+/// Compiles the statement starting with `line`, reading more lines as long as it is
+/// incomplete. An expression, or a line starting with '=', returns its values. Returns
+/// the whole statement with the outcome, or `None` when it was interrupted.
+fn read_statement(
+    ctx: &LuaState,
+    editor: &mut Editor<LuaHelper>,
+    line: String,
+) -> Option<(String, seo2::Result<LuaFunction>)> {
+    let line = match line.strip_prefix('=') {
+        Some(expr) => format!("return {}", expr),
+        None => line.clone(),
+    };
+    if let Ok(function) = ctx.load(format!("return {}", line).as_bytes(), "=stdin") {
+        return Some((line, Ok(function)));
+    }
+    let mut source = line;
+    loop {
+        let result = ctx.load(source.as_bytes(), "=stdin");
+        if result.is_ok() || !is_incomplete(source.as_bytes()) {
+            return Some((source, result));
+        }
+        match editor.readline(CONTINUATION_PROMPT) {
+            Ok(more) => {
+                source.push('\n');
+                source.push_str(&more);
+            }
+            Err(ReadlineError::Interrupted) => return None,
+            Err(_) => return Some((source, result)),
+        }
+    }
+}
+
+/// Passes the values to the global `print`, if there are any.
+fn print_values(ctx: &LuaState, values: Vec<LuaValue>) -> Result<(), String> {
+    if values.is_empty() {
+        return Ok(());
+    }
+    match ctx.get_global::<LuaValue>("print") {
+        Ok(LuaValue::Function(print)) => print
+            .call(ctx, &values)
+            .map(|_| ())
+            .map_err(|err| super::describe_error(ctx, &err)),
+        _ => Err("error calling 'print'".to_owned()),
+    }
+}
+
+fn history_path() -> Option<PathBuf> {
+    env::var_os("HOME").map(|home| PathBuf::from(home).join(HISTORY_FILE))
+}
+
+/// Reads and runs statements until the end of the input. Errors are reported without
+/// ending the session, and Ctrl-C only drops the statement being typed.
+pub fn run(ctx: &LuaState, progname: &str) {
+    let mut editor = Editor::<LuaHelper>::new();
+    editor.set_helper(Some(LuaHelper { globals: ctx.globals() }));
+    let history = history_path();
+    if let Some(ref path) = history {
+        let _ = editor.load_history(path);
+    }
+    loop {
+        let line = match editor.readline(PROMPT) {
+            Ok(line) => line,
+            Err(ReadlineError::Interrupted) => continue,
+            Err(_) => break,
+        };
+        if line.trim().is_empty() {
+            continue;
+        }
+        let (source, function) = match read_statement(ctx, &mut editor, line) {
+            Some(statement) => statement,
+            None => continue,
+        };
+        editor.add_history_entry(source.as_str());
+        let result = super::run(ctx, function, &[]).and_then(|values| print_values(ctx, values));
+        if let Err(msg) = result {
+            super::report(progname, &msg);
+        }
+    }
+    if let Some(ref path) = history {
+        let _ = editor.save_history(path);
+    }
+    println!();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_incomplete() {
+        assert!(is_incomplete(b"function f()"));
+        assert!(is_incomplete(b"for i = 1, 3 do\n  if i then"));
+        assert!(is_incomplete(b"t = {1, 2,"));
+        assert!(is_incomplete(b"x = [==[ long ]] string"));
+        assert!(is_incomplete(b"--[[ comment"));
+        assert!(is_incomplete(b"x = 1 + "));
+        assert!(is_incomplete(b"s = 'a\\\n"));
+        assert!(!is_incomplete(b"repeat x = x + 1 until x > 3"));
+        assert!(!is_incomplete(b"x = = 1"));
+        assert!(!is_incomplete(b"s = \"end -- do\" -- function"));
+        assert!(!is_incomplete(b"s = 'unfinished\nx = 1"));
+        assert!(!is_incomplete(b"x = [[do]] end"));
+    }
+
+    #[test]
+    fn test_complete_names() {
+        let ctx = LuaState::new();
+        ctx.exec(b"point = {x = 1, xy = 2, [1] = 3}", "=test").unwrap();
+        let globals = ctx.globals();
+        assert_eq!(complete_names(&globals, "print(poi"), (6, vec!["point".to_owned()]));
+        assert_eq!(
+            complete_names(&globals, "point.x"),
+            (6, vec!["x".to_owned(), "xy".to_owned()])
+        );
+        assert_eq!(
+            complete_names(&globals, "string.pa"),
+            (7, vec!["pack".to_owned(), "packsize".to_owned()])
+        );
+        assert_eq!(complete_names(&globals, "point.x.y"), (8, Vec::new()));
+
+        let index = LuaTable::new(ctx.get_ref_id());
+        index.set_string(&ctx.intern(b"method"), &LuaValue::Boolean(true));
+        let metatable = LuaTable::new(ctx.get_ref_id());
+        metatable.set_string(&ctx.intern(b"__index"), &LuaValue::Table(index));
+        let object = LuaTable::new(ctx.get_ref_id());
+        object.set_metatable(Some(metatable));
+        globals.set_string(&ctx.intern(b"object"), &LuaValue::Table(object));
+        assert_eq!(complete_names(&globals, "object:me"), (7, vec!["method".to_owned()]));
+    }
+}
@@ -0,0 +1,628 @@
+// Runtime checks for the behaviours of scripts that are legal Lua but usually bugs, in the
+// spirit of ASAN or Valgrind. Each check is off unless the host turns it on, and reports
+// what it finds either as a warning kept in the state or as an error raised in the script.
+
+use std::cell::{Ref, RefCell};
+use std::collections::HashSet;
+use std::fmt;
+
+use nom_lua53::op::{BinOp, UnOp};
+
+use chunk;
+use expression::{get_metamethod, num_coercion};
+use types::{LuaState, LuaTable, LuaValue, Number};
+use {LuaError, Result};
+
+/// What a check does with what it finds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    /// The finding is kept in the state, for `LuaState::take_findings`, and the script
+    /// goes on. The same finding at the same place is only kept once until then.
+    Warning,
+    /// The finding is raised as an error where it was made.
+    Error,
+}
+
+/// The checks the state runs, none by default.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Sanitizers {
+    /// Reports reading a global that was never assigned, and assigning a new global from
+    /// a function called by the main chunk, such as a chunk it loads or a module it
+    /// requires.
+    pub strict_globals: Option<Severity>,
+    /// Globals the strict mode takes as declared even before they are assigned.
+    pub allowed_globals: HashSet<String>,
+    /// Reports `#`, `ipairs`, `table.insert` and `table.unpack` on a table whose positive
+    /// integer keys don't form a sequence, which makes its border ambiguous.
+    pub sequence_holes: Option<Severity>,
+    /// Reports the keys of a table that differ only in type, such as `1` and `"1"`, and the
+    /// float keys that aren't kept as they are: the integral ones in the range of
+    /// integers, which become integers, the fractional ones and NaN.
+    pub key_confusion: Option<Severity>,
+    /// Reports the strings that arithmetic and bitwise operators convert to numbers, and
+    /// the numbers that concatenation converts to strings.
+    pub implicit_coercion: Option<Severity>,
+    /// Keeps track of where the nil values of running chunks come from, so that the
+    /// errors raised by operations on them can tell.
+    pub nil_provenance: bool,
+}
+
+/// Something a check found, and where.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Finding {
+    /// The check that made the finding, e.g. "strict globals".
+    pub check: &'static str,
+    /// The chunk and line of the statement running, as in error messages.
+    pub location: String,
+    pub message: String,
+}
+
+impl fmt::Display for Finding {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {} ({})", self.location, self.message, self.check)
+    }
+}
+
+/// The configuration of the checks of a state and what they learnt so far.
+#[derive(Debug, Default)]
+pub struct Sanitizer {
+    config: RefCell<Sanitizers>,
+    /// Globals assigned by a main chunk or allowed by the host.
+    declared: RefCell<HashSet<String>>,
+    findings: RefCell<Vec<Finding>>,
+    /// The findings kept since they were last taken, each being kept once.
+    reported: RefCell<HashSet<Finding>>,
+}
+
+impl Sanitizer {
+    pub fn configure(&self, config: Sanitizers) {
+        *self.declared.borrow_mut() = config.allowed_globals.clone();
+        *self.config.borrow_mut() = config;
+    }
+
+    pub fn config(&self) -> Ref<'_, Sanitizers> {
+        self.config.borrow()
+    }
+
+    pub fn take_findings(&self) -> Vec<Finding> {
+        self.reported.borrow_mut().clear();
+        self.findings.replace(Vec::new())
+    }
+}
+
+/// The place of the innermost chunk running, as "chunkname:line".
+pub fn location(ctx: &LuaState) -> String {
+    let stack = ctx.get_call_stack();
+    let info = stack.iter().rev().find(|info| info.function.get_chunk().is_some());
+    match info {
+        Some(info) => {
+            let source = chunk::chunk_id(info.function.get_chunk().unwrap().get_chunkname());
+            match info.current_line.get() {
+                line if line > 0 => format!("{}:{}", source, line),
+                _ => source,
+            }
+        }
+        None => "?".to_owned(),
+    }
+}
+
+/// Records a finding of `check` at the current location, or raises it.
+fn report(ctx: &LuaState, check: &'static str, severity: Severity, message: String) -> Result<()> {
+    let finding = Finding {
+        check,
+        location: location(ctx),
+        message,
+    };
+    match severity {
+        Severity::Warning => {
+            // A check made in a loop reports the same thing at the same place each time.
+            let sanitizer = ctx.get_sanitizer();
+            if sanitizer.reported.borrow_mut().insert(finding.clone()) {
+                sanitizer.findings.borrow_mut().push(finding);
+            }
+            Ok(())
+        }
+        Severity::Error => Err(LuaError::OtherError(finding.to_string())),
+    }
+}
+
+/// Number of missing indices listed in a report on the holes of a sequence.
+const MAX_HOLES_LISTED: usize = 8;
+
+/// Remembers where a script created `table`, if a check may report it.
+pub fn table_created(ctx: &LuaState, table: &LuaTable) {
+    let config = ctx.get_sanitizer().config();
+    if config.sequence_holes.is_some() || config.key_confusion.is_some() || config.nil_provenance {
+        table.set_site(location(ctx));
+    }
+}
+
+/// Checks that `table` is a proper sequence before `operation` relies on its border.
+/// Tables with a `__len` metamethod define their own length. The holes are only sorted
+/// out when there are some to report.
+pub fn check_sequence(ctx: &LuaState, table: &LuaTable, operation: &str) -> Result<()> {
+    let severity = match ctx.get_sanitizer().config().sequence_holes {
+        Some(severity) => severity,
+        None => return Ok(()),
+    };
+    if get_metamethod(&LuaValue::Table(table.clone()), &ctx.names().len) != LuaValue::Nil {
+        return Ok(());
+    }
+    let missing = table.missing_indices();
+    if missing == 0 {
+        return Ok(());
+    }
+    let holes = table.sequence_holes(MAX_HOLES_LISTED);
+    let mut listed: Vec<String> = holes.iter().map(|i| i.to_string()).collect();
+    if missing > holes.len() {
+        listed.push(format!("... ({} missing)", missing));
+    }
+    let site = table.get_site().unwrap_or_else(|| "?".to_owned());
+    let message = format!(
+        "{} on a table created at {} whose sequence misses {}",
+        operation,
+        site,
+        listed.join(", ")
+    );
+    report(ctx, "sequence holes", severity, message)
+}
+
+/// Whether the origins of nil values are tracked.
+pub fn tracks_nils(ctx: &LuaState) -> bool {
+    ctx.get_sanitizer().config().nil_provenance
+}
+
+/// A key or an operand as it reads in a script, with quotes around strings.
+pub fn describe_value(key: &LuaValue) -> String {
+    match *key {
+        LuaValue::Str(ref s) => format!("\"{}\"", String::from_utf8_lossy(s)),
+        LuaValue::Number(ref n) => n.to_string(),
+        _ => key.type_name().to_owned(),
+    }
+}
+
+/// The key of another type that `key` would be confused with, if `table` holds one.
+fn twin_key(table: &LuaTable, key: &LuaValue) -> Option<LuaValue> {
+    let twin = match *key {
+        // Integral floats read as the integer they are stored as. The others are kept
+        // as floats, and infinities have no string that converts back to them.
+        LuaValue::Number(ref n @ Number::Float(_)) => match n.to_exact_int() {
+            Some(i) => LuaValue::Str(i.to_string().into()),
+            None if n.to_float().is_finite() && n.to_float().fract() != 0.0 => LuaValue::Str(n.to_string().into()),
+            None => return None,
+        },
+        LuaValue::Number(ref n) => LuaValue::Str(n.to_string().into()),
+        // The table stores integral floats as integers by itself.
+        LuaValue::Str(_) => match num_coercion(key.clone()) {
+            LuaValue::Number(n) => LuaValue::Number(n),
+            _ => return None,
+        },
+        _ => return None,
+    };
+    if table.get(&twin) == LuaValue::Nil {
+        None
+    } else {
+        Some(twin)
+    }
+}
+
+/// Where a table was created, to end a report about it.
+fn table_site(table: &LuaTable) -> String {
+    match table.get_site() {
+        Some(site) => format!(" in a table created at {}", site),
+        None => String::new(),
+    }
+}
+
+/// Checks the assignment of `value` to `key` in `table`, which has no metamethod for it.
+pub fn check_key_write(ctx: &LuaState, table: &LuaTable, key: &LuaValue, value: &LuaValue) -> Result<()> {
+    let severity = match ctx.get_sanitizer().config().key_confusion {
+        Some(severity) => severity,
+        None => return Ok(()),
+    };
+    if *value == LuaValue::Nil {
+        return Ok(());
+    }
+    // Infinities and the integral floats out of the range of integers stay floats.
+    let problem = match *key {
+        LuaValue::Number(ref n @ Number::Float(_)) => match n.to_exact_int() {
+            Some(i) => Some(format!("float key {} stored as the integer {}", describe_value(key), i)),
+            None if n.to_float().is_finite() && n.to_float().fract() != 0.0 => {
+                Some(format!("fractional float key {}", describe_value(key)))
+            }
+            None => None,
+        },
+        _ => None,
+    };
+    let problem = problem.or_else(|| {
+        if table.get(key) != LuaValue::Nil {
+            return None;
+        }
+        twin_key(table, key).map(|twin| {
+            format!("key {} set next to the key {}", describe_value(key), describe_value(&twin))
+        })
+    });
+    match problem {
+        Some(problem) => report(ctx, "key confusion", severity, problem + &table_site(table)),
+        None => Ok(()),
+    }
+}
+
+/// Checks the read of `key` in `table`, which found nothing there.
+pub fn check_key_miss(ctx: &LuaState, table: &LuaTable, key: &LuaValue) -> Result<()> {
+    let severity = match ctx.get_sanitizer().config().key_confusion {
+        Some(severity) => severity,
+        None => return Ok(()),
+    };
+    let problem = match *key {
+        LuaValue::Number(Number::Float(f)) if f.is_nan() => Some("NaN key, which is never found".to_owned()),
+        _ => twin_key(table, key).map(|twin| {
+            format!("key {} missed next to the key {}", describe_value(key), describe_value(&twin))
+        }),
+    };
+    match problem {
+        Some(problem) => report(ctx, "key confusion", severity, problem + &table_site(table)),
+        None => Ok(()),
+    }
+}
+
+/// Checks the operands of `symbol`, which converts them to numbers if `to_number` and
+/// to strings otherwise. Operands the operator rejects anyway are left to it.
+fn check_coercion(ctx: &LuaState, symbol: &str, to_number: bool, operands: &[&LuaValue]) -> Result<()> {
+    let severity = match ctx.get_sanitizer().config().implicit_coercion {
+        Some(severity) => severity,
+        None => return Ok(()),
+    };
+    let converted = |value: &LuaValue| match *value {
+        LuaValue::Str(_) if to_number => matches!(num_coercion(value.clone()), LuaValue::Number(_)),
+        LuaValue::Number(_) => !to_number,
+        _ => false,
+    };
+    let accepted = |value: &&LuaValue| match **value {
+        LuaValue::Number(_) => true,
+        LuaValue::Str(_) => !to_number || converted(value),
+        _ => false,
+    };
+    if !operands.iter().all(accepted) {
+        return Ok(());
+    }
+    let shown: Vec<String> = operands.iter().map(|value| describe_value(value)).collect();
+    for value in operands.iter().filter(|value| converted(value)) {
+        let message = format!(
+            "{} {} converted to a {} by '{}' on {}",
+            value.type_name(),
+            describe_value(value),
+            if to_number { "number" } else { "string" },
+            symbol,
+            shown.join(" and ")
+        );
+        report(ctx, "implicit coercion", severity, message)?;
+    }
+    Ok(())
+}
+
+/// Checks the operands of a binary operator before it runs.
+pub fn check_binary_coercion(ctx: &LuaState, op: &BinOp, left: &LuaValue, right: &LuaValue) -> Result<()> {
+    let (symbol, to_number) = match *op {
+        BinOp::Concat => ("..", false),
+        BinOp::Plus => ("+", true),
+        BinOp::Minus => ("-", true),
+        BinOp::Mul => ("*", true),
+        BinOp::Div => ("/", true),
+        BinOp::IntDiv => ("//", true),
+        BinOp::Mod => ("%", true),
+        BinOp::Pow => ("^", true),
+        BinOp::BitAnd => ("&", true),
+        BinOp::BitOr => ("|", true),
+        BinOp::BitXor => ("~", true),
+        BinOp::BitShl => ("<<", true),
+        BinOp::BitShr => (">>", true),
+        _ => return Ok(()),
+    };
+    check_coercion(ctx, symbol, to_number, &[left, right])
+}
+
+/// Checks the operand of a unary operator before it runs.
+pub fn check_unary_coercion(ctx: &LuaState, op: &UnOp, operand: &LuaValue) -> Result<()> {
+    match *op {
+        UnOp::Minus => check_coercion(ctx, "-", true, &[operand]),
+        UnOp::BitNot => check_coercion(ctx, "~", true, &[operand]),
+        _ => Ok(()),
+    }
+}
+
+/// Whether the running chunk was called by another one rather than by the host.
+fn in_function(ctx: &LuaState) -> bool {
+    let stack = ctx.get_call_stack();
+    stack.iter().filter(|info| info.function.get_chunk().is_some()).count() > 1
+}
+
+/// Checks the read of the global `name`, which was found to be nil.
+pub fn check_global_read(ctx: &LuaState, name: &str) -> Result<()> {
+    let severity = match ctx.get_sanitizer().config().strict_globals {
+        Some(severity) => severity,
+        None => return Ok(()),
+    };
+    if ctx.get_sanitizer().declared.borrow().contains(name) {
+        return Ok(());
+    }
+    report(ctx, "strict globals", severity, format!("variable '{}' is not declared", name))
+}
+
+/// Checks the assignment of the global `name`, whose current value is nil when `is_new`
+/// returns true. It is only looked up when the check needs it.
+pub fn check_global_write<F: FnOnce() -> bool>(ctx: &LuaState, name: &str, is_new: F) -> Result<()> {
+    let severity = match ctx.get_sanitizer().config().strict_globals {
+        Some(severity) => severity,
+        None => return Ok(()),
+    };
+    let sanitizer = ctx.get_sanitizer();
+    if sanitizer.declared.borrow().contains(name) {
+        return Ok(());
+    }
+    if in_function(ctx) && is_new() {
+        report(ctx, "strict globals", severity, format!("assign to undeclared variable '{}'", name))?;
+    }
+    sanitizer.declared.borrow_mut().insert(name.to_owned());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chunk::load_block;
+    use expression::{index_value, set_index};
+    use nom_lua53::{Args, Exp, ExpOrVarName, ExpSuffix, FunctionCall, PrefixExp, Statement};
+    use nom_lua53::name::VarName;
+    use nom_lua53::num::Numeral;
+    use nom_lua53::stat_expr_types::{Assignment, Block};
+    use stdlib::table;
+    use types::{LuaValue, Number};
+
+    fn strict(severity: Severity, allowed: &[&str]) -> LuaState {
+        let ctx = LuaState::new();
+        ctx.set_sanitizers(Sanitizers {
+            strict_globals: Some(severity),
+            allowed_globals: allowed.iter().map(|name| name.to_string()).collect(),
+            ..Sanitizers::default()
+        });
+        ctx
+    }
+
+    #[test]
+    fn test_disabled() {
+        let ctx = LuaState::new();
+        assert_eq!(check_global_read(&ctx, "x"), Ok(()));
+        assert_eq!(check_global_write(&ctx, "x", || panic!("global looked up")), Ok(()));
+        assert!(ctx.take_findings().is_empty());
+    }
+
+    #[test]
+    fn test_undeclared_read() {
+        let ctx = strict(Severity::Warning, &["host"]);
+        assert_eq!(check_global_read(&ctx, "host"), Ok(()));
+        assert_eq!(check_global_read(&ctx, "x"), Ok(()));
+        let findings = ctx.take_findings();
+        assert_eq!(findings.len(), 1);
+        assert_eq!(findings[0].message, "variable 'x' is not declared");
+        assert_eq!(findings[0].to_string(), "?: variable 'x' is not declared (strict globals)");
+
+        // The main chunk declares the globals it assigns, even to nil.
+        assert_eq!(check_global_write(&ctx, "x", || true), Ok(()));
+        assert_eq!(check_global_read(&ctx, "x"), Ok(()));
+        assert!(ctx.take_findings().is_empty());
+    }
+
+    #[test]
+    fn test_repeated_findings() {
+        let ctx = strict(Severity::Warning, &[]);
+        for _ in 0..3 {
+            check_global_read(&ctx, "x").unwrap();
+            check_global_read(&ctx, "y").unwrap();
+        }
+        let messages = |findings: Vec<Finding>| findings.into_iter().map(|f| f.message).collect::<Vec<_>>();
+        assert_eq!(
+            messages(ctx.take_findings()),
+            vec!["variable 'x' is not declared", "variable 'y' is not declared"]
+        );
+        check_global_read(&ctx, "x").unwrap();
+        assert_eq!(messages(ctx.take_findings()), vec!["variable 'x' is not declared"]);
+    }
+
+    #[test]
+    fn test_error_severity() {
+        let ctx = strict(Severity::Error, &[]);
+        match check_global_read(&ctx, "y") {
+            Err(LuaError::OtherError(msg)) => assert_eq!(msg, "?: variable 'y' is not declared (strict globals)"),
+            other => panic!("{:?}", other),
+        }
+        assert!(ctx.take_findings().is_empty());
+    }
+
+    #[test]
+    fn test_write_in_function() {
+        let ctx = strict(Severity::Error, &[]);
+        // z = 1
+        let assign = Statement::Assignment(Assignment {
+            vars: vec![PrefixExp {
+                prefix: ExpOrVarName::VarName(VarName(b"z")),
+                suffix_chain: Vec::new(),
+            }],
+            vals: vec![Exp::Num(Numeral::Int(1))],
+        });
+        let inner = load_block(&ctx, &Block {
+            stmts: vec![assign],
+            ret_stmt: None,
+        });
+        ctx.set_global("inner", LuaValue::Function(inner.clone())).unwrap();
+        // inner()
+        let call = Statement::FuncCall(PrefixExp {
+            prefix: ExpOrVarName::VarName(VarName(b"inner")),
+            suffix_chain: vec![ExpSuffix::FuncCall(FunctionCall {
+                method: None,
+                args: Args::ExpList(Vec::new()),
+            })],
+        });
+        let outer = load_block(&ctx, &Block {
+            stmts: vec![call],
+            ret_stmt: None,
+        });
+        match outer.call(&ctx, &[]) {
+            Err(LuaError::OtherError(msg)) => {
+                assert_eq!(msg, "test: assign to undeclared variable 'z' (strict globals)")
+            }
+            other => panic!("{:?}", other),
+        }
+
+        // Run as a main chunk, the same assignment declares `z`.
+        assert_eq!(inner.call(&ctx, &[]), Ok(vec![]));
+        assert_eq!(outer.call(&ctx, &[]), Ok(vec![]));
+    }
+
+    #[test]
+    fn test_sequence_holes() {
+        let ctx = LuaState::new();
+        ctx.set_sanitizers(Sanitizers {
+            sequence_holes: Some(Severity::Warning),
+            ..Sanitizers::default()
+        });
+        let int = |i| LuaValue::Number(Number::Int(i));
+        let table = LuaTable::new(ctx.get_ref_id());
+        table.set_site("test:3".to_owned());
+        for i in 1..4 {
+            table.set(&int(i), &int(i)).unwrap();
+        }
+        assert_eq!(check_sequence(&ctx, &table, "'#'"), Ok(()));
+        assert!(ctx.take_findings().is_empty());
+
+        table.set(&int(2), &LuaValue::Nil).unwrap();
+        for i in 5..20 {
+            table.set(&int(i), &int(i)).unwrap();
+        }
+        let list = LuaValue::Table(table.clone());
+        assert!(table::insert(&ctx, &[list.clone(), int(0)]).is_ok());
+        assert!(table::unpack(&ctx, &[list.clone(), int(1), int(2)]).is_ok());
+        let findings = ctx.take_findings();
+        assert_eq!(findings.len(), 1);
+        assert_eq!(findings[0].message, "'table.insert' on a table created at test:3 whose sequence misses 2, 4");
+
+        // The insertion filled the hole at 4.
+        for i in 21..40 {
+            table.set(&int(i * 2), &int(i)).unwrap();
+        }
+        assert_eq!(check_sequence(&ctx, &table, "'#'"), Ok(()));
+        assert_eq!(
+            ctx.take_findings()[0].message,
+            "'#' on a table created at test:3 whose sequence misses 2, 20, 21, 22, 23, 24, 25, 26, ... (41 missing)"
+        );
+
+        // A table whose length is its own business.
+        let metatable = LuaTable::new(ctx.get_ref_id());
+        metatable.set_string(&ctx.intern(b"__len"), &ctx.create_native("__len", |_: &LuaState, _: &[LuaValue]| {
+            Ok(vec![LuaValue::Number(Number::Int(0))])
+        }));
+        table.set_metatable(Some(metatable));
+        assert_eq!(check_sequence(&ctx, &table, "'#'"), Ok(()));
+        assert!(ctx.take_findings().is_empty());
+    }
+
+    #[test]
+    fn test_key_confusion() {
+        let ctx = LuaState::new();
+        ctx.set_sanitizers(Sanitizers {
+            key_confusion: Some(Severity::Warning),
+            ..Sanitizers::default()
+        });
+        let int = |i| LuaValue::Number(Number::Int(i));
+        let float = |f| LuaValue::Number(Number::Float(f));
+        let string = |s: &str| LuaValue::Str(s.into());
+        let table = LuaTable::new(ctx.get_ref_id());
+        table.set_site("test:1".to_owned());
+        let t = LuaValue::Table(table.clone());
+        set_index(&t, &int(1), &string("a"), &ctx).unwrap();
+        set_index(&t, &string("x"), &string("b"), &ctx).unwrap();
+        set_index(&t, &int(1), &string("c"), &ctx).unwrap();
+        assert!(ctx.take_findings().is_empty());
+
+        set_index(&t, &string("1"), &string("d"), &ctx).unwrap();
+        set_index(&t, &float(2.0), &string("e"), &ctx).unwrap();
+        set_index(&t, &float(2.5), &string("f"), &ctx).unwrap();
+        assert_eq!(index_value(&t, &string("2"), &ctx), Ok(LuaValue::Nil));
+        assert_eq!(index_value(&t, &float(::std::f64::NAN), &ctx), Ok(LuaValue::Nil));
+        assert_eq!(index_value(&t, &string("y"), &ctx), Ok(LuaValue::Nil));
+        let messages: Vec<String> = ctx.take_findings().into_iter().map(|f| f.message).collect();
+        assert_eq!(
+            messages,
+            vec![
+                "key \"1\" set next to the key 1 in a table created at test:1",
+                "float key 2.0 stored as the integer 2 in a table created at test:1",
+                "fractional float key 2.5 in a table created at test:1",
+                "key \"2\" missed next to the key 2 in a table created at test:1",
+                "NaN key, which is never found in a table created at test:1",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_float_keys_out_of_range() {
+        let ctx = LuaState::new();
+        ctx.set_sanitizers(Sanitizers {
+            key_confusion: Some(Severity::Warning),
+            ..Sanitizers::default()
+        });
+        let float = |f| LuaValue::Number(Number::Float(f));
+        let table = LuaTable::new(ctx.get_ref_id());
+        let t = LuaValue::Table(table.clone());
+        // The string an integer saturated from these floats would read as.
+        let saturated = LuaValue::Str(isize::max_value().to_string().into());
+        set_index(&t, &saturated, &LuaValue::Boolean(true), &ctx).unwrap();
+        let keys = [::std::f64::INFINITY, ::std::f64::NEG_INFINITY, 1e300, 9223372036854775808.0];
+        for &f in keys.iter() {
+            assert_eq!(index_value(&t, &float(f), &ctx), Ok(LuaValue::Nil));
+        }
+        for (i, &f) in keys.iter().enumerate() {
+            set_index(&t, &float(f), &LuaValue::Number(Number::Int(i as isize)), &ctx).unwrap();
+        }
+        assert!(ctx.take_findings().is_empty());
+        // They are kept as floats instead of saturating to the same integer.
+        for (i, &f) in keys.iter().enumerate() {
+            assert_eq!(table.get(&float(f)), LuaValue::Number(Number::Int(i as isize)));
+        }
+        assert_eq!(table.get(&LuaValue::Number(Number::Int(isize::max_value()))), LuaValue::Nil);
+        assert_eq!(table.hash_entries().len(), keys.len() + 1);
+    }
+
+    #[test]
+    fn test_implicit_coercion() {
+        let ctx = LuaState::new();
+        ctx.set_sanitizers(Sanitizers {
+            implicit_coercion: Some(Severity::Warning),
+            ..Sanitizers::default()
+        });
+        let int = |i| LuaValue::Number(Number::Int(i));
+        let string = |s: &str| LuaValue::Str(s.into());
+        check_binary_coercion(&ctx, &BinOp::Plus, &int(1), &int(2)).unwrap();
+        check_binary_coercion(&ctx, &BinOp::Concat, &string("a"), &string("b")).unwrap();
+        check_binary_coercion(&ctx, &BinOp::Plus, &string("a"), &int(2)).unwrap();
+        check_binary_coercion(&ctx, &BinOp::Lt, &string("1"), &int(2)).unwrap();
+        check_unary_coercion(&ctx, &UnOp::Length, &string("1")).unwrap();
+        assert!(ctx.take_findings().is_empty());
+
+        check_binary_coercion(&ctx, &BinOp::Plus, &string("10"), &int(2)).unwrap();
+        check_binary_coercion(&ctx, &BinOp::Concat, &string("n = "), &int(2)).unwrap();
+        let messages: Vec<String> = ctx.take_findings().into_iter().map(|f| f.message).collect();
+        assert_eq!(
+            messages,
+            vec![
+                "string \"10\" converted to a number by '+' on \"10\" and 2",
+                "number 2 converted to a string by '..' on \"n = \" and 2",
+            ]
+        );
+
+        ctx.set_sanitizers(Sanitizers {
+            implicit_coercion: Some(Severity::Error),
+            ..Sanitizers::default()
+        });
+        assert!(check_binary_coercion(&ctx, &BinOp::BitAnd, &int(3), &string("1")).is_err());
+    }
+}
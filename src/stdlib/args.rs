@@ -0,0 +1,64 @@
+// Helpers to check the arguments received by the native functions, and to build the
+// same "bad argument" messages as the reference implementation.
+
+use types::{LuaTable, LuaValue, Number};
+use expression::num_coercion;
+use {LuaError, Result};
+
+pub fn arg_error(n: usize, fname: &str, msg: &str) -> LuaError {
+    LuaError::OtherError(format!("bad argument #{} to '{}' ({})", n, fname, msg))
+}
+
+pub fn type_error(n: usize, fname: &str, expected: &str, got: Option<&LuaValue>) -> LuaError {
+    let got = match got {
+        Some(value) => value.type_name(),
+        None => "no value",
+    };
+    LuaError::TypeError(format!(
+        "bad argument #{} to '{}' ({} expected, got {})",
+        n, fname, expected, got
+    ))
+}
+
+pub fn check_number(args: &[LuaValue], n: usize, fname: &str) -> Result<Number> {
+    let arg = args.get(n - 1);
+    match arg.cloned().map(num_coercion) {
+        Some(LuaValue::Number(num)) => Ok(num),
+        _ => Err(type_error(n, fname, "number", arg)),
+    }
+}
+
+pub fn check_integer(args: &[LuaValue], n: usize, fname: &str) -> Result<isize> {
+    check_number(args, n, fname)?
+        .to_exact_int()
+        .ok_or_else(|| arg_error(n, fname, "number has no integer representation"))
+}
+
+pub fn opt_integer(args: &[LuaValue], n: usize, fname: &str, default: isize) -> Result<isize> {
+    match args.get(n - 1) {
+        None | Some(&LuaValue::Nil) => Ok(default),
+        Some(_) => check_integer(args, n, fname),
+    }
+}
+
+pub fn check_string(args: &[LuaValue], n: usize, fname: &str) -> Result<Vec<u8>> {
+    match args.get(n - 1) {
+        Some(&LuaValue::Str(ref s)) => Ok(s.to_vec()),
+        Some(&LuaValue::Number(ref num)) => Ok(num.to_string().into_bytes()),
+        other => Err(type_error(n, fname, "string", other)),
+    }
+}
+
+pub fn opt_string(args: &[LuaValue], n: usize, fname: &str, default: &[u8]) -> Result<Vec<u8>> {
+    match args.get(n - 1) {
+        None | Some(&LuaValue::Nil) => Ok(default.to_vec()),
+        Some(_) => check_string(args, n, fname),
+    }
+}
+
+pub fn check_table(args: &[LuaValue], n: usize, fname: &str) -> Result<LuaTable> {
+    match args.get(n - 1) {
+        Some(&LuaValue::Table(ref t)) => Ok(t.clone()),
+        other => Err(type_error(n, fname, "table", other)),
+    }
+}
@@ -0,0 +1,329 @@
+// The functions of the base library, which live directly in the global table.
+
+use std::io::{self, Read, Write};
+
+use types::{LuaFunction, LuaState, LuaValue, NativeFunction, Number};
+use chunk;
+use expression::{call_function, first_value, get_metamethod, index_value};
+use sanitizer;
+use Result;
+use LuaError::*;
+use super::Capabilities;
+use super::args::{arg_error, check_integer, opt_string, type_error};
+
+pub fn open(ctx: &LuaState, capabilities: &Capabilities) {
+    let functions: [(bool, &str, NativeFunction); 7] = [
+        (capabilities.read_files, "dofile", dofile),
+        (true, "ipairs", ipairs),
+        (true, "load", load),
+        (capabilities.read_files, "loadfile", loadfile),
+        (true, "print", print),
+        (true, "tostring", tostring),
+        (true, "type", lua_type),
+    ];
+    let globals = ctx.get_global_table();
+    for &(enabled, name, native) in functions.iter() {
+        if enabled {
+            let function = LuaFunction::new_native(ctx.get_ref_id(), name, native);
+            globals.set_string(&ctx.intern(name.as_bytes()), &LuaValue::Function(function));
+        }
+    }
+    globals.set_string(&ctx.intern(b"_G"), &LuaValue::Table(globals.clone()));
+    super::loaded_table(ctx).set_string(&ctx.intern(b"_G"), &LuaValue::Table(globals.clone()));
+}
+
+/// The environment of a loaded chunk: the argument number `n` if it is present, even
+/// when it is nil, and the global table otherwise.
+fn chunk_env(ctx: &LuaState, args: &[LuaValue], n: usize) -> LuaValue {
+    match args.get(n - 1) {
+        Some(env) => env.clone(),
+        None => LuaValue::Table(ctx.get_global_table().clone()),
+    }
+}
+
+/// Turns the outcome of loading a chunk into the function, or nil and the message.
+fn load_result(result: Result<LuaFunction>) -> Vec<LuaValue> {
+    match result {
+        Ok(function) => vec![LuaValue::Function(function)],
+        Err(err) => vec![LuaValue::Nil, LuaValue::Str(err.to_string().into())],
+    }
+}
+
+/// Gathers the pieces returned by a reader function until it returns nil or an empty
+/// string.
+fn read_pieces(ctx: &LuaState, reader: &LuaValue) -> Result<Vec<u8>> {
+    let mut source = Vec::new();
+    loop {
+        match first_value(call_function(reader, &[], ctx)?) {
+            LuaValue::Nil => return Ok(source),
+            LuaValue::Str(ref piece) if piece.is_empty() => return Ok(source),
+            LuaValue::Str(piece) => source.extend(piece.as_bytes()),
+            LuaValue::Number(n) => source.extend(n.to_string().into_bytes()),
+            _ => return Err(OtherError("reader function must return a string".to_owned())),
+        }
+    }
+}
+
+fn load(ctx: &LuaState, args: &[LuaValue]) -> Result<Vec<LuaValue>> {
+    let mode = opt_string(args, 3, "load", b"bt")?;
+    let env = chunk_env(ctx, args, 4);
+    let (source, default_name) = match args.get(0) {
+        Some(&LuaValue::Str(ref s)) => (Ok(s.to_vec()), String::from_utf8_lossy(s).into_owned()),
+        Some(&LuaValue::Number(ref n)) => (Ok(n.to_string().into_bytes()), n.to_string()),
+        Some(reader @ &LuaValue::Function(_)) => (read_pieces(ctx, reader), "=(load)".to_owned()),
+        other => return Err(type_error(1, "load", "string", other)),
+    };
+    let chunkname = opt_string(args, 2, "load", default_name.as_bytes())?;
+    let chunkname = String::from_utf8_lossy(&chunkname).into_owned();
+    Ok(load_result(source.and_then(|source| {
+        chunk::check_mode(&source, &mode)?;
+        chunk::load(ctx, source, &chunkname, env)
+    })))
+}
+
+/// Loads a file, or the standard input when there is no file name.
+pub fn load_file(ctx: &LuaState, filename: Option<Vec<u8>>, mode: &[u8], env: LuaValue) -> Result<LuaFunction> {
+    let (source, chunkname) = match filename {
+        Some(filename) => {
+            let chunkname = format!("@{}", String::from_utf8_lossy(&filename));
+            (super::read_source(&filename)?, chunkname)
+        }
+        None => {
+            let mut source = Vec::new();
+            if let Err(err) = io::stdin().read_to_end(&mut source) {
+                return Err(OtherError(format!("cannot read stdin: {}", err)));
+            }
+            super::skip_shebang(&mut source);
+            (source, "=stdin".to_owned())
+        }
+    };
+    chunk::check_mode(&source, mode)?;
+    chunk::load(ctx, source, &chunkname, env)
+}
+
+fn optional_filename(args: &[LuaValue], fname: &str) -> Result<Option<Vec<u8>>> {
+    match args.get(0) {
+        None | Some(&LuaValue::Nil) => Ok(None),
+        Some(_) => super::args::check_string(args, 1, fname).map(Some),
+    }
+}
+
+fn loadfile(ctx: &LuaState, args: &[LuaValue]) -> Result<Vec<LuaValue>> {
+    let filename = optional_filename(args, "loadfile")?;
+    let mode = opt_string(args, 2, "loadfile", b"bt")?;
+    let env = chunk_env(ctx, args, 3);
+    Ok(load_result(load_file(ctx, filename, &mode, env)))
+}
+
+fn dofile(ctx: &LuaState, args: &[LuaValue]) -> Result<Vec<LuaValue>> {
+    let filename = optional_filename(args, "dofile")?;
+    let env = LuaValue::Table(ctx.get_global_table().clone());
+    let function = load_file(ctx, filename, b"bt", env)?;
+    function.call(ctx, &[])
+}
+
+/// Converts any value to a string the way `tostring` does, through the `__tostring`
+/// metamethod or the `__name` field of the metatable when there is one.
+pub fn to_display_string(ctx: &LuaState, value: &LuaValue) -> Result<Vec<u8>> {
+    let handler = get_metamethod(value, &ctx.names().tostring);
+    if handler != LuaValue::Nil {
+        return match first_value(call_function(&handler, &[value.clone()], ctx)?) {
+            LuaValue::Str(s) => Ok(s.to_vec()),
+            LuaValue::Number(n) => Ok(n.to_string().into_bytes()),
+            _ => Err(OtherError("'__tostring' must return a string".to_owned())),
+        };
+    }
+    let kind = match get_metamethod(value, &ctx.names().name) {
+        LuaValue::Str(name) => String::from_utf8_lossy(&name).into_owned(),
+        _ => value.type_name().to_owned(),
+    };
+    Ok(match *value {
+        LuaValue::Nil => b"nil".to_vec(),
+        LuaValue::Boolean(b) => b.to_string().into_bytes(),
+        LuaValue::Number(ref n) => n.to_string().into_bytes(),
+        LuaValue::Str(ref s) => s.to_vec(),
+        LuaValue::Table(ref t) => format!("{}: 0x{:08x}", kind, t.get_ref_id()).into_bytes(),
+        LuaValue::Function(ref f) if f.get_chunk().is_none() => {
+            format!("function: builtin: 0x{:08x}", f.get_ref_id()).into_bytes()
+        }
+        LuaValue::Function(ref f) => format!("function: 0x{:08x}", f.get_ref_id()).into_bytes(),
+        LuaValue::UserData(ref u) => format!("{}: 0x{:08x}", kind, u.get_ref_id()).into_bytes(),
+    })
+}
+
+/// Returns an iterator over the pairs `(1, t[1])`, `(2, t[2])`, ... of a value, up to the
+/// first nil one.
+fn ipairs(ctx: &LuaState, args: &[LuaValue]) -> Result<Vec<LuaValue>> {
+    match args.get(0) {
+        Some(&LuaValue::Table(ref t)) => sanitizer::check_sequence(ctx, t, "'ipairs'")?,
+        Some(_) => (),
+        None => return Err(arg_error(1, "ipairs", "table expected, got no value")),
+    }
+    let iterator = LuaFunction::new_native(ctx.get_ref_id(), "ipairs_aux", ipairs_aux);
+    Ok(vec![LuaValue::Function(iterator), args[0].clone(), LuaValue::Number(Number::Int(0))])
+}
+
+fn ipairs_aux(ctx: &LuaState, args: &[LuaValue]) -> Result<Vec<LuaValue>> {
+    let i = check_integer(args, 2, "ipairs")? + 1;
+    let key = LuaValue::Number(Number::Int(i));
+    let value = index_value(&args[0], &key, ctx)?;
+    Ok(match value {
+        LuaValue::Nil => vec![LuaValue::Nil],
+        value => vec![key, value],
+    })
+}
+
+fn tostring(ctx: &LuaState, args: &[LuaValue]) -> Result<Vec<LuaValue>> {
+    match args.get(0) {
+        Some(value) => Ok(vec![LuaValue::Str(to_display_string(ctx, value)?.into())]),
+        None => Err(arg_error(1, "tostring", "value expected")),
+    }
+}
+
+fn lua_type(_ctx: &LuaState, args: &[LuaValue]) -> Result<Vec<LuaValue>> {
+    match args.get(0) {
+        Some(value) => Ok(vec![LuaValue::Str(value.type_name().as_bytes().into())]),
+        None => Err(arg_error(1, "type", "value expected")),
+    }
+}
+
+/// Writes its arguments to the standard output, separated by tabs, bypassing the default
+/// output file of `io` as the reference implementation does.
+fn print(ctx: &LuaState, args: &[LuaValue]) -> Result<Vec<LuaValue>> {
+    let mut line = Vec::new();
+    for (i, value) in args.iter().enumerate() {
+        if i > 0 {
+            line.push(b'\t');
+        }
+        line.extend(to_display_string(ctx, value)?);
+    }
+    line.push(b'\n');
+    let stdout = io::stdout();
+    let mut stdout = stdout.lock();
+    if let Err(err) = stdout.write_all(&line).and_then(|_| stdout.flush()) {
+        return Err(OtherError(format!("cannot write to stdout: {}", err)));
+    }
+    Ok(Vec::new())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use types::{LuaTable, Number};
+
+    fn string(s: &str) -> LuaValue {
+        LuaValue::Str(s.into())
+    }
+
+    #[test]
+    fn test_load_errors() {
+        let ctx = LuaState::new();
+        assert_eq!(
+            load(&ctx, &[string("\x1bLua"), LuaValue::Nil, string("t")]).unwrap(),
+            vec![
+                LuaValue::Nil,
+                string("attempt to load a binary chunk (mode is 't')"),
+            ]
+        );
+        assert_eq!(
+            load(&ctx, &[string("return 1"), string("=x"), string("b")]).unwrap(),
+            vec![
+                LuaValue::Nil,
+                string("attempt to load a text chunk (mode is 'b')"),
+            ]
+        );
+        assert!(load(&ctx, &[LuaValue::Boolean(true)]).is_err());
+        let missing = loadfile(&ctx, &[string("/nonexistent/seo2.lua")]).unwrap();
+        assert_eq!(
+            missing,
+            vec![
+                LuaValue::Nil,
+                string("cannot open /nonexistent/seo2.lua: No such file or directory"),
+            ]
+        );
+        assert!(dofile(&ctx, &[string("/nonexistent/seo2.lua")]).is_err());
+    }
+
+    #[test]
+    fn test_file_capabilities() {
+        let ctx = LuaState::new();
+        assert_eq!(ctx.get_global_table().get_string(&ctx.intern(b"loadfile")), LuaValue::Nil);
+        assert_eq!(ctx.get_global_table().get_string(&ctx.intern(b"dofile")), LuaValue::Nil);
+        assert!(ctx.get_global_table().get_string(&ctx.intern(b"load")) != LuaValue::Nil);
+
+        let ctx = LuaState::with_capabilities(Capabilities {
+            read_files: true,
+            ..Capabilities::default()
+        });
+        assert!(ctx.get_global_table().get_string(&ctx.intern(b"loadfile")) != LuaValue::Nil);
+        assert!(ctx.get_global_table().get_string(&ctx.intern(b"dofile")) != LuaValue::Nil);
+    }
+
+    #[test]
+    fn test_type_and_tostring() {
+        let ctx = LuaState::new();
+        let native = ctx.create_native("f", |_ctx: &LuaState, _args: &[LuaValue]| Ok(Vec::new()));
+        assert_eq!(lua_type(&ctx, &[native.clone()]).unwrap(), vec![string("function")]);
+        assert!(lua_type(&ctx, &[]).is_err());
+        let shown = to_display_string(&ctx, &native).unwrap();
+        assert!(shown.starts_with(b"function: builtin: 0x"));
+        assert_eq!(
+            tostring(&ctx, &[LuaValue::Number(Number::Float(1.))]).unwrap(),
+            vec![string("1.0")]
+        );
+        assert_eq!(tostring(&ctx, &[LuaValue::Nil]).unwrap(), vec![string("nil")]);
+
+        // A native function as the `__tostring` metamethod.
+        let table = LuaTable::new(ctx.get_ref_id());
+        let metatable = LuaTable::new(ctx.get_ref_id());
+        let greeting = ctx.create_native("__tostring", |_ctx: &LuaState, _args: &[LuaValue]| {
+            Ok(vec![string("hello")])
+        });
+        metatable.set_string(&ctx.intern(b"__tostring"), &greeting);
+        table.set_metatable(Some(metatable.clone()));
+        assert_eq!(tostring(&ctx, &[LuaValue::Table(table.clone())]).unwrap(), vec![string("hello")]);
+        metatable.set_string(&ctx.intern(b"__tostring"), &LuaValue::Nil);
+        metatable.set_string(&ctx.intern(b"__name"), &string("Point"));
+        let shown = to_display_string(&ctx, &LuaValue::Table(table)).unwrap();
+        assert!(shown.starts_with(b"Point: 0x"));
+    }
+
+    #[test]
+    fn test_ipairs() {
+        let ctx = LuaState::new();
+        let table = LuaTable::new(ctx.get_ref_id());
+        let int = |i| LuaValue::Number(Number::Int(i));
+        table.set(&int(1), &string("a")).unwrap();
+        table.set(&int(3), &string("c")).unwrap();
+        let list = LuaValue::Table(table);
+        let mut iter = ipairs(&ctx, &[list.clone()]).unwrap();
+        assert_eq!(iter.split_off(1), vec![list.clone(), int(0)]);
+        let next = iter.remove(0);
+        assert_eq!(call_function(&next, &[list.clone(), int(0)], &ctx).unwrap(), vec![int(1), string("a")]);
+        assert_eq!(call_function(&next, &[list.clone(), int(1)], &ctx).unwrap(), vec![LuaValue::Nil]);
+        assert!(ipairs(&ctx, &[]).is_err());
+    }
+
+    #[test]
+    fn test_reader_function() {
+        fn reader(_ctx: &LuaState, _args: &[LuaValue]) -> Result<Vec<LuaValue>> {
+            Ok(vec![LuaValue::Boolean(false)])
+        }
+        fn failing(_ctx: &LuaState, _args: &[LuaValue]) -> Result<Vec<LuaValue>> {
+            Err(OtherError("broken reader".to_owned()))
+        }
+        let ctx = LuaState::new();
+        let reader = LuaValue::Function(LuaFunction::new_native(0, "reader", reader));
+        assert_eq!(
+            load(&ctx, &[reader, LuaValue::Nil, string("b")]).unwrap(),
+            vec![LuaValue::Nil, string("reader function must return a string")]
+        );
+        let failing = LuaValue::Function(LuaFunction::new_native(0, "reader", failing));
+        assert_eq!(
+            load(&ctx, &[failing]).unwrap(),
+            vec![LuaValue::Nil, string("broken reader")]
+        );
+        let globals = ctx.get_global_table();
+        assert_eq!(globals.get_string(&ctx.intern(b"_G")), LuaValue::Table(globals.clone()));
+    }
+}
@@ -0,0 +1,580 @@
+// The `debug` library. It sees the calls in progress through the call stack of the
+// state, and the local variables of chunks through the frames of their calls.
+
+use std::cmp;
+use std::rc::Rc;
+
+use bytecode::{Frame, Reg};
+use types::{CallInfo, Hook, HookMask, LuaFunction, LuaState, LuaTable, LuaValue, Number};
+use chunk;
+use Result;
+use LuaError::*;
+use super::args::{arg_error, check_integer, check_string, opt_integer, opt_string, type_error};
+
+/// Calls shown at the top and at the bottom of a traceback too long to be shown whole.
+const TRACEBACK_FIRST: usize = 10;
+const TRACEBACK_LAST: usize = 11;
+
+pub fn open(ctx: &LuaState) {
+    super::register_library(
+        ctx,
+        "debug",
+        &[
+            ("gethook", gethook),
+            ("getinfo", getinfo),
+            ("getlocal", getlocal),
+            ("getmetatable", getmetatable),
+            ("getupvalue", getupvalue),
+            ("sethook", sethook),
+            ("setlocal", setlocal),
+            ("setmetatable", setmetatable),
+            ("setupvalue", setupvalue),
+            ("traceback", traceback),
+        ],
+    );
+}
+
+fn int(i: isize) -> LuaValue {
+    LuaValue::Number(Number::Int(i))
+}
+
+fn string(s: &str) -> LuaValue {
+    LuaValue::Str(s.as_bytes().into())
+}
+
+fn check_function(args: &[LuaValue], n: usize, fname: &str) -> Result<LuaFunction> {
+    match args.get(n - 1) {
+        Some(&LuaValue::Function(ref f)) => Ok(f.clone()),
+        other => Err(type_error(n, fname, "function", other)),
+    }
+}
+
+/// Index in the call stack of the call at `level`, level 0 being the running function.
+fn call_index(ctx: &LuaState, level: isize) -> Option<usize> {
+    let depth = ctx.get_call_stack().len() as isize;
+    if level < 0 || level >= depth {
+        None
+    } else {
+        Some((depth - 1 - level) as usize)
+    }
+}
+
+fn check_level(ctx: &LuaState, args: &[LuaValue], n: usize, fname: &str) -> Result<usize> {
+    let level = check_integer(args, n, fname)?;
+    call_index(ctx, level).ok_or_else(|| arg_error(n, fname, "level out of range"))
+}
+
+fn getinfo(ctx: &LuaState, args: &[LuaValue]) -> Result<Vec<LuaValue>> {
+    let options = opt_string(args, 2, "getinfo", b"flnStu")?;
+    let (function, index) = match args.get(0) {
+        Some(&LuaValue::Function(ref f)) => (f.clone(), None),
+        _ => match call_index(ctx, check_integer(args, 1, "getinfo")?) {
+            Some(index) => (ctx.get_call_stack()[index].function.clone(), Some(index)),
+            None => return Ok(vec![LuaValue::Nil]),
+        },
+    };
+    let info = LuaTable::new(ctx.get_ref_id());
+    let set = |key: &str, value: LuaValue| info.set_string(&ctx.intern(key.as_bytes()), &value);
+    for option in options.iter() {
+        match *option {
+            b'S' => {
+                let (source, what, first, last) = match function.get_chunk() {
+                    Some(chunk) => {
+                        let proto = chunk.get_proto();
+                        let what = if proto.line_defined == 0 { "main" } else { "Lua" };
+                        (chunk.get_chunkname().to_owned(), what, proto.line_defined, proto.last_line_defined)
+                    }
+                    None => ("=[C]".to_owned(), "C", -1, -1),
+                };
+                set("short_src", string(&chunk::chunk_id(&source)));
+                set("source", string(&source));
+                set("linedefined", int(first));
+                set("lastlinedefined", int(last));
+                set("what", string(what));
+            }
+            b'l' => {
+                let line = index.map_or(-1, |index| ctx.get_call_stack()[index].current_line.get());
+                set("currentline", int(line));
+            }
+            b'u' => {
+                // Chunks have a single upvalue, their `_ENV`.
+                let (nups, nparams, isvararg) = match function.get_chunk() {
+                    Some(chunk) => (1, chunk.get_proto().params, chunk.get_proto().is_vararg),
+                    None => (0, 0, true),
+                };
+                set("nups", int(nups));
+                set("nparams", int(nparams as isize));
+                set("isvararg", LuaValue::Boolean(isvararg));
+            }
+            b'n' => {
+                let name = index.and_then(|index| ctx.get_call_stack()[index].name.clone());
+                match name {
+                    Some(name) => {
+                        set("name", string(&name.name));
+                        set("namewhat", string(name.kind));
+                    }
+                    None => set("namewhat", string("")),
+                }
+            }
+            b't' => set("istailcall", LuaValue::Boolean(false)),
+            b'L' => {
+                if let Some(chunk) = function.get_chunk() {
+                    let lines = LuaTable::new(ctx.get_ref_id());
+                    for line in chunk.active_lines() {
+                        lines.set(&int(line), &LuaValue::Boolean(true))?;
+                    }
+                    set("activelines", LuaValue::Table(lines));
+                }
+            }
+            b'f' => set("func", LuaValue::Function(function.clone())),
+            _ => return Err(arg_error(2, "getinfo", "invalid option")),
+        }
+    }
+    Ok(vec![LuaValue::Table(info)])
+}
+
+/// Where a local variable of a call lives in its frame.
+enum Slot {
+    Register(Reg),
+    Vararg(usize),
+}
+
+/// Finds the local `n` of the call at `index`: its frame, its slot there and its name.
+/// Locals are numbered in the order they were declared in among those in scope, and
+/// negative numbers stand for the extra arguments.
+fn find_local(ctx: &LuaState, index: usize, n: isize) -> Option<(Rc<Frame>, Slot, String)> {
+    let calls = ctx.get_call_stack();
+    let info = &calls[index];
+    let frame = match *info.frame.borrow() {
+        Some(ref frame) => frame.clone(),
+        None => return None,
+    };
+    if n < 0 {
+        let vararg = (-n - 1) as usize;
+        return frame.vararg(vararg).map(|_| (frame, Slot::Vararg(vararg), "(*vararg)".to_owned()));
+    }
+    if n < 1 {
+        return None;
+    }
+    let local = info.function.get_chunk()?.get_proto().active_locals(frame.pc()).nth(n as usize - 1)?.clone();
+    Some((frame, Slot::Register(local.reg), local.name))
+}
+
+fn getlocal(ctx: &LuaState, args: &[LuaValue]) -> Result<Vec<LuaValue>> {
+    if let Some(&LuaValue::Function(_)) = args.get(0) {
+        // Only the parameters are known outside of a call, and chunks have none.
+        check_integer(args, 2, "getlocal")?;
+        return Ok(vec![LuaValue::Nil]);
+    }
+    let index = check_level(ctx, args, 1, "getlocal")?;
+    let n = check_integer(args, 2, "getlocal")?;
+    match find_local(ctx, index, n) {
+        Some((frame, Slot::Register(reg), name)) => Ok(vec![string(&name), frame.get(reg)]),
+        Some((frame, Slot::Vararg(i), name)) => Ok(vec![string(&name), frame.vararg(i).unwrap()]),
+        None => Ok(vec![LuaValue::Nil]),
+    }
+}
+
+fn setlocal(ctx: &LuaState, args: &[LuaValue]) -> Result<Vec<LuaValue>> {
+    let index = check_level(ctx, args, 1, "setlocal")?;
+    let n = check_integer(args, 2, "setlocal")?;
+    let value = match args.get(2) {
+        Some(value) => value,
+        None => return Err(arg_error(3, "setlocal", "value expected")),
+    };
+    match find_local(ctx, index, n) {
+        Some((frame, slot, name)) => {
+            match slot {
+                Slot::Register(reg) => frame.set(reg, value.clone()),
+                Slot::Vararg(i) => frame.set_vararg(i, value.clone()),
+            }
+            Ok(vec![string(&name)])
+        }
+        None => Ok(vec![LuaValue::Nil]),
+    }
+}
+
+/// The only upvalue known is the `_ENV` of chunks. Native functions keep their state out
+/// of reach.
+fn getupvalue(_ctx: &LuaState, args: &[LuaValue]) -> Result<Vec<LuaValue>> {
+    let function = check_function(args, 1, "getupvalue")?;
+    let n = check_integer(args, 2, "getupvalue")?;
+    match function.get_chunk() {
+        Some(chunk) if n == 1 => Ok(vec![string("_ENV"), chunk.get_env()]),
+        _ => Ok(Vec::new()),
+    }
+}
+
+fn setupvalue(_ctx: &LuaState, args: &[LuaValue]) -> Result<Vec<LuaValue>> {
+    let value = match args.get(2) {
+        Some(value) => value.clone(),
+        None => return Err(arg_error(3, "setupvalue", "value expected")),
+    };
+    let function = check_function(args, 1, "setupvalue")?;
+    let n = check_integer(args, 2, "setupvalue")?;
+    match function.get_chunk() {
+        Some(chunk) if n == 1 => {
+            chunk.set_env(value);
+            Ok(vec![string("_ENV")])
+        }
+        _ => Ok(Vec::new()),
+    }
+}
+
+fn sethook(ctx: &LuaState, args: &[LuaValue]) -> Result<Vec<LuaValue>> {
+    let hook = match args.get(0) {
+        None | Some(&LuaValue::Nil) => None,
+        Some(_) => {
+            let mask = check_string(args, 2, "sethook")?;
+            let function = check_function(args, 1, "sethook")?;
+            let count = opt_integer(args, 3, "sethook", 0)?;
+            let mask = HookMask {
+                call: mask.contains(&b'c'),
+                ret: mask.contains(&b'r'),
+                line: mask.contains(&b'l'),
+            };
+            let count = cmp::max(count, 0) as usize;
+            if mask == HookMask::default() && count == 0 {
+                None
+            } else {
+                Some(Hook {
+                    function,
+                    mask,
+                    count,
+                })
+            }
+        }
+    };
+    ctx.set_hook(hook);
+    Ok(Vec::new())
+}
+
+fn gethook(ctx: &LuaState, _args: &[LuaValue]) -> Result<Vec<LuaValue>> {
+    match ctx.get_hook() {
+        Some(hook) => {
+            let mut mask = String::new();
+            if hook.mask.call {
+                mask.push('c');
+            }
+            if hook.mask.ret {
+                mask.push('r');
+            }
+            if hook.mask.line {
+                mask.push('l');
+            }
+            Ok(vec![
+                LuaValue::Function(hook.function),
+                string(&mask),
+                int(hook.count as isize),
+            ])
+        }
+        None => Ok(vec![LuaValue::Nil, string(""), int(0)]),
+    }
+}
+
+/// Unlike the one of the base library, ignores the `__metatable` field.
+fn getmetatable(_ctx: &LuaState, args: &[LuaValue]) -> Result<Vec<LuaValue>> {
+    let metatable = match args.get(0) {
+        Some(&LuaValue::Table(ref t)) => t.get_metatable(),
+        Some(&LuaValue::UserData(ref u)) => u.get_metatable(),
+        Some(_) => None,
+        None => return Err(arg_error(1, "getmetatable", "value expected")),
+    };
+    Ok(vec![metatable.map_or(LuaValue::Nil, LuaValue::Table)])
+}
+
+fn setmetatable(_ctx: &LuaState, args: &[LuaValue]) -> Result<Vec<LuaValue>> {
+    let metatable = match args.get(1) {
+        Some(&LuaValue::Nil) => None,
+        Some(&LuaValue::Table(ref t)) => Some(t.clone()),
+        _ => return Err(arg_error(2, "setmetatable", "nil or table expected")),
+    };
+    match args[0] {
+        LuaValue::Table(ref t) => t.set_metatable(metatable),
+        LuaValue::UserData(ref u) => u.set_metatable(metatable),
+        ref other => {
+            return Err(OtherError(format!(
+                "cannot change the metatable of a {} value",
+                other.type_name()
+            )))
+        }
+    }
+    Ok(vec![args[0].clone()])
+}
+
+/// Name under which a library of `package.loaded` holds `function`, such as
+/// "string.format", or "print" for the base library.
+fn global_function_name(ctx: &LuaState, function: &LuaFunction) -> Option<String> {
+    let target = LuaValue::Function(function.clone());
+    let mut names = Vec::new();
+    for (module, library) in super::loaded_table(ctx).hash_entries() {
+        if let (LuaValue::Str(module), LuaValue::Table(library)) = (module, library) {
+            for (key, value) in library.hash_entries() {
+                if let LuaValue::Str(key) = key {
+                    if value != target {
+                        continue;
+                    }
+                    let key = String::from_utf8_lossy(&key).into_owned();
+                    if module.as_bytes() == b"_G" {
+                        names.push(key);
+                    } else {
+                        names.push(format!("{}.{}", String::from_utf8_lossy(&module), key));
+                    }
+                }
+            }
+        }
+    }
+    names.into_iter().min_by_key(|name| (name.len(), name.clone()))
+}
+
+fn describe_call(ctx: &LuaState, info: &CallInfo) -> String {
+    if let Some(name) = global_function_name(ctx, &info.function) {
+        return format!("function '{}'", name);
+    }
+    match (&info.name, info.function.get_chunk()) {
+        (&Some(ref name), _) => format!("{} '{}'", name.kind, name.name),
+        (&None, Some(_)) => "main chunk".to_owned(),
+        (&None, None) => "?".to_owned(),
+    }
+}
+
+/// Lines of a traceback, one for each call from `level` to the outermost.
+pub fn traceback_lines(ctx: &LuaState, level: isize) -> String {
+    let stack = ctx.get_call_stack();
+    let calls: Vec<&CallInfo> = stack.iter().rev().skip(cmp::max(level, 0) as usize).collect();
+    let elided = calls.len() > TRACEBACK_FIRST + TRACEBACK_LAST;
+    let mut lines = String::new();
+    for (i, info) in calls.iter().enumerate() {
+        if elided && i >= TRACEBACK_FIRST && i < calls.len() - TRACEBACK_LAST {
+            if i == TRACEBACK_FIRST {
+                lines.push_str("\n\t...");
+            }
+            continue;
+        }
+        let source = match info.function.get_chunk() {
+            Some(chunk) => chunk::chunk_id(chunk.get_chunkname()),
+            None => "[C]".to_owned(),
+        };
+        lines.push_str(&format!("\n\t{}:", source));
+        if info.current_line.get() > 0 {
+            lines.push_str(&format!("{}:", info.current_line.get()));
+        }
+        lines.push_str(&format!(" in {}", describe_call(ctx, info)));
+    }
+    lines
+}
+
+fn traceback(ctx: &LuaState, args: &[LuaValue]) -> Result<Vec<LuaValue>> {
+    let mut result = match args.get(0) {
+        None | Some(&LuaValue::Nil) => Vec::new(),
+        Some(&LuaValue::Str(ref s)) => s.to_vec(),
+        Some(&LuaValue::Number(ref n)) => n.to_string().into_bytes(),
+        Some(other) => return Ok(vec![other.clone()]),
+    };
+    let level = opt_integer(args, 2, "traceback", 1)?;
+    if !result.is_empty() {
+        result.push(b'\n');
+    }
+    result.extend(b"stack traceback:");
+    result.extend(traceback_lines(ctx, level).into_bytes());
+    Ok(vec![LuaValue::Str(result.into())])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use types::CallName;
+    use expression::call_function;
+
+    fn library_function(ctx: &LuaState, name: &str) -> LuaValue {
+        match ctx.get_global_table().get_string(&ctx.intern(b"debug")) {
+            LuaValue::Table(t) => t.get_string(&ctx.intern(name.as_bytes())),
+            _ => panic!("no debug library"),
+        }
+    }
+
+    /// Calls `body` from a native function the caller knows as the local `outer`.
+    fn call_outer<F>(ctx: &LuaState, body: F) -> Vec<LuaValue>
+    where
+        F: Fn(&LuaState) -> Result<Vec<LuaValue>> + 'static,
+    {
+        let outer = LuaFunction::new_closure(0, "outer", move |ctx: &LuaState, _args: &[LuaValue]| body(ctx));
+        let name = CallName {
+            name: "outer".to_owned(),
+            kind: "local",
+        };
+        outer.call_named(ctx, &[], Some(name)).unwrap()
+    }
+
+    fn field(table: &LuaValue, key: &str) -> LuaValue {
+        match *table {
+            LuaValue::Table(ref t) => t.get_string(&key.into()),
+            _ => panic!("not a table"),
+        }
+    }
+
+    #[test]
+    fn test_getinfo() {
+        let ctx = LuaState::new();
+        let getinfo = library_function(&ctx, "getinfo");
+        let info = call_outer(&ctx, move |ctx| call_function(&getinfo, &[int(1)], ctx));
+        assert_eq!(field(&info[0], "name"), string("outer"));
+        assert_eq!(field(&info[0], "namewhat"), string("local"));
+        assert_eq!(field(&info[0], "what"), string("C"));
+        assert_eq!(field(&info[0], "short_src"), string("[C]"));
+        assert_eq!(field(&info[0], "currentline"), int(-1));
+        assert_eq!(field(&info[0], "nparams"), int(0));
+
+        let getinfo = library_function(&ctx, "getinfo");
+        let info = call_function(&getinfo, &[int(0), string("nf")], &ctx).unwrap();
+        assert_eq!(field(&info[0], "func"), getinfo);
+        assert_eq!(field(&info[0], "namewhat"), string(""));
+        assert_eq!(field(&info[0], "source"), LuaValue::Nil);
+        assert_eq!(call_function(&getinfo, &[int(1)], &ctx).unwrap(), vec![LuaValue::Nil]);
+        assert!(call_function(&getinfo, &[int(0), string("x")], &ctx).is_err());
+
+        let block = ::nom_lua53::stat_expr_types::Block {
+            stmts: Vec::new(),
+            ret_stmt: None,
+        };
+        let main = LuaValue::Function(chunk::load_block(&ctx, &block));
+        let info = call_function(&getinfo, &[main, string("Su")], &ctx).unwrap();
+        assert_eq!(field(&info[0], "what"), string("main"));
+        assert_eq!(field(&info[0], "linedefined"), int(0));
+        assert_eq!(field(&info[0], "nparams"), int(0));
+        assert_eq!(field(&info[0], "isvararg"), LuaValue::Boolean(true));
+        assert_eq!(field(&info[0], "nups"), int(1));
+    }
+
+    #[test]
+    fn test_traceback() {
+        let ctx = LuaState::new();
+        let traceback = library_function(&ctx, "traceback");
+        let trace = call_outer(&ctx, move |ctx| call_function(&traceback, &[string("boom")], ctx));
+        assert_eq!(trace, vec![string("boom\nstack traceback:\n\t[C]: in local 'outer'")]);
+
+        let traceback = library_function(&ctx, "traceback");
+        let trace = call_outer(&ctx, move |ctx| call_function(&traceback, &[LuaValue::Nil, int(0)], ctx));
+        assert_eq!(
+            trace,
+            vec![
+                string("stack traceback:\n\t[C]: in function 'debug.traceback'\n\t[C]: in local 'outer'"),
+            ]
+        );
+
+        let traceback = library_function(&ctx, "traceback");
+        let message = LuaValue::Boolean(true);
+        assert_eq!(call_function(&traceback, &[message.clone()], &ctx).unwrap(), vec![message]);
+    }
+
+    #[test]
+    fn test_hooks() {
+        let ctx = LuaState::new();
+        let events = Rc::new(RefCell::new(Vec::new()));
+        let seen = events.clone();
+        let hook = LuaFunction::new_closure(0, "hook", move |_ctx: &LuaState, args: &[LuaValue]| {
+            seen.borrow_mut().push(args.to_vec());
+            Ok(Vec::new())
+        });
+        let sethook = library_function(&ctx, "sethook");
+        let args = [LuaValue::Function(hook.clone()), string("cr"), int(2)];
+        call_function(&sethook, &args, &ctx).unwrap();
+        call_outer(&ctx, |_ctx| Ok(Vec::new()));
+        ctx.trace_statement(None).unwrap();
+        ctx.trace_statement(None).unwrap();
+        let event = |name: &str| vec![string(name), LuaValue::Nil];
+        assert_eq!(
+            *events.borrow(),
+            vec![event("return"), event("call"), event("return"), event("count")]
+        );
+
+        let gethook = library_function(&ctx, "gethook");
+        assert_eq!(
+            call_function(&gethook, &[], &ctx).unwrap(),
+            vec![LuaValue::Function(hook), string("cr"), int(2)]
+        );
+        call_function(&sethook, &[], &ctx).unwrap();
+        assert_eq!(
+            call_function(&gethook, &[], &ctx).unwrap(),
+            vec![LuaValue::Nil, string(""), int(0)]
+        );
+    }
+
+    #[test]
+    fn test_locals() {
+        use nom_lua53::{Args, Exp, ExpOrVarName, ExpSuffix, FunctionCall, LVarAssignment, PrefixExp, Statement};
+        use nom_lua53::name::VarName;
+        use nom_lua53::num::Numeral;
+        use nom_lua53::stat_expr_types::Block;
+        let ctx = LuaState::new();
+        let getlocal = library_function(&ctx, "getlocal");
+        let setlocal = library_function(&ctx, "setlocal");
+        let seen = Rc::new(RefCell::new(Vec::new()));
+        let record = seen.clone();
+        ctx.register_native("probe", move |ctx: &LuaState, _args: &[LuaValue]| {
+            // Level 1 is the probe itself, level 2 the chunk calling it.
+            let mut result = call_function(&getlocal, &[int(2), int(1)], ctx)?;
+            result.extend(call_function(&setlocal, &[int(2), int(2), int(5)], ctx)?);
+            result.extend(call_function(&getlocal, &[int(2), int(3)], ctx)?);
+            result.extend(call_function(&getlocal, &[int(2), int(-1)], ctx)?);
+            result.extend(call_function(&getlocal, &[int(1), int(1)], ctx)?);
+            assert!(call_function(&getlocal, &[int(10), int(1)], ctx).is_err());
+            record.borrow_mut().extend(result);
+            Ok(Vec::new())
+        });
+
+        // local a, b = 1, 2; probe(); return b
+        let variable = |name: &'static [u8]| PrefixExp {
+            prefix: ExpOrVarName::VarName(VarName(name)),
+            suffix_chain: Vec::new(),
+        };
+        let mut call = variable(b"probe");
+        call.suffix_chain.push(ExpSuffix::FuncCall(FunctionCall {
+            method: None,
+            args: Args::ExpList(Vec::new()),
+        }));
+        let block = Block {
+            stmts: vec![
+                Statement::LVarAssign(LVarAssignment {
+                    vars: vec![VarName(b"a"), VarName(b"b")],
+                    vals: Some(vec![Exp::Num(Numeral::Int(1)), Exp::Num(Numeral::Int(2))]),
+                }),
+                Statement::FuncCall(call),
+            ],
+            ret_stmt: Some(vec![Exp::PrefixExp(Box::new(variable(b"b")))]),
+        };
+        let chunk = chunk::load_block(&ctx, &block);
+        assert_eq!(chunk.call(&ctx, &[string("extra")]).unwrap(), vec![int(5)]);
+        assert_eq!(
+            *seen.borrow(),
+            vec![
+                string("a"),
+                int(1),
+                string("b"),
+                LuaValue::Nil,
+                string("(*vararg)"),
+                string("extra"),
+                LuaValue::Nil,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_metatables_and_upvalues() {
+        let ctx = LuaState::new();
+        let t = LuaValue::Table(LuaTable::new(ctx.get_ref_id()));
+        let mt = LuaValue::Table(LuaTable::new(ctx.get_ref_id()));
+        assert_eq!(setmetatable(&ctx, &[t.clone(), mt.clone()]).unwrap(), vec![t.clone()]);
+        assert_eq!(getmetatable(&ctx, &[t.clone()]).unwrap(), vec![mt]);
+        assert!(setmetatable(&ctx, &[t.clone(), int(1)]).is_err());
+        assert!(setmetatable(&ctx, &[int(1), LuaValue::Nil]).is_err());
+        assert_eq!(getmetatable(&ctx, &[int(1)]).unwrap(), vec![LuaValue::Nil]);
+
+        let native = library_function(&ctx, "getinfo");
+        assert_eq!(getupvalue(&ctx, &[native.clone(), int(1)]).unwrap(), Vec::new());
+        assert!(setupvalue(&ctx, &[native, int(1)]).is_err());
+        assert!(getupvalue(&ctx, &[t, int(1)]).is_err());
+    }
+}
@@ -0,0 +1,995 @@
+// The `io` library. Files are userdata holding a `FileState`, which share a metatable
+// kept in the registry under "FILE*". The handles do their own buffering so that reads
+// can look ahead, as the "n" format needs to.
+
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+
+use interner::LuaString;
+use types::{LuaFunction, LuaState, LuaTable, LuaUserData, LuaValue, Number};
+use Result;
+use LuaError::*;
+use super::args::{arg_error, check_string, opt_integer, opt_string, type_error};
+
+const BUFFER_SIZE: usize = 8192;
+
+/// Longest numeral the "n" format reads.
+const MAX_NUMERAL_LENGTH: usize = 200;
+
+pub fn open(ctx: &LuaState) {
+    let methods = super::new_library(
+        ctx,
+        &[
+            ("close", f_close),
+            ("flush", f_flush),
+            ("lines", f_lines),
+            ("read", f_read),
+            ("seek", f_seek),
+            ("setvbuf", f_setvbuf),
+            ("write", f_write),
+        ],
+    );
+    let metatable = super::new_library(ctx, &[("__tostring", f_tostring)]);
+    metatable.set_string(&ctx.intern(b"__index"), &LuaValue::Table(methods));
+    metatable.set_string(&ctx.intern(b"__name"), &LuaValue::Str("FILE*".into()));
+    ctx.get_registry()
+        .set_string(&ctx.names().file_handle, &LuaValue::Table(metatable));
+
+    let library = super::register_library(
+        ctx,
+        "io",
+        &[
+            ("close", close),
+            ("flush", flush),
+            ("input", input),
+            ("lines", lines),
+            ("open", open_file),
+            ("output", output),
+            ("read", read),
+            ("type", io_type),
+            ("write", write),
+        ],
+    );
+    let stdin = LuaFile::new(ctx, Stream::Stdin(io::stdin()), true);
+    let stdout = LuaFile::new(ctx, Stream::Stdout(io::stdout()), true);
+    let stderr = LuaFile::new(ctx, Stream::Stderr(io::stderr()), true);
+    // Rust already buffers the standard output by lines.
+    stdout.set_buffering(Buffering::No, 0);
+    stderr.set_buffering(Buffering::No, 0);
+    let registry = ctx.get_registry();
+    registry.set_string(&ctx.names().io_input, &stdin.to_value());
+    registry.set_string(&ctx.names().io_output, &stdout.to_value());
+    library.set_string(&ctx.intern(b"stdin"), &stdin.to_value());
+    library.set_string(&ctx.intern(b"stdout"), &stdout.to_value());
+    library.set_string(&ctx.intern(b"stderr"), &stderr.to_value());
+}
+
+
+enum Stream {
+    Stdin(io::Stdin),
+    Stdout(io::Stdout),
+    Stderr(io::Stderr),
+    Disk(File),
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match *self {
+            Stream::Stdin(ref mut s) => s.read(buf),
+            Stream::Disk(ref mut f) => f.read(buf),
+            _ => Err(io::Error::new(io::ErrorKind::Other, "Bad file descriptor")),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match *self {
+            Stream::Stdout(ref mut s) => s.write(buf),
+            Stream::Stderr(ref mut s) => s.write(buf),
+            Stream::Disk(ref mut f) => f.write(buf),
+            Stream::Stdin(_) => Err(io::Error::new(io::ErrorKind::Other, "Bad file descriptor")),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match *self {
+            Stream::Stdout(ref mut s) => s.flush(),
+            Stream::Stderr(ref mut s) => s.flush(),
+            Stream::Disk(ref mut f) => f.flush(),
+            Stream::Stdin(_) => Ok(()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Buffering {
+    No,
+    Full,
+    Line,
+}
+
+/// An open file along with its read-ahead and pending writes.
+struct Handle {
+    stream: Stream,
+    read_buffer: Vec<u8>,
+    read_pos: usize,
+    write_buffer: Vec<u8>,
+    buffering: Buffering,
+    buffer_size: usize,
+}
+
+impl Handle {
+    fn new(stream: Stream) -> Handle {
+        Handle {
+            stream,
+            read_buffer: Vec::new(),
+            read_pos: 0,
+            write_buffer: Vec::new(),
+            buffering: Buffering::Full,
+            buffer_size: BUFFER_SIZE,
+        }
+    }
+
+    fn flush_writes(&mut self) -> io::Result<()> {
+        if !self.write_buffer.is_empty() {
+            let result = self.stream.write_all(&self.write_buffer);
+            self.write_buffer.clear();
+            result?;
+        }
+        Ok(())
+    }
+
+    /// Gives back the bytes read ahead, so that the position of the stream is the one
+    /// the script expects.
+    fn drop_read_ahead(&mut self) -> io::Result<()> {
+        let unread = (self.read_buffer.len() - self.read_pos) as i64;
+        self.read_buffer.clear();
+        self.read_pos = 0;
+        if unread > 0 {
+            if let Stream::Disk(ref mut f) = self.stream {
+                f.seek(SeekFrom::Current(-unread))?;
+            }
+        }
+        Ok(())
+    }
+
+    fn peek(&mut self) -> io::Result<Option<u8>> {
+        if self.read_pos == self.read_buffer.len() {
+            self.flush_writes()?;
+            self.read_buffer.resize(BUFFER_SIZE, 0);
+            self.read_pos = 0;
+            let count = match self.stream.read(&mut self.read_buffer) {
+                Ok(count) => count,
+                Err(err) => {
+                    self.read_buffer.clear();
+                    return Err(err);
+                }
+            };
+            self.read_buffer.truncate(count);
+        }
+        Ok(self.read_buffer.get(self.read_pos).cloned())
+    }
+
+    fn next(&mut self) -> io::Result<Option<u8>> {
+        let c = self.peek()?;
+        if c.is_some() {
+            self.read_pos += 1;
+        }
+        Ok(c)
+    }
+
+    fn read_line(&mut self, keep_newline: bool) -> io::Result<Option<Vec<u8>>> {
+        let mut line = Vec::new();
+        loop {
+            match self.next()? {
+                Some(b'\n') => {
+                    if keep_newline {
+                        line.push(b'\n');
+                    }
+                    return Ok(Some(line));
+                }
+                Some(c) => line.push(c),
+                None if line.is_empty() => return Ok(None),
+                None => return Ok(Some(line)),
+            }
+        }
+    }
+
+    fn read_all(&mut self) -> io::Result<Vec<u8>> {
+        let mut data = Vec::new();
+        while self.peek()?.is_some() {
+            data.extend_from_slice(&self.read_buffer[self.read_pos..]);
+            self.read_pos = self.read_buffer.len();
+        }
+        Ok(data)
+    }
+
+    /// Reads up to `count` bytes. Reading zero bytes tests for the end of the file.
+    fn read_chars(&mut self, count: usize) -> io::Result<Option<Vec<u8>>> {
+        if count == 0 {
+            return Ok(self.peek()?.map(|_| Vec::new()));
+        }
+        let mut data = Vec::new();
+        while data.len() < count && self.peek()?.is_some() {
+            let available = self.read_buffer.len() - self.read_pos;
+            let taken = ::std::cmp::min(available, count - data.len());
+            data.extend_from_slice(&self.read_buffer[self.read_pos..self.read_pos + taken]);
+            self.read_pos += taken;
+        }
+        Ok(if data.is_empty() { None } else { Some(data) })
+    }
+
+    /// Reads the longest prefix of the input that looks like a numeral, then converts
+    /// it. Like the reference implementation, what was read is lost when the conversion
+    /// fails.
+    fn read_number(&mut self) -> io::Result<Option<Number>> {
+        let mut numeral = NumeralReader {
+            handle: self,
+            buffer: Vec::new(),
+        };
+        while numeral.current()?.map_or(false, is_space) {
+            numeral.handle.next()?;
+        }
+        numeral.accept(b"-+")?;
+        let mut count = 0;
+        let mut hex = false;
+        if numeral.accept(b"0")? {
+            if numeral.accept(b"xX")? {
+                hex = true;
+            } else {
+                count = 1;
+            }
+        }
+        count += numeral.digits(hex)?;
+        if numeral.accept(b".")? {
+            count += numeral.digits(hex)?;
+        }
+        if count > 0 && numeral.accept(if hex { b"pP" } else { b"eE" })? {
+            numeral.accept(b"-+")?;
+            numeral.digits(false)?;
+        }
+        Ok(parse_numeral(&numeral.buffer))
+    }
+
+    fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        self.drop_read_ahead()?;
+        self.write_buffer.extend_from_slice(data);
+        let full = match self.buffering {
+            Buffering::No => true,
+            Buffering::Line => data.contains(&b'\n'),
+            Buffering::Full => self.write_buffer.len() >= self.buffer_size,
+        };
+        if full {
+            self.flush_writes()?;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.flush_writes()?;
+        self.stream.flush()
+    }
+
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.flush_writes()?;
+        let unread = (self.read_buffer.len() - self.read_pos) as i64;
+        self.read_buffer.clear();
+        self.read_pos = 0;
+        let pos = match pos {
+            SeekFrom::Current(offset) => SeekFrom::Current(offset - unread),
+            other => other,
+        };
+        match self.stream {
+            Stream::Disk(ref mut f) => f.seek(pos),
+            _ => Err(io::Error::new(io::ErrorKind::Other, "Illegal seek")),
+        }
+    }
+}
+
+impl Drop for Handle {
+    fn drop(&mut self) {
+        let _ = self.flush();
+    }
+}
+
+fn is_space(c: u8) -> bool {
+    c == b' ' || (c >= b'\t' && c <= b'\r')
+}
+
+/// Accumulates the characters of a numeral read from a file.
+struct NumeralReader<'a> {
+    handle: &'a mut Handle,
+    buffer: Vec<u8>,
+}
+
+impl<'a> NumeralReader<'a> {
+    fn current(&mut self) -> io::Result<Option<u8>> {
+        self.handle.peek()
+    }
+
+    /// Consumes the current character if it is one of `set`.
+    fn accept(&mut self, set: &[u8]) -> io::Result<bool> {
+        match self.current()? {
+            Some(c) if set.contains(&c) && self.buffer.len() < MAX_NUMERAL_LENGTH => {
+                self.buffer.push(c);
+                self.handle.next()?;
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    fn digits(&mut self, hex: bool) -> io::Result<usize> {
+        let mut count = 0;
+        loop {
+            match self.current()? {
+                Some(c) if (hex && (c as char).is_digit(16)) || (c as char).is_digit(10) => {
+                    if self.buffer.len() >= MAX_NUMERAL_LENGTH {
+                        return Ok(count);
+                    }
+                    self.buffer.push(c);
+                    self.handle.next()?;
+                    count += 1;
+                }
+                _ => return Ok(count),
+            }
+        }
+    }
+}
+
+/// Converts a decimal or hexadecimal numeral, with an optional sign. Hexadecimal
+/// integers wrap around on overflow, while decimal ones become floats.
+fn parse_numeral(numeral: &[u8]) -> Option<Number> {
+    let text = ::std::str::from_utf8(numeral).ok()?;
+    let (negative, unsigned) = match numeral.first() {
+        Some(&b'-') => (true, &text[1..]),
+        Some(&b'+') => (false, &text[1..]),
+        _ => (false, text),
+    };
+    if unsigned.starts_with("0x") || unsigned.starts_with("0X") {
+        return parse_hex(&unsigned[2..], negative);
+    }
+    if unsigned.starts_with('+') || unsigned.starts_with('-') {
+        return None;
+    }
+    if let Ok(i) = unsigned.parse::<isize>() {
+        return Some(Number::Int(if negative { i.wrapping_neg() } else { i }));
+    }
+    unsigned
+        .parse::<f64>()
+        .ok()
+        .map(|f| Number::Float(if negative { -f } else { f }))
+}
+
+fn parse_hex(digits: &str, negative: bool) -> Option<Number> {
+    let (mantissa, exponent) = match digits.find(|c| c == 'p' || c == 'P') {
+        Some(i) => (&digits[..i], Some(digits[i + 1..].parse::<i32>().ok()?)),
+        None => (digits, None),
+    };
+    let (integral, fraction) = match mantissa.find('.') {
+        Some(i) => (&mantissa[..i], Some(&mantissa[i + 1..])),
+        None => (mantissa, None),
+    };
+    if integral.is_empty() && fraction.map_or(true, |f| f.is_empty()) {
+        return None;
+    }
+    if fraction.is_none() && exponent.is_none() {
+        let mut value: isize = 0;
+        for c in integral.chars() {
+            value = value.wrapping_mul(16).wrapping_add(c.to_digit(16)? as isize);
+        }
+        return Some(Number::Int(if negative { value.wrapping_neg() } else { value }));
+    }
+    let mut value = 0f64;
+    for c in integral.chars() {
+        value = value * 16.0 + c.to_digit(16)? as f64;
+    }
+    let mut scale = exponent.unwrap_or(0);
+    for c in fraction.unwrap_or("").chars() {
+        value = value * 16.0 + c.to_digit(16)? as f64;
+        scale -= 4;
+    }
+    let value = value * 2f64.powi(scale);
+    Some(Number::Float(if negative { -value } else { value }))
+}
+
+/// What a file userdata holds.
+struct FileState {
+    handle: Option<Handle>,
+    standard: bool,
+}
+
+/// A file handle, as seen by scripts: a userdata known to hold a `FileState`.
+#[derive(Clone, PartialEq)]
+pub struct LuaFile {
+    userdata: LuaUserData,
+}
+
+impl fmt::Debug for LuaFile {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.is_closed() {
+            write!(f, "file (closed)")
+        } else {
+            write!(f, "file (0x{:08x})", self.userdata.get_ref_id())
+        }
+    }
+}
+
+impl LuaFile {
+    fn new(ctx: &LuaState, stream: Stream, standard: bool) -> LuaFile {
+        let metatable = match ctx.get_registry().get_string(&ctx.names().file_handle) {
+            LuaValue::Table(t) => t,
+            _ => LuaTable::new(ctx.get_ref_id()),
+        };
+        let state = FileState {
+            handle: Some(Handle::new(stream)),
+            standard,
+        };
+        LuaFile {
+            userdata: LuaUserData::new(ctx.get_ref_id(), state, Some(metatable)),
+        }
+    }
+
+    fn from_value(value: &LuaValue) -> Option<LuaFile> {
+        match *value {
+            LuaValue::UserData(ref u) if u.is::<FileState>() => Some(LuaFile {
+                userdata: u.clone(),
+            }),
+            _ => None,
+        }
+    }
+
+    fn to_value(&self) -> LuaValue {
+        LuaValue::UserData(self.userdata.clone())
+    }
+
+    /// Runs `f` on the state of the file. The library never holds the state while running
+    /// code that could reach the file again.
+    fn with_state<T, F: FnOnce(&mut FileState) -> T>(&self, f: F) -> T {
+        f(&mut self.userdata.borrow_mut::<FileState>().unwrap())
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.with_state(|state| state.handle.is_none())
+    }
+
+    fn is_standard(&self) -> bool {
+        self.with_state(|state| state.standard)
+    }
+
+    fn set_buffering(&self, buffering: Buffering, size: usize) {
+        self.with_state(|state| if let Some(ref mut handle) = state.handle {
+            handle.buffering = buffering;
+            handle.buffer_size = size;
+        })
+    }
+
+    /// Runs `f` on the open handle. Callers check beforehand that the file is open.
+    fn with_handle<T, F>(&self, f: F) -> io::Result<T>
+    where
+        F: FnOnce(&mut Handle) -> io::Result<T>,
+    {
+        self.with_state(|state| match state.handle {
+            Some(ref mut handle) => f(handle),
+            None => Err(io::Error::new(io::ErrorKind::Other, "file is closed")),
+        })
+    }
+
+    fn close(&self) -> io::Result<()> {
+        match self.with_state(|state| state.handle.take()) {
+            Some(mut handle) => handle.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+fn to_file(args: &[LuaValue], fname: &str) -> Result<LuaFile> {
+    match args.get(0).and_then(LuaFile::from_value) {
+        Some(ref f) if f.is_closed() => Err(OtherError("attempt to use a closed file".to_owned())),
+        Some(f) => Ok(f),
+        None => Err(type_error(1, fname, "FILE*", args.get(0))),
+    }
+}
+
+/// One of the default files, which the script may have closed.
+fn default_file(ctx: &LuaState, key: &LuaString) -> Result<LuaFile> {
+    match LuaFile::from_value(&ctx.get_registry().get_string(key)) {
+        Some(ref f) if !f.is_closed() => Ok(f.clone()),
+        _ => Err(OtherError(format!(
+            "standard {} file is closed",
+            String::from_utf8_lossy(&key["_IO_".len()..])
+        ))),
+    }
+}
+
+fn success_or_failure(result: io::Result<()>) -> Vec<LuaValue> {
+    match result {
+        Ok(()) => vec![LuaValue::Boolean(true)],
+        Err(err) => super::io_failure(&err, None),
+    }
+}
+
+/// Checks a mode string as accepted by `fopen`.
+fn check_mode(mode: &[u8]) -> bool {
+    match mode.split_first() {
+        Some((first, rest)) if b"rwa".contains(first) => {
+            let rest = if rest.first() == Some(&b'+') {
+                &rest[1..]
+            } else {
+                rest
+            };
+            rest.iter().all(|&c| c == b'b')
+        }
+        _ => false,
+    }
+}
+
+fn open_stream(ctx: &LuaState, filename: &[u8], mode: &[u8]) -> io::Result<Stream> {
+    let update = mode.contains(&b'+');
+    let reads = mode[0] == b'r' || update;
+    let writes = mode[0] != b'r' || update;
+    let capabilities = ctx.get_capabilities();
+    if (reads && !capabilities.read_files) || (writes && !capabilities.write_files) {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            "file access not granted by the host",
+        ));
+    }
+    let mut options = OpenOptions::new();
+    match mode[0] {
+        b'r' => options.read(true).write(update),
+        b'w' => options.write(true).create(true).truncate(true).read(update),
+        _ => options.append(true).create(true).read(update),
+    };
+    options.open(super::to_path(filename)).map(Stream::Disk)
+}
+
+/// Opens a file for `io.lines`, `io.input` or `io.output`, which raise an error when it
+/// fails.
+fn open_checked(ctx: &LuaState, filename: &[u8], mode: &[u8]) -> Result<LuaFile> {
+    match open_stream(ctx, filename, mode) {
+        Ok(stream) => Ok(LuaFile::new(ctx, stream, false)),
+        Err(err) => {
+            let failure = super::io_failure(&err, None);
+            let reason = match failure[1] {
+                LuaValue::Str(ref s) => String::from_utf8_lossy(s).into_owned(),
+                _ => String::new(),
+            };
+            Err(OtherError(format!(
+                "cannot open file '{}' ({})",
+                String::from_utf8_lossy(filename),
+                reason
+            )))
+        }
+    }
+}
+
+/// Reads from `file` following the formats, the first of which is the argument number
+/// `first_arg` of the function. Reading stops at the first format that fails, which
+/// produces a nil.
+fn read_formats(
+    file: &LuaFile,
+    formats: &[LuaValue],
+    first_arg: usize,
+    fname: &str,
+) -> Result<Vec<LuaValue>> {
+    enum Format {
+        Number,
+        Line(bool),
+        All,
+        Chars(usize),
+    }
+    let default = [LuaValue::Str("l".into())];
+    let formats = if formats.is_empty() { &default[..] } else { formats };
+    let mut results = Vec::with_capacity(formats.len());
+    for (i, format) in formats.iter().enumerate() {
+        let n = first_arg + i;
+        let format = match *format {
+            LuaValue::Number(ref num) => match num.to_exact_int() {
+                Some(count) if count < 0 => Format::Chars(0),
+                Some(count) => Format::Chars(count as usize),
+                None => return Err(arg_error(n, fname, "number has no integer representation")),
+            },
+            LuaValue::Str(ref spec) => {
+                let spec = if spec.first() == Some(&b'*') {
+                    &spec[1..]
+                } else {
+                    &spec[..]
+                };
+                match spec.first() {
+                    Some(&b'n') => Format::Number,
+                    Some(&b'l') => Format::Line(false),
+                    Some(&b'L') => Format::Line(true),
+                    Some(&b'a') => Format::All,
+                    _ => return Err(arg_error(n, fname, "invalid format")),
+                }
+            }
+            ref other => return Err(type_error(n, fname, "string", Some(other))),
+        };
+        let value = file.with_handle(|handle| {
+            Ok(match format {
+                Format::Number => handle.read_number()?.map(LuaValue::Number),
+                Format::Line(keep) => handle.read_line(keep)?.map(|line| LuaValue::Str(line.into())),
+                Format::All => Some(LuaValue::Str(handle.read_all()?.into())),
+                Format::Chars(count) => handle.read_chars(count)?.map(|chars| LuaValue::Str(chars.into())),
+            })
+        });
+        match value {
+            Ok(Some(value)) => results.push(value),
+            Ok(None) => {
+                results.push(LuaValue::Nil);
+                break;
+            }
+            Err(err) => return Ok(super::io_failure(&err, None)),
+        }
+    }
+    Ok(results)
+}
+
+fn write_values(file: &LuaFile, values: &[LuaValue], first_arg: usize) -> Result<Vec<LuaValue>> {
+    for (i, value) in values.iter().enumerate() {
+        let data = match *value {
+            LuaValue::Str(ref s) => s.to_vec(),
+            LuaValue::Number(ref n) => n.to_string().into_bytes(),
+            _ => return Err(type_error(first_arg + i, "write", "string", Some(value))),
+        };
+        if let Err(err) = file.with_handle(|handle| handle.write(&data)) {
+            return Ok(super::io_failure(&err, None));
+        }
+    }
+    Ok(vec![file.to_value()])
+}
+
+/// Builds the iterator returned by `lines`, which closes the file at the end when it was
+/// opened for the occasion.
+fn lines_iterator(ctx: &LuaState, file: LuaFile, formats: Vec<LuaValue>, close: bool) -> LuaValue {
+    let iterator = move |_ctx: &LuaState, _args: &[LuaValue]| -> Result<Vec<LuaValue>> {
+        if file.is_closed() {
+            return Err(OtherError("file is already closed".to_owned()));
+        }
+        let results = read_formats(&file, &formats, 1, "lines")?;
+        if results[0] != LuaValue::Nil {
+            return Ok(results);
+        }
+        if results.len() > 1 {
+            return Err(OtherError(match results[1] {
+                LuaValue::Str(ref s) => String::from_utf8_lossy(s).into_owned(),
+                _ => String::new(),
+            }));
+        }
+        if close {
+            let _ = file.close();
+        }
+        Ok(vec![LuaValue::Nil])
+    };
+    LuaValue::Function(LuaFunction::new_closure(ctx.get_ref_id(), "lines", iterator))
+}
+
+fn close_file(file: &LuaFile) -> Vec<LuaValue> {
+    if file.is_standard() {
+        return vec![
+            LuaValue::Nil,
+            LuaValue::Str("cannot close standard file".into()),
+        ];
+    }
+    success_or_failure(file.close())
+}
+
+fn f_close(_ctx: &LuaState, args: &[LuaValue]) -> Result<Vec<LuaValue>> {
+    Ok(close_file(&to_file(args, "close")?))
+}
+
+fn f_flush(_ctx: &LuaState, args: &[LuaValue]) -> Result<Vec<LuaValue>> {
+    let file = to_file(args, "flush")?;
+    Ok(success_or_failure(file.with_handle(|handle| handle.flush())))
+}
+
+fn f_lines(ctx: &LuaState, args: &[LuaValue]) -> Result<Vec<LuaValue>> {
+    let file = to_file(args, "lines")?;
+    Ok(vec![lines_iterator(ctx, file, args[1..].to_vec(), false)])
+}
+
+fn f_read(_ctx: &LuaState, args: &[LuaValue]) -> Result<Vec<LuaValue>> {
+    let file = to_file(args, "read")?;
+    read_formats(&file, &args[1..], 2, "read")
+}
+
+fn f_seek(_ctx: &LuaState, args: &[LuaValue]) -> Result<Vec<LuaValue>> {
+    let file = to_file(args, "seek")?;
+    let whence = opt_string(args, 2, "seek", b"cur")?;
+    let offset = opt_integer(args, 3, "seek", 0)? as i64;
+    let pos = match &whence[..] {
+        b"set" => {
+            if offset < 0 {
+                return Ok(super::io_failure(
+                    &io::Error::new(io::ErrorKind::InvalidInput, "Invalid argument"),
+                    None,
+                ));
+            }
+            SeekFrom::Start(offset as u64)
+        }
+        b"cur" => SeekFrom::Current(offset),
+        b"end" => SeekFrom::End(offset),
+        _ => {
+            let msg = format!("invalid option '{}'", String::from_utf8_lossy(&whence));
+            return Err(arg_error(2, "seek", &msg));
+        }
+    };
+    Ok(match file.with_handle(|handle| handle.seek(pos)) {
+        Ok(pos) => vec![LuaValue::Number(Number::Int(pos as isize))],
+        Err(err) => super::io_failure(&err, None),
+    })
+}
+
+fn f_setvbuf(_ctx: &LuaState, args: &[LuaValue]) -> Result<Vec<LuaValue>> {
+    let file = to_file(args, "setvbuf")?;
+    let mode = check_string(args, 2, "setvbuf")?;
+    let buffering = match &mode[..] {
+        b"no" => Buffering::No,
+        b"full" => Buffering::Full,
+        b"line" => Buffering::Line,
+        _ => {
+            let msg = format!("invalid option '{}'", String::from_utf8_lossy(&mode));
+            return Err(arg_error(2, "setvbuf", &msg));
+        }
+    };
+    let size = opt_integer(args, 3, "setvbuf", BUFFER_SIZE as isize)?;
+    let size = if size <= 0 { BUFFER_SIZE } else { size as usize };
+    let result = file.with_handle(|handle| handle.flush_writes());
+    file.set_buffering(buffering, size);
+    Ok(success_or_failure(result))
+}
+
+fn f_tostring(_ctx: &LuaState, args: &[LuaValue]) -> Result<Vec<LuaValue>> {
+    match args.get(0).and_then(LuaFile::from_value) {
+        Some(f) => Ok(vec![LuaValue::Str(format!("{:?}", f).into())]),
+        None => Err(type_error(1, "tostring", "FILE*", args.get(0))),
+    }
+}
+
+fn f_write(_ctx: &LuaState, args: &[LuaValue]) -> Result<Vec<LuaValue>> {
+    let file = to_file(args, "write")?;
+    write_values(&file, &args[1..], 2)
+}
+
+fn close(ctx: &LuaState, args: &[LuaValue]) -> Result<Vec<LuaValue>> {
+    match args.get(0) {
+        None | Some(&LuaValue::Nil) => Ok(close_file(&default_file(ctx, &ctx.names().io_output)?)),
+        Some(_) => f_close(ctx, args),
+    }
+}
+
+fn flush(ctx: &LuaState, _args: &[LuaValue]) -> Result<Vec<LuaValue>> {
+    let file = default_file(ctx, &ctx.names().io_output)?;
+    Ok(success_or_failure(file.with_handle(|handle| handle.flush())))
+}
+
+/// Shared by `io.input` and `io.output`, which take either a file name or a handle.
+fn set_default_file(
+    ctx: &LuaState,
+    args: &[LuaValue],
+    key: &LuaString,
+    mode: &[u8],
+    fname: &str,
+) -> Result<Vec<LuaValue>> {
+    let registry = ctx.get_registry();
+    match args.get(0) {
+        None | Some(&LuaValue::Nil) => {}
+        Some(&LuaValue::UserData(_)) => {
+            let file = to_file(args, fname)?;
+            registry.set_string(key, &file.to_value());
+        }
+        Some(_) => {
+            let filename = check_string(args, 1, fname)?;
+            let file = open_checked(ctx, &filename, mode)?;
+            registry.set_string(key, &file.to_value());
+        }
+    }
+    Ok(vec![registry.get_string(key)])
+}
+
+fn input(ctx: &LuaState, args: &[LuaValue]) -> Result<Vec<LuaValue>> {
+    set_default_file(ctx, args, &ctx.names().io_input, b"r", "input")
+}
+
+fn output(ctx: &LuaState, args: &[LuaValue]) -> Result<Vec<LuaValue>> {
+    set_default_file(ctx, args, &ctx.names().io_output, b"w", "output")
+}
+
+fn lines(ctx: &LuaState, args: &[LuaValue]) -> Result<Vec<LuaValue>> {
+    let formats = if args.len() > 1 { args[1..].to_vec() } else { Vec::new() };
+    let iterator = match args.get(0) {
+        None | Some(&LuaValue::Nil) => {
+            lines_iterator(ctx, default_file(ctx, &ctx.names().io_input)?, formats, false)
+        }
+        Some(_) => {
+            let filename = check_string(args, 1, "lines")?;
+            lines_iterator(ctx, open_checked(ctx, &filename, b"r")?, formats, true)
+        }
+    };
+    Ok(vec![iterator])
+}
+
+fn open_file(ctx: &LuaState, args: &[LuaValue]) -> Result<Vec<LuaValue>> {
+    let filename = check_string(args, 1, "open")?;
+    let mode = opt_string(args, 2, "open", b"r")?;
+    if !check_mode(&mode) {
+        return Err(arg_error(2, "open", "invalid mode"));
+    }
+    Ok(match open_stream(ctx, &filename, &mode) {
+        Ok(stream) => vec![LuaFile::new(ctx, stream, false).to_value()],
+        Err(err) => super::io_failure(&err, Some(&filename)),
+    })
+}
+
+fn read(ctx: &LuaState, args: &[LuaValue]) -> Result<Vec<LuaValue>> {
+    read_formats(&default_file(ctx, &ctx.names().io_input)?, args, 1, "read")
+}
+
+fn io_type(_ctx: &LuaState, args: &[LuaValue]) -> Result<Vec<LuaValue>> {
+    if args.is_empty() {
+        return Err(arg_error(1, "type", "value expected"));
+    }
+    Ok(vec![match LuaFile::from_value(&args[0]) {
+        Some(ref f) if f.is_closed() => LuaValue::Str("closed file".into()),
+        Some(_) => LuaValue::Str("file".into()),
+        None => LuaValue::Nil,
+    }])
+}
+
+fn write(ctx: &LuaState, args: &[LuaValue]) -> Result<Vec<LuaValue>> {
+    write_values(&default_file(ctx, &ctx.names().io_output)?, args, 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use stdlib::Capabilities;
+    use std::env;
+    use std::fs;
+
+    fn string(s: &str) -> LuaValue {
+        LuaValue::Str(s.into())
+    }
+
+    fn int(i: isize) -> LuaValue {
+        LuaValue::Number(Number::Int(i))
+    }
+
+    fn temp_file(name: &str, content: &str) -> LuaValue {
+        let path = env::temp_dir().join(format!("seo2_io_{}_{}", name, ::std::process::id()));
+        fs::write(&path, content).unwrap();
+        LuaValue::Str(path.to_string_lossy().into_owned().into())
+    }
+
+    fn open(ctx: &LuaState, name: &LuaValue, mode: &str) -> LuaValue {
+        open_file(ctx, &[name.clone(), string(mode)]).unwrap().remove(0)
+    }
+
+    #[test]
+    fn test_read_formats() {
+        let ctx = LuaState::with_capabilities(Capabilities::all());
+        let name = temp_file("formats", "12 0x1F -3.5e1 abc\nsecond line\nrest");
+        let file = open(&ctx, &name, "r");
+        assert_eq!(
+            f_read(&ctx, &[file.clone(), string("n"), string("*n"), string("n")]).unwrap(),
+            vec![int(12), int(31), LuaValue::Number(Number::Float(-35.))]
+        );
+        assert_eq!(f_read(&ctx, &[file.clone(), int(4)]).unwrap(), vec![string(" abc")]);
+        assert_eq!(f_read(&ctx, &[file.clone(), string("L")]).unwrap(), vec![string("\n")]);
+        assert_eq!(f_read(&ctx, &[file.clone()]).unwrap(), vec![string("second line")]);
+        assert_eq!(f_read(&ctx, &[file.clone(), int(0)]).unwrap(), vec![string("")]);
+        assert_eq!(f_read(&ctx, &[file.clone(), string("a")]).unwrap(), vec![string("rest")]);
+        assert_eq!(f_read(&ctx, &[file.clone(), string("a")]).unwrap(), vec![string("")]);
+        assert_eq!(
+            f_read(&ctx, &[file.clone(), string("l"), string("l")]).unwrap(),
+            vec![LuaValue::Nil]
+        );
+        assert_eq!(f_read(&ctx, &[file.clone(), int(0)]).unwrap(), vec![LuaValue::Nil]);
+        assert!(f_read(&ctx, &[file.clone(), string("x")]).is_err());
+        assert_eq!(f_close(&ctx, &[file.clone()]).unwrap(), vec![LuaValue::Boolean(true)]);
+        assert_eq!(
+            f_read(&ctx, &[file]),
+            Err(OtherError("attempt to use a closed file".to_owned()))
+        );
+    }
+
+    #[test]
+    fn test_write_and_seek() {
+        let ctx = LuaState::with_capabilities(Capabilities::all());
+        let name = temp_file("write", "");
+        let file = open(&ctx, &name, "w+");
+        assert_eq!(
+            f_write(&ctx, &[file.clone(), string("hello "), int(42), string("\n")]).unwrap(),
+            vec![file.clone()]
+        );
+        assert_eq!(f_seek(&ctx, &[file.clone(), string("set")]).unwrap(), vec![int(0)]);
+        assert_eq!(f_read(&ctx, &[file.clone(), int(5)]).unwrap(), vec![string("hello")]);
+        assert_eq!(f_seek(&ctx, &[file.clone()]).unwrap(), vec![int(5)]);
+        f_write(&ctx, &[file.clone(), string("!")]).unwrap();
+        assert_eq!(f_seek(&ctx, &[file.clone(), string("end")]).unwrap(), vec![int(9)]);
+        assert!(f_seek(&ctx, &[file.clone(), string("nowhere")]).is_err());
+        f_close(&ctx, &[file]).unwrap();
+        let path = match name {
+            LuaValue::Str(ref s) => String::from_utf8(s.to_vec()).unwrap(),
+            _ => unreachable!(),
+        };
+        assert_eq!(fs::read(path).unwrap(), b"hello!42\n");
+    }
+
+    #[test]
+    fn test_lines() {
+        let ctx = LuaState::with_capabilities(Capabilities::all());
+        let name = temp_file("lines", "a\nb\n");
+        let iterator = lines(&ctx, &[name.clone()]).unwrap().remove(0);
+        let call = |f: &LuaValue| match *f {
+            LuaValue::Function(ref f) => f.call(&ctx, &[]).unwrap(),
+            _ => unreachable!(),
+        };
+        assert_eq!(call(&iterator), vec![string("a")]);
+        assert_eq!(call(&iterator), vec![string("b")]);
+        assert_eq!(call(&iterator), vec![LuaValue::Nil]);
+        // The file was closed when reaching its end.
+        assert!(match iterator {
+            LuaValue::Function(ref f) => f.call(&ctx, &[]).is_err(),
+            _ => false,
+        });
+
+        let file = open(&ctx, &name, "r");
+        let iterator = f_lines(&ctx, &[file.clone(), string("L")]).unwrap().remove(0);
+        assert_eq!(call(&iterator), vec![string("a\n")]);
+        assert_eq!(io_type(&ctx, &[file.clone()]).unwrap(), vec![string("file")]);
+        f_close(&ctx, &[file.clone()]).unwrap();
+        assert_eq!(io_type(&ctx, &[file]).unwrap(), vec![string("closed file")]);
+        assert_eq!(io_type(&ctx, &[int(1)]).unwrap(), vec![LuaValue::Nil]);
+    }
+
+    #[test]
+    fn test_open_errors() {
+        let ctx = LuaState::with_capabilities(Capabilities::all());
+        let name = temp_file("modes", "");
+        assert!(open_file(&ctx, &[name.clone(), string("rw")]).is_err());
+        assert!(open_file(&ctx, &[name.clone(), string("r+bb")]).is_ok());
+        let missing = string("/nonexistent/seo2/file");
+        let failure = open_file(&ctx, &[missing.clone()]).unwrap();
+        assert_eq!(failure[0], LuaValue::Nil);
+        assert_eq!(
+            failure[1],
+            string("/nonexistent/seo2/file: No such file or directory")
+        );
+        assert_eq!(failure[2], int(2));
+        assert!(lines(&ctx, &[missing]).is_err());
+
+        let reader = LuaState::with_capabilities(Capabilities {
+            read_files: true,
+            ..Capabilities::default()
+        });
+        assert!(open_file(&reader, &[name.clone(), string("r")]).unwrap()[0] != LuaValue::Nil);
+        let denied = open_file(&reader, &[name.clone(), string("a")]).unwrap();
+        assert_eq!(denied[0], LuaValue::Nil);
+        assert!(match denied[1] {
+            LuaValue::Str(ref s) => s.ends_with(b": file access not granted by the host"),
+            _ => false,
+        });
+        assert!(open_file(&reader, &[name.clone(), string("r+")]).unwrap()[0] == LuaValue::Nil);
+        assert!(output(&reader, &[name.clone()]).is_err());
+        assert!(lines(&LuaState::new(), &[name.clone()]).is_err());
+
+        let stdout = ctx.get_registry().get_string(&ctx.names().io_output);
+        assert!(LuaFile::from_value(&stdout).is_some());
+        assert_eq!(f_close(&ctx, &[stdout]).unwrap()[0], LuaValue::Nil);
+    }
+
+    #[test]
+    fn test_numerals() {
+        assert_eq!(parse_numeral(b"0x10"), Some(Number::Int(16)));
+        assert_eq!(parse_numeral(b"-0x1p4"), Some(Number::Float(-16.)));
+        assert_eq!(parse_numeral(b"0x.8"), Some(Number::Float(0.5)));
+        assert_eq!(parse_numeral(b"1e"), None);
+        assert_eq!(parse_numeral(b"-"), None);
+        assert_eq!(parse_numeral(b"9223372036854775808"),
+                   Some(Number::Float(9223372036854775808.)));
+    }
+}
@@ -0,0 +1,391 @@
+use std::f64;
+
+use types::{LuaState, LuaValue, Number};
+use expression::num_coercion;
+use Result;
+use LuaError::*;
+use super::args::{arg_error, check_integer, check_number, type_error};
+
+pub fn open(ctx: &LuaState) {
+    let library = super::register_library(
+        ctx,
+        "math",
+        &[
+            ("abs", abs),
+            ("acos", acos),
+            ("asin", asin),
+            ("atan", atan),
+            ("ceil", ceil),
+            ("cos", cos),
+            ("exp", exp),
+            ("floor", floor),
+            ("fmod", fmod),
+            ("log", log),
+            ("max", max),
+            ("min", min),
+            ("modf", modf),
+            ("random", random),
+            ("randomseed", randomseed),
+            ("sin", sin),
+            ("sqrt", sqrt),
+            ("tan", tan),
+            ("tointeger", tointeger),
+            ("type", math_type),
+            ("ult", ult),
+        ],
+    );
+    library.set_string(&ctx.intern(b"pi"), &float(f64::consts::PI));
+    library.set_string(&ctx.intern(b"huge"), &float(f64::INFINITY));
+    library.set_string(&ctx.intern(b"maxinteger"), &int(isize::max_value()));
+    library.set_string(&ctx.intern(b"mininteger"), &int(isize::min_value()));
+    seed_random(ctx, 0);
+}
+
+fn int(i: isize) -> LuaValue {
+    LuaValue::Number(Number::Int(i))
+}
+
+fn float(f: f64) -> LuaValue {
+    LuaValue::Number(Number::Float(f))
+}
+
+/// Converts a float with an integral value to an integer when it fits, as the functions
+/// rounding numbers return integers whenever they can.
+fn float_to_int(f: f64) -> LuaValue {
+    match Number::Float(f).to_exact_int() {
+        Some(i) => int(i),
+        None => float(f),
+    }
+}
+
+fn float_function(args: &[LuaValue], fname: &str, function: fn(f64) -> f64) -> Result<Vec<LuaValue>> {
+    Ok(vec![float(function(check_number(args, 1, fname)?.to_float()))])
+}
+
+pub fn abs(_ctx: &LuaState, args: &[LuaValue]) -> Result<Vec<LuaValue>> {
+    Ok(vec![match check_number(args, 1, "abs")? {
+        Number::Int(i) => int(i.wrapping_abs()),
+        Number::Float(f) => float(f.abs()),
+    }])
+}
+
+pub fn ceil(_ctx: &LuaState, args: &[LuaValue]) -> Result<Vec<LuaValue>> {
+    Ok(vec![match check_number(args, 1, "ceil")? {
+        Number::Int(i) => int(i),
+        Number::Float(f) => float_to_int(f.ceil()),
+    }])
+}
+
+pub fn floor(_ctx: &LuaState, args: &[LuaValue]) -> Result<Vec<LuaValue>> {
+    Ok(vec![match check_number(args, 1, "floor")? {
+        Number::Int(i) => int(i),
+        Number::Float(f) => float_to_int(f.floor()),
+    }])
+}
+
+pub fn sqrt(_ctx: &LuaState, args: &[LuaValue]) -> Result<Vec<LuaValue>> {
+    float_function(args, "sqrt", f64::sqrt)
+}
+
+pub fn exp(_ctx: &LuaState, args: &[LuaValue]) -> Result<Vec<LuaValue>> {
+    float_function(args, "exp", f64::exp)
+}
+
+pub fn sin(_ctx: &LuaState, args: &[LuaValue]) -> Result<Vec<LuaValue>> {
+    float_function(args, "sin", f64::sin)
+}
+
+pub fn cos(_ctx: &LuaState, args: &[LuaValue]) -> Result<Vec<LuaValue>> {
+    float_function(args, "cos", f64::cos)
+}
+
+pub fn tan(_ctx: &LuaState, args: &[LuaValue]) -> Result<Vec<LuaValue>> {
+    float_function(args, "tan", f64::tan)
+}
+
+pub fn asin(_ctx: &LuaState, args: &[LuaValue]) -> Result<Vec<LuaValue>> {
+    float_function(args, "asin", f64::asin)
+}
+
+pub fn acos(_ctx: &LuaState, args: &[LuaValue]) -> Result<Vec<LuaValue>> {
+    float_function(args, "acos", f64::acos)
+}
+
+pub fn atan(_ctx: &LuaState, args: &[LuaValue]) -> Result<Vec<LuaValue>> {
+    let y = check_number(args, 1, "atan")?.to_float();
+    let x = match args.get(1) {
+        None | Some(&LuaValue::Nil) => 1.,
+        Some(_) => check_number(args, 2, "atan")?.to_float(),
+    };
+    Ok(vec![float(y.atan2(x))])
+}
+
+pub fn log(_ctx: &LuaState, args: &[LuaValue]) -> Result<Vec<LuaValue>> {
+    let x = check_number(args, 1, "log")?.to_float();
+    let res = match args.get(1) {
+        None | Some(&LuaValue::Nil) => x.ln(),
+        Some(_) => {
+            let base = check_number(args, 2, "log")?.to_float();
+            if base == 2. {
+                x.log2()
+            } else if base == 10. {
+                x.log10()
+            } else {
+                x.ln() / base.ln()
+            }
+        }
+    };
+    Ok(vec![float(res)])
+}
+
+pub fn fmod(_ctx: &LuaState, args: &[LuaValue]) -> Result<Vec<LuaValue>> {
+    match (args.get(0), args.get(1)) {
+        (Some(&LuaValue::Number(Number::Int(m))), Some(&LuaValue::Number(Number::Int(d)))) => {
+            match d {
+                0 => Err(arg_error(2, "fmod", "zero")),
+                // Avoids the overflow of mininteger % -1
+                -1 => Ok(vec![int(0)]),
+                // Rust's remainder truncates towards zero, as C's fmod does
+                _ => Ok(vec![int(m % d)]),
+            }
+        }
+        _ => {
+            let m = check_number(args, 1, "fmod")?.to_float();
+            let d = check_number(args, 2, "fmod")?.to_float();
+            Ok(vec![float(m % d)])
+        }
+    }
+}
+
+pub fn modf(_ctx: &LuaState, args: &[LuaValue]) -> Result<Vec<LuaValue>> {
+    if let Some(&LuaValue::Number(Number::Int(i))) = args.get(0) {
+        return Ok(vec![int(i), float(0.)]);
+    }
+    let n = check_number(args, 1, "modf")?.to_float();
+    let integral = if n < 0. { n.ceil() } else { n.floor() };
+    // The test is needed for infinities
+    let fractional = if n == integral { 0. } else { n - integral };
+    Ok(vec![float(integral), float(fractional)])
+}
+
+pub fn tointeger(_ctx: &LuaState, args: &[LuaValue]) -> Result<Vec<LuaValue>> {
+    match args.get(0).cloned().map(num_coercion) {
+        Some(LuaValue::Number(n)) => Ok(vec![n.to_exact_int().map_or(LuaValue::Nil, int)]),
+        Some(_) => Ok(vec![LuaValue::Nil]),
+        None => Err(arg_error(1, "tointeger", "value expected")),
+    }
+}
+
+pub fn math_type(_ctx: &LuaState, args: &[LuaValue]) -> Result<Vec<LuaValue>> {
+    match args.get(0) {
+        Some(&LuaValue::Number(Number::Int(_))) => Ok(vec![LuaValue::Str("integer".into())]),
+        Some(&LuaValue::Number(Number::Float(_))) => Ok(vec![LuaValue::Str("float".into())]),
+        Some(_) => Ok(vec![LuaValue::Nil]),
+        None => Err(arg_error(1, "type", "value expected")),
+    }
+}
+
+pub fn ult(_ctx: &LuaState, args: &[LuaValue]) -> Result<Vec<LuaValue>> {
+    let a = check_integer(args, 1, "ult")?;
+    let b = check_integer(args, 2, "ult")?;
+    Ok(vec![LuaValue::Boolean((a as u64) < (b as u64))])
+}
+
+// Integers are compared as such, so that no precision is lost above 2^53.
+fn number_less_than(a: &Number, b: &Number) -> bool {
+    match (a, b) {
+        (&Number::Int(i), &Number::Int(j)) => i < j,
+        _ => a.to_float() < b.to_float(),
+    }
+}
+
+fn extremum(args: &[LuaValue], fname: &str, replace: fn(&Number, &Number) -> bool) -> Result<Vec<LuaValue>> {
+    let mut best = check_number(args, 1, fname)?;
+    for i in 2..args.len() + 1 {
+        let candidate = check_number(args, i, fname)?;
+        if replace(&candidate, &best) {
+            best = candidate;
+        }
+    }
+    Ok(vec![LuaValue::Number(best)])
+}
+
+pub fn max(_ctx: &LuaState, args: &[LuaValue]) -> Result<Vec<LuaValue>> {
+    extremum(args, "max", |candidate, best| number_less_than(best, candidate))
+}
+
+pub fn min(_ctx: &LuaState, args: &[LuaValue]) -> Result<Vec<LuaValue>> {
+    extremum(args, "min", |candidate, best| number_less_than(candidate, best))
+}
+
+// The pseudo-random generator is xoshiro256**, as in later versions of the reference
+// implementation: unlike C's rand(), it behaves the same on every platform, so that a
+// seeded run can be reproduced anywhere.
+
+fn next_random(ctx: &LuaState) -> u64 {
+    let mut s = ctx.get_random_state().get();
+    let result = s[1].wrapping_mul(5).rotate_left(7).wrapping_mul(9);
+    let t = s[1] << 17;
+    s[2] ^= s[0];
+    s[3] ^= s[1];
+    s[1] ^= s[2];
+    s[0] ^= s[3];
+    s[2] ^= t;
+    s[3] = s[3].rotate_left(45);
+    ctx.get_random_state().set(s);
+    return result;
+}
+
+fn seed_random(ctx: &LuaState, seed: isize) {
+    ctx.get_random_state().set([seed as u64, 0xff, 0, 0]);
+    // Discards the first values to avoid correlations with the seed
+    for _ in 0..16 {
+        next_random(ctx);
+    }
+}
+
+/// Projects a random integer into [0, n], without the bias of a modulo.
+fn project(ctx: &LuaState, random: u64, n: u64) -> u64 {
+    if n & n.wrapping_add(1) == 0 {
+        // n + 1 is a power of 2
+        return random & n;
+    }
+    // Smallest 2^b - 1 not smaller than n
+    let mut lim = n;
+    lim |= lim >> 1;
+    lim |= lim >> 2;
+    lim |= lim >> 4;
+    lim |= lim >> 8;
+    lim |= lim >> 16;
+    lim |= lim >> 32;
+    let mut random = random & lim;
+    while random > n {
+        random = next_random(ctx) & lim;
+    }
+    return random;
+}
+
+pub fn random(ctx: &LuaState, args: &[LuaValue]) -> Result<Vec<LuaValue>> {
+    let rv = next_random(ctx);
+    let (low, up) = match args.len() {
+        0 => {
+            // Keeps 53 bits, the precision of a float in [0, 1)
+            return Ok(vec![float((rv >> 11) as f64 * 0.5f64.powi(53))]);
+        }
+        1 => (1, check_integer(args, 1, "random")?),
+        2 => (
+            check_integer(args, 1, "random")?,
+            check_integer(args, 2, "random")?,
+        ),
+        _ => return Err(OtherError("wrong number of arguments".to_owned())),
+    };
+    if low > up {
+        return Err(arg_error(1, "random", "interval is empty"));
+    }
+    if !(low >= 0 || up <= isize::max_value() + low) {
+        return Err(arg_error(1, "random", "interval too large"));
+    }
+    let offset = project(ctx, rv, (up as u64).wrapping_sub(low as u64));
+    Ok(vec![int((offset as isize).wrapping_add(low))])
+}
+
+pub fn randomseed(ctx: &LuaState, args: &[LuaValue]) -> Result<Vec<LuaValue>> {
+    match args.get(0) {
+        Some(&LuaValue::Number(ref n)) => seed_random(ctx, n.to_int()),
+        other => return Err(type_error(1, "randomseed", "number", other)),
+    }
+    Ok(vec![])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rounding() {
+        let ctx = LuaState::new();
+        assert_eq!(floor(&ctx, &[float(3.7)]).unwrap(), vec![int(3)]);
+        assert_eq!(floor(&ctx, &[float(-3.5)]).unwrap(), vec![int(-4)]);
+        assert_eq!(ceil(&ctx, &[float(3.2)]).unwrap(), vec![int(4)]);
+        assert_eq!(ceil(&ctx, &[int(3)]).unwrap(), vec![int(3)]);
+        // Too large for an integer
+        assert_eq!(floor(&ctx, &[float(1e100)]).unwrap(), vec![float(1e100)]);
+        assert_eq!(
+            floor(&ctx, &[float(f64::INFINITY)]).unwrap(),
+            vec![float(f64::INFINITY)]
+        );
+    }
+
+    #[test]
+    fn test_integer_results() {
+        let ctx = LuaState::new();
+        assert_eq!(abs(&ctx, &[int(-3)]).unwrap(), vec![int(3)]);
+        assert_eq!(abs(&ctx, &[float(-3.)]).unwrap(), vec![float(3.)]);
+        assert_eq!(
+            abs(&ctx, &[int(isize::min_value())]).unwrap(),
+            vec![int(isize::min_value())]
+        );
+        assert_eq!(max(&ctx, &[int(1), float(2.5), int(3)]).unwrap(), vec![int(3)]);
+        assert_eq!(min(&ctx, &[int(1), float(0.5), int(3)]).unwrap(), vec![float(0.5)]);
+        assert_eq!(fmod(&ctx, &[int(-7), int(3)]).unwrap(), vec![int(-1)]);
+        assert_eq!(fmod(&ctx, &[float(-7.), int(3)]).unwrap(), vec![float(-1.)]);
+        assert_eq!(
+            fmod(&ctx, &[int(isize::min_value()), int(-1)]).unwrap(),
+            vec![int(0)]
+        );
+        let res = fmod(&ctx, &[int(1), int(0)]).unwrap_err();
+        assert_eq!(res, OtherError("bad argument #2 to 'fmod' (zero)".to_owned()));
+        assert_eq!(modf(&ctx, &[float(-3.5)]).unwrap(), vec![float(-3.), float(-0.5)]);
+        assert_eq!(modf(&ctx, &[int(4)]).unwrap(), vec![int(4), float(0.)]);
+    }
+
+    #[test]
+    fn test_conversions() {
+        let ctx = LuaState::new();
+        assert_eq!(tointeger(&ctx, &[float(3.)]).unwrap(), vec![int(3)]);
+        assert_eq!(tointeger(&ctx, &[float(3.5)]).unwrap(), vec![LuaValue::Nil]);
+        assert_eq!(
+            math_type(&ctx, &[int(1)]).unwrap(),
+            vec![LuaValue::Str("integer".into())]
+        );
+        assert_eq!(
+            math_type(&ctx, &[float(1.)]).unwrap(),
+            vec![LuaValue::Str("float".into())]
+        );
+        assert_eq!(math_type(&ctx, &[LuaValue::Boolean(true)]).unwrap(), vec![LuaValue::Nil]);
+        assert_eq!(ult(&ctx, &[int(1), int(-1)]).unwrap(), vec![LuaValue::Boolean(true)]);
+        assert_eq!(log(&ctx, &[int(8), int(2)]).unwrap(), vec![float(3.)]);
+        assert_eq!(log(&ctx, &[int(100), int(10)]).unwrap(), vec![float(2.)]);
+    }
+
+    #[test]
+    fn test_random() {
+        let ctx = LuaState::new();
+        randomseed(&ctx, &[int(42)]).unwrap();
+        let first: Vec<Vec<LuaValue>> = (0..10)
+            .map(|_| random(&ctx, &[int(1), int(6)]).unwrap())
+            .collect();
+        randomseed(&ctx, &[int(42)]).unwrap();
+        let second: Vec<Vec<LuaValue>> = (0..10)
+            .map(|_| random(&ctx, &[int(1), int(6)]).unwrap())
+            .collect();
+        assert_eq!(first, second);
+
+        for _ in 0..100 {
+            match random(&ctx, &[int(3)]).unwrap()[0] {
+                LuaValue::Number(Number::Int(i)) => assert!(i >= 1 && i <= 3),
+                ref other => panic!("unexpected random value {:?}", other),
+            }
+            match random(&ctx, &[]).unwrap()[0] {
+                LuaValue::Number(Number::Float(f)) => assert!(f >= 0. && f < 1.),
+                ref other => panic!("unexpected random value {:?}", other),
+            }
+        }
+
+        let res = random(&ctx, &[int(2), int(1)]).unwrap_err();
+        assert_eq!(
+            res,
+            OtherError("bad argument #1 to 'random' (interval is empty)".to_owned())
+        );
+    }
+}
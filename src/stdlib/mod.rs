@@ -0,0 +1,155 @@
+mod args;
+pub mod base;
+pub mod debug;
+pub mod io;
+pub mod math;
+pub mod os;
+pub mod package;
+pub mod string;
+pub mod table;
+pub mod utf8;
+
+use std::fs::File;
+use std::io as std_io;
+use std::io::Read;
+use std::path::PathBuf;
+
+use types::{LuaFunction, LuaState, LuaTable, LuaValue, NativeFunction, Number};
+use {LuaError, Result};
+
+/// Functions of the standard library that reach the filesystem, the environment or the
+/// process. None of them is registered unless the host enables it when building the
+/// `LuaState`. Likewise, `package.path` only starts from `LUA_PATH` with `path_env`.
+///
+/// `loadfile` and `dofile` need `read_files`. The `io` functions are always there for the standard files, but they
+/// only open a file for reading with `read_files` and for writing with `write_files`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Capabilities {
+    pub getenv: bool,
+    pub remove: bool,
+    pub rename: bool,
+    pub tmpname: bool,
+    pub exit: bool,
+    pub path_env: bool,
+    pub read_files: bool,
+    pub write_files: bool,
+}
+
+impl Capabilities {
+    pub fn all() -> Capabilities {
+        Capabilities {
+            getenv: true,
+            remove: true,
+            rename: true,
+            tmpname: true,
+            exit: true,
+            path_env: true,
+            read_files: true,
+            write_files: true,
+        }
+    }
+}
+
+pub fn open_libs(ctx: &LuaState, capabilities: &Capabilities) {
+    base::open(ctx, capabilities);
+    debug::open(ctx);
+    io::open(ctx);
+    math::open(ctx);
+    os::open(ctx, capabilities);
+    string::open(ctx);
+    table::open(ctx);
+    utf8::open(ctx);
+    package::open(ctx, capabilities);
+}
+
+/// Creates a table holding the given functions and stores it in the global table and in
+/// `package.loaded` under `name`.
+fn register_library(ctx: &LuaState, name: &str, functions: &[(&str, NativeFunction)]) -> LuaTable {
+    let library = new_library(ctx, functions);
+    ctx.get_global_table()
+        .set_string(&ctx.intern(name.as_bytes()), &LuaValue::Table(library.clone()));
+    loaded_table(ctx).set_string(&ctx.intern(name.as_bytes()), &LuaValue::Table(library.clone()));
+    return library;
+}
+
+/// The table of the modules already loaded, which `package.loaded` refers to.
+fn loaded_table(ctx: &LuaState) -> LuaTable {
+    let registry = ctx.get_registry();
+    match registry.get_string(&ctx.names().loaded) {
+        LuaValue::Table(t) => t,
+        _ => {
+            let loaded = LuaTable::new(ctx.get_ref_id());
+            registry.set_string(&ctx.names().loaded, &LuaValue::Table(loaded.clone()));
+            loaded
+        }
+    }
+}
+
+/// Creates a table holding the given functions.
+fn new_library(ctx: &LuaState, functions: &[(&str, NativeFunction)]) -> LuaTable {
+    let library = LuaTable::new(ctx.get_ref_id());
+    for &(fname, native) in functions {
+        let function = LuaFunction::new_native(ctx.get_ref_id(), fname, native);
+        library.set_string(&ctx.intern(fname.as_bytes()), &LuaValue::Function(function));
+    }
+    return library;
+}
+
+#[cfg(unix)]
+fn to_path(bytes: &[u8]) -> PathBuf {
+    use std::ffi::OsStr;
+    use std::os::unix::ffi::OsStrExt;
+    PathBuf::from(OsStr::from_bytes(bytes))
+}
+
+#[cfg(not(unix))]
+fn to_path(bytes: &[u8]) -> PathBuf {
+    PathBuf::from(String::from_utf8_lossy(bytes).into_owned())
+}
+
+/// Reads a file of source code, without its "#!" line.
+fn read_source(filename: &[u8]) -> Result<Vec<u8>> {
+    let mut source = Vec::new();
+    let read = File::open(to_path(filename)).and_then(|mut f| f.read_to_end(&mut source));
+    if let Err(err) = read {
+        let failure = io_failure(&err, Some(filename));
+        return Err(LuaError::OtherError(match failure[1] {
+            LuaValue::Str(ref msg) => format!("cannot open {}", String::from_utf8_lossy(msg)),
+            _ => "cannot open file".to_owned(),
+        }));
+    }
+    skip_shebang(&mut source);
+    Ok(source)
+}
+
+/// Drops the first line of a script if it starts with '#', as in executable scripts.
+fn skip_shebang(source: &mut Vec<u8>) {
+    if source.first() == Some(&b'#') {
+        // Keep the newline so that line numbers stay right.
+        let end = source.iter().position(|&c| c == b'\n').unwrap_or(source.len());
+        source.drain(..end);
+    }
+}
+
+/// Builds the `nil, message, code` triple the library functions return when the operating
+/// system reports an error.
+fn io_failure(err: &std_io::Error, filename: Option<&[u8]>) -> Vec<LuaValue> {
+    // Drop the " (os error N)" suffix, the code is returned on its own.
+    let description = err.to_string();
+    let description = match description.find(" (os error") {
+        Some(end) => description[..end].to_owned(),
+        None => description,
+    };
+    let mut message = Vec::new();
+    if let Some(name) = filename {
+        message.extend_from_slice(name);
+        message.extend_from_slice(b": ");
+    }
+    message.extend_from_slice(description.as_bytes());
+    let code = err.raw_os_error().unwrap_or(0) as isize;
+    vec![
+        LuaValue::Nil,
+        LuaValue::Str(message.into()),
+        LuaValue::Number(Number::Int(code)),
+    ]
+}
@@ -0,0 +1,531 @@
+// The `os` library. There is no binding to the C library, so the functions dealing with
+// dates treat the local time zone as UTC, and `os.clock` counts the wall-clock time since
+// the creation of the state rather than the processor time of the program.
+
+use std::env;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use types::{LuaFunction, LuaState, LuaTable, LuaValue, NativeFunction, Number};
+use expression::num_coercion;
+use Result;
+use LuaError::*;
+use super::Capabilities;
+use super::args::{arg_error, check_integer, check_number, check_string, check_table, opt_string};
+
+pub fn open(ctx: &LuaState, capabilities: &Capabilities) {
+    let library = super::register_library(
+        ctx,
+        "os",
+        &[
+            ("clock", clock),
+            ("date", date),
+            ("difftime", difftime),
+            ("time", time),
+        ],
+    );
+    let gated: [(bool, &str, NativeFunction); 5] = [
+        (capabilities.exit, "exit", exit),
+        (capabilities.getenv, "getenv", getenv),
+        (capabilities.remove, "remove", remove),
+        (capabilities.rename, "rename", rename),
+        (capabilities.tmpname, "tmpname", tmpname),
+    ];
+    for &(enabled, name, native) in gated.iter() {
+        if enabled {
+            let function = LuaFunction::new_native(ctx.get_ref_id(), name, native);
+            library.set_string(&ctx.intern(name.as_bytes()), &LuaValue::Function(function));
+        }
+    }
+}
+
+fn int(i: isize) -> LuaValue {
+    LuaValue::Number(Number::Int(i))
+}
+
+/// Seconds elapsed since the creation of the state. Unlike the C function, this is wall-clock
+/// time: it keeps counting while the program sleeps or waits for input, and it doesn't add
+/// up the time spent by other threads. Getting the processor time would need the C library.
+fn clock(ctx: &LuaState, _args: &[LuaValue]) -> Result<Vec<LuaValue>> {
+    let elapsed = ctx.get_start_time().elapsed();
+    let seconds = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 * 1e-9;
+    Ok(vec![LuaValue::Number(Number::Float(seconds))])
+}
+
+fn difftime(_ctx: &LuaState, args: &[LuaValue]) -> Result<Vec<LuaValue>> {
+    let t2 = check_time(args, 1, "difftime")?;
+    let t1 = match args.get(1) {
+        None | Some(&LuaValue::Nil) => 0,
+        Some(_) => check_time(args, 2, "difftime")?,
+    };
+    Ok(vec![LuaValue::Number(Number::Float((t2 - t1) as f64))])
+}
+
+fn time(ctx: &LuaState, args: &[LuaValue]) -> Result<Vec<LuaValue>> {
+    let table = match args.get(0) {
+        None | Some(&LuaValue::Nil) => return Ok(vec![int(now() as isize)]),
+        Some(_) => check_table(args, 1, "time")?,
+    };
+    let year = get_field(ctx, &table, "year", None, 1900)?;
+    let month = get_field(ctx, &table, "month", None, 1)?;
+    let day = get_field(ctx, &table, "day", None, 0)?;
+    let hour = get_field(ctx, &table, "hour", Some(12), 0)?;
+    let min = get_field(ctx, &table, "min", Some(0), 0)?;
+    let sec = get_field(ctx, &table, "sec", Some(0), 0)?;
+
+    // Out of range fields carry over to the next larger ones, like mktime does.
+    let months = (year + 1900) * 12 + month;
+    let first = days_from_civil(months.div_euclid(12), months.rem_euclid(12) as u32 + 1, 1);
+    let days = first + day - 1;
+    let t = days * 86400 + hour * 3600 + min * 60 + sec;
+    if t < isize::min_value() as i64 || t > isize::max_value() as i64 {
+        return Err(OtherError(
+            "time result cannot be represented in this installation".to_owned(),
+        ));
+    }
+    set_all_fields(ctx, &table, &DateTime::from_timestamp(t));
+    Ok(vec![int(t as isize)])
+}
+
+fn date(ctx: &LuaState, args: &[LuaValue]) -> Result<Vec<LuaValue>> {
+    let format = opt_string(args, 1, "date", b"%c")?;
+    let t = match args.get(1) {
+        None | Some(&LuaValue::Nil) => now(),
+        Some(_) => check_time(args, 2, "date")?,
+    };
+    // A leading '!' asks for UTC, which is also what the local time is here.
+    let format = if format.first() == Some(&b'!') {
+        &format[1..]
+    } else {
+        &format[..]
+    };
+    let date = DateTime::from_timestamp(t);
+    if format == b"*t" {
+        let table = LuaTable::new(ctx.get_ref_id());
+        set_all_fields(ctx, &table, &date);
+        return Ok(vec![LuaValue::Table(table)]);
+    }
+    Ok(vec![LuaValue::Str(strftime(format, &date)?.into())])
+}
+
+fn getenv(_ctx: &LuaState, args: &[LuaValue]) -> Result<Vec<LuaValue>> {
+    let name = check_string(args, 1, "getenv")?;
+    let value = match env::var_os(super::to_path(&name)) {
+        Some(value) => LuaValue::Str(value.to_string_lossy().into_owned().into()),
+        None => LuaValue::Nil,
+    };
+    Ok(vec![value])
+}
+
+fn remove(_ctx: &LuaState, args: &[LuaValue]) -> Result<Vec<LuaValue>> {
+    let filename = check_string(args, 1, "remove")?;
+    let path = super::to_path(&filename);
+    // Like the C function, this removes empty directories as well.
+    let result = match fs::symlink_metadata(&path) {
+        Ok(ref metadata) if metadata.is_dir() => fs::remove_dir(&path),
+        _ => fs::remove_file(&path),
+    };
+    Ok(match result {
+        Ok(()) => vec![LuaValue::Boolean(true)],
+        Err(err) => super::io_failure(&err, Some(&filename)),
+    })
+}
+
+fn rename(_ctx: &LuaState, args: &[LuaValue]) -> Result<Vec<LuaValue>> {
+    let from = check_string(args, 1, "rename")?;
+    let to = check_string(args, 2, "rename")?;
+    Ok(match fs::rename(super::to_path(&from), super::to_path(&to)) {
+        Ok(()) => vec![LuaValue::Boolean(true)],
+        Err(err) => super::io_failure(&err, Some(&from)),
+    })
+}
+
+/// Creates an empty file with a fresh name in the temporary directory and returns its
+/// name, so that no other process can claim it in between.
+fn tmpname(_ctx: &LuaState, _args: &[LuaValue]) -> Result<Vec<LuaValue>> {
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+    for _ in 0..100 {
+        let nanos = match SystemTime::now().duration_since(UNIX_EPOCH) {
+            Ok(d) => d.subsec_nanos(),
+            Err(_) => 0,
+        };
+        let unique = COUNTER.fetch_add(1, Ordering::Relaxed);
+        let path = env::temp_dir().join(format!(
+            "lua_{:x}_{:x}{:x}",
+            process::id(),
+            unique,
+            nanos
+        ));
+        match OpenOptions::new().write(true).create_new(true).open(&path) {
+            Ok(_) => {
+                let name = path.to_string_lossy().into_owned().into_bytes();
+                return Ok(vec![LuaValue::Str(name.into())]);
+            }
+            Err(ref err) if err.kind() == io::ErrorKind::AlreadyExists => continue,
+            Err(_) => break,
+        }
+    }
+    Err(OtherError("unable to generate a unique filename".to_owned()))
+}
+
+fn exit(_ctx: &LuaState, args: &[LuaValue]) -> Result<Vec<LuaValue>> {
+    let code = match args.get(0) {
+        None | Some(&LuaValue::Nil) => 0,
+        Some(&LuaValue::Boolean(success)) => if success { 0 } else { 1 },
+        Some(_) => check_integer(args, 1, "exit")?,
+    };
+    let _ = io::stdout().flush();
+    process::exit(code as i32);
+}
+
+/// Current time in seconds since the epoch.
+fn now() -> i64 {
+    match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(d) => d.as_secs() as i64,
+        Err(e) => -(e.duration().as_secs() as i64),
+    }
+}
+
+fn check_time(args: &[LuaValue], n: usize, fname: &str) -> Result<i64> {
+    check_number(args, n, fname)?
+        .to_exact_int()
+        .map(|t| t as i64)
+        .ok_or_else(|| arg_error(n, fname, "number has no integer representation"))
+}
+
+/// Reads a field of a date table, shifted by `delta` like the fields of a C `struct tm`.
+fn get_field(ctx: &LuaState, table: &LuaTable, key: &str, default: Option<i64>, delta: i64) -> Result<i64> {
+    let value = num_coercion(table.get_string(&ctx.intern(key.as_bytes())));
+    match value {
+        LuaValue::Number(ref n) => match n.to_exact_int() {
+            Some(res) => {
+                let res = res as i64;
+                let in_bounds = if res >= 0 {
+                    res - delta <= i32::max_value() as i64
+                } else {
+                    i32::min_value() as i64 + delta <= res
+                };
+                if !in_bounds {
+                    return Err(OtherError(format!("field '{}' is out-of-bound", key)));
+                }
+                Ok(res - delta)
+            }
+            None => Err(OtherError(format!("field '{}' is not an integer", key))),
+        },
+        LuaValue::Nil => {
+            default.ok_or_else(|| OtherError(format!("field '{}' missing in date table", key)))
+        }
+        _ => Err(OtherError(format!("field '{}' is not an integer", key))),
+    }
+}
+
+fn set_all_fields(ctx: &LuaState, table: &LuaTable, date: &DateTime) {
+    let fields = [
+        ("year", date.year),
+        ("month", date.month as i64),
+        ("day", date.day as i64),
+        ("hour", date.hour as i64),
+        ("min", date.min as i64),
+        ("sec", date.sec as i64),
+        ("yday", date.yday as i64 + 1),
+        ("wday", date.wday as i64 + 1),
+    ];
+    for &(key, value) in fields.iter() {
+        table.set_string(&ctx.intern(key.as_bytes()), &int(value as isize));
+    }
+    table.set_string(&ctx.intern(b"isdst"), &LuaValue::Boolean(false));
+}
+
+/// Number of days between the epoch and the given date of the proleptic Gregorian
+/// calendar.
+fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let yoe = year - era * 400;
+    let mp = if month > 2 { month - 3 } else { month + 9 } as i64;
+    let doy = (153 * mp + 2) / 5 + day as i64 - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
+
+/// A point in time broken down in the fields of the calendar.
+#[derive(Debug, PartialEq)]
+struct DateTime {
+    year: i64,
+    /// 1 to 12
+    month: u32,
+    /// 1 to 31
+    day: u32,
+    hour: u32,
+    min: u32,
+    sec: u32,
+    /// Days since Sunday
+    wday: u32,
+    /// Days since January 1st
+    yday: u32,
+}
+
+impl DateTime {
+    fn from_timestamp(t: i64) -> DateTime {
+        let days = t.div_euclid(86400);
+        let secs = t.rem_euclid(86400) as u32;
+
+        let z = days + 719468;
+        let era = z.div_euclid(146097);
+        let doe = z - era * 146097;
+        let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+        let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+        let mp = (5 * doy + 2) / 153;
+        let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+        let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+        let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+        DateTime {
+            year,
+            month,
+            day,
+            hour: secs / 3600,
+            min: secs / 60 % 60,
+            sec: secs % 60,
+            // The epoch was a Thursday.
+            wday: (days + 4).rem_euclid(7) as u32,
+            yday: (days - days_from_civil(year, 1, 1)) as u32,
+        }
+    }
+
+    /// Year and number of the week as defined by ISO 8601: weeks start on Monday, and
+    /// the first week of a year is the one holding its first Thursday.
+    fn iso_week(&self) -> (i64, i64) {
+        let monday_based = (self.wday as i64 + 6) % 7;
+        let week = (self.yday as i64 - monday_based + 10) / 7;
+        if week < 1 {
+            (self.year - 1, iso_weeks_in_year(self.year - 1))
+        } else if week > iso_weeks_in_year(self.year) {
+            (self.year + 1, 1)
+        } else {
+            (self.year, week)
+        }
+    }
+}
+
+fn iso_weeks_in_year(year: i64) -> i64 {
+    let p = |y: i64| (y + y.div_euclid(4) - y.div_euclid(100) + y.div_euclid(400)).rem_euclid(7);
+    if p(year) == 4 || p(year - 1) == 3 {
+        53
+    } else {
+        52
+    }
+}
+
+const DAYS: [&str; 7] = [
+    "Sunday", "Monday", "Tuesday", "Wednesday", "Thursday", "Friday", "Saturday"
+];
+
+const MONTHS: [&str; 12] = [
+    "January", "February", "March", "April", "May", "June", "July", "August", "September",
+    "October", "November", "December",
+];
+
+/// Formats a date like the C function of the same name in the "C" locale.
+fn strftime(format: &[u8], date: &DateTime) -> Result<Vec<u8>> {
+    let mut out = Vec::new();
+    let mut i = 0;
+    while i < format.len() {
+        if format[i] != b'%' {
+            out.push(format[i]);
+            i += 1;
+            continue;
+        }
+        let start = i + 1;
+        // The E and O modifiers select alternative representations, which are the plain
+        // ones in the "C" locale.
+        let modifier = match format.get(start) {
+            Some(&b'E') | Some(&b'O') => Some(format[start]),
+            _ => None,
+        };
+        let end = if modifier.is_some() { start + 1 } else { start };
+        let conversion = format.get(end).cloned();
+        let valid = match (modifier, conversion) {
+            (None, Some(c)) => b"aAbBcCdDeFgGhHIjmMnprRStTuUVwWxXyYzZ%".contains(&c),
+            (Some(b'E'), Some(c)) => b"cCxXyY".contains(&c),
+            (Some(_), Some(c)) => b"deHImMSuUVwWy".contains(&c),
+            _ => false,
+        };
+        if !valid {
+            let last = ::std::cmp::min(end + 1, format.len());
+            return Err(arg_error(
+                1,
+                "date",
+                &format!(
+                    "invalid conversion specifier '%{}'",
+                    String::from_utf8_lossy(&format[start..last])
+                ),
+            ));
+        }
+        out.extend(conversion_string(conversion.unwrap(), date)?.into_bytes());
+        i = end + 1;
+    }
+    Ok(out)
+}
+
+fn conversion_string(conversion: u8, date: &DateTime) -> Result<String> {
+    let composite = |format: &[u8]| -> Result<String> {
+        Ok(String::from_utf8_lossy(&strftime(format, date)?).into_owned())
+    };
+    let hour12 = if date.hour % 12 == 0 { 12 } else { date.hour % 12 };
+    Ok(match conversion {
+        b'a' => DAYS[date.wday as usize][..3].to_owned(),
+        b'A' => DAYS[date.wday as usize].to_owned(),
+        b'b' | b'h' => MONTHS[date.month as usize - 1][..3].to_owned(),
+        b'B' => MONTHS[date.month as usize - 1].to_owned(),
+        b'c' => composite(b"%a %b %e %H:%M:%S %Y")?,
+        b'C' => format!("{:02}", date.year.div_euclid(100)),
+        b'd' => format!("{:02}", date.day),
+        b'D' | b'x' => composite(b"%m/%d/%y")?,
+        b'e' => format!("{:2}", date.day),
+        b'F' => composite(b"%Y-%m-%d")?,
+        b'g' => format!("{:02}", date.iso_week().0.rem_euclid(100)),
+        b'G' => date.iso_week().0.to_string(),
+        b'H' => format!("{:02}", date.hour),
+        b'I' => format!("{:02}", hour12),
+        b'j' => format!("{:03}", date.yday + 1),
+        b'm' => format!("{:02}", date.month),
+        b'M' => format!("{:02}", date.min),
+        b'n' => "\n".to_owned(),
+        b'p' => if date.hour < 12 { "AM" } else { "PM" }.to_owned(),
+        b'r' => composite(b"%I:%M:%S %p")?,
+        b'R' => composite(b"%H:%M")?,
+        b'S' => format!("{:02}", date.sec),
+        b't' => "\t".to_owned(),
+        b'T' | b'X' => composite(b"%H:%M:%S")?,
+        b'u' => (if date.wday == 0 { 7 } else { date.wday }).to_string(),
+        b'U' => format!("{:02}", (date.yday + 7 - date.wday) / 7),
+        b'V' => format!("{:02}", date.iso_week().1),
+        b'w' => date.wday.to_string(),
+        b'W' => format!("{:02}", (date.yday + 7 - (date.wday + 6) % 7) / 7),
+        b'y' => format!("{:02}", date.year.rem_euclid(100)),
+        b'Y' => date.year.to_string(),
+        b'z' => "+0000".to_owned(),
+        b'Z' => "UTC".to_owned(),
+        _ => "%".to_owned(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn string(s: &str) -> LuaValue {
+        LuaValue::Str(s.into())
+    }
+
+    fn format(ctx: &LuaState, fmt: &str, t: isize) -> String {
+        match date(ctx, &[string(fmt), int(t)]).unwrap()[0] {
+            LuaValue::Str(ref s) => String::from_utf8(s.to_vec()).unwrap(),
+            ref other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_date_format() {
+        let ctx = LuaState::new();
+        assert_eq!(format(&ctx, "!%Y-%m-%d %H:%M:%S", 0), "1970-01-01 00:00:00");
+        assert_eq!(format(&ctx, "%c", 0), "Thu Jan  1 00:00:00 1970");
+        assert_eq!(format(&ctx, "%x %X %D", -1), "12/31/69 23:59:59 12/31/69");
+        assert_eq!(
+            format(&ctx, "%A %B %j %U %W %V %G %u %w %I %p", 1700000000),
+            "Tuesday November 318 46 46 46 2023 2 2 10 PM"
+        );
+        // 2021-01-01 belongs to the last ISO week of 2020.
+        assert_eq!(format(&ctx, "%G-W%V-%u %Ey %Od", 1609459200), "2020-W53-5 21 01");
+        assert!(date(&ctx, &[string("%Ez"), int(0)]).is_err());
+        assert!(date(&ctx, &[string("%"), int(0)]).is_err());
+        assert!(date(&ctx, &[string("%Q"), int(0)]).is_err());
+    }
+
+    #[test]
+    fn test_date_table() {
+        let ctx = LuaState::new();
+        let table = match date(&ctx, &[string("*t"), int(951782400)]).unwrap()[0] {
+            LuaValue::Table(ref t) => t.clone(),
+            ref other => panic!("{:?}", other),
+        };
+        // 2000-02-29, a Tuesday
+        assert_eq!(table.get_string(&ctx.intern(b"year")), int(2000));
+        assert_eq!(table.get_string(&ctx.intern(b"month")), int(2));
+        assert_eq!(table.get_string(&ctx.intern(b"day")), int(29));
+        assert_eq!(table.get_string(&ctx.intern(b"wday")), int(3));
+        assert_eq!(table.get_string(&ctx.intern(b"yday")), int(60));
+        assert_eq!(table.get_string(&ctx.intern(b"isdst")), LuaValue::Boolean(false));
+        assert_eq!(time(&ctx, &[LuaValue::Table(table)]).unwrap(), vec![int(951782400)]);
+        assert!(match date(&ctx, &[string("!*t"), int(0)]).unwrap()[0] {
+            LuaValue::Table(ref t) => t.get_string(&ctx.intern(b"year")) == int(1970),
+            _ => false,
+        });
+        assert_eq!(format(&ctx, "*tx", 0), "*tx");
+    }
+
+    #[test]
+    fn test_time_normalizes() {
+        let ctx = LuaState::new();
+        let table = LuaTable::new(ctx.get_ref_id());
+        table.set_string(&ctx.intern(b"year"), &int(2023));
+        table.set_string(&ctx.intern(b"month"), &int(14));
+        table.set_string(&ctx.intern(b"day"), &int(0));
+        table.set_string(&ctx.intern(b"hour"), &int(0));
+        let result = time(&ctx, &[LuaValue::Table(table.clone())]).unwrap();
+        assert_eq!(result, vec![int(1706659200)]);
+        assert_eq!(table.get_string(&ctx.intern(b"year")), int(2024));
+        assert_eq!(table.get_string(&ctx.intern(b"month")), int(1));
+        assert_eq!(table.get_string(&ctx.intern(b"day")), int(31));
+
+        table.set_string(&ctx.intern(b"day"), &LuaValue::Nil);
+        assert!(time(&ctx, &[LuaValue::Table(table.clone())]).is_err());
+        table.set_string(&ctx.intern(b"day"), &LuaValue::Number(Number::Float(1.5)));
+        assert!(time(&ctx, &[LuaValue::Table(table)]).is_err());
+    }
+
+    #[test]
+    fn test_capabilities() {
+        let os = |ctx: &LuaState| match ctx.get_global_table().get_string(&ctx.intern(b"os")) {
+            LuaValue::Table(t) => t,
+            other => panic!("{:?}", other),
+        };
+        let ctx = LuaState::new();
+        assert_eq!(os(&ctx).get_string(&ctx.intern(b"remove")), LuaValue::Nil);
+        assert_eq!(os(&ctx).get_string(&ctx.intern(b"exit")), LuaValue::Nil);
+        assert!(os(&ctx).get_string(&ctx.intern(b"time")) != LuaValue::Nil);
+
+        let ctx = LuaState::with_capabilities(Capabilities {
+            remove: true,
+            ..Capabilities::default()
+        });
+        assert!(os(&ctx).get_string(&ctx.intern(b"remove")) != LuaValue::Nil);
+        assert_eq!(os(&ctx).get_string(&ctx.intern(b"rename")), LuaValue::Nil);
+    }
+
+    #[test]
+    fn test_files() {
+        let ctx = LuaState::new();
+        let name = tmpname(&ctx, &[]).unwrap().remove(0);
+        let renamed = match name {
+            LuaValue::Str(ref s) => {
+                let mut s = s.to_vec();
+                s.extend_from_slice(b".renamed");
+                LuaValue::Str(s.into())
+            }
+            ref other => panic!("{:?}", other),
+        };
+        assert_eq!(
+            rename(&ctx, &[name.clone(), renamed.clone()]).unwrap(),
+            vec![LuaValue::Boolean(true)]
+        );
+        let failure = remove(&ctx, &[name]).unwrap();
+        assert_eq!(failure[0], LuaValue::Nil);
+        assert_eq!(failure.len(), 3);
+        assert_eq!(remove(&ctx, &[renamed]).unwrap(), vec![LuaValue::Boolean(true)]);
+    }
+}
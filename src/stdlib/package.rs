@@ -0,0 +1,322 @@
+// The `package` library and `require`. Modules are looked for by the functions of
+// `package.searchers`: first in `package.preload`, then along `package.path`, then by the
+// searchers the host added with `LuaState::add_searcher`.
+
+use std::env;
+use std::fs::File;
+
+use types::{LuaFunction, LuaState, LuaTable, LuaValue, Number};
+use chunk;
+use Result;
+use LuaError::*;
+use super::args::{check_string, opt_string};
+use super::Capabilities;
+
+const DEFAULT_PATH: &str = "/usr/local/share/lua/5.3/?.lua;/usr/local/share/lua/5.3/?/init.lua;\
+                            /usr/local/lib/lua/5.3/?.lua;/usr/local/lib/lua/5.3/?/init.lua;\
+                            ./?.lua;./?/init.lua";
+
+pub fn open(ctx: &LuaState, capabilities: &Capabilities) {
+    let package = super::register_library(ctx, "package", &[("searchpath", searchpath)]);
+    let loaded = super::loaded_table(ctx);
+    let preload = LuaTable::new(ctx.get_ref_id());
+    ctx.get_registry()
+        .set_string(&ctx.names().preload, &LuaValue::Table(preload.clone()));
+    package.set_string(&ctx.intern(b"loaded"), &LuaValue::Table(loaded));
+    package.set_string(&ctx.intern(b"preload"), &LuaValue::Table(preload));
+    let path = if capabilities.path_env {
+        initial_path()
+    } else {
+        DEFAULT_PATH.to_owned()
+    };
+    package.set_string(&ctx.intern(b"path"), &LuaValue::Str(path.into()));
+    package.set_string(&ctx.intern(b"cpath"), &LuaValue::Str("".into()));
+    package.set_string(&ctx.intern(b"config"), &LuaValue::Str("/\n;\n?\n!\n-\n".into()));
+
+    let searchers = LuaTable::new(ctx.get_ref_id());
+    let preload_searcher = LuaFunction::new_native(ctx.get_ref_id(), "searcher", search_preload);
+    searchers.set(&int(1), &LuaValue::Function(preload_searcher)).unwrap();
+    let lua_package = package.clone();
+    let lua_searcher = move |ctx: &LuaState, args: &[LuaValue]| search_lua(ctx, args, &lua_package);
+    let lua_searcher = LuaFunction::new_closure(ctx.get_ref_id(), "searcher", lua_searcher);
+    searchers.set(&int(2), &LuaValue::Function(lua_searcher)).unwrap();
+    package.set_string(&ctx.intern(b"searchers"), &LuaValue::Table(searchers));
+
+    let require_package = package.clone();
+    let require = move |ctx: &LuaState, args: &[LuaValue]| require(ctx, args, &require_package);
+    let require = LuaFunction::new_closure(ctx.get_ref_id(), "require", require);
+    ctx.get_global_table()
+        .set_string(&ctx.intern(b"require"), &LuaValue::Function(require));
+}
+
+fn int(i: isize) -> LuaValue {
+    LuaValue::Number(Number::Int(i))
+}
+
+/// The path from `LUA_PATH_5_3` or `LUA_PATH`, where ";;" stands for the default path.
+fn initial_path() -> String {
+    let path = env::var("LUA_PATH_5_3").or_else(|_| env::var("LUA_PATH"));
+    match path {
+        Ok(path) => path.replace(";;", &format!(";{};", DEFAULT_PATH)),
+        Err(_) => DEFAULT_PATH.to_owned(),
+    }
+}
+
+/// Appends a searcher written in Rust to `package.searchers`. It receives the name of a
+/// module and returns its source code, or `None` when it doesn't provide that module.
+pub fn add_searcher<F>(ctx: &LuaState, lookup: F) -> Result<()>
+where
+    F: Fn(&str) -> Option<Vec<u8>> + 'static,
+{
+    let searcher = move |ctx: &LuaState, args: &[LuaValue]| -> Result<Vec<LuaValue>> {
+        let name = String::from_utf8_lossy(&check_string(args, 1, "searcher")?).into_owned();
+        match lookup(&name) {
+            Some(source) => {
+                let chunkname = format!("={}", name);
+                match chunk::load(ctx, source, &chunkname, global_env(ctx)) {
+                    Ok(loader) => Ok(vec![LuaValue::Function(loader)]),
+                    Err(err) => Err(OtherError(format!(
+                        "error loading module '{}' from host searcher:\n\t{}",
+                        name, err
+                    ))),
+                }
+            }
+            None => Ok(vec![
+                LuaValue::Str(format!("\n\tno module '{}' in host searcher", name).into()),
+            ]),
+        }
+    };
+    let searcher = LuaFunction::new_closure(ctx.get_ref_id(), "searcher", searcher);
+    let package = match ctx.get_global_table().get_string(&ctx.intern(b"package")) {
+        LuaValue::Table(t) => t,
+        _ => return Err(OtherError("'package' must be a table".to_owned())),
+    };
+    match package.get_string(&ctx.intern(b"searchers")) {
+        LuaValue::Table(searchers) => {
+            let position = searchers.sequence_border() as isize + 1;
+            searchers.set(&int(position), &LuaValue::Function(searcher))
+        }
+        _ => Err(OtherError("'package.searchers' must be a table".to_owned())),
+    }
+}
+
+fn global_env(ctx: &LuaState) -> LuaValue {
+    LuaValue::Table(ctx.get_global_table().clone())
+}
+
+fn require(ctx: &LuaState, args: &[LuaValue], package: &LuaTable) -> Result<Vec<LuaValue>> {
+    let name = check_string(args, 1, "require")?;
+    let key = LuaValue::Str(name.clone().into());
+    let loaded = super::loaded_table(ctx);
+    match loaded.get(&key) {
+        LuaValue::Nil | LuaValue::Boolean(false) => {}
+        module => return Ok(vec![module]),
+    }
+
+    let (loader, extra) = find_loader(ctx, &name, package)?;
+    let module = ::expression::first_value(loader.call(ctx, &[key.clone(), extra])?);
+    if module != LuaValue::Nil {
+        loaded.set(&key, &module)?;
+    }
+    if loaded.get(&key) == LuaValue::Nil {
+        loaded.set(&key, &LuaValue::Boolean(true))?;
+    }
+    Ok(vec![loaded.get(&key)])
+}
+
+/// Asks each searcher in turn for a loader, collecting the reasons of their failures.
+fn find_loader(ctx: &LuaState, name: &[u8], package: &LuaTable) -> Result<(LuaFunction, LuaValue)> {
+    let searchers = match package.get_string(&ctx.intern(b"searchers")) {
+        LuaValue::Table(t) => t,
+        _ => return Err(OtherError("'package.searchers' must be a table".to_owned())),
+    };
+    let mut message = Vec::new();
+    for i in 1.. {
+        let searcher = match searchers.get(&int(i)) {
+            LuaValue::Nil => break,
+            searcher => searcher,
+        };
+        let mut results = ::expression::call_function(&searcher, &[LuaValue::Str(name.to_vec().into())], ctx)?;
+        results.resize(2, LuaValue::Nil);
+        match results[0] {
+            LuaValue::Function(ref loader) => return Ok((loader.clone(), results[1].clone())),
+            LuaValue::Str(ref s) => message.extend_from_slice(s),
+            LuaValue::Number(ref n) => message.extend(n.to_string().into_bytes()),
+            _ => {}
+        }
+    }
+    Err(OtherError(format!(
+        "module '{}' not found:{}",
+        String::from_utf8_lossy(name),
+        String::from_utf8_lossy(&message)
+    )))
+}
+
+fn search_preload(ctx: &LuaState, args: &[LuaValue]) -> Result<Vec<LuaValue>> {
+    let name = check_string(args, 1, "searcher")?;
+    let preload = match ctx.get_registry().get_string(&ctx.names().preload) {
+        LuaValue::Table(t) => t,
+        _ => return Err(OtherError("'package.preload' must be a table".to_owned())),
+    };
+    match preload.get(&LuaValue::Str(name.clone().into())) {
+        LuaValue::Nil => {
+            let msg = format!("\n\tno field package.preload['{}']", String::from_utf8_lossy(&name));
+            Ok(vec![LuaValue::Str(msg.into())])
+        }
+        loader => Ok(vec![loader]),
+    }
+}
+
+fn search_lua(ctx: &LuaState, args: &[LuaValue], package: &LuaTable) -> Result<Vec<LuaValue>> {
+    let name = check_string(args, 1, "searcher")?;
+    let path = match package.get_string(&ctx.intern(b"path")) {
+        LuaValue::Str(s) => s,
+        _ => return Err(OtherError("'package.path' must be a string".to_owned())),
+    };
+    let filename = match search_path(&name, &path, b".", b"/") {
+        Ok(filename) => filename,
+        Err(message) => return Ok(vec![LuaValue::Str(message.into())]),
+    };
+    let loaded = super::read_source(&filename).and_then(|source| {
+        let chunkname = format!("@{}", String::from_utf8_lossy(&filename));
+        chunk::load(ctx, source, &chunkname, global_env(ctx))
+    });
+    match loaded {
+        Ok(loader) => Ok(vec![LuaValue::Function(loader), LuaValue::Str(filename.into())]),
+        Err(err) => Err(OtherError(format!(
+            "error loading module '{}' from file '{}':\n\t{}",
+            String::from_utf8_lossy(&name),
+            String::from_utf8_lossy(&filename),
+            err
+        ))),
+    }
+}
+
+/// Replaces each '?' of the templates of `path` by `name`, returning the first file that
+/// can be read, or the list of the files tried.
+fn search_path(name: &[u8], path: &[u8], sep: &[u8], rep: &[u8]) -> ::std::result::Result<Vec<u8>, Vec<u8>> {
+    let name = if sep.is_empty() {
+        name.to_vec()
+    } else {
+        replace(name, sep, rep)
+    };
+    let mut message = Vec::new();
+    for template in path.split(|&c| c == b';').filter(|t| !t.is_empty()) {
+        let filename = replace(template, b"?", &name);
+        if File::open(super::to_path(&filename)).is_ok() {
+            return Ok(filename);
+        }
+        message.extend_from_slice(b"\n\tno file '");
+        message.extend_from_slice(&filename);
+        message.push(b'\'');
+    }
+    Err(message)
+}
+
+fn replace(s: &[u8], pattern: &[u8], replacement: &[u8]) -> Vec<u8> {
+    let mut result = Vec::with_capacity(s.len());
+    let mut i = 0;
+    while i < s.len() {
+        if s[i..].starts_with(pattern) {
+            result.extend_from_slice(replacement);
+            i += pattern.len();
+        } else {
+            result.push(s[i]);
+            i += 1;
+        }
+    }
+    result
+}
+
+fn searchpath(_ctx: &LuaState, args: &[LuaValue]) -> Result<Vec<LuaValue>> {
+    let name = check_string(args, 1, "searchpath")?;
+    let path = check_string(args, 2, "searchpath")?;
+    let sep = opt_string(args, 3, "searchpath", b".")?;
+    let rep = opt_string(args, 4, "searchpath", b"/")?;
+    Ok(match search_path(&name, &path, &sep, &rep) {
+        Ok(filename) => vec![LuaValue::Str(filename.into())],
+        Err(message) => vec![LuaValue::Nil, LuaValue::Str(message.into())],
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    fn string(s: &str) -> LuaValue {
+        LuaValue::Str(s.into())
+    }
+
+    fn package(ctx: &LuaState) -> LuaTable {
+        match ctx.get_global_table().get_string(&ctx.intern(b"package")) {
+            LuaValue::Table(t) => t,
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_searchpath() {
+        let ctx = LuaState::new();
+        let dir = env::temp_dir().join(format!("seo2_package_{}", ::std::process::id()));
+        fs::create_dir_all(dir.join("a")).unwrap();
+        fs::write(dir.join("a").join("b.lua"), "return 1").unwrap();
+        let dir = dir.to_string_lossy().into_owned();
+        let path = string(&format!("{0}/?.x;;{0}/?.lua", dir));
+        assert_eq!(
+            searchpath(&ctx, &[string("a.b"), path.clone()]).unwrap(),
+            vec![string(&format!("{}/a/b.lua", dir))]
+        );
+        assert_eq!(
+            searchpath(&ctx, &[string("a.c"), path]).unwrap(),
+            vec![
+                LuaValue::Nil,
+                string(&format!("\n\tno file '{0}/a/c.x'\n\tno file '{0}/a/c.lua'", dir)),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_require_preload() {
+        fn loader(_ctx: &LuaState, args: &[LuaValue]) -> Result<Vec<LuaValue>> {
+            Ok(vec![args[0].clone()])
+        }
+        let ctx = LuaState::new();
+        let preload = match package(&ctx).get_string(&ctx.intern(b"preload")) {
+            LuaValue::Table(t) => t,
+            other => panic!("{:?}", other),
+        };
+        let loader = LuaFunction::new_native(ctx.get_ref_id(), "loader", loader);
+        preload.set_string(&ctx.intern(b"mod"), &LuaValue::Function(loader));
+        let require = ctx.get_global_table().get_string(&ctx.intern(b"require"));
+        let call = |name: &str| ::expression::call_function(&require, &[string(name)], &ctx);
+        assert_eq!(call("mod").unwrap(), vec![string("mod")]);
+        let loaded = super::super::loaded_table(&ctx);
+        assert_eq!(loaded.get_string(&ctx.intern(b"mod")), string("mod"));
+        assert_eq!(call("string").unwrap(), vec![ctx.get_global_table().get_string(&ctx.intern(b"string"))]);
+
+        package(&ctx).set_string(&ctx.intern(b"path"), &string("/nonexistent/?.lua"));
+        match call("missing") {
+            Err(OtherError(msg)) => assert_eq!(
+                msg,
+                "module 'missing' not found:\n\tno field package.preload['missing']\
+                 \n\tno file '/nonexistent/missing.lua'"
+            ),
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_host_searcher() {
+        let ctx = LuaState::new();
+        ctx.add_searcher(|name| if name == "bundled" { Some(Vec::new()) } else { None }).unwrap();
+        let searchers = match package(&ctx).get_string(&ctx.intern(b"searchers")) {
+            LuaValue::Table(t) => t,
+            other => panic!("{:?}", other),
+        };
+        assert_eq!(searchers.sequence_border(), 3);
+        let searcher = searchers.get(&int(3));
+        let missing = ::expression::call_function(&searcher, &[string("other")], &ctx).unwrap();
+        assert_eq!(missing, vec![string("\n\tno module 'other' in host searcher")]);
+    }
+}
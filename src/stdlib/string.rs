@@ -0,0 +1,605 @@
+use std::cmp;
+
+use chunk;
+use expression::boolean_coercion;
+use types::{LuaState, LuaValue, Number};
+use Result;
+use LuaError::*;
+use super::args::{arg_error, check_integer, check_number, check_string, opt_integer, type_error};
+
+/// Maximum size for the binary representation of an integer.
+const MAX_INT_SIZE: usize = 16;
+/// Size of a Lua integer, in bytes.
+const LUA_INT_SIZE: usize = 8;
+/// Default maximum alignment for the '!' option, the one of the widest native type.
+const NATIVE_ALIGN: usize = 8;
+/// Maximum size of a packed item, mirroring the reference implementation's limit.
+const MAX_SIZE: usize = 0x7fff_ffff;
+/// Byte used for padding and alignment.
+const PACK_PAD_BYTE: u8 = 0;
+
+pub fn open(ctx: &LuaState) {
+    super::register_library(
+        ctx,
+        "string",
+        &[
+            ("dump", dump),
+            ("pack", pack),
+            ("packsize", packsize),
+            ("unpack", unpack),
+        ],
+    );
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum KOption {
+    Int,
+    Uint,
+    Float,
+    Char,
+    Str,
+    Zstr,
+    Padding,
+    PaddAlign,
+    Nop,
+}
+
+/// Reads a `string.pack` format string, keeping track of the endianness and maximum
+/// alignment set along the way.
+struct FormatReader<'a> {
+    fmt: &'a [u8],
+    pos: usize,
+    little: bool,
+    max_align: usize,
+    fname: &'static str,
+}
+
+impl<'a> FormatReader<'a> {
+    fn new(fmt: &'a [u8], fname: &'static str) -> FormatReader<'a> {
+        FormatReader {
+            fmt,
+            pos: 0,
+            little: cfg!(target_endian = "little"),
+            max_align: 1,
+            fname,
+        }
+    }
+
+    fn done(&self) -> bool {
+        self.pos >= self.fmt.len()
+    }
+
+    fn read_number(&mut self) -> Option<usize> {
+        let mut value = None;
+        while let Some(digit) = self.fmt.get(self.pos).filter(|c| c.is_ascii_digit()) {
+            let current = value.unwrap_or(0);
+            if current > (MAX_SIZE - 9) / 10 {
+                break;
+            }
+            value = Some(current * 10 + (digit - b'0') as usize);
+            self.pos += 1;
+        }
+        return value;
+    }
+
+    fn read_size_limit(&mut self, default: usize) -> Result<usize> {
+        let size = self.read_number().unwrap_or(default);
+        if size > MAX_INT_SIZE || size == 0 {
+            return Err(OtherError(format!(
+                "integral size ({}) out of limits [1,{}]",
+                size, MAX_INT_SIZE
+            )));
+        }
+        return Ok(size);
+    }
+
+    fn read_option(&mut self) -> Result<(KOption, usize)> {
+        let opt = self.fmt[self.pos];
+        self.pos += 1;
+        Ok(match opt {
+            b'b' => (KOption::Int, 1),
+            b'B' => (KOption::Uint, 1),
+            b'h' => (KOption::Int, 2),
+            b'H' => (KOption::Uint, 2),
+            b'l' | b'j' => (KOption::Int, 8),
+            b'L' | b'J' | b'T' => (KOption::Uint, 8),
+            b'f' => (KOption::Float, 4),
+            b'd' | b'n' => (KOption::Float, 8),
+            b'i' => (KOption::Int, self.read_size_limit(4)?),
+            b'I' => (KOption::Uint, self.read_size_limit(4)?),
+            b's' => (KOption::Str, self.read_size_limit(8)?),
+            b'c' => match self.read_number() {
+                Some(size) => (KOption::Char, size),
+                None => {
+                    return Err(OtherError(
+                        "missing size for format option 'c'".to_owned(),
+                    ))
+                }
+            },
+            b'z' => (KOption::Zstr, 0),
+            b'x' => (KOption::Padding, 1),
+            b'X' => (KOption::PaddAlign, 0),
+            b' ' => (KOption::Nop, 0),
+            b'<' => {
+                self.little = true;
+                (KOption::Nop, 0)
+            }
+            b'>' => {
+                self.little = false;
+                (KOption::Nop, 0)
+            }
+            b'=' => {
+                self.little = cfg!(target_endian = "little");
+                (KOption::Nop, 0)
+            }
+            b'!' => {
+                self.max_align = self.read_size_limit(NATIVE_ALIGN)?;
+                (KOption::Nop, 0)
+            }
+            _ => {
+                return Err(OtherError(
+                    format!("invalid format option '{}'", opt as char),
+                ))
+            }
+        })
+    }
+
+    /// Reads the next option, returning it along with its size and the padding needed
+    /// to align it, given the number of bytes already handled.
+    fn read_details(&mut self, total: usize) -> Result<(KOption, usize, usize)> {
+        let (opt, size) = self.read_option()?;
+        let mut align = size;
+        if opt == KOption::PaddAlign {
+            // 'X' takes its alignment from the following option, which is otherwise ignored.
+            let invalid = arg_error(1, self.fname, "invalid next option for option 'X'");
+            if self.done() {
+                return Err(invalid);
+            }
+            let (next, next_size) = self.read_option()?;
+            align = next_size;
+            if next == KOption::Char || align == 0 {
+                return Err(invalid);
+            }
+        }
+        if align <= 1 || opt == KOption::Char {
+            return Ok((opt, size, 0));
+        }
+        let align = cmp::min(align, self.max_align);
+        if align & (align - 1) != 0 {
+            return Err(arg_error(1, self.fname, "format asks for alignment not power of 2"));
+        }
+        Ok((opt, size, (align - (total & (align - 1))) & (align - 1)))
+    }
+}
+
+fn pack_int(buffer: &mut Vec<u8>, n: u64, little: bool, size: usize, negative: bool) {
+    let mut bytes = vec![0u8; size];
+    let mut n = n;
+    for i in 0..cmp::min(size, LUA_INT_SIZE) {
+        bytes[i] = (n & 0xff) as u8;
+        n >>= 8;
+    }
+    if negative {
+        // Sign extension for integers wider than a Lua integer
+        for i in LUA_INT_SIZE..size {
+            bytes[i] = 0xff;
+        }
+    }
+    if !little {
+        bytes.reverse();
+    }
+    buffer.extend(bytes);
+}
+
+fn unpack_int(data: &[u8], little: bool, size: usize, signed: bool) -> Result<isize> {
+    let limit = cmp::min(size, LUA_INT_SIZE);
+    let byte_at = |i: usize| data[if little { i } else { size - 1 - i }];
+    let mut res: u64 = 0;
+    for i in (0..limit).rev() {
+        res <<= 8;
+        res |= byte_at(i) as u64;
+    }
+    if size < LUA_INT_SIZE {
+        if signed {
+            let mask = 1u64 << (size * 8 - 1);
+            res = (res ^ mask).wrapping_sub(mask);
+        }
+    } else if size > LUA_INT_SIZE {
+        // The bytes we could not read must only be the sign extension
+        let mask = if !signed || (res as i64) >= 0 { 0 } else { 0xff };
+        for i in limit..size {
+            if byte_at(i) != mask {
+                return Err(OtherError(format!(
+                    "{}-byte integer does not fit into Lua Integer",
+                    size
+                )));
+            }
+        }
+    }
+    return Ok(res as i64 as isize);
+}
+
+fn pack_float(buffer: &mut Vec<u8>, f: f64, little: bool, size: usize) {
+    let mut bytes = if size == 4 {
+        (f as f32).to_le_bytes().to_vec()
+    } else {
+        f.to_le_bytes().to_vec()
+    };
+    if !little {
+        bytes.reverse();
+    }
+    buffer.extend(bytes);
+}
+
+fn unpack_float(data: &[u8], little: bool, size: usize) -> f64 {
+    let mut bytes = data[..size].to_vec();
+    if !little {
+        bytes.reverse();
+    }
+    if size == 4 {
+        f32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as f64
+    } else {
+        let mut raw = [0u8; 8];
+        raw.copy_from_slice(&bytes);
+        f64::from_le_bytes(raw)
+    }
+}
+
+pub fn pack(_ctx: &LuaState, args: &[LuaValue]) -> Result<Vec<LuaValue>> {
+    let fmt = check_string(args, 1, "pack")?;
+    let mut reader = FormatReader::new(&fmt, "pack");
+    let mut buffer = Vec::new();
+    let mut arg = 1;
+    while !reader.done() {
+        let (opt, size, ntoalign) = reader.read_details(buffer.len())?;
+        buffer.resize(buffer.len() + ntoalign, PACK_PAD_BYTE);
+        arg += 1;
+        match opt {
+            KOption::Int => {
+                let n = check_integer(args, arg, "pack")?;
+                if size < LUA_INT_SIZE {
+                    let lim = 1isize << (size * 8 - 1);
+                    if n < -lim || n >= lim {
+                        return Err(arg_error(arg, "pack", "integer overflow"));
+                    }
+                }
+                pack_int(&mut buffer, n as u64, reader.little, size, n < 0);
+            }
+            KOption::Uint => {
+                let n = check_integer(args, arg, "pack")?;
+                if size < LUA_INT_SIZE && (n as u64) >= (1u64 << (size * 8)) {
+                    return Err(arg_error(arg, "pack", "unsigned overflow"));
+                }
+                pack_int(&mut buffer, n as u64, reader.little, size, false);
+            }
+            KOption::Float => {
+                let f = check_number(args, arg, "pack")?.to_float();
+                pack_float(&mut buffer, f, reader.little, size);
+            }
+            KOption::Char => {
+                let s = check_string(args, arg, "pack")?;
+                if s.len() > size {
+                    return Err(arg_error(arg, "pack", "string longer than given size"));
+                }
+                buffer.extend(&s);
+                buffer.resize(buffer.len() + size - s.len(), PACK_PAD_BYTE);
+            }
+            KOption::Str => {
+                let s = check_string(args, arg, "pack")?;
+                if size < LUA_INT_SIZE && (s.len() as u64) >= (1u64 << (size * 8)) {
+                    return Err(arg_error(
+                        arg,
+                        "pack",
+                        "string length does not fit in given size",
+                    ));
+                }
+                pack_int(&mut buffer, s.len() as u64, reader.little, size, false);
+                buffer.extend(&s);
+            }
+            KOption::Zstr => {
+                let s = check_string(args, arg, "pack")?;
+                if s.contains(&0) {
+                    return Err(arg_error(arg, "pack", "string contains zeros"));
+                }
+                buffer.extend(&s);
+                buffer.push(0);
+            }
+            KOption::Padding => {
+                buffer.push(PACK_PAD_BYTE);
+                arg -= 1;
+            }
+            KOption::PaddAlign | KOption::Nop => {
+                arg -= 1;
+            }
+        }
+    }
+    Ok(vec![LuaValue::Str(buffer.into())])
+}
+
+pub fn packsize(_ctx: &LuaState, args: &[LuaValue]) -> Result<Vec<LuaValue>> {
+    let fmt = check_string(args, 1, "packsize")?;
+    let mut reader = FormatReader::new(&fmt, "packsize");
+    let mut total: usize = 0;
+    while !reader.done() {
+        let (opt, size, ntoalign) = reader.read_details(total)?;
+        let size = size + ntoalign;
+        if size > MAX_SIZE - total {
+            return Err(arg_error(1, "packsize", "format result too large"));
+        }
+        total += size;
+        if opt == KOption::Str || opt == KOption::Zstr {
+            return Err(arg_error(1, "packsize", "variable-length format"));
+        }
+    }
+    Ok(vec![LuaValue::Number(Number::Int(total as isize))])
+}
+
+/// Turns a possibly negative string position into an absolute one, as the reference
+/// implementation's `posrelat`.
+fn relative_position(pos: isize, len: usize) -> isize {
+    if pos >= 0 {
+        pos
+    } else if pos.unsigned_abs() > len {
+        0
+    } else {
+        len as isize + pos + 1
+    }
+}
+
+pub fn unpack(_ctx: &LuaState, args: &[LuaValue]) -> Result<Vec<LuaValue>> {
+    let fmt = check_string(args, 1, "unpack")?;
+    let data = check_string(args, 2, "unpack")?;
+    let init = relative_position(opt_integer(args, 3, "unpack", 1)?, data.len());
+    if init < 1 || (init - 1) as usize > data.len() {
+        return Err(arg_error(3, "unpack", "initial position out of string"));
+    }
+    let mut pos = (init - 1) as usize;
+    let mut reader = FormatReader::new(&fmt, "unpack");
+    let mut results = Vec::new();
+    while !reader.done() {
+        let (opt, size, ntoalign) = reader.read_details(pos)?;
+        if ntoalign + size > data.len() - pos {
+            return Err(arg_error(2, "unpack", "data string too short"));
+        }
+        pos += ntoalign;
+        match opt {
+            KOption::Int | KOption::Uint => {
+                let n = unpack_int(&data[pos..], reader.little, size, opt == KOption::Int)?;
+                results.push(LuaValue::Number(Number::Int(n)));
+            }
+            KOption::Float => {
+                let f = unpack_float(&data[pos..], reader.little, size);
+                results.push(LuaValue::Number(Number::Float(f)));
+            }
+            KOption::Char => {
+                results.push(LuaValue::Str(data[pos..pos + size].to_vec().into()));
+            }
+            KOption::Str => {
+                let len = unpack_int(&data[pos..], reader.little, size, false)? as usize;
+                if len > data.len() - pos - size {
+                    return Err(arg_error(2, "unpack", "data string too short"));
+                }
+                results.push(LuaValue::Str(data[pos + size..pos + size + len].to_vec().into()));
+                pos += len;
+            }
+            KOption::Zstr => {
+                let len = match data[pos..].iter().position(|&b| b == 0) {
+                    Some(len) => len,
+                    None => {
+                        return Err(arg_error(2, "unpack", "unfinished string for format 'z'"))
+                    }
+                };
+                results.push(LuaValue::Str(data[pos..pos + len].to_vec().into()));
+                pos += len + 1;
+            }
+            KOption::Padding | KOption::PaddAlign | KOption::Nop => {}
+        }
+        pos += size;
+    }
+    results.push(LuaValue::Number(Number::Int(pos as isize + 1)));
+    Ok(results)
+}
+
+/// `string.dump(f [, strip])`: the precompiled chunk of a function, in the format of
+/// the reference implementation.
+pub fn dump(_ctx: &LuaState, args: &[LuaValue]) -> Result<Vec<LuaValue>> {
+    let function = match args.get(0) {
+        Some(&LuaValue::Function(ref f)) => f,
+        arg => return Err(type_error(1, "dump", "function", arg)),
+    };
+    let strip = args.get(1).map_or(false, boolean_coercion);
+    match function.get_chunk() {
+        Some(c) => Ok(vec![LuaValue::Str(chunk::dump(c, strip)?.into())]),
+        None => Err(OtherError("unable to dump given function".to_owned())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn int(i: isize) -> LuaValue {
+        LuaValue::Number(Number::Int(i))
+    }
+
+    fn string(s: &[u8]) -> LuaValue {
+        LuaValue::Str(s.into())
+    }
+
+    #[test]
+    fn test_pack_integers() {
+        let ctx = LuaState::new();
+        let res = pack(&ctx, &[string(b"<i2>i2"), int(0x102), int(0x102)]).unwrap();
+        assert_eq!(res, vec![string(b"\x02\x01\x01\x02")]);
+
+        let res = pack(&ctx, &[string(b"<b"), int(-1)]).unwrap();
+        assert_eq!(res, vec![string(b"\xff")]);
+
+        // Wider than a Lua integer, sign extended
+        let res = pack(&ctx, &[string(b"<i10"), int(-2)]).unwrap();
+        assert_eq!(res, vec![string(b"\xfe\xff\xff\xff\xff\xff\xff\xff\xff\xff")]);
+
+        let res = unpack(&ctx, &[string(b"<i10"), res[0].clone()]).unwrap();
+        assert_eq!(res, vec![int(-2), int(11)]);
+    }
+
+    #[test]
+    fn test_pack_overflow() {
+        let ctx = LuaState::new();
+        let res = pack(&ctx, &[string(b"b"), int(128)]).unwrap_err();
+        assert_eq!(
+            res,
+            OtherError("bad argument #2 to 'pack' (integer overflow)".to_owned())
+        );
+
+        let res = pack(&ctx, &[string(b"B"), int(-1)]).unwrap_err();
+        assert_eq!(
+            res,
+            OtherError("bad argument #2 to 'pack' (unsigned overflow)".to_owned())
+        );
+
+        let res = pack(&ctx, &[string(b"i17"), int(1)]).unwrap_err();
+        assert_eq!(
+            res,
+            OtherError("integral size (17) out of limits [1,16]".to_owned())
+        );
+
+        let res = unpack(&ctx, &[string(b"<I9"), string(b"\0\0\0\0\0\0\0\0\x01")]).unwrap_err();
+        assert_eq!(
+            res,
+            OtherError("9-byte integer does not fit into Lua Integer".to_owned())
+        );
+    }
+
+    #[test]
+    fn test_pack_strings() {
+        let ctx = LuaState::new();
+        let res = pack(&ctx, &[string(b"<s1zc3"), string(b"ab"), string(b"cd"), string(b"e")])
+            .unwrap();
+        assert_eq!(res, vec![string(b"\x02abcd\0e\0\0")]);
+
+        let res = unpack(&ctx, &[string(b"<s1zc3"), res[0].clone()]).unwrap();
+        assert_eq!(res, vec![string(b"ab"), string(b"cd"), string(b"e\0\0"), int(10)]);
+
+        let res = pack(&ctx, &[string(b"z"), string(b"a\0b")]).unwrap_err();
+        assert_eq!(
+            res,
+            OtherError("bad argument #2 to 'pack' (string contains zeros)".to_owned())
+        );
+
+        let res = unpack(&ctx, &[string(b"z"), string(b"abc")]).unwrap_err();
+        assert_eq!(
+            res,
+            OtherError("bad argument #2 to 'unpack' (unfinished string for format 'z')".to_owned())
+        );
+    }
+
+    #[test]
+    fn test_pack_floats() {
+        let ctx = LuaState::new();
+        let res = pack(&ctx, &[string(b">d<f"), int(1), LuaValue::Number(Number::Float(-2.5))])
+            .unwrap();
+        assert_eq!(res, vec![string(b"\x3f\xf0\0\0\0\0\0\0\0\0\x20\xc0")]);
+
+        let res = unpack(&ctx, &[string(b">d<f"), res[0].clone()]).unwrap();
+        assert_eq!(
+            res,
+            vec![
+                LuaValue::Number(Number::Float(1.)),
+                LuaValue::Number(Number::Float(-2.5)),
+                int(13),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_alignment() {
+        let ctx = LuaState::new();
+        let res = packsize(&ctx, &[string(b"!bi")]).unwrap();
+        assert_eq!(res, vec![int(8)]);
+
+        let res = packsize(&ctx, &[string(b"!4 b d")]).unwrap();
+        assert_eq!(res, vec![int(12)]);
+
+        // Without '!', the maximum alignment is 1
+        let res = packsize(&ctx, &[string(b"bi")]).unwrap();
+        assert_eq!(res, vec![int(5)]);
+
+        let res = packsize(&ctx, &[string(b"!bXi8")]).unwrap();
+        assert_eq!(res, vec![int(8)]);
+
+        let res = packsize(&ctx, &[string(b"!bi3")]).unwrap_err();
+        assert_eq!(
+            res,
+            OtherError(
+                "bad argument #1 to 'packsize' (format asks for alignment not power of 2)"
+                    .to_owned()
+            )
+        );
+
+        let res = packsize(&ctx, &[string(b"s")]).unwrap_err();
+        assert_eq!(
+            res,
+            OtherError("bad argument #1 to 'packsize' (variable-length format)".to_owned())
+        );
+    }
+
+    #[test]
+    fn test_unpack_position() {
+        let ctx = LuaState::new();
+        let res = unpack(&ctx, &[string(b"B"), string(b"\x01\x02\x03"), int(-1)]).unwrap();
+        assert_eq!(res, vec![int(3), int(4)]);
+
+        let res = unpack(&ctx, &[string(b"B"), string(b"\x01"), int(3)]).unwrap_err();
+        assert_eq!(
+            res,
+            OtherError("bad argument #3 to 'unpack' (initial position out of string)".to_owned())
+        );
+
+        let res = unpack(&ctx, &[string(b"i4"), string(b"\x01")]).unwrap_err();
+        assert_eq!(
+            res,
+            OtherError("bad argument #2 to 'unpack' (data string too short)".to_owned())
+        );
+    }
+
+    #[test]
+    fn test_dump() {
+        use bytecode::luac::{self, abc, abx, Prototype, Upvalue, OP_ADD, OP_LOADK, OP_RETURN, RK_CONSTANT};
+        let ctx = LuaState::new();
+        let proto = Prototype {
+            source: Some(b"@answer.lua".to_vec()),
+            is_vararg: 1,
+            max_stack_size: 2,
+            code: vec![
+                abx(OP_LOADK, 0, 0),
+                abc(OP_ADD, 0, 0, RK_CONSTANT | 1),
+                abc(OP_RETURN, 0, 2, 0),
+                abc(OP_RETURN, 0, 1, 0),
+            ],
+            constants: vec![int(40), int(2)],
+            upvalues: vec![Upvalue {
+                in_stack: true,
+                index: 0,
+                name: Some(b"_ENV".to_vec()),
+            }],
+            line_info: vec![1, 1, 1, 1],
+            ..Prototype::default()
+        };
+        let data = luac::dump(&proto, false);
+        let env = LuaValue::Table(ctx.get_global_table().clone());
+        let function = chunk::load(&ctx, data.clone(), "=test", env).unwrap();
+        assert_eq!(function.get_chunk().unwrap().get_chunkname(), "@answer.lua");
+        assert_eq!(function.call(&ctx, &[]).unwrap(), vec![int(42)]);
+
+        let res = dump(&ctx, &[LuaValue::Function(function.clone())]).unwrap();
+        assert_eq!(res, vec![LuaValue::Str(data.into())]);
+        let res = dump(&ctx, &[LuaValue::Function(function), LuaValue::Boolean(true)]).unwrap();
+        assert_eq!(res, vec![LuaValue::Str(luac::dump(&proto, true).into())]);
+
+        let native = ctx.create_native("f", |_, _| Ok(Vec::new()));
+        let res = dump(&ctx, &[native]).unwrap_err();
+        assert_eq!(res, OtherError("unable to dump given function".to_owned()));
+        assert!(dump(&ctx, &[int(1)]).is_err());
+    }
+}
@@ -0,0 +1,540 @@
+use types::{LuaState, LuaTable, LuaValue, Number};
+use expression::{boolean_coercion, call_function, first_value, index_value, length_of, less_than,
+                 set_index};
+use sanitizer;
+use Result;
+use LuaError::*;
+use super::args::{arg_error, check_integer, check_table, opt_integer, opt_string, type_error};
+
+/// Maximum number of values `table.unpack` can return, mirroring the maximum stack size
+/// of the reference implementation.
+const MAX_RESULTS: u64 = 1_000_000;
+
+pub fn open(ctx: &LuaState) {
+    super::register_library(
+        ctx,
+        "table",
+        &[
+            ("concat", concat),
+            ("insert", insert),
+            ("move", move_elements),
+            ("pack", pack),
+            ("remove", remove),
+            ("sort", sort),
+            ("unpack", unpack),
+        ],
+    );
+}
+
+fn int(i: isize) -> LuaValue {
+    LuaValue::Number(Number::Int(i))
+}
+
+// Tables without metatable can't have their accesses intercepted, so they can use the
+// sequence part directly.
+fn is_raw(table: &LuaTable) -> bool {
+    table.get_metatable().is_none()
+}
+
+fn geti(table: &LuaValue, i: isize, ctx: &LuaState) -> Result<LuaValue> {
+    index_value(table, &int(i), ctx)
+}
+
+fn seti(table: &LuaValue, i: isize, value: LuaValue, ctx: &LuaState) -> Result<()> {
+    set_index(table, &int(i), &value, ctx)
+}
+
+/// The length of the sequence as seen by the `#` operator, which must be an integer.
+fn aux_getn(table: &LuaValue, ctx: &LuaState) -> Result<isize> {
+    match length_of(table, ctx)? {
+        LuaValue::Number(ref num) => num.to_exact_int()
+            .ok_or_else(|| OtherError("object length is not an integer".to_owned())),
+        _ => Err(OtherError("object length is not an integer".to_owned())),
+    }
+}
+
+pub fn insert(ctx: &LuaState, args: &[LuaValue]) -> Result<Vec<LuaValue>> {
+    let table = check_table(args, 1, "insert")?;
+    sanitizer::check_sequence(ctx, &table, "'table.insert'")?;
+    let list = LuaValue::Table(table.clone());
+    // First empty element
+    let e = aux_getn(&list, ctx)? + 1;
+    let pos = match args.len() {
+        2 => e,
+        3 => {
+            let pos = check_integer(args, 2, "insert")?;
+            // Checks that 1 <= pos <= e
+            if (pos as usize).wrapping_sub(1) >= e as usize {
+                return Err(arg_error(2, "insert", "position out of bounds"));
+            }
+            pos
+        }
+        _ => {
+            return Err(OtherError(
+                "wrong number of arguments to 'insert'".to_owned(),
+            ))
+        }
+    };
+    let value = args[args.len() - 1].clone();
+    if is_raw(&table) {
+        table.sequence_insert(pos as usize, value);
+        return Ok(vec![]);
+    }
+    for i in (pos + 1..e + 1).rev() {
+        let moved = geti(&list, i - 1, ctx)?;
+        seti(&list, i, moved, ctx)?;
+    }
+    seti(&list, pos, value, ctx)?;
+    Ok(vec![])
+}
+
+pub fn remove(ctx: &LuaState, args: &[LuaValue]) -> Result<Vec<LuaValue>> {
+    let table = check_table(args, 1, "remove")?;
+    let list = LuaValue::Table(table.clone());
+    let size = aux_getn(&list, ctx)?;
+    let mut pos = opt_integer(args, 2, "remove", size)?;
+    // Checks that 1 <= pos <= size + 1 when the position is given
+    if pos != size && (pos as usize).wrapping_sub(1) > size as usize {
+        return Err(arg_error(1, "remove", "position out of bounds"));
+    }
+    if is_raw(&table) && pos >= 1 && pos <= size {
+        return Ok(vec![table.sequence_remove(pos as usize)]);
+    }
+    let result = geti(&list, pos, ctx)?;
+    while pos < size {
+        let moved = geti(&list, pos + 1, ctx)?;
+        seti(&list, pos, moved, ctx)?;
+        pos += 1;
+    }
+    seti(&list, pos, LuaValue::Nil, ctx)?;
+    Ok(vec![result])
+}
+
+pub fn move_elements(ctx: &LuaState, args: &[LuaValue]) -> Result<Vec<LuaValue>> {
+    let source = check_table(args, 1, "move")?;
+    let f = check_integer(args, 2, "move")?;
+    let e = check_integer(args, 3, "move")?;
+    let t = check_integer(args, 4, "move")?;
+    let destination = match args.get(4) {
+        None | Some(&LuaValue::Nil) => source.clone(),
+        Some(_) => check_table(args, 5, "move")?,
+    };
+    if e >= f {
+        if !(f > 0 || e < isize::max_value() + f) {
+            return Err(arg_error(3, "move", "too many elements to move"));
+        }
+        let n = e - f + 1;
+        if t > isize::max_value() - n + 1 {
+            return Err(arg_error(4, "move", "destination wrap around"));
+        }
+        let from = LuaValue::Table(source.clone());
+        let to = LuaValue::Table(destination.clone());
+        if t > e || t <= f || destination != source {
+            for i in 0..n {
+                let moved = geti(&from, f + i, ctx)?;
+                seti(&to, t + i, moved, ctx)?;
+            }
+        } else {
+            // Overlapping ranges, moving up: start from the end
+            for i in (0..n).rev() {
+                let moved = geti(&from, f + i, ctx)?;
+                seti(&to, t + i, moved, ctx)?;
+            }
+        }
+    }
+    Ok(vec![LuaValue::Table(destination)])
+}
+
+pub fn concat(ctx: &LuaState, args: &[LuaValue]) -> Result<Vec<LuaValue>> {
+    let table = check_table(args, 1, "concat")?;
+    let list = LuaValue::Table(table.clone());
+    let sep = opt_string(args, 2, "concat", b"")?;
+    let first = opt_integer(args, 3, "concat", 1)?;
+    let last = match args.get(3) {
+        None | Some(&LuaValue::Nil) => aux_getn(&list, ctx)?,
+        Some(_) => check_integer(args, 4, "concat")?,
+    };
+    if first > last {
+        return Ok(vec![LuaValue::Str("".into())]);
+    }
+    let values = if is_raw(&table) && first >= 1 && last as usize <= table.sequence_border() {
+        table.sequence_range(first as usize, last as usize)
+    } else {
+        let mut values = Vec::new();
+        let mut i = first;
+        loop {
+            values.push(geti(&list, i, ctx)?);
+            if i == last {
+                break;
+            }
+            i += 1;
+        }
+        values
+    };
+    let mut buffer = Vec::new();
+    for (i, value) in values.into_iter().enumerate() {
+        if i > 0 {
+            buffer.extend(&sep);
+        }
+        match value {
+            LuaValue::Str(s) => buffer.extend(s.as_bytes()),
+            LuaValue::Number(n) => buffer.extend(n.to_string().into_bytes()),
+            _ => {
+                return Err(OtherError(format!(
+                    "invalid value (at index {}) in table for 'concat'",
+                    first + i as isize
+                )))
+            }
+        }
+    }
+    Ok(vec![LuaValue::Str(buffer.into())])
+}
+
+pub fn pack(ctx: &LuaState, args: &[LuaValue]) -> Result<Vec<LuaValue>> {
+    let table = LuaTable::with_capacity(ctx.get_ref_id(), args.len());
+    for (i, value) in args.iter().enumerate() {
+        table.set(&int(i as isize + 1), value)?;
+    }
+    table.set_string(&ctx.intern(b"n"), &int(args.len() as isize));
+    Ok(vec![LuaValue::Table(table)])
+}
+
+pub fn unpack(ctx: &LuaState, args: &[LuaValue]) -> Result<Vec<LuaValue>> {
+    let list = args.get(0).cloned().unwrap_or(LuaValue::Nil);
+    let first = opt_integer(args, 2, "unpack", 1)?;
+    let last = match args.get(2) {
+        None | Some(&LuaValue::Nil) => {
+            if let LuaValue::Table(ref table) = list {
+                sanitizer::check_sequence(ctx, table, "'table.unpack'")?;
+            }
+            aux_getn(&list, ctx)?
+        }
+        Some(_) => check_integer(args, 3, "unpack")?,
+    };
+    if first > last {
+        return Ok(vec![]);
+    }
+    if (last as u64).wrapping_sub(first as u64) >= MAX_RESULTS {
+        return Err(OtherError("too many results to unpack".to_owned()));
+    }
+    if let LuaValue::Table(ref table) = list {
+        if is_raw(table) && first >= 1 && last as usize <= table.sequence_border() {
+            return Ok(table.sequence_range(first as usize, last as usize));
+        }
+    }
+    let mut values = Vec::with_capacity((last - first) as usize + 1);
+    for i in first..last + 1 {
+        values.push(geti(&list, i, ctx)?);
+    }
+    Ok(values)
+}
+
+/// The elements being sorted, which are either accessed through the table with their
+/// metamethods, or through a copy of the sequence part of a raw table.
+trait Sequence {
+    fn get(&mut self, i: isize) -> Result<LuaValue>;
+    fn set(&mut self, i: isize, value: LuaValue) -> Result<()>;
+}
+
+struct MetaSequence<'a> {
+    table: LuaValue,
+    ctx: &'a LuaState,
+}
+
+impl<'a> Sequence for MetaSequence<'a> {
+    fn get(&mut self, i: isize) -> Result<LuaValue> {
+        geti(&self.table, i, self.ctx)
+    }
+    fn set(&mut self, i: isize, value: LuaValue) -> Result<()> {
+        seti(&self.table, i, value, self.ctx)
+    }
+}
+
+impl Sequence for Vec<LuaValue> {
+    fn get(&mut self, i: isize) -> Result<LuaValue> {
+        Ok(self[(i - 1) as usize].clone())
+    }
+    fn set(&mut self, i: isize, value: LuaValue) -> Result<()> {
+        self[(i - 1) as usize] = value;
+        Ok(())
+    }
+}
+
+/// The quicksort of the reference implementation, which detects inconsistent
+/// comparators when its partition loops run past the pivot.
+struct Sorter<'a> {
+    comparator: Option<LuaValue>,
+    ctx: &'a LuaState,
+}
+
+impl<'a> Sorter<'a> {
+    fn less(&self, a: &LuaValue, b: &LuaValue) -> Result<bool> {
+        match self.comparator {
+            Some(ref f) => {
+                let result = call_function(f, &[a.clone(), b.clone()], self.ctx)?;
+                Ok(boolean_coercion(&first_value(result)))
+            }
+            None => less_than(a, b),
+        }
+    }
+
+    fn partition<S: Sequence>(&self, seq: &mut S, lo: isize, up: isize, pivot: &LuaValue) -> Result<isize> {
+        let invalid = || OtherError("invalid order function for sorting".to_owned());
+        // Invariant: a[lo .. i] <= pivot <= a[j .. up], a[up - 1] == pivot
+        let mut i = lo;
+        let mut j = up - 1;
+        loop {
+            i += 1;
+            let mut a_i = seq.get(i)?;
+            while self.less(&a_i, pivot)? {
+                if i == up - 1 {
+                    return Err(invalid());
+                }
+                i += 1;
+                a_i = seq.get(i)?;
+            }
+            j -= 1;
+            let mut a_j = seq.get(j)?;
+            while self.less(pivot, &a_j)? {
+                if j < i {
+                    return Err(invalid());
+                }
+                j -= 1;
+                a_j = seq.get(j)?;
+            }
+            if j < i {
+                // Puts the pivot at its final place
+                seq.set(up - 1, a_i)?;
+                seq.set(i, pivot.clone())?;
+                return Ok(i);
+            }
+            seq.set(i, a_j)?;
+            seq.set(j, a_i)?;
+        }
+    }
+
+    fn sort<S: Sequence>(&self, seq: &mut S, mut lo: isize, mut up: isize) -> Result<()> {
+        while lo < up {
+            // Sorts the elements at lo, p and up
+            let a_lo = seq.get(lo)?;
+            let a_up = seq.get(up)?;
+            if self.less(&a_up, &a_lo)? {
+                seq.set(lo, a_up)?;
+                seq.set(up, a_lo)?;
+            }
+            if up - lo == 1 {
+                break;
+            }
+            let p = lo + (up - lo) / 2;
+            let a_p = seq.get(p)?;
+            let a_lo = seq.get(lo)?;
+            if self.less(&a_p, &a_lo)? {
+                seq.set(p, a_lo)?;
+                seq.set(lo, a_p)?;
+            } else {
+                let a_up = seq.get(up)?;
+                if self.less(&a_up, &a_p)? {
+                    seq.set(p, a_up)?;
+                    seq.set(up, a_p)?;
+                }
+            }
+            if up - lo == 2 {
+                break;
+            }
+            let pivot = seq.get(p)?;
+            let a_up1 = seq.get(up - 1)?;
+            seq.set(p, a_up1)?;
+            seq.set(up - 1, pivot.clone())?;
+            let p = self.partition(seq, lo, up, &pivot)?;
+            // Recurses on the smaller interval, loops on the larger one
+            if p - lo < up - p {
+                self.sort(seq, lo, p - 1)?;
+                lo = p + 1;
+            } else {
+                self.sort(seq, p + 1, up)?;
+                up = p - 1;
+            }
+        }
+        Ok(())
+    }
+}
+
+pub fn sort(ctx: &LuaState, args: &[LuaValue]) -> Result<Vec<LuaValue>> {
+    let table = check_table(args, 1, "sort")?;
+    let list = LuaValue::Table(table.clone());
+    let n = aux_getn(&list, ctx)?;
+    if n > 1 {
+        if n >= i32::max_value() as isize {
+            return Err(arg_error(1, "sort", "array too big"));
+        }
+        let comparator = match args.get(1) {
+            None | Some(&LuaValue::Nil) => None,
+            Some(&LuaValue::Function(_)) => Some(args[1].clone()),
+            other => return Err(type_error(2, "sort", "function", other)),
+        };
+        let sorter = Sorter {
+            comparator,
+            ctx,
+        };
+        if is_raw(&table) {
+            let mut values = table.sequence_range(1, n as usize);
+            sorter.sort(&mut values, 1, n)?;
+            for (i, value) in values.iter().enumerate() {
+                table.set(&int(i as isize + 1), value)?;
+            }
+        } else {
+            sorter.sort(&mut MetaSequence { table: list, ctx }, 1, n)?;
+        }
+    }
+    Ok(vec![])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use types::LuaFunction;
+
+    fn string(s: &[u8]) -> LuaValue {
+        LuaValue::Str(s.into())
+    }
+
+    fn sequence(ctx: &LuaState, values: &[LuaValue]) -> LuaTable {
+        let table = LuaTable::new(ctx.get_ref_id());
+        for (i, value) in values.iter().enumerate() {
+            table.set(&int(i as isize + 1), value).unwrap();
+        }
+        table
+    }
+
+    fn greater(_ctx: &LuaState, args: &[LuaValue]) -> Result<Vec<LuaValue>> {
+        Ok(vec![LuaValue::Boolean(less_than(&args[1], &args[0])?)])
+    }
+
+    fn always(_ctx: &LuaState, _args: &[LuaValue]) -> Result<Vec<LuaValue>> {
+        Ok(vec![LuaValue::Boolean(true)])
+    }
+
+    fn three(_ctx: &LuaState, _args: &[LuaValue]) -> Result<Vec<LuaValue>> {
+        Ok(vec![int(3)])
+    }
+
+    fn native(ctx: &LuaState, f: ::types::NativeFunction) -> LuaValue {
+        LuaValue::Function(LuaFunction::new_native(ctx.get_ref_id(), "f", f))
+    }
+
+    #[test]
+    fn test_insert_remove() {
+        let ctx = LuaState::new();
+        let t = sequence(&ctx, &[int(1), int(2)]);
+        insert(&ctx, &[LuaValue::Table(t.clone()), int(3)]).unwrap();
+        insert(&ctx, &[LuaValue::Table(t.clone()), int(1), int(0)]).unwrap();
+        assert_eq!(t.sequence_range(1, 4), vec![int(0), int(1), int(2), int(3)]);
+
+        let res = insert(&ctx, &[LuaValue::Table(t.clone()), int(6), int(0)]).unwrap_err();
+        assert_eq!(
+            res,
+            OtherError("bad argument #2 to 'insert' (position out of bounds)".to_owned())
+        );
+
+        let res = remove(&ctx, &[LuaValue::Table(t.clone())]).unwrap();
+        assert_eq!(res, vec![int(3)]);
+        let res = remove(&ctx, &[LuaValue::Table(t.clone()), int(1)]).unwrap();
+        assert_eq!(res, vec![int(0)]);
+        assert_eq!(t.sequence_border(), 2);
+        assert_eq!(t.sequence_range(1, 2), vec![int(1), int(2)]);
+    }
+
+    #[test]
+    fn test_metamethods() {
+        let ctx = LuaState::new();
+        let backing = sequence(&ctx, &[string(b"a"), string(b"b"), string(b"c")]);
+        let meta = LuaTable::new(ctx.get_ref_id());
+        meta.set_string(&ctx.intern(b"__index"), &LuaValue::Table(backing.clone()));
+        meta.set_string(&ctx.intern(b"__newindex"), &LuaValue::Table(backing.clone()));
+        meta.set_string(&ctx.intern(b"__len"), &native(&ctx, three));
+        let proxy = LuaTable::new(ctx.get_ref_id());
+        proxy.set_metatable(Some(meta));
+        let proxy = LuaValue::Table(proxy);
+
+        let res = unpack(&ctx, &[proxy.clone()]).unwrap();
+        assert_eq!(res, vec![string(b"a"), string(b"b"), string(b"c")]);
+
+        let res = concat(&ctx, &[proxy.clone(), string(b", ")]).unwrap();
+        assert_eq!(res, vec![string(b"a, b, c")]);
+
+        insert(&ctx, &[proxy.clone(), string(b"d")]).unwrap();
+        assert_eq!(backing.sequence_border(), 4);
+        assert_eq!(backing.get(&int(4)), string(b"d"));
+    }
+
+    #[test]
+    fn test_pack_unpack() {
+        let ctx = LuaState::new();
+        let res = pack(&ctx, &[int(1), LuaValue::Nil, int(3)]).unwrap();
+        let t = match res[0] {
+            LuaValue::Table(ref t) => t.clone(),
+            _ => panic!("table.pack should return a table"),
+        };
+        assert_eq!(t.get_string(&ctx.intern(b"n")), int(3));
+        assert_eq!(t.get(&int(3)), int(3));
+
+        let res = unpack(&ctx, &[res[0].clone(), int(1), int(3)]).unwrap();
+        assert_eq!(res, vec![int(1), LuaValue::Nil, int(3)]);
+
+        let res = unpack(&ctx, &[res[0].clone(), int(1), int(isize::max_value())]).unwrap_err();
+        assert_eq!(res, OtherError("too many results to unpack".to_owned()));
+    }
+
+    #[test]
+    fn test_concat() {
+        let ctx = LuaState::new();
+        let t = LuaValue::Table(sequence(&ctx, &[int(1), string(b"b"), LuaValue::Boolean(true)]));
+        let res = concat(&ctx, &[t.clone(), string(b"-"), int(1), int(2)]).unwrap();
+        assert_eq!(res, vec![string(b"1-b")]);
+
+        let res = concat(&ctx, &[t.clone()]).unwrap_err();
+        assert_eq!(
+            res,
+            OtherError("invalid value (at index 3) in table for 'concat'".to_owned())
+        );
+    }
+
+    #[test]
+    fn test_move() {
+        let ctx = LuaState::new();
+        let t = sequence(&ctx, &[int(1), int(2), int(3)]);
+        move_elements(&ctx, &[LuaValue::Table(t.clone()), int(1), int(3), int(2)]).unwrap();
+        assert_eq!(t.sequence_range(1, 4), vec![int(1), int(1), int(2), int(3)]);
+
+        let other = LuaTable::new(ctx.get_ref_id());
+        let res = move_elements(
+            &ctx,
+            &[LuaValue::Table(t.clone()), int(2), int(3), int(1), LuaValue::Table(other.clone())],
+        ).unwrap();
+        assert_eq!(res, vec![LuaValue::Table(other.clone())]);
+        assert_eq!(other.sequence_range(1, 2), vec![int(1), int(2)]);
+    }
+
+    #[test]
+    fn test_sort() {
+        let ctx = LuaState::new();
+        let values: Vec<LuaValue> = [5, 3, 9, 1, 7, 2, 8, 6, 4, 0].iter().map(|&i| int(i)).collect();
+        let t = sequence(&ctx, &values);
+        sort(&ctx, &[LuaValue::Table(t.clone())]).unwrap();
+        let sorted: Vec<LuaValue> = (0..10).map(int).collect();
+        assert_eq!(t.sequence_range(1, 10), sorted);
+
+        sort(&ctx, &[LuaValue::Table(t.clone()), native(&ctx, greater)]).unwrap();
+        let sorted: Vec<LuaValue> = (0..10).rev().map(int).collect();
+        assert_eq!(t.sequence_range(1, 10), sorted);
+
+        let res = sort(&ctx, &[LuaValue::Table(t.clone()), native(&ctx, always)]).unwrap_err();
+        assert_eq!(res, OtherError("invalid order function for sorting".to_owned()));
+
+        let res = sort(&ctx, &[LuaValue::Table(t.clone()), int(1)]).unwrap_err();
+        assert_eq!(
+            res,
+            TypeError("bad argument #2 to 'sort' (function expected, got number)".to_owned())
+        );
+    }
+}
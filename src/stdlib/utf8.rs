@@ -0,0 +1,309 @@
+// The `utf8` library of Lua 5.3, which works on the bytes of strings and accepts the same
+// sequences as the reference implementation: code points up to 0x10FFFF in up to four
+// bytes, surrogates included.
+
+use types::{LuaFunction, LuaState, LuaValue, Number};
+use Result;
+use LuaError::*;
+use super::args::{arg_error, check_integer, check_string, opt_integer};
+
+const MAX_UNICODE: u32 = 0x10FFFF;
+
+/// Pattern matching exactly one UTF-8 byte sequence.
+const CHAR_PATTERN: &[u8] = b"[\x00-\x7F\xC2-\xF4][\x80-\xBF]*";
+
+pub fn open(ctx: &LuaState) {
+    let library = super::register_library(
+        ctx,
+        "utf8",
+        &[
+            ("char", char),
+            ("codepoint", codepoint),
+            ("codes", codes),
+            ("len", len),
+            ("offset", offset),
+        ],
+    );
+    library.set_string(&ctx.intern(b"charpattern"), &LuaValue::Str(CHAR_PATTERN.to_vec().into()));
+}
+
+fn int(i: isize) -> LuaValue {
+    LuaValue::Number(Number::Int(i))
+}
+
+fn is_continuation(s: &[u8], pos: usize) -> bool {
+    // Past the end reads as the terminating zero of a C string.
+    s.get(pos).map_or(false, |&c| c & 0xC0 == 0x80)
+}
+
+/// Translates a relative position, negative ones counting from the end of the string.
+fn relative_position(pos: isize, len: usize) -> isize {
+    if pos >= 0 {
+        pos
+    } else if pos.wrapping_neg() as usize > len {
+        0
+    } else {
+        len as isize + pos + 1
+    }
+}
+
+/// Decodes the sequence starting at `pos`, returning the code point and the position of
+/// the next sequence, or `None` if the bytes aren't a valid sequence.
+fn decode(s: &[u8], pos: usize) -> Option<(u32, usize)> {
+    const LIMITS: [u32; 4] = [0xFF, 0x7F, 0x7FF, 0xFFFF];
+    let mut c = s[pos] as u32;
+    if c < 0x80 {
+        return Some((c, pos + 1));
+    }
+    let mut res = 0u32;
+    let mut count = 0;
+    while c & 0x40 != 0 {
+        count += 1;
+        let cc = match s.get(pos + count) {
+            Some(&cc) if cc & 0xC0 == 0x80 => cc as u32,
+            _ => return None,
+        };
+        res = (res << 6) | (cc & 0x3F);
+        c <<= 1;
+    }
+    if count > 3 {
+        return None;
+    }
+    res |= (c & 0x7F) << (count * 5);
+    if res > MAX_UNICODE || res <= LIMITS[count] {
+        return None;
+    }
+    Some((res, pos + count + 1))
+}
+
+/// Encodes a code point of up to `MAX_UNICODE`.
+fn encode(code: u32, buffer: &mut Vec<u8>) {
+    if code < 0x80 {
+        buffer.push(code as u8);
+        return;
+    }
+    let mut tail = Vec::with_capacity(3);
+    let mut code = code;
+    // Largest value that fits in the first byte.
+    let mut first_max = 0x3F;
+    while code > first_max {
+        tail.push(0x80 | (code & 0x3F) as u8);
+        code >>= 6;
+        first_max >>= 1;
+    }
+    buffer.push(((!first_max << 1) | code) as u8);
+    buffer.extend(tail.iter().rev());
+}
+
+fn char(_ctx: &LuaState, args: &[LuaValue]) -> Result<Vec<LuaValue>> {
+    let mut result = Vec::with_capacity(args.len());
+    for n in 1..args.len() + 1 {
+        let code = check_integer(args, n, "char")?;
+        if code < 0 || code > MAX_UNICODE as isize {
+            return Err(arg_error(n, "char", "value out of range"));
+        }
+        encode(code as u32, &mut result);
+    }
+    Ok(vec![LuaValue::Str(result.into())])
+}
+
+fn codepoint(_ctx: &LuaState, args: &[LuaValue]) -> Result<Vec<LuaValue>> {
+    let s = check_string(args, 1, "codepoint")?;
+    let posi = relative_position(opt_integer(args, 2, "codepoint", 1)?, s.len());
+    let pose = relative_position(opt_integer(args, 3, "codepoint", posi)?, s.len());
+    if posi < 1 {
+        return Err(arg_error(2, "codepoint", "out of range"));
+    }
+    if pose > s.len() as isize {
+        return Err(arg_error(3, "codepoint", "out of range"));
+    }
+    let mut codes = Vec::new();
+    let mut pos = posi as usize - 1;
+    while pos < pose as usize {
+        match decode(&s, pos) {
+            Some((code, next)) => {
+                codes.push(int(code as isize));
+                pos = next;
+            }
+            None => return Err(OtherError("invalid UTF-8 code".to_owned())),
+        }
+    }
+    Ok(codes)
+}
+
+/// Counts the code points between two positions, or returns nil and the position of the
+/// first invalid byte.
+fn len(_ctx: &LuaState, args: &[LuaValue]) -> Result<Vec<LuaValue>> {
+    let s = check_string(args, 1, "len")?;
+    let posi = relative_position(opt_integer(args, 2, "len", 1)?, s.len());
+    let posj = relative_position(opt_integer(args, 3, "len", -1)?, s.len());
+    if posi < 1 || posi - 1 > s.len() as isize {
+        return Err(arg_error(2, "len", "initial position out of string"));
+    }
+    if posj - 1 >= s.len() as isize {
+        return Err(arg_error(3, "len", "final position out of string"));
+    }
+    let mut count = 0;
+    let mut pos = posi - 1;
+    while pos <= posj - 1 {
+        match decode(&s, pos as usize) {
+            Some((_, next)) => pos = next as isize,
+            None => return Ok(vec![LuaValue::Nil, int(pos + 1)]),
+        }
+        count += 1;
+    }
+    Ok(vec![int(count)])
+}
+
+/// Position of the n-th character counting from `i`, or of the start of the character
+/// holding `i` when n is 0.
+fn offset(_ctx: &LuaState, args: &[LuaValue]) -> Result<Vec<LuaValue>> {
+    let s = check_string(args, 1, "offset")?;
+    let mut n = check_integer(args, 2, "offset")?;
+    let len = s.len() as isize;
+    let default = if n >= 0 { 1 } else { len + 1 };
+    let posi = relative_position(opt_integer(args, 3, "offset", default)?, s.len());
+    if posi < 1 || posi - 1 > len {
+        return Err(arg_error(3, "offset", "position out of range"));
+    }
+    let mut pos = posi - 1;
+    if n == 0 {
+        while pos > 0 && is_continuation(&s, pos as usize) {
+            pos -= 1;
+        }
+    } else {
+        if is_continuation(&s, pos as usize) {
+            return Err(OtherError(
+                "initial position is a continuation byte".to_owned(),
+            ));
+        }
+        if n < 0 {
+            while n < 0 && pos > 0 {
+                pos -= 1;
+                while pos > 0 && is_continuation(&s, pos as usize) {
+                    pos -= 1;
+                }
+                n += 1;
+            }
+        } else {
+            n -= 1;
+            while n > 0 && pos < len {
+                pos += 1;
+                while is_continuation(&s, pos as usize) {
+                    pos += 1;
+                }
+                n -= 1;
+            }
+        }
+    }
+    Ok(vec![if n == 0 { int(pos + 1) } else { LuaValue::Nil }])
+}
+
+fn codes(ctx: &LuaState, args: &[LuaValue]) -> Result<Vec<LuaValue>> {
+    let s = check_string(args, 1, "codes")?;
+    let iterator = LuaFunction::new_native(ctx.get_ref_id(), "codes_iterator", codes_iterator);
+    Ok(vec![LuaValue::Function(iterator), LuaValue::Str(s.into()), int(0)])
+}
+
+/// Iterator of `utf8.codes`: the control value is the position of the previous
+/// character.
+fn codes_iterator(_ctx: &LuaState, args: &[LuaValue]) -> Result<Vec<LuaValue>> {
+    let s = check_string(args, 1, "codes")?;
+    let len = s.len() as isize;
+    let mut pos = check_integer(args, 2, "codes")? - 1;
+    if pos < 0 {
+        pos = 0;
+    } else if pos < len {
+        pos += 1;
+        while is_continuation(&s, pos as usize) {
+            pos += 1;
+        }
+    }
+    if pos >= len {
+        return Ok(vec![LuaValue::Nil]);
+    }
+    match decode(&s, pos as usize) {
+        Some((code, next)) if !is_continuation(&s, next) => Ok(vec![int(pos + 1), int(code as isize)]),
+        _ => Err(OtherError("invalid UTF-8 code".to_owned())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn string(s: &[u8]) -> LuaValue {
+        LuaValue::Str(s.into())
+    }
+
+    #[test]
+    fn test_char_and_codepoint() {
+        let ctx = LuaState::new();
+        let s = char(&ctx, &[int(72), int(0xE9), int(0x20AC), int(0x1F600)]).unwrap();
+        assert_eq!(s, vec![string("Hé€😀".as_bytes())]);
+        assert_eq!(
+            codepoint(&ctx, &[s[0].clone(), int(1), int(-1)]).unwrap(),
+            vec![int(72), int(0xE9), int(0x20AC), int(0x1F600)]
+        );
+        assert_eq!(codepoint(&ctx, &[s[0].clone(), int(2)]).unwrap(), vec![int(0xE9)]);
+        assert!(codepoint(&ctx, &[s[0].clone(), int(3)]).is_err());
+        assert!(codepoint(&ctx, &[s[0].clone(), int(1), int(11)]).is_err());
+        assert_eq!(char(&ctx, &[int(0x10FFFF)]).unwrap(), vec![string(b"\xF4\x8F\xBF\xBF")]);
+        assert!(char(&ctx, &[int(0x110000)]).is_err());
+        assert!(char(&ctx, &[int(0x7FFFFFFF)]).is_err());
+    }
+
+    #[test]
+    fn test_len() {
+        let ctx = LuaState::new();
+        let s = string("añb€".as_bytes());
+        assert_eq!(len(&ctx, &[s.clone()]).unwrap(), vec![int(4)]);
+        assert_eq!(len(&ctx, &[s.clone(), int(4)]).unwrap(), vec![int(2)]);
+        assert_eq!(len(&ctx, &[s.clone(), int(3)]).unwrap(), vec![LuaValue::Nil, int(3)]);
+        assert_eq!(len(&ctx, &[s.clone(), int(-3)]).unwrap(), vec![int(1)]);
+        assert_eq!(len(&ctx, &[s.clone(), int(8)]).unwrap(), vec![int(0)]);
+        assert!(len(&ctx, &[s.clone(), int(9)]).is_err());
+        assert_eq!(
+            len(&ctx, &[string(b"ab\xFFc")]).unwrap(),
+            vec![LuaValue::Nil, int(3)]
+        );
+        // Overlong encoding of '/'
+        assert_eq!(
+            len(&ctx, &[string(b"\xC0\xAF")]).unwrap(),
+            vec![LuaValue::Nil, int(1)]
+        );
+    }
+
+    #[test]
+    fn test_offset() {
+        let ctx = LuaState::new();
+        let s = string("añb€".as_bytes());
+        assert_eq!(offset(&ctx, &[s.clone(), int(3)]).unwrap(), vec![int(4)]);
+        assert_eq!(offset(&ctx, &[s.clone(), int(-1)]).unwrap(), vec![int(5)]);
+        assert_eq!(offset(&ctx, &[s.clone(), int(5)]).unwrap(), vec![int(8)]);
+        assert_eq!(offset(&ctx, &[s.clone(), int(6)]).unwrap(), vec![LuaValue::Nil]);
+        assert_eq!(offset(&ctx, &[s.clone(), int(0), int(7)]).unwrap(), vec![int(5)]);
+        assert!(offset(&ctx, &[s.clone(), int(1), int(3)]).is_err());
+    }
+
+    #[test]
+    fn test_codes() {
+        let ctx = LuaState::new();
+        let s = string("a€b".as_bytes());
+        let mut control = int(0);
+        let mut seen = Vec::new();
+        loop {
+            let step = codes_iterator(&ctx, &[s.clone(), control]).unwrap();
+            if step[0] == LuaValue::Nil {
+                break;
+            }
+            seen.push((step[0].clone(), step[1].clone()));
+            control = step[0].clone();
+        }
+        assert_eq!(
+            seen,
+            vec![(int(1), int(0x61)), (int(2), int(0x20AC)), (int(5), int(0x62))]
+        );
+        assert!(codes_iterator(&ctx, &[string(b"\xE2\x82"), int(0)]).is_err());
+    }
+}
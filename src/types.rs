@@ -1,86 +1,369 @@
+use std::any::{self, Any, TypeId};
 use std::rc::Rc;
 use std::collections::HashMap;
-use std::cell::{Cell, RefCell};
+use std::cell::{Cell, Ref, RefCell, RefMut};
 use std::hash::{Hash, Hasher};
-
-use std::collections::vec_deque::VecDeque;
+use std::fmt;
+use std::time::Instant;
 
 use super::{LuaError, Result};
+use super::bytecode::Frame;
+use super::chunk::{self, Chunk};
+use super::convert::{FromLua, FromLuaMulti, HostFunction, IntoLua, IntoLuaMulti};
+use super::expression;
+use super::interner::{BuildKeyHasher, Interner, LuaString, Names};
+use super::sanitizer::{Finding, Sanitizer, Sanitizers};
+use super::stdlib;
+use super::stdlib::Capabilities;
 
-pub type Scope = LuaTable;
+/// Most positional slots a table reserves before values are stored in them.
+const MAX_PREALLOCATED: usize = 1 << 12;
 
 #[derive(Debug)]
 pub struct LuaState {
     last_id: Cell<usize>,
     global: LuaTable,
-    scope_stack: VecDeque<Scope>,
+    registry: LuaTable,
+    interner: RefCell<Interner>,
+    names: Names,
+    sanitizer: Sanitizer,
+    random_state: Cell<[u64; 4]>,
+    start_time: Instant,
+    capabilities: Capabilities,
+    call_stack: RefCell<Vec<CallInfo>>,
+    hook: RefCell<Option<Hook>>,
+    /// Set while a hook runs, since hooks don't fire from inside one another.
+    in_hook: Cell<bool>,
+    /// Statements run since the last count event.
+    hook_counter: Cell<usize>,
+    /// The stack where the last error was raised, kept since it unwinds on the way out.
+    error_traceback: RefCell<Option<String>>,
+}
+
+/// How the calling code referred to a function, e.g. `global` for `print` in
+/// `print(x)` or `method` for `m` in `obj:m()`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CallName {
+    pub name: String,
+    pub kind: &'static str,
+}
+
+/// A function call in progress, as seen by the `debug` library.
+#[derive(Debug)]
+pub struct CallInfo {
+    pub function: LuaFunction,
+    pub name: Option<CallName>,
+    /// Where a chunk keeps its locals while it runs.
+    pub frame: RefCell<Option<Rc<Frame>>>,
+    /// Line of the statement being run, or -1 when it isn't known.
+    pub current_line: Cell<isize>,
+}
+
+/// The events a hook is called for.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct HookMask {
+    pub call: bool,
+    pub ret: bool,
+    pub line: bool,
+}
+
+/// A function installed by `debug.sethook`. It also receives a count event every
+/// `count` statements when `count` isn't zero.
+#[derive(Debug, Clone)]
+pub struct Hook {
+    pub function: LuaFunction,
+    pub mask: HookMask,
+    pub count: usize,
 }
 
 impl LuaState {
     pub fn new() -> LuaState {
-        let mut ret = LuaState {
-            last_id: Cell::new(0),
+        LuaState::with_capabilities(Capabilities::default())
+    }
+
+    /// Creates a state whose standard library may reach outside of the interpreter as
+    /// far as `capabilities` allows it. `new` grants nothing.
+    pub fn with_capabilities(capabilities: Capabilities) -> LuaState {
+        let mut interner = Interner::new();
+        let names = Names::new(&mut interner);
+        let ret = LuaState {
+            // The global table and the registry take the first two identifiers.
+            last_id: Cell::new(1),
             global: LuaTable::new(0),
-            scope_stack: VecDeque::new(),
+            registry: LuaTable::new(1),
+            interner: RefCell::new(interner),
+            names,
+            sanitizer: Sanitizer::default(),
+            random_state: Cell::new([0; 4]),
+            start_time: Instant::now(),
+            capabilities,
+            call_stack: RefCell::new(Vec::new()),
+            hook: RefCell::new(None),
+            in_hook: Cell::new(false),
+            hook_counter: Cell::new(0),
+            error_traceback: RefCell::new(None),
         };
 
-        ret.push_scope();
-        let table = LuaValue::Table(ret.global.clone());
-        ret.get_local_scope()
-            .unwrap()
-            .set_string("_ENV".to_owned(), &table);
+        stdlib::open_libs(&ret, &ret.capabilities);
         return ret;
     }
 
+    /// Lets `require` find modules through `lookup`, which receives the name of a module
+    /// and returns its source code, or `None` when it doesn't provide that module. It is
+    /// tried after `package.preload` and `package.path`.
+    pub fn add_searcher<F>(&self, lookup: F) -> Result<()>
+    where
+        F: Fn(&str) -> Option<Vec<u8>> + 'static,
+    {
+        stdlib::package::add_searcher(self, lookup)
+    }
+
+    /// Runs a chunk of source code in the global environment and returns its results.
+    /// `chunkname` names the chunk in messages: "=name" stands for itself, "@file" for
+    /// a file, and anything else for the source code.
+    pub fn exec(&self, source: &[u8], chunkname: &str) -> Result<Vec<LuaValue>> {
+        self.load(source, chunkname)?.call(self, &[])
+    }
+
+    /// Compiles a chunk of source code without running it. Its `_ENV` is the global table.
+    pub fn load(&self, source: &[u8], chunkname: &str) -> Result<LuaFunction> {
+        chunk::check_mode(source, b"t")?;
+        let env = LuaValue::Table(self.global.clone());
+        chunk::load(self, source.to_vec(), chunkname, env)
+    }
+
+    /// Compiles a file as `loadfile` does, or the standard input when there is no file
+    /// name. A first line starting with '#' is skipped, and the file may hold a
+    /// precompiled chunk.
+    pub fn load_file(&self, filename: Option<&[u8]>) -> Result<LuaFunction> {
+        let env = LuaValue::Table(self.global.clone());
+        stdlib::base::load_file(self, filename.map(|f| f.to_vec()), b"bt", env)
+    }
+
+    /// The stack traceback of the last error raised by a call, in the format of
+    /// `debug.traceback`, if it wasn't taken yet.
+    pub fn take_traceback(&self) -> Option<String> {
+        self.error_traceback.borrow_mut().take()
+    }
+
+    /// Turns on the checks of `sanitizers`, and off the others.
+    pub fn set_sanitizers(&self, sanitizers: Sanitizers) {
+        self.sanitizer.configure(sanitizers);
+    }
+
+    /// The warnings of the sanitizers since the last call, the oldest first.
+    pub fn take_findings(&self) -> Vec<Finding> {
+        self.sanitizer.take_findings()
+    }
+
+    /// Evaluates an expression, or a list of them, in the global environment.
+    pub fn eval<R: FromLuaMulti>(&self, expr: &str) -> Result<R> {
+        let source = format!("return {}", expr);
+        let values = self.exec(source.as_bytes(), expr)?;
+        R::from_lua_multi(values, self)
+    }
+
+    /// Calls a function, or any value with a `__call` metamethod.
+    pub fn call<A: IntoLuaMulti, R: FromLuaMulti>(&self, function: &LuaValue, args: A) -> Result<R> {
+        let args = args.into_lua_multi(self)?;
+        let values = expression::call_function(function, &args, self)?;
+        R::from_lua_multi(values, self)
+    }
+
+    /// The table of global variables, shared by all the chunks run in the state.
+    pub fn globals(&self) -> LuaTable {
+        self.global.clone()
+    }
+
+    /// Reads a global variable, going through the metatable of the global table.
+    pub fn get_global<T: FromLua>(&self, name: &str) -> Result<T> {
+        let key = LuaValue::Str(name.as_bytes().into());
+        let value = expression::index_value(&LuaValue::Table(self.global.clone()), &key, self)?;
+        T::from_lua(value, self)
+    }
+
+    pub fn set_global<T: IntoLua>(&self, name: &str, value: T) -> Result<()> {
+        let key = LuaValue::Str(name.as_bytes().into());
+        let value = value.into_lua(self)?;
+        expression::set_index(&LuaValue::Table(self.global.clone()), &key, &value, self)
+    }
+
+    /// Wraps a Rust function or closure as a Lua function. A closure keeps whatever it
+    /// captured for as long as the function lives.
+    pub fn create_native<F>(&self, name: &str, function: F) -> LuaValue
+    where
+        F: Fn(&LuaState, &[LuaValue]) -> Result<Vec<LuaValue>> + 'static,
+    {
+        LuaValue::Function(LuaFunction::new_closure(self.get_ref_id(), name, function))
+    }
+
+    /// Wraps a Rust closure with typed parameters and results, such as
+    /// `|a: i64, name: String| -> Result<bool>`. The arguments are converted before the
+    /// call, and a wrong one raises a "bad argument" error naming the function `name`.
+    pub fn create_function<A, R, F>(&self, name: &str, function: F) -> LuaValue
+    where
+        F: HostFunction<A, R> + 'static,
+    {
+        let fname = name.to_owned();
+        self.create_native(name, move |ctx: &LuaState, args: &[LuaValue]| {
+            function.call_host(ctx, args, &fname)
+        })
+    }
+
+    /// Makes a Rust function or closure available to scripts as the global `name`.
+    pub fn register_native<F>(&self, name: &str, function: F)
+    where
+        F: Fn(&LuaState, &[LuaValue]) -> Result<Vec<LuaValue>> + 'static,
+    {
+        let function = self.create_native(name, function);
+        self.global.map_set(&LuaValue::Str(self.intern(name.as_bytes())), &function);
+    }
+
+    pub fn get_global_table(&self) -> &LuaTable {
+        &self.global
+    }
+
+    /// Table where the libraries keep the values scripts must not reach, such as the
+    /// default files of `io`.
+    pub fn get_registry(&self) -> &LuaTable {
+        &self.registry
+    }
+
+    /// The checks that run in the state, and what they found.
+    pub fn get_sanitizer(&self) -> &Sanitizer {
+        &self.sanitizer
+    }
+
+    /// State of the pseudo-random generator behind `math.random`.
+    pub fn get_random_state(&self) -> &Cell<[u64; 4]> {
+        &self.random_state
+    }
+
+    /// Instant at which the state was created, the origin of `os.clock`.
+    pub fn get_start_time(&self) -> Instant {
+        self.start_time
+    }
+
+    /// What the host allowed the standard library to reach when creating the state.
+    pub fn get_capabilities(&self) -> &Capabilities {
+        &self.capabilities
+    }
+
+    /// The string with the given bytes, shared with the equal strings of the state when
+    /// it is short.
+    pub fn intern(&self, bytes: &[u8]) -> LuaString {
+        self.interner.borrow_mut().intern(bytes)
+    }
+
+    /// The strings looked up by the interpreter and the standard library, already
+    /// interned.
+    pub fn names(&self) -> &Names {
+        &self.names
+    }
+
+    /// Interns the value if it is a string, so that it can be a key compared by pointer.
+    pub fn intern_value(&self, value: LuaValue) -> LuaValue {
+        match value {
+            LuaValue::Str(s) => LuaValue::Str(self.interner.borrow_mut().intern_string(&s)),
+            value => value,
+        }
+    }
+
     pub fn get_ref_id(&self) -> usize {
         self.last_id.set(self.last_id.get() + 1);
         return self.last_id.get();
     }
 
-    pub fn resolve_name(&self, name: &String) -> Option<&Scope> {
-        for scope in self.scope_stack.iter() {
-            if scope.contains_key(name) {
-                return Some(scope);
-            }
+    /// The calls in progress, the innermost last.
+    pub fn get_call_stack(&self) -> Ref<'_, Vec<CallInfo>> {
+        self.call_stack.borrow()
+    }
+
+    fn push_call(&self, function: &LuaFunction, name: Option<CallName>) {
+        if self.call_stack.borrow().is_empty() {
+            *self.error_traceback.borrow_mut() = None;
         }
-        return None;
+        self.call_stack.borrow_mut().push(CallInfo {
+            function: function.clone(),
+            name,
+            frame: RefCell::new(None),
+            current_line: Cell::new(-1),
+        });
     }
 
-    pub fn resolve_name_mut(&mut self, name: &String) -> Option<&mut Scope> {
-        for scope in self.scope_stack.iter_mut() {
-            if scope.contains_key(name) {
-                return Some(scope);
-            }
+    /// Keeps the stack as it is when an error is raised, unless the error comes from a
+    /// deeper call that already did.
+    fn record_traceback(&self) {
+        if self.error_traceback.borrow().is_none() {
+            let lines = stdlib::debug::traceback_lines(self, 0);
+            *self.error_traceback.borrow_mut() = Some(format!("stack traceback:{}", lines));
         }
-        return None;
     }
 
-    pub fn get_local_scope(&self) -> Option<&Scope> {
-        self.scope_stack.front()
+    fn pop_call(&self) {
+        self.call_stack.borrow_mut().pop();
     }
 
-    pub fn get_mutable_local_scope(&mut self) -> Option<&mut Scope> {
-        self.scope_stack.front_mut()
+    pub fn get_hook(&self) -> Option<Hook> {
+        self.hook.borrow().clone()
     }
 
-    pub fn push_scope(&mut self) {
-        let id = self.get_ref_id();
-        self.scope_stack.push_front(LuaTable::new(id));
+    pub fn set_hook(&self, hook: Option<Hook>) {
+        self.hook_counter.set(0);
+        *self.hook.borrow_mut() = hook;
     }
 
-    pub fn pop_scope(&mut self) {
-        if self.scope_stack.is_empty() {
-            panic!("No scope to pop!")
+    /// The hook to call for an event, unless a hook is already running.
+    fn active_hook(&self) -> Option<Hook> {
+        if self.in_hook.get() {
+            return None;
         }
-        self.scope_stack.pop_front();
+        self.get_hook()
+    }
+
+    fn run_hook(&self, hook: &Hook, event: &str, line: Option<isize>) -> Result<()> {
+        let line = line.map_or(LuaValue::Nil, |l| LuaValue::Number(Number::Int(l)));
+        self.in_hook.set(true);
+        let result = hook.function.call(self, &[LuaValue::Str(event.as_bytes().into()), line]);
+        self.in_hook.set(false);
+        result.map(|_| ())
+    }
+
+    /// Records that the running function reached a statement at `line`, if it is known,
+    /// and runs the line and count hooks.
+    pub fn trace_statement(&self, line: Option<isize>) -> Result<()> {
+        let new_line = match (line, self.call_stack.borrow().last()) {
+            (Some(line), Some(info)) => info.current_line.replace(line) != line,
+            _ => false,
+        };
+        let hook = match self.active_hook() {
+            Some(hook) => hook,
+            None => return Ok(()),
+        };
+        if hook.count > 0 {
+            let counter = self.hook_counter.get() + 1;
+            if counter >= hook.count {
+                self.hook_counter.set(0);
+                self.run_hook(&hook, "count", None)?;
+            } else {
+                self.hook_counter.set(counter);
+            }
+        }
+        if hook.mask.line && new_line {
+            self.run_hook(&hook, "line", line)?;
+        }
+        Ok(())
     }
 }
 
 #[derive(Debug, Eq)]
 struct CoreTable {
     pub ref_id: usize,
-    pub map: RefCell<HashMap<LuaValue, LuaValue>>,
+    pub map: RefCell<HashMap<LuaValue, LuaValue, BuildKeyHasher>>,
     pub vector: RefCell<Vec<LuaValue>>,
+    pub metatable: RefCell<Option<LuaTable>>,
+    /// Where the script created the table, when a sanitizer keeps track of it.
+    pub site: RefCell<Option<String>>,
 }
 
 impl CoreTable {}
@@ -107,26 +390,163 @@ impl LuaTable {
         LuaTable {
             content: Rc::new(CoreTable {
                 ref_id: id,
-                map: RefCell::new(HashMap::new()),
+                map: RefCell::new(HashMap::default()),
                 vector: RefCell::new(Vec::new()),
+                metatable: RefCell::new(None),
+                site: RefCell::new(None),
             }),
         }
     }
 
+    /// Creates a table with room for `capacity` positional values. The capacity is only a
+    /// hint, which precompiled chunks may set to anything, so no more than
+    /// `MAX_PREALLOCATED` slots are reserved up front.
     pub fn with_capacity(id: usize, capacity: usize) -> LuaTable {
         LuaTable {
             content: Rc::new(CoreTable {
                 ref_id: id,
-                map: RefCell::new(HashMap::new()),
-                vector: RefCell::new(Vec::with_capacity(capacity)),
+                map: RefCell::new(HashMap::default()),
+                vector: RefCell::new(Vec::with_capacity(capacity.min(MAX_PREALLOCATED))),
+                metatable: RefCell::new(None),
+                site: RefCell::new(None),
             }),
         }
     }
 
+    pub fn get_ref_id(&self) -> usize {
+        self.content.ref_id
+    }
+
+    pub fn get_metatable(&self) -> Option<LuaTable> {
+        self.content.metatable.borrow().clone()
+    }
+
+    pub fn set_metatable(&self, metatable: Option<LuaTable>) {
+        *self.content.metatable.borrow_mut() = metatable;
+    }
+
+    pub fn get_site(&self) -> Option<String> {
+        self.content.site.borrow().clone()
+    }
+
+    pub fn set_site(&self, site: String) {
+        *self.content.site.borrow_mut() = Some(site);
+    }
+
+    /// How many indices are missing below the greatest positive integer key, without
+    /// sorting the keys. The table is a proper sequence when none is.
+    pub fn missing_indices(&self) -> usize {
+        let seq = self.content.vector.borrow();
+        let (mut count, mut last) = (0, 0);
+        for i in (1..seq.len() + 1).filter(|&i| seq[i - 1] != LuaValue::Nil) {
+            count += 1;
+            last = i;
+        }
+        for key in self.content.map.borrow().keys() {
+            if let LuaValue::Number(Number::Int(i)) = *key {
+                if i > 0 {
+                    count += 1;
+                    last = last.max(i as usize);
+                }
+            }
+        }
+        last - count
+    }
+
+    /// The first `limit` indices missing below the greatest positive integer key.
+    pub fn sequence_holes(&self, limit: usize) -> Vec<usize> {
+        let seq = self.content.vector.borrow();
+        let mut present: Vec<usize> = (1..seq.len() + 1).filter(|&i| seq[i - 1] != LuaValue::Nil).collect();
+        present.extend(self.content.map.borrow().keys().filter_map(|key| match *key {
+            LuaValue::Number(Number::Int(i)) if i > 0 => Some(i as usize),
+            _ => None,
+        }));
+        present.sort();
+        let mut holes = Vec::new();
+        let mut next = 1;
+        for &i in present.iter() {
+            holes.extend((next..i).take(limit - holes.len()));
+            next = i + 1;
+        }
+        holes
+    }
+
+    /// Length of the table, as given by the `#` operator when there is no `__len`.
+    pub fn len(&self) -> usize {
+        self.sequence_border()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.content.vector.borrow().is_empty() && self.content.map.borrow().is_empty()
+    }
+
+    /// Copy of all the pairs of the table: the sequence in order, then the other pairs
+    /// in no particular order.
+    pub fn pairs(&self) -> Vec<(LuaValue, LuaValue)> {
+        let mut pairs: Vec<(LuaValue, LuaValue)> = self.content.vector
+            .borrow()
+            .iter()
+            .enumerate()
+            .map(|(i, v)| (LuaValue::Number(Number::Int(i as isize + 1)), v.clone()))
+            .collect();
+        pairs.extend(self.hash_entries());
+        pairs
+    }
+
+    /// The sequence part of the table never ends with a nil value, and the next index is
+    /// never in the hash part, so its length is always a valid border.
     pub fn sequence_border(&self) -> usize {
         return self.content.vector.borrow().len();
     }
 
+    /// Returns a copy of the values stored from index `first` to `last` included, which
+    /// must be within the sequence part of the table.
+    pub fn sequence_range(&self, first: usize, last: usize) -> Vec<LuaValue> {
+        assert!(first >= 1 && last <= self.sequence_border());
+        if first > last {
+            return Vec::new();
+        }
+        return self.content.vector.borrow()[first - 1..last].to_vec();
+    }
+
+    /// Inserts a value at `idx` in the sequence part, shifting up the following elements.
+    pub fn sequence_insert(&self, idx: usize, value: LuaValue) {
+        assert!(idx >= 1 && idx <= self.sequence_border() + 1);
+        self.content.vector.borrow_mut().insert(idx - 1, value);
+        self.trim_sequence();
+        self.extend_sequence();
+    }
+
+    /// Removes the value at `idx` from the sequence part, shifting down the following
+    /// elements.
+    pub fn sequence_remove(&self, idx: usize) -> LuaValue {
+        assert!(idx >= 1 && idx <= self.sequence_border());
+        let value = self.content.vector.borrow_mut().remove(idx - 1);
+        self.trim_sequence();
+        return value;
+    }
+
+    // Moves to the sequence part the values of the hash part that directly follow it.
+    fn extend_sequence(&self) {
+        let mut seq = self.content.vector.borrow_mut();
+        let mut map = self.content.map.borrow_mut();
+        loop {
+            let next = LuaValue::Number(Number::Int(seq.len() as isize + 1));
+            match map.remove(&next) {
+                Some(value) => seq.push(value),
+                None => break,
+            }
+        }
+    }
+
+    // Removes the trailing nil values of the sequence part.
+    fn trim_sequence(&self) {
+        let mut seq = self.content.vector.borrow_mut();
+        while seq.last() == Some(&LuaValue::Nil) {
+            seq.pop();
+        }
+    }
+
     pub fn set(&self, key: &LuaValue, value: &LuaValue) -> Result<()> {
         match key {
             &LuaValue::Nil => Err(LuaError::IndexError(
@@ -139,11 +559,11 @@ impl LuaTable {
                             "Using NaN as a table index".to_owned(),
                         ))
                     } else {
-                        let round = f.round();
-                        if round == f {
-                            self.set(&LuaValue::Number(Number::Int(round as isize)), value)?
-                        } else {
-                            self.map_set(key, value)
+                        // Only the floats with an integer of the same value are stored
+                        // as it, not infinities or integral floats out of range.
+                        match num.to_exact_int() {
+                            Some(i) => self.set(&LuaValue::Number(Number::Int(i)), value)?,
+                            None => self.map_set(key, value),
                         };
                         Ok(())
                     }
@@ -163,13 +583,6 @@ impl LuaTable {
         }
     }
 
-    fn contains_key(&self, key: &String) -> bool {
-        self.content
-            .map
-            .borrow()
-            .contains_key(&LuaValue::Str(key.clone()))
-    }
-
     fn map_set(&self, key: &LuaValue, value: &LuaValue) {
         let mut map = self.content.map.borrow_mut();
         match *value {
@@ -185,22 +598,37 @@ impl LuaTable {
     }
 
     fn sequence_set(&self, idx: isize, value: &LuaValue) {
-        let mut seq = self.content.vector.borrow_mut();
         assert!(idx >= 1);
 
         let idx = (idx - 1) as usize;
-        if idx == seq.len() {
-            seq.push(value.clone());
+        let len = self.sequence_border();
+        if idx == len {
+            if *value != LuaValue::Nil {
+                self.content.vector.borrow_mut().push(value.clone());
+                self.extend_sequence();
+            }
         } else {
-            seq[idx] = value.clone();
+            self.content.vector.borrow_mut()[idx] = value.clone();
+            if idx + 1 == len {
+                self.trim_sequence();
+            }
         }
     }
 
-    pub fn set_string(&self, key: String, value: &LuaValue) {
-        self.map_set(&LuaValue::Str(key), value)
+    /// Copy of the pairs stored in the hash part of the table.
+    pub fn hash_entries(&self) -> Vec<(LuaValue, LuaValue)> {
+        self.content.map
+            .borrow()
+            .iter()
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect()
     }
-    pub fn get_string(&self, key: String) -> LuaValue {
-        self.map_get(&LuaValue::Str(key))
+
+    pub fn set_string(&self, key: &LuaString, value: &LuaValue) {
+        self.map_set(&LuaValue::Str(key.clone()), value)
+    }
+    pub fn get_string(&self, key: &LuaString) -> LuaValue {
+        self.map_get(&LuaValue::Str(key.clone()))
     }
 
     pub fn get(&self, key: &LuaValue) -> LuaValue {
@@ -211,16 +639,14 @@ impl LuaTable {
                     if f.is_nan() {
                         LuaValue::Nil
                     } else {
-                        let round = f.round();
-                        if round == f {
-                            self.get(&LuaValue::Number(Number::Int(round as isize)))
-                        } else {
-                            self.map_get(key)
+                        match num.to_exact_int() {
+                            Some(i) => self.get(&LuaValue::Number(Number::Int(i))),
+                            None => self.map_get(key),
                         }
                     }
                 }
                 &Number::Int(i) => {
-                    if i < 1 || (i as usize) > self.content.vector.borrow().len() + 1 {
+                    if i < 1 || (i as usize) > self.content.vector.borrow().len() {
                         self.map_get(key)
                     } else {
                         self.sequence_get(&i)
@@ -265,16 +691,63 @@ impl Number {
             &Number::Int(i) => i,
         }
     }
+    /// Returns the integer value of the number if it has an exact representation, as
+    /// required by the functions of the standard library expecting integers.
+    pub fn to_exact_int(&self) -> Option<isize> {
+        match self {
+            &Number::Int(i) => Some(i),
+            &Number::Float(f) => {
+                let min = isize::min_value() as f64;
+                if f.floor() == f && f >= min && f < -min {
+                    Some(f as isize)
+                } else {
+                    None
+                }
+            }
+        }
+    }
 }
 impl ToString for Number {
     fn to_string(&self) -> String {
         match self {
-            &Number::Float(f) => f.to_string(),
+            &Number::Float(f) => float_to_string(f),
             &Number::Int(i) => i.to_string()
         }
     }
 }
 
+/// Formats a float as "%.14g" does, adding ".0" when it would read as an integer.
+fn float_to_string(f: f64) -> String {
+    if f.is_nan() {
+        return if f.is_sign_negative() { "-nan" } else { "nan" }.to_owned();
+    }
+    if f.is_infinite() {
+        return if f < 0. { "-inf" } else { "inf" }.to_owned();
+    }
+    // Rounding to 14 significant digits may change the exponent, so take it from there.
+    let scientific = format!("{:.13e}", f);
+    let (mantissa, exponent) = scientific.split_at(scientific.find('e').unwrap());
+    let exponent: i32 = exponent[1..].parse().unwrap();
+    let trim = |digits: &str| -> String {
+        if digits.contains('.') {
+            digits.trim_end_matches('0').trim_end_matches('.').to_owned()
+        } else {
+            digits.to_owned()
+        }
+    };
+    if exponent < -4 || exponent >= 14 {
+        let sign = if exponent < 0 { '-' } else { '+' };
+        format!("{}e{}{:02}", trim(mantissa), sign, exponent.abs())
+    } else {
+        let fixed = trim(&format!("{:.*}", (13 - exponent) as usize, f));
+        if fixed.bytes().all(|c| c == b'-' || c.is_ascii_digit()) {
+            fixed + ".0"
+        } else {
+            fixed
+        }
+    }
+}
+
 // This trait is there to say that the equality is symmetric, reflexive and transitive,
 // which isn't the case for floats (NaN != NaN). However, for now these properties are
 // only used in the table hashmap, and the specs say that a Lua table won't ever have NaN
@@ -291,11 +764,407 @@ impl Hash for Number {
     }
 }
 
+pub type NativeFunction = fn(&LuaState, &[LuaValue]) -> Result<Vec<LuaValue>>;
+
+enum Callable {
+    Native(Box<dyn Fn(&LuaState, &[LuaValue]) -> Result<Vec<LuaValue>>>),
+    Chunk(Chunk),
+}
+
+struct CoreFunction {
+    pub ref_id: usize,
+    pub name: String,
+    pub callable: Callable,
+}
+
+impl PartialEq for CoreFunction {
+    fn eq(&self, other: &CoreFunction) -> bool {
+        return self.ref_id == other.ref_id;
+    }
+}
+
+impl Eq for CoreFunction {}
+
+impl Hash for CoreFunction {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.ref_id.hash(state);
+    }
+}
+
+#[derive(PartialEq, Eq, Hash, Clone)]
+pub struct LuaFunction {
+    content: Rc<CoreFunction>,
+}
+
+impl fmt::Debug for LuaFunction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "function: {}", self.content.name)
+    }
+}
+
+impl LuaFunction {
+    pub fn new_native(id: usize, name: &str, native: NativeFunction) -> LuaFunction {
+        LuaFunction::new_closure(id, name, native)
+    }
+
+    /// Wraps a Rust closure, which keeps whatever state it captured between calls.
+    pub fn new_closure<F>(id: usize, name: &str, closure: F) -> LuaFunction
+    where
+        F: Fn(&LuaState, &[LuaValue]) -> Result<Vec<LuaValue>> + 'static,
+    {
+        LuaFunction {
+            content: Rc::new(CoreFunction {
+                ref_id: id,
+                name: name.to_owned(),
+                callable: Callable::Native(Box::new(closure)),
+            }),
+        }
+    }
+
+    pub fn new_chunk(id: usize, chunk: Chunk) -> LuaFunction {
+        LuaFunction {
+            content: Rc::new(CoreFunction {
+                ref_id: id,
+                name: chunk.get_chunkname().to_owned(),
+                callable: Callable::Chunk(chunk),
+            }),
+        }
+    }
+
+    pub fn get_ref_id(&self) -> usize {
+        self.content.ref_id
+    }
+
+    pub fn get_name(&self) -> &str {
+        &self.content.name
+    }
+
+    /// The source code behind the function, unless it is native.
+    pub fn get_chunk(&self) -> Option<&Chunk> {
+        match self.content.callable {
+            Callable::Chunk(ref chunk) => Some(chunk),
+            Callable::Native(_) => None,
+        }
+    }
+
+    pub fn call(&self, ctx: &LuaState, args: &[LuaValue]) -> Result<Vec<LuaValue>> {
+        self.call_named(ctx, args, None)
+    }
+
+    /// Calls the function, `name` telling the `debug` library how the caller refers to
+    /// it.
+    pub fn call_named(&self, ctx: &LuaState, args: &[LuaValue], name: Option<CallName>) -> Result<Vec<LuaValue>> {
+        ctx.push_call(self, name);
+        let result = match ctx.active_hook() {
+            Some(ref hook) if hook.mask.call => ctx.run_hook(hook, "call", None),
+            _ => Ok(()),
+        };
+        let result = result.and_then(|_| match self.content.callable {
+            Callable::Native(ref native) => native(ctx, args),
+            Callable::Chunk(ref chunk) => chunk::call(chunk, ctx, args),
+        });
+        let result = match (result, ctx.active_hook()) {
+            (Ok(values), Some(ref hook)) if hook.mask.ret => ctx.run_hook(hook, "return", None).map(|_| values),
+            (result, _) => result,
+        };
+        if result.is_err() {
+            ctx.record_traceback();
+        }
+        ctx.pop_call();
+        result
+    }
+}
+
+struct CoreUserData {
+    pub ref_id: usize,
+    pub metatable: RefCell<Option<LuaTable>>,
+    pub type_id: TypeId,
+    pub data: RefCell<Box<dyn Any>>,
+}
+
+/// A Rust value handed to scripts, which can only act on it through its metatable.
+#[derive(Clone)]
+pub struct LuaUserData {
+    content: Rc<CoreUserData>,
+}
+
+impl PartialEq for LuaUserData {
+    fn eq(&self, other: &LuaUserData) -> bool {
+        self.content.ref_id == other.content.ref_id
+    }
+}
+
+impl Eq for LuaUserData {}
+
+impl Hash for LuaUserData {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.content.ref_id.hash(state);
+    }
+}
+
+impl fmt::Debug for LuaUserData {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "userdata: 0x{:08x}", self.content.ref_id)
+    }
+}
+
+impl LuaUserData {
+    pub fn new<T: Any>(id: usize, data: T, metatable: Option<LuaTable>) -> LuaUserData {
+        LuaUserData {
+            content: Rc::new(CoreUserData {
+                ref_id: id,
+                metatable: RefCell::new(metatable),
+                type_id: TypeId::of::<T>(),
+                data: RefCell::new(Box::new(data)),
+            }),
+        }
+    }
+
+    pub fn get_ref_id(&self) -> usize {
+        self.content.ref_id
+    }
+
+    pub fn get_metatable(&self) -> Option<LuaTable> {
+        self.content.metatable.borrow().clone()
+    }
+
+    pub fn set_metatable(&self, metatable: Option<LuaTable>) {
+        *self.content.metatable.borrow_mut() = metatable;
+    }
+
+    /// Whether the userdata holds a `T`. Unlike borrowing, this works while the value is
+    /// in use.
+    pub fn is<T: Any>(&self) -> bool {
+        self.content.type_id == TypeId::of::<T>()
+    }
+
+    fn check_type<T: Any>(&self) -> Result<()> {
+        if self.is::<T>() {
+            Ok(())
+        } else {
+            Err(LuaError::TypeError(format!(
+                "{} expected, got another userdata",
+                any::type_name::<T>()
+            )))
+        }
+    }
+
+    /// Borrows the value as a `T`, failing if it has another type or is borrowed mutably.
+    pub fn borrow<T: Any>(&self) -> Result<Ref<'_, T>> {
+        self.check_type::<T>()?;
+        match self.content.data.try_borrow() {
+            Ok(data) => Ok(Ref::map(data, |data| data.downcast_ref::<T>().unwrap())),
+            Err(_) => Err(LuaError::OtherError("userdata is already in use".to_owned())),
+        }
+    }
+
+    /// Borrows the value mutably as a `T`, failing if it has another type or is already
+    /// borrowed.
+    pub fn borrow_mut<T: Any>(&self) -> Result<RefMut<'_, T>> {
+        self.check_type::<T>()?;
+        match self.content.data.try_borrow_mut() {
+            Ok(data) => Ok(RefMut::map(data, |data| data.downcast_mut::<T>().unwrap())),
+            Err(_) => Err(LuaError::OtherError("userdata is already in use".to_owned())),
+        }
+    }
+}
+
 #[derive(Debug, PartialEq, Eq, Clone, Hash)]
 pub enum LuaValue {
     Nil,
     Number(Number),
     Boolean(bool),
-    Str(String),
+    Str(LuaString),
     Table(LuaTable),
+    Function(LuaFunction),
+    UserData(LuaUserData),
+}
+
+impl LuaValue {
+    /// The name of the value's type, as returned by the `type` function.
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            &LuaValue::Nil => "nil",
+            &LuaValue::Number(_) => "number",
+            &LuaValue::Boolean(_) => "boolean",
+            &LuaValue::Str(_) => "string",
+            &LuaValue::Table(_) => "table",
+            &LuaValue::Function(_) => "function",
+            &LuaValue::UserData(_) => "userdata",
+        }
+    }
+
+    pub fn is_nil(&self) -> bool {
+        *self == LuaValue::Nil
+    }
+
+    pub fn as_bool(&self) -> Option<bool> {
+        match *self {
+            LuaValue::Boolean(b) => Some(b),
+            _ => None,
+        }
+    }
+
+    /// The value of a number with an exact integer representation, without converting
+    /// strings.
+    pub fn as_integer(&self) -> Option<isize> {
+        match *self {
+            LuaValue::Number(ref n) => n.to_exact_int(),
+            _ => None,
+        }
+    }
+
+    pub fn as_float(&self) -> Option<f64> {
+        match *self {
+            LuaValue::Number(ref n) => Some(n.to_float()),
+            _ => None,
+        }
+    }
+
+    pub fn as_bytes(&self) -> Option<&[u8]> {
+        match *self {
+            LuaValue::Str(ref s) => Some(s),
+            _ => None,
+        }
+    }
+
+    /// The contents of a string, if they are valid UTF-8.
+    pub fn as_str(&self) -> Option<&str> {
+        self.as_bytes().and_then(|s| ::std::str::from_utf8(s).ok())
+    }
+
+    pub fn as_table(&self) -> Option<&LuaTable> {
+        match *self {
+            LuaValue::Table(ref t) => Some(t),
+            _ => None,
+        }
+    }
+
+    pub fn as_function(&self) -> Option<&LuaFunction> {
+        match *self {
+            LuaValue::Function(ref f) => Some(f),
+            _ => None,
+        }
+    }
+
+    pub fn as_userdata(&self) -> Option<&LuaUserData> {
+        match *self {
+            LuaValue::UserData(ref u) => Some(u),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, PartialEq)]
+    struct Point {
+        x: i32,
+        y: i32,
+    }
+
+    #[test]
+    fn test_userdata_borrowing() {
+        let point = LuaUserData::new(1, Point { x: 1, y: 2 }, None);
+        assert!(point.is::<Point>());
+        assert!(!point.is::<String>());
+        point.borrow_mut::<Point>().unwrap().x = 3;
+        assert_eq!(*point.borrow::<Point>().unwrap(), Point { x: 3, y: 2 });
+        assert!(point.borrow::<String>().is_err());
+        {
+            let _reading = point.borrow::<Point>().unwrap();
+            assert!(point.borrow_mut::<Point>().is_err());
+            assert!(point.is::<Point>());
+        }
+        assert!(point.borrow_mut::<Point>().is_ok());
+    }
+
+    #[test]
+    fn test_sequence_holes() {
+        let int = |i| LuaValue::Number(Number::Int(i));
+        let table = LuaTable::new(1);
+        assert_eq!((table.sequence_holes(8), table.missing_indices()), (vec![], 0));
+        for &i in [1, 2, 4, 7].iter() {
+            table.set(&int(i), &int(i)).unwrap();
+        }
+        table.set(&LuaValue::Str("x".into()), &int(0)).unwrap();
+        table.set(&int(-1), &int(0)).unwrap();
+        assert_eq!((table.sequence_holes(8), table.missing_indices()), (vec![3, 5, 6], 3));
+        assert_eq!(table.sequence_holes(2), vec![3, 5]);
+        table.set(&int(2), &LuaValue::Nil).unwrap();
+        assert_eq!((table.sequence_holes(8), table.missing_indices()), (vec![2, 3, 5, 6], 4));
+    }
+
+    #[test]
+    fn test_globals() {
+        let ctx = LuaState::new();
+        ctx.set_global("answer", 42).unwrap();
+        assert_eq!(ctx.get_global::<i64>("answer"), Ok(42));
+        assert_eq!(ctx.get_global::<Option<String>>("missing"), Ok(None));
+        assert!(ctx.get_global::<String>("answer").is_ok());
+        assert!(ctx.get_global::<LuaTable>("answer").is_err());
+        let globals = ctx.globals();
+        assert_eq!(globals.get_string(&ctx.intern(b"answer")).as_integer(), Some(42));
+        assert!(ctx.get_global::<LuaTable>("string").is_ok());
+
+        let add = ctx.create_function("add", |a: i64, b: i64| Ok(a + b));
+        let sum: i64 = ctx.call(&add, (2, 3)).unwrap();
+        assert_eq!(sum, 5);
+        assert!(ctx.call::<_, i64>(&LuaValue::Nil, ()).is_err());
+    }
+
+    #[test]
+    fn test_table_traversal() {
+        let table = LuaTable::new(1);
+        assert!(table.is_empty());
+        let key = LuaValue::Str("k".into());
+        for i in 1..4 {
+            table.set(&LuaValue::Number(Number::Int(i)), &LuaValue::Boolean(true)).unwrap();
+        }
+        table.set(&key, &LuaValue::Number(Number::Float(0.5))).unwrap();
+        assert_eq!(table.len(), 3);
+        let pairs = table.pairs();
+        assert_eq!(pairs.len(), 4);
+        assert_eq!(pairs[0].0.as_integer(), Some(1));
+        assert_eq!(pairs[3], (key, LuaValue::Number(Number::Float(0.5))));
+        assert_eq!(pairs[3].1.as_float(), Some(0.5));
+        assert_eq!(pairs[3].0.as_str(), Some("k"));
+        assert!(pairs[0].1.as_table().is_none());
+    }
+
+    #[test]
+    fn test_float_to_string() {
+        let show = |f: f64| Number::Float(f).to_string();
+        assert_eq!(show(1.), "1.0");
+        assert_eq!(show(-0.5), "-0.5");
+        assert_eq!(show(0.1), "0.1");
+        assert_eq!(show(1. / 3.), "0.33333333333333");
+        assert_eq!(show(1e15), "1e+15");
+        assert_eq!(show(123456789012345.), "1.2345678901234e+14");
+        assert_eq!(show(2.5e-7), "2.5e-07");
+        assert_eq!(show(1. / 0.), "inf");
+        assert_eq!(Number::Int(3).to_string(), "3");
+    }
+
+    #[test]
+    fn test_userdata_identity() {
+        let first = LuaValue::UserData(LuaUserData::new(1, 0u8, None));
+        let second = LuaValue::UserData(LuaUserData::new(2, 0u8, None));
+        assert_eq!(first.clone(), first);
+        assert!(first != second);
+        assert_eq!(first.type_name(), "userdata");
+
+        let table = LuaTable::new(3);
+        table.set(&first, &LuaValue::Boolean(true)).unwrap();
+        assert_eq!(table.get(&first), LuaValue::Boolean(true));
+        assert_eq!(table.get(&second), LuaValue::Nil);
+
+        if let LuaValue::UserData(ref u) = first {
+            let metatable = LuaTable::new(4);
+            u.set_metatable(Some(metatable.clone()));
+            assert_eq!(u.get_metatable(), Some(metatable));
+        }
+    }
 }